@@ -0,0 +1,535 @@
+use std::f32::consts::PI;
+
+use rand::Rng;
+
+use crate::image_handling::PixelF;
+use crate::integrator::{accumulated, scaled, sky_color, Integrator, TraceContext, SHADOW_EPSILON};
+use crate::light::Light;
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::sampler::rng;
+use crate::stats;
+use crate::vectors::V3;
+
+// A bidirectional path tracer. For every camera sample we trace two paths: one out from the camera
+// like usual, and one out from a random point on a random light. Then we join every point on one
+// to every point on the other with a shadow ray, and each of those joins is a whole path from the
+// light to the camera. Paths of the same length can be made lots of ways, by taking more points
+// from one side and fewer from the other, and each way is good at different kinds of light:
+//
+// - taking nothing from the light path is just the camera path running into a light;
+// - taking one point from the light path is sampling a light directly, like the path integrator;
+// - taking one point from the camera path is light tracing. We follow light out from the lamp and
+//   check whether the camera can see where it landed. That can be any pixel, not just the one we're
+//   rendering, so the light gets splatted onto the film wherever it lands;
+// - everything else joins up a point from the middle of each path.
+//
+// Every way of making a path is weighted by how likely it was to be made that way compared to all
+// the others (multiple importance sampling, with the balance heuristic), so no light gets counted
+// twice, and each path mostly comes from whichever way suits it best. Working out those weights needs
+// the chance of sampling each point on each path going both forwards and backwards, per unit area,
+// which is what Vertex keeps track of. This follows the approach in PBRT pretty closely.
+//
+// Mirrors and glass can't be joined onto, since they only send light off in one direction. Paths
+// through them only come from the ways which don't join there. The same goes for points inside of
+// translucent materials.
+//
+// Splats only show up properly once the films of all of the image have been merged together, since
+// each pixel's splats come from light paths traced for every other pixel.
+
+/// Traces paths from both the camera and the lights, and joins them together.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BidirectionalIntegrator;
+
+impl Integrator for BidirectionalIntegrator {
+    fn radiance(&self, ray: Ray, context: &TraceContext) -> PixelF {
+        let mut rand = rng();
+
+        let mut camera_path = Vec::with_capacity(context.max_depth.min(16) + 2);
+        camera_path.push(Vertex {
+            kind: VertexKind::Camera,
+            point: ray.origin,
+            normal: V3::zero(),
+            beta: PixelF::white(),
+            pdf_fwd: 1.,
+            pdf_rev: 0.,
+            delta: false,
+            rough: false,
+        });
+        let pdf_dir = context
+            .camera
+            .map_or(1., |camera| camera.direction_pdf(ray.dir));
+        let mut radiance = random_walk(
+            context,
+            ray,
+            PixelF::white(),
+            pdf_dir,
+            context.max_depth + 2,
+            &mut camera_path,
+            &mut rand,
+        );
+
+        let mut light_path = Vec::with_capacity(context.max_depth.min(16) + 1);
+        if !context.lights.is_empty() {
+            let light = &context.lights[rand.gen_range(0..context.lights.len())];
+            let (point, normal) = light.sample_surface(&mut rand);
+            let pdf_pos = 1. / (context.lights.len() as f32 * light.area());
+            light_path.push(Vertex {
+                kind: VertexKind::Light {
+                    light: Some(light),
+                    emitted: light.emission,
+                },
+                point,
+                normal,
+                beta: scaled(light.emission, 1. / pdf_pos),
+                pdf_fwd: pdf_pos,
+                pdf_rev: 0.,
+                delta: false,
+                rough: false,
+            });
+            // Lights give off light evenly in all directions out of their fronts, so we send light out
+            // in a cosine-weighted direction, and the cosines cancel out.
+            let mut dir = normal + V3::random_on_unit_sphere();
+            if dir.near_zero() {
+                dir = normal;
+            }
+            let pdf_dir = normal.dot(&dir.normalized()) / PI;
+            let mut light_ray = Ray::new(point, dir);
+            light_ray.min = SHADOW_EPSILON / dir.magnitude();
+            random_walk(
+                context,
+                light_ray,
+                scaled(light.emission, PI / pdf_pos),
+                pdf_dir,
+                context.max_depth + 1,
+                &mut light_path,
+                &mut rand,
+            );
+        }
+
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                // Paths straight from a light to the camera only come from the camera path, and the
+                // path with no bounces at all is just the camera.
+                let depth = s + t;
+                if (s == 1 && t == 1) || depth < 2 || depth - 2 > context.max_depth {
+                    continue;
+                }
+                let contribution = connect(context, &camera_path, &light_path, s, t, &mut rand);
+                radiance = accumulated(radiance, contribution);
+            }
+        }
+        radiance
+    }
+}
+
+#[derive(Clone, Debug)]
+enum VertexKind<'a> {
+    Camera,
+    /// A point on a light, either where a light path started or where a camera path ran into one.
+    /// Camera paths can run into emissive things which we don't know how to sample as lights.
+    Light {
+        light: Option<&'a Light>,
+        emitted: PixelF,
+    },
+    Surface {
+        material: Material,
+    },
+}
+
+/// A point along a camera or light path.
+#[derive(Clone, Debug)]
+struct Vertex<'a> {
+    kind: VertexKind<'a>,
+    point: V3,
+    /// Faces the side the path arrived from, or outwards for where a light path started.
+    normal: V3,
+    /// The light, or for camera paths the importance, carried this far over the chance of having
+    /// made the path this far.
+    beta: PixelF,
+    /// The chance, per unit area, of the vertex before this one on its path sampling this one.
+    pdf_fwd: f32,
+    /// The same for the vertex after this one, if the path had been traced the other way.
+    pdf_rev: f32,
+    /// Whether the path carried on from here in a single direction, like off of a mirror. Paths
+    /// like that can't have come from joining onto this vertex.
+    delta: bool,
+    /// Whether the material here scatters light every which way, at least some of the time, so can
+    /// be joined onto. Coated materials are rough even where the path glanced off of the coat.
+    rough: bool,
+}
+
+impl<'a> Vertex<'a> {
+    fn on_surface(&self) -> bool {
+        match &self.kind {
+            VertexKind::Camera => false,
+            VertexKind::Light { .. } => true,
+            VertexKind::Surface { material } => !material.is_volumetric(),
+        }
+    }
+
+    /// Whether we can join another path onto this vertex, from the middle of its own path.
+    fn connectible(&self) -> bool {
+        matches!(self.kind, VertexKind::Surface { .. }) && self.rough
+    }
+
+    /// How much of the light arriving along `prev` heads off along `dir_out`, cosine and all.
+    fn f(&self, prev: &Vertex, dir_out: V3) -> PixelF {
+        match &self.kind {
+            VertexKind::Surface { material } => material
+                .evaluate(self.point - prev.point, dir_out, self.normal)
+                .map_or(PixelF::black(), |(value, _)| value),
+            _ => PixelF::black(),
+        }
+    }
+
+    /// Turn a pdf per unit solid angle for heading from here towards `next` into one per unit area
+    /// at `next`.
+    fn convert_pdf(&self, pdf: f32, next: &Vertex) -> f32 {
+        let to_next = next.point - self.point;
+        let distance_squared = to_next.magnitude_squared();
+        if distance_squared == 0. {
+            return 0.;
+        }
+        let mut pdf = pdf / distance_squared;
+        if next.on_surface() {
+            pdf *= next.normal.dot(&to_next).abs() / distance_squared.sqrt();
+        }
+        pdf
+    }
+
+    /// The chance, per unit area, of sampling `next` from here, having arrived from `prev`.
+    fn pdf(&self, context: &TraceContext, prev: Option<&Vertex>, next: &Vertex) -> f32 {
+        let dir_out = next.point - self.point;
+        let pdf = match &self.kind {
+            VertexKind::Camera => context
+                .camera
+                .map_or(0., |camera| camera.direction_pdf(dir_out)),
+            VertexKind::Light { .. } => return self.pdf_light(next),
+            VertexKind::Surface { material } => match prev {
+                Some(prev) => material.scatter_pdf(self.point - prev.point, dir_out, self.normal),
+                None => 0.,
+            },
+        };
+        self.convert_pdf(pdf, next)
+    }
+
+    /// For a point on a light, the chance per unit area of a light path starting here heading
+    /// off towards `next`.
+    fn pdf_light(&self, next: &Vertex) -> f32 {
+        let dir = (next.point - self.point).normalized();
+        self.convert_pdf(self.normal.dot(&dir).abs() / PI, next)
+    }
+
+    /// For a point on a light, the chance per unit area of a light path starting here.
+    fn pdf_light_origin(&self, lights: usize) -> f32 {
+        match self.kind {
+            VertexKind::Light {
+                light: Some(light), ..
+            } => 1. / (lights as f32 * light.area()),
+            _ => 0.,
+        }
+    }
+}
+
+/// Follow a path on from its first vertex, adding a vertex everywhere it hits something, until it
+/// has `max_vertices` of them. Camera paths add up the sky if they fly off into it, since nothing
+/// else can find it, and that light gets handed back.
+fn random_walk<'a>(
+    context: &TraceContext<'a>,
+    mut ray: Ray,
+    mut beta: PixelF,
+    mut pdf_dir: f32,
+    max_vertices: usize,
+    vertices: &mut Vec<Vertex<'a>>,
+    rand: &mut impl Rng,
+) -> PixelF {
+    let camera_path = matches!(vertices[0].kind, VertexKind::Camera);
+    while vertices.len() < max_vertices {
+        let depth = vertices.len() - 1;
+        if depth > 0 || !camera_path {
+            stats::record(|s| s.secondary_rays += 1);
+        }
+        let collision = match context.intersect(ray) {
+            Some(collision) => collision,
+            None => {
+                stats::record(|s| s.path_ended(depth));
+                if camera_path {
+                    return sky_color(ray).attenuate(beta);
+                }
+                return PixelF::black();
+            }
+        };
+        stats::record(|s| s.hits += 1);
+
+        let material = &collision.material;
+        let prev = vertices.len() - 1;
+        let mut vertex = Vertex {
+            kind: VertexKind::Surface {
+                material: material.clone(),
+            },
+            point: collision.point(),
+            normal: collision.normal,
+            beta,
+            pdf_fwd: 0.,
+            pdf_rev: 0.,
+            delta: false,
+            rough: false,
+        };
+        vertex.pdf_fwd = vertices[prev].convert_pdf(pdf_dir, &vertex);
+
+        // Lights don't scatter anything. Light paths just stop when they hit one, but camera paths
+        // have found some light.
+        if material.emission().is_some() {
+            stats::record(|s| s.path_ended(depth));
+            if camera_path {
+                vertex.kind = VertexKind::Light {
+                    light: context.light(collision.primitive_index),
+                    emitted: material.emitted(collision.front_facing),
+                };
+                vertices.push(vertex);
+            }
+            return PixelF::black();
+        }
+
+        let (dir_in, dir_out) = (ray.dir, collision.ray_out.dir);
+        // Points inside of translucent materials can't be joined onto either. Joins to anywhere
+        // outside are always blocked by the surface, and joins to anywhere else inside would skip
+        // over the light lost on the way.
+        vertex.rough =
+            !collision.subsurface && material.bsdf(dir_in, dir_out, vertex.normal).is_some();
+        let pdf_rev_dir = if vertex.rough
+            && !material.scattered_specularly(dir_in, dir_out, vertex.normal)
+        {
+            pdf_dir = material.scatter_pdf(dir_in, dir_out, vertex.normal);
+            material.scatter_pdf(dir_out * -1., dir_in * -1., vertex.normal)
+        } else {
+            vertex.delta = true;
+            pdf_dir = 0.;
+            0.
+        };
+        vertices[prev].pdf_rev = vertex.convert_pdf(pdf_rev_dir, &vertices[prev]);
+        vertices.push(vertex);
+
+        beta = beta.attenuate(collision.color);
+        ray = collision.ray_out;
+        beta = match context.roulette(depth + 1, beta, rand) {
+            Some(beta) => beta,
+            None => return PixelF::black(),
+        };
+    }
+
+    stats::record(|s| {
+        s.max_depth_terminations += 1;
+        s.path_ended(max_vertices - 1);
+    });
+    PixelF::black()
+}
+
+/// Join the first `s` vertices of the light path onto the first `t` of the camera path, and give
+/// back the weighted light that carries to the camera. Joins straight onto the camera get splatted
+/// instead, so they give back nothing.
+fn connect(
+    context: &TraceContext,
+    camera_path: &[Vertex],
+    light_path: &[Vertex],
+    s: usize,
+    t: usize,
+    rand: &mut impl Rng,
+) -> PixelF {
+    if s == 0 {
+        // The camera path ran into a light all on its own.
+        let last = &camera_path[t - 1];
+        return match last.kind {
+            VertexKind::Light { light, emitted } => {
+                let contribution = last.beta.attenuate(emitted);
+                // Lights we can't sample can only be found this way.
+                let weight = if light.is_some() {
+                    mis_weight(context, camera_path, light_path, None, s, t)
+                } else {
+                    1.
+                };
+                scaled(contribution, weight)
+            }
+            _ => PixelF::black(),
+        };
+    }
+
+    if t == 1 {
+        // Light tracing: see whether the camera can see the end of the light path.
+        let last = &light_path[s - 1];
+        let camera = match context.camera {
+            Some(camera) => camera,
+            None => return PixelF::black(),
+        };
+        // Where the camera sees a shadow catcher, the raytracer works out the pixel itself.
+        if !last.connectible()
+            || matches!(&last.kind, VertexKind::Surface { material } if material.is_matte())
+        {
+            return PixelF::black();
+        }
+        let (x, y) = match camera.project(last.point) {
+            Some(pixel) => pixel,
+            None => return PixelF::black(),
+        };
+        let to_camera = camera.position() - last.point;
+        // The camera's response to light from this direction, over the chance of having picked it.
+        let importance = camera.direction_pdf(to_camera * -1.) / to_camera.magnitude_squared();
+        let contribution = scaled(
+            last.beta.attenuate(last.f(&light_path[s - 2], to_camera)),
+            importance,
+        );
+        if is_black(contribution) || !context.visible(last.point, camera.position()) {
+            return PixelF::black();
+        }
+        let sampled = Vertex {
+            kind: VertexKind::Camera,
+            point: camera.position(),
+            normal: V3::zero(),
+            beta: PixelF::white(),
+            pdf_fwd: 1.,
+            pdf_rev: 0.,
+            delta: false,
+            rough: false,
+        };
+        let weight = mis_weight(context, camera_path, light_path, Some(sampled), s, t);
+        context.splat(x, y, scaled(contribution, weight));
+        return PixelF::black();
+    }
+
+    let last = &camera_path[t - 1];
+    if !last.connectible() {
+        return PixelF::black();
+    }
+    let before = &camera_path[t - 2];
+
+    if s == 1 {
+        // Pick a fresh point on a light, like the path integrator does.
+        let light = &context.lights[rand.gen_range(0..context.lights.len())];
+        let sample = match light.sample(last.point, rand) {
+            Some(sample) => sample,
+            None => return PixelF::black(),
+        };
+        let light_pdf = sample.pdf / context.lights.len() as f32;
+        let contribution = scaled(
+            last.beta
+                .attenuate(last.f(before, sample.point - last.point))
+                .attenuate(sample.emission),
+            1. / light_pdf,
+        );
+        if is_black(contribution) || !context.visible(last.point, sample.point) {
+            return PixelF::black();
+        }
+        let mut sampled = Vertex {
+            kind: VertexKind::Light {
+                light: Some(light),
+                emitted: sample.emission,
+            },
+            point: sample.point,
+            normal: sample.normal,
+            beta: PixelF::white(),
+            pdf_fwd: 0.,
+            pdf_rev: 0.,
+            delta: false,
+            rough: false,
+        };
+        sampled.pdf_fwd = sampled.pdf_light_origin(context.lights.len());
+        let weight = mis_weight(context, camera_path, light_path, Some(sampled), s, t);
+        return scaled(contribution, weight);
+    }
+
+    // Join up a point from the middle of each path.
+    let light_last = &light_path[s - 1];
+    if !light_last.connectible() {
+        return PixelF::black();
+    }
+    let between = light_last.point - last.point;
+    let contribution = scaled(
+        last.beta
+            .attenuate(last.f(before, between))
+            .attenuate(light_last.f(&light_path[s - 2], between * -1.))
+            .attenuate(light_last.beta),
+        1. / between.magnitude_squared(),
+    );
+    if is_black(contribution) || !context.visible(last.point, light_last.point) {
+        return PixelF::black();
+    }
+    let weight = mis_weight(context, camera_path, light_path, None, s, t);
+    scaled(contribution, weight)
+}
+
+/// The balance heuristic weight for making a path by joining the first `s` vertices of the light
+/// path onto the first `t` of the camera path, against every other way of making the same path.
+/// `sampled` stands in for the last vertex on whichever side was sampled fresh for the join.
+fn mis_weight(
+    context: &TraceContext,
+    camera_path: &[Vertex],
+    light_path: &[Vertex],
+    sampled: Option<Vertex>,
+    s: usize,
+    t: usize,
+) -> f32 {
+    if s + t == 2 {
+        return 1.;
+    }
+    let mut camera = camera_path[..t].to_vec();
+    let mut light = light_path[..s].to_vec();
+    if let Some(sampled) = sampled {
+        if s == 1 {
+            light[0] = sampled;
+        } else if t == 1 {
+            camera[0] = sampled;
+        }
+    }
+
+    // Work out the chances of each vertex either side of the join being sampled from the other side.
+    camera[t - 1].delta = false;
+    camera[t - 1].pdf_rev = if s > 0 {
+        let before = if s > 1 { Some(&light[s - 2]) } else { None };
+        light[s - 1].pdf(context, before, &camera[t - 1])
+    } else {
+        camera[t - 1].pdf_light_origin(context.lights.len())
+    };
+    if t > 1 {
+        camera[t - 2].pdf_rev = if s > 0 {
+            camera[t - 1].pdf(context, Some(&light[s - 1]), &camera[t - 2])
+        } else {
+            camera[t - 1].pdf_light(&camera[t - 2])
+        };
+    }
+    if s > 0 {
+        light[s - 1].delta = false;
+        let before = if t > 1 { Some(&camera[t - 2]) } else { None };
+        light[s - 1].pdf_rev = camera[t - 1].pdf(context, before, &light[s - 1]);
+    }
+    if s > 1 {
+        light[s - 2].pdf_rev = light[s - 1].pdf(context, Some(&camera[t - 1]), &light[s - 2]);
+    }
+
+    // Then walk out along each path, working out how likely every other way of making this path is
+    // compared to this one. Zero pdfs come from mirrors and glass, which get skipped over anyway.
+    let remap = |pdf: f32| if pdf == 0. { 1. } else { pdf };
+    let mut sum = 0.;
+    let mut ratio = 1.;
+    for i in (1..t).rev() {
+        ratio *= remap(camera[i].pdf_rev) / remap(camera[i].pdf_fwd);
+        // Joining straight onto the camera needs somewhere to splat.
+        let splattable = i > 1 || context.camera.is_some();
+        if !camera[i].delta && !camera[i - 1].delta && splattable {
+            sum += ratio;
+        }
+    }
+    ratio = 1.;
+    for i in (0..s).rev() {
+        ratio *= remap(light[i].pdf_rev) / remap(light[i].pdf_fwd);
+        let delta_before = i > 0 && light[i - 1].delta;
+        if !light[i].delta && !delta_before {
+            sum += ratio;
+        }
+    }
+    1. / (1. + sum)
+}
+
+fn is_black(p: PixelF) -> bool {
+    p.r <= 0. && p.g <= 0. && p.b <= 0.
+}
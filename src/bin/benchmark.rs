@@ -1,89 +1,420 @@
 use std::f32::consts::PI;
+use std::process::ExitCode;
+use std::str::FromStr;
 use std::time::Instant;
 
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+
 use tracer_r::prelude::*;
 use tracer_r::*;
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        eprintln!("usage: {} <number_of_spheres>", &args[0]);
+// Runs every combination of scene, sphere count, strategy and thread count a number of times, and
+// reports timings as JSON. Handing it the JSON from an earlier run as a baseline compares against
+// it, failing if anything got slower by more than the threshold. That makes it easy to keep an eye
+// on performance between changes, or in CI.
+
+/// Benchmark rendering across a matrix of scenes and settings.
+#[derive(Parser)]
+#[command(name = "benchmark")]
+struct Cli {
+    /// Scenes to render: 'random', 'grid' or 'sample'. The sample scene has a fixed number of spheres.
+    #[arg(long, value_delimiter = ',', default_value = "random,grid", value_parser = parse_scene)]
+    scenes: Vec<BenchScene>,
+    /// How many spheres to put in the random and grid scenes.
+    #[arg(long, value_delimiter = ',', default_value = "64,512")]
+    spheres: Vec<usize>,
+    /// Strategies to render with: 'naive', 'bvh' or 'bvh_flat'.
+    #[arg(long, value_delimiter = ',', default_value = "naive,bvh,bvh_flat", value_parser = parse_strategy)]
+    strategies: Vec<BenchStrategy>,
+    /// Thread counts to render with. 1 renders in series, and 0 uses every core.
+    #[arg(long, value_delimiter = ',', default_value = "1,0")]
+    threads: Vec<usize>,
+    /// How many times to render each combination.
+    #[arg(long, default_value_t = 5)]
+    runs: usize,
+    /// Image size, like 640x480.
+    #[arg(short, long, default_value = "128x128", value_parser = parse_resolution)]
+    resolution: (usize, usize),
+    /// Samples per pixel.
+    #[arg(short, long, default_value_t = 4)]
+    samples: usize,
+    /// Maximum number of bounces per path.
+    #[arg(short, long, default_value_t = 16)]
+    depth: usize,
+    /// Seed for the scenes and the renders, so that every run does the same work.
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Where to write the JSON report. It's printed if this is left out.
+    #[arg(short, long)]
+    output: Option<String>,
+    /// A report from an earlier run to compare against.
+    #[arg(short, long)]
+    baseline: Option<String>,
+    /// How much slower than the baseline, as a fraction, counts as a regression.
+    #[arg(long, default_value_t = 0.1)]
+    threshold: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BenchScene {
+    Random,
+    Grid,
+    Sample,
+}
+
+impl FromStr for BenchScene {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Self::Random),
+            "grid" => Ok(Self::Grid),
+            "sample" => Ok(Self::Sample),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BenchStrategy {
+    Naive,
+    Bvh,
+    BvhFlat,
+}
+
+impl FromStr for BenchStrategy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "naive" => Ok(Self::Naive),
+            "bvh" => Ok(Self::Bvh),
+            "bvh_flat" => Ok(Self::BvhFlat),
+            _ => Err(()),
+        }
     }
+}
 
-    let num_spheres = usize::from_str_radix(&args[1], 10)
-        .or(Err(&args[1]))
-        .expect("invalid number of spheres");
+impl BenchScene {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Random => "random",
+            Self::Grid => "grid",
+            Self::Sample => "sample",
+        }
+    }
+}
 
-    let (r, c, e, b) = setup(num_spheres);
+impl BenchStrategy {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Naive => "naive",
+            Self::Bvh => "bvh",
+            Self::BvhFlat => "bvh_flat",
+        }
+    }
+}
 
-    timed_run("naive", || {
-        let i = render(&r, &c, &e, b);
-        i.save("dump.png".to_owned()).unwrap();
-    });
+fn parse_scene(s: &str) -> Result<BenchScene, String> {
+    BenchScene::from_str(s).map_err(|_| "expected 'random', 'grid' or 'sample'".to_owned())
+}
 
-    timed_run("bvh", || {
-        let bvh = BVHBuildNode::new(e.clone(), 4);
-        let i = render(&r, &c, &bvh, b);
-        i.save("dump.png".to_owned()).unwrap();
-    });
+fn parse_strategy(s: &str) -> Result<BenchStrategy, String> {
+    BenchStrategy::from_str(s).map_err(|_| "expected 'naive', 'bvh' or 'bvh_flat'".to_owned())
+}
+
+fn parse_resolution(s: &str) -> Result<(usize, usize), String> {
+    match parse_pair(s, 'x') {
+        Some((width, height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err("expected <width>x<height>, like 640x480".to_owned()),
+    }
+}
 
-    timed_run("flat bvh", || {
-        let bvh = BVHBuildNode::new(e.clone(), 4);
-        let fbvh: BVHFlat = bvh.into();
-        let i = render(&r, &c, &fbvh, b);
-        i.save("dump.png".to_owned()).unwrap();
-    });
+/// Everything we ran, and how long it took.
+#[derive(Debug, Serialize, Deserialize)]
+struct Report {
+    resolution: (usize, usize),
+    samples: usize,
+    depth: usize,
+    runs: usize,
+    seed: u64,
+    results: Vec<BenchResult>,
+}
 
-    timed_run("naive parallel", || {
-        let i = par_render(&r, &c, &e, b);
-        i.save("dump.png".to_owned()).unwrap();
-    });
+/// The timings for one combination of settings.
+#[derive(Debug, Serialize, Deserialize)]
+struct BenchResult {
+    /// Identifies this combination, for matching it up with a baseline.
+    name: String,
+    scene: BenchScene,
+    primitives: usize,
+    strategy: BenchStrategy,
+    threads: usize,
+    build_seconds: f64,
+    /// How the BVH turned out, when there is one.
+    bvh: Option<BVHStats>,
+    render_seconds: Summary,
+    primary_rays_per_second: f64,
+    /// Counts from one render, when built with the stats feature.
+    ray_stats: Option<RayStats>,
+    /// Every ray cast, including secondary and shadow rays, when built with the stats feature.
+    rays_per_second: Option<f64>,
+}
 
-    timed_run("bvh parallel", || {
-        let bvh = BVHBuildNode::new(e.clone(), 4);
-        let i = par_render(&r, &c, &bvh, b);
-        i.save("dump.png".to_owned()).unwrap();
-    });
+/// Summary statistics over a number of runs.
+#[derive(Debug, Serialize, Deserialize)]
+struct Summary {
+    mean: f64,
+    median: f64,
+    stddev: f64,
+    min: f64,
+    max: f64,
+}
 
-    timed_run("flat bvh parallel", || {
-        let bvh = BVHBuildNode::new(e.clone(), 4);
-        let fbvh: BVHFlat = bvh.into();
-        let i = par_render(&r, &c, &fbvh, b);
-        i.save("dump.png".to_owned()).unwrap();
-    });
+impl Summary {
+    fn new(mut values: Vec<f64>) -> Self {
+        values.sort_by(f64::total_cmp);
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let middle = values.len() / 2;
+        let median = if values.len().is_multiple_of(2) {
+            (values[middle - 1] + values[middle]) / 2.
+        } else {
+            values[middle]
+        };
+        // Sample standard deviation, since our runs are a sample of all the runs we could have done.
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.).max(1.);
+        Summary {
+            mean,
+            median,
+            stddev: variance.sqrt(),
+            min: values[0],
+            max: values[values.len() - 1],
+        }
+    }
 }
 
-fn setup(num_spheres: usize) -> (Raytracer, Camera, Vec<Primitive>, (usize, usize)) {
-    let bounds = (256, 256);
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(&cli) {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    let rt = Raytracer::default().ss_amt(16).max_depth(32);
+fn run(cli: &Cli) -> Result<ExitCode, String> {
+    if cli.runs == 0 || cli.samples == 0 {
+        return Err("we need at least one run and one sample per pixel".to_owned());
+    }
+    // Read the baseline first, so we don't find out it's missing after benchmarking for ages.
+    let baseline = match &cli.baseline {
+        Some(filename) => Some(load_report(filename)?),
+        None => None,
+    };
 
+    let raytracer = Raytracer::default()
+        .ss_amt(cli.samples)
+        .max_depth(cli.depth);
     let fov: f32 = 70.0 * PI / 180.0;
-    let camera = Camera::new(V3::new(0., 0., -5.), V3::z(), V3::y(), fov, bounds);
+    let camera = Camera::new(V3::new(0., 0., -5.), V3::z(), V3::y(), fov, cli.resolution);
+
+    let mut results = Vec::new();
+    for &scene in &cli.scenes {
+        // The sample scene is always the same size, so don't run it once per sphere count.
+        let sphere_counts = if scene == BenchScene::Sample {
+            &cli.spheres[..1.min(cli.spheres.len())]
+        } else {
+            &cli.spheres[..]
+        };
+        for &spheres in sphere_counts {
+            for &strategy in &cli.strategies {
+                for &threads in &cli.threads {
+                    let result = bench(cli, &raytracer, &camera, scene, spheres, strategy, threads)?;
+                    eprintln!(
+                        "{}: {:.4}s mean, {:.4}s median, {:.4}s stddev, {:.0} rays/s",
+                        result.name,
+                        result.render_seconds.mean,
+                        result.render_seconds.median,
+                        result.render_seconds.stddev,
+                        result.primary_rays_per_second
+                    );
+                    results.push(result);
+                }
+            }
+        }
+    }
+
+    let report = Report {
+        resolution: cli.resolution,
+        samples: cli.samples,
+        depth: cli.depth,
+        runs: cli.runs,
+        seed: cli.seed,
+        results,
+    };
+    let json = serde_json::to_string_pretty(&report).map_err(|e| e.to_string())?;
+    match &cli.output {
+        Some(filename) => {
+            std::fs::write(filename, json).map_err(|e| format!("{}: {}", filename, e))?
+        }
+        None => println!("{}", json),
+    }
+
+    match baseline {
+        Some(baseline) if !compare(&report, &baseline, cli.threshold) => Ok(ExitCode::FAILURE),
+        _ => Ok(ExitCode::SUCCESS),
+    }
+}
+
+/// Build and render one combination of settings a number of times.
+fn bench(
+    cli: &Cli,
+    raytracer: &Raytracer,
+    camera: &Camera,
+    scene: BenchScene,
+    spheres: usize,
+    strategy: BenchStrategy,
+    threads: usize,
+) -> Result<BenchResult, String> {
+    seed_sampler(cli.seed);
+    let elements = match scene {
+        BenchScene::Random => random_spheres(
+            spheres,
+            Bounds {
+                min_point: V3::new(-10., -10., 10.),
+                max_point: V3::new(10., 10., 24.),
+            },
+        ),
+        BenchScene::Grid => {
+            let side = (spheres as f32).sqrt().ceil().max(1.) as usize;
+            big_sphere_grid((side, side), ((-6., -6.), (6., 6.)), 5.)
+        }
+        BenchScene::Sample => sample_scene(),
+    };
+    let primitives = elements.primitives.len();
+    let name = format!("{}/{}/{}/{}", scene.name(), primitives, strategy.name(), threads);
 
-    let elements = random_spheres(
-        num_spheres,
-        Bounds {
-            min_point: V3::new(-10., -10., 10.),
-            max_point: V3::new(10., 10., 24.),
-        },
-    );
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let start = Instant::now();
+    let (build_seconds, bvh, (render_times, ray_stats)) = match strategy {
+        BenchStrategy::Naive => (0., None, time_renders(cli, raytracer, camera, &elements, threads, &pool)),
+        BenchStrategy::Bvh => {
+            let bvh = BVHBuildNode::new(elements, 4)?;
+            let build_seconds = start.elapsed().as_secs_f64();
+            let stats = bvh.stats();
+            (build_seconds, Some(stats), time_renders(cli, raytracer, camera, &bvh, threads, &pool))
+        }
+        BenchStrategy::BvhFlat => {
+            let bvh: BVHFlat = BVHBuildNode::new(elements, 4)?.into();
+            let build_seconds = start.elapsed().as_secs_f64();
+            let stats = bvh.stats();
+            (build_seconds, Some(stats), time_renders(cli, raytracer, camera, &bvh, threads, &pool))
+        }
+    };
 
-    (rt, camera, elements, bounds)
+    let render_seconds = Summary::new(render_times);
+    let primary_rays = (cli.resolution.0 * cli.resolution.1 * cli.samples) as f64;
+    let ray_stats = RayStats::ENABLED.then_some(ray_stats);
+    Ok(BenchResult {
+        name,
+        scene,
+        primitives,
+        strategy,
+        threads,
+        build_seconds,
+        bvh,
+        primary_rays_per_second: primary_rays / render_seconds.mean,
+        rays_per_second: ray_stats
+            .as_ref()
+            .map(|stats| stats.total_rays() as f64 / render_seconds.mean),
+        ray_stats,
+        render_seconds,
+    })
 }
 
-fn timed_run<F>(description: &str, f: F)
+/// Render a scene a number of times, returning how many seconds each took, and the counts from the
+/// last render.
+fn time_renders<S>(
+    cli: &Cli,
+    raytracer: &Raytracer,
+    camera: &Camera,
+    scene: &S,
+    threads: usize,
+    pool: &rayon::ThreadPool,
+) -> (Vec<f64>, RayStats)
 where
-    F: Fn() -> (),
+    S: Drawable + Send + Sync,
 {
-    let now = Instant::now();
-    for _ in 0..5 {
-        f();
+    let mut stats = RayStats::default();
+    let times = (0..cli.runs)
+        .map(|run| {
+            let seed = stream_seed(cli.seed, run as u64);
+            let start = Instant::now();
+            if threads == 1 {
+                seed_sampler(seed);
+                let mut film = Film::new(cli.resolution, raytracer.pixel_filter());
+                raytracer.render_film(scene, &mut film, camera).unwrap();
+                stats = film.stats;
+            } else {
+                let film = pool.install(|| {
+                    TileScheduler::default()
+                        .seed(seed)
+                        .render(raytracer, camera, scene, cli.resolution, |_| {})
+                });
+                stats = film.stats;
+            }
+            start.elapsed().as_secs_f64()
+        })
+        .collect();
+    (times, stats)
+}
+
+fn load_report(filename: &str) -> Result<Report, String> {
+    let json = std::fs::read_to_string(filename).map_err(|e| format!("{}: {}", filename, e))?;
+    serde_json::from_str(&json).map_err(|e| format!("{}: {}", filename, e))
+}
+
+/// Compare mean render times against a baseline, printing what changed.
+/// Returns false if anything got slower by more than the threshold.
+fn compare(report: &Report, baseline: &Report, threshold: f64) -> bool {
+    if (report.resolution, report.samples, report.depth)
+        != (baseline.resolution, baseline.samples, baseline.depth)
+    {
+        eprintln!("warning: the baseline was run with a different resolution, sample count or depth");
+    }
+
+    let mut passed = true;
+    for result in &report.results {
+        let Some(base) = baseline.results.iter().find(|b| b.name == result.name) else {
+            eprintln!("{}: not in the baseline", result.name);
+            continue;
+        };
+        let change = result.render_seconds.mean / base.render_seconds.mean - 1.;
+        let verdict = if change > threshold {
+            passed = false;
+            "REGRESSION"
+        } else if change < -threshold {
+            "improvement"
+        } else {
+            "ok"
+        };
+        eprintln!(
+            "{}: {:.4}s vs {:.4}s, {:+.1}% {}",
+            result.name,
+            result.render_seconds.mean,
+            base.render_seconds.mean,
+            change * 100.,
+            verdict
+        );
     }
-    let elapsed = now.elapsed();
-    println!(
-        "Ran {} in {:.3} seconds",
-        description,
-        elapsed.as_secs_f32() / 5.
-    );
+    passed
 }
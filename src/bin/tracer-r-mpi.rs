@@ -1,4 +1,6 @@
 use std::f32::consts::PI;
+use std::net::TcpListener;
+use std::process::Command;
 use std::sync::{mpsc, Arc};
 use std::thread;
 
@@ -6,13 +8,99 @@ use tracer_r::prelude::*;
 use tracer_r::*;
 
 fn main() {
-
 	let args: Vec<String> = std::env::args().collect();
-	if args.len() != 2 {
-		eprintln!("usage: {} <filename>", &args[0]);
-		return;
+	let result = match args.get(1).map(|s| s.as_str()) {
+		Some("coordinator") if args.len() == 4 || args.len() == 5 => {
+			coordinate(&args[2], &args[3], args.get(4))
+		}
+		Some("worker") if args.len() == 3 => run_worker(args[2].as_str()),
+		Some("local") if args.len() == 4 || args.len() == 5 => local(&args[2], &args[3], args.get(4)),
+		Some(filename) if args.len() == 2 => {
+			threaded(filename);
+			Ok(())
+		}
+		_ => {
+			eprintln!("usage: {} <filename>", &args[0]);
+			eprintln!("\tRenders with threads passing messages over channels, all in this process.");
+			eprintln!("   or: {} coordinator <filename> <listen_address> [scene_file]", &args[0]);
+			eprintln!("\tHands out tiles to workers which connect over TCP, and saves the result.");
+			eprintln!("   or: {} worker <coordinator_address>", &args[0]);
+			eprintln!("\tRenders tiles for a coordinator until it's done.");
+			eprintln!("   or: {} local <filename> <number_of_workers> [scene_file]", &args[0]);
+			eprintln!("\tRuns a coordinator with some worker processes on this machine.");
+			return;
+		}
+	};
+
+	if let Err(e) = result {
+		eprintln!("error: {}", e);
+		std::process::exit(1);
+	}
+}
+
+/// The scene we render when we aren't given one.
+fn default_scene() -> SceneDescription {
+	SceneDescription::new(
+		CameraDescription {
+			position: V3::new(0., 0., -5.),
+			direction: V3::z(),
+			up: V3::y(),
+			fov: 70.0,
+			bounds: (512, 512),
+		},
+		Raytracer::default().ss_amt(8).max_depth(32),
+		big_sphere_grid((14, 14), ((-6., -6.), (6., 6.)), 5.),
+	)
+}
+
+fn load_scene(scene_file: Option<&String>) -> Result<SceneDescription, String> {
+	match scene_file {
+		Some(filename) => SceneDescription::load(filename),
+		None => Ok(default_scene()),
+	}
+}
+
+/// Render a scene with whatever workers connect to us, then save it.
+fn coordinate(filename: &str, address: &str, scene_file: Option<&String>) -> Result<(), String> {
+	let scene = load_scene(scene_file)?;
+	let listener = TcpListener::bind(address).map_err(|e| e.to_string())?;
+	println!("Waiting for workers on {}...", listener.local_addr().map_err(|e| e.to_string())?);
+	run_coordinator(filename, listener, &scene)
+}
+
+fn run_coordinator(filename: &str, listener: TcpListener, scene: &SceneDescription) -> Result<(), String> {
+	let film = Coordinator::default().render(listener, scene, |completed, total| {
+		println!("Got tile {}/{}", completed, total);
+	})?;
+	film.to_image().save(filename.to_owned())
+}
+
+/// Spin up a coordinator on some free port of localhost, and a few worker processes to go with it.
+fn local(filename: &str, workers: &str, scene_file: Option<&String>) -> Result<(), String> {
+	let workers: usize = workers.parse().map_err(|_| "invalid number of workers")?;
+	let scene = load_scene(scene_file)?;
+	let listener = TcpListener::bind("127.0.0.1:0").map_err(|e| e.to_string())?;
+	let address = listener.local_addr().map_err(|e| e.to_string())?.to_string();
+
+	let exe = std::env::current_exe().map_err(|e| e.to_string())?;
+	let mut children = Vec::new();
+	for _ in 0..workers {
+		let child = Command::new(&exe)
+			.args(["worker", &address])
+			.spawn()
+			.map_err(|e| e.to_string())?;
+		children.push(child);
 	}
 
+	let result = run_coordinator(filename, listener, &scene);
+	for mut child in children {
+		child.wait().map_err(|e| e.to_string())?;
+	}
+	result
+}
+
+/// Render in bands on separate threads, which send their results back over channels.
+fn threaded(filename: &str) {
     let bounds = (512, 512);
 
     let rt = Arc::new(Raytracer::default().ss_amt(8).max_depth(32));
@@ -27,9 +115,9 @@ fn main() {
     ));
 
     let elements = big_sphere_grid((14, 14), ((-6., -6.), (6., 6.)), 5.);
-    let bvh = Arc::new(BVHBuildNode::new(elements, 4));
+    let bvh = Arc::new(BVHBuildNode::new(elements, 4).unwrap());
 
-    let chunks = ImageBuffer::bands(bounds, 32);
+    let chunks = Film::bands(bounds, 32, rt.pixel_filter());
 
     // We process senders in a scope block so that the root sender gets dropped at the end.
     // We can move out our receiver and handles, which are all we really need now.
@@ -45,7 +133,7 @@ fn main() {
             let child_bvh = bvh.clone();
             handles.push(thread::spawn(move || {
                 child_rt
-                    .render(&*child_bvh, &mut chunk, &child_camera)
+                    .render_film(&*child_bvh, &mut chunk, &child_camera)
                     .unwrap();
                 println!("Sending!!");
                 child_sender.send(chunk).unwrap();
@@ -57,15 +145,11 @@ fn main() {
 
     // Iterate over the reciever, blocking between values recieved.
     // When all senders have been closed, the reciever will as well.
-    let mut processed_chunks: Vec<ImageBuffer> = receiver.iter().collect();
-
-    // Sort our recieved chunks by y-offset, so they're not jumbled.
-    processed_chunks.sort_by_key(|c| c.offset.1);
-
-    // Concatenate the chunks into an image, then write it.
-    let mut image = ImageBuffer::new(bounds.0, 0);
-    for mut chunk in processed_chunks {
-        image.append_rows(&mut chunk);
+    // Each chunk gets merged into the full film as soon as it arrives. Order doesn't matter, since
+    // merging just adds up samples.
+    let mut film = Film::new(bounds, rt.pixel_filter());
+    for chunk in receiver.iter() {
+        film.merge(&chunk);
     }
 
 	// Our threads should really be done if all the senders are dropped.
@@ -74,5 +158,5 @@ fn main() {
         h.join().unwrap();
     }
 
-	image.save(args[1].clone()).unwrap();
+	film.to_image().save(filename.to_owned()).unwrap();
 }
@@ -1,10 +1,170 @@
-use std::env;
-use std::f32::consts::PI;
+use std::process::ExitCode;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use clap::{Args, Parser, Subcommand};
 
 use tracer_r::prelude::*;
 use tracer_r::*;
 
+/// A little raytracer.
+#[derive(Parser)]
+#[command(name = "tracer-r")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Render a scene to an image.
+    Render(RenderArgs),
+    /// Time how long a scene takes to build and render.
+    Bench(BenchArgs),
+    /// Print statistics about a scene and its BVH.
+    Inspect(InspectArgs),
+    /// Convert a scene or mesh to a JSON scene file or an OBJ mesh.
+    Convert(ConvertArgs),
+    /// Compare two images, reporting how different they are.
+    Diff(DiffArgs),
+}
+
+/// Which scene to use, and how to build it.
+#[derive(Args)]
+struct SceneArgs {
+    /// One of 'sample', 'caustics', 'grid', 'random', 'voxels:<density_grid_file>', or the name of a
+    /// .json scene file, .obj mesh, or .gltf or .glb glTF scene.
+    #[arg(long, default_value = "sample", value_parser = parse_scene)]
+    scene: RtScene,
+    /// How to organize the scene for rendering: 'naive', 'bvh' or 'bvh_flat'.
+    #[arg(long, default_value = "bvh_flat", value_parser = parse_strategy)]
+    strategy: RtStrategy,
+    /// Seed for everything random, including randomly generated scenes, to make runs repeatable.
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+/// How to render. Anything left out comes from the scene file, or falls back to a default.
+#[derive(Args)]
+struct RenderOptions {
+    /// Image size, like 640x480. Defaults to 512x512.
+    #[arg(short, long, value_parser = parse_resolution)]
+    resolution: Option<(usize, usize)>,
+    /// Samples per pixel. Defaults to 16.
+    #[arg(short, long)]
+    samples: Option<usize>,
+    /// Maximum number of bounces per path. Defaults to 32.
+    #[arg(short, long)]
+    depth: Option<usize>,
+    /// Bounces a path always gets before Russian roulette may end it. Defaults to 4.
+    #[arg(long)]
+    roulette_depth: Option<usize>,
+    /// How to light each ray: 'scatter', 'path', 'bdpt', 'photons' (or 'photons:<count>'),
+    /// 'spectral', 'whitted', 'ao' (or 'ao:<distance>'), 'normals', 'albedo' or 'facing'. Defaults
+    /// to the scene file's choice, or 'scatter'.
+    #[arg(long, value_parser = parse_integrator)]
+    integrator: Option<IntegratorKind>,
+    /// How far around each point the photons integrator gathers photons from. Defaults to 0.05.
+    #[arg(long)]
+    photon_radius: Option<f32>,
+    /// How many sets of photons the photons integrator traces, each gathered over a smaller radius
+    /// than the last, which sharpens caustics. Defaults to 1.
+    #[arg(long)]
+    photon_passes: Option<usize>,
+    /// Reconstruction filter, like 'gaussian' or 'mitchell:2'. One of 'box', 'tent', 'gaussian' or
+    /// 'mitchell', optionally followed by a radius in pixels.
+    #[arg(long, value_parser = parse_filter)]
+    filter: Option<Filter>,
+    /// Leave the sky transparent wherever it's seen directly, for compositing. It still lights the
+    /// scene.
+    #[arg(long)]
+    transparent: bool,
+    /// How many threads to render with. 0 uses every core, and 1 renders in series.
+    #[arg(short = 'j', long, default_value_t = 0)]
+    threads: usize,
+}
+
+#[derive(Args)]
+struct RenderArgs {
+    /// The image to write.
+    output: String,
+    #[command(flatten)]
+    scene: SceneArgs,
+    #[command(flatten)]
+    options: RenderOptions,
+    /// Image format: 'png', 'jpg', 'bmp', 'tga', 'tiff' or 'exr'. Defaults to the output's extension.
+    #[arg(short, long, value_parser = parse_format)]
+    format: Option<OutputFormat>,
+    /// Clean up the image with the edge-aware denoiser.
+    #[arg(long)]
+    denoise: bool,
+    /// Also save the depth, normal, albedo, id and position passes next to the image.
+    #[arg(long)]
+    passes: bool,
+    /// Save progress to <output>.checkpoint every minute.
+    #[arg(long)]
+    checkpoint: bool,
+    /// Carry on from <output>.checkpoint. Also keeps saving checkpoints.
+    #[arg(long)]
+    resume: bool,
+    /// Width and height of the tiles rendered in parallel, in pixels.
+    #[arg(long, default_value_t = 32)]
+    tile_size: usize,
+    /// Order to render tiles in: 'scanline', 'spiral' or 'hilbert'.
+    #[arg(long, default_value = "spiral", value_parser = parse_tile_order)]
+    tile_order: TileOrder,
+    /// Instead of shading, colour each pixel by how much of the BVH its primary ray had to look at:
+    /// 'nodes', 'primitives' or 'total'.
+    #[arg(long, value_parser = parse_heatmap_metric)]
+    heatmap: Option<HeatmapMetric>,
+    /// The count at the top of the heatmap's colour scale. Defaults to the most expensive pixel.
+    #[arg(long, requires = "heatmap")]
+    heatmap_max: Option<usize>,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    #[command(flatten)]
+    scene: SceneArgs,
+    #[command(flatten)]
+    options: RenderOptions,
+    /// How many times to render the scene.
+    #[arg(long, default_value_t = 5)]
+    runs: usize,
+}
+
+#[derive(Args)]
+struct InspectArgs {
+    #[command(flatten)]
+    scene: SceneArgs,
+}
+
+#[derive(Args)]
+struct ConvertArgs {
+    /// The scene to write, either a .json scene file or a .obj mesh.
+    output: String,
+    #[command(flatten)]
+    scene: SceneArgs,
+    #[command(flatten)]
+    options: RenderOptions,
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    first: String,
+    second: String,
+    /// Write an image of the differences between them.
+    #[arg(short, long)]
+    output: Option<String>,
+    /// Brighten the differences image by this much, since differences are usually faint.
+    #[arg(long, default_value_t = 8.)]
+    amplify: f32,
+    /// Fail if the root mean square error is above this.
+    #[arg(long)]
+    threshold: Option<f32>,
+}
+
+#[derive(Clone)]
 enum RtStrategy {
     Naive,
     BVHPointers,
@@ -24,10 +184,16 @@ impl FromStr for RtStrategy {
     }
 }
 
+#[derive(Clone)]
 enum RtScene {
     Sample,
+    Caustics,
     Grid,
     Random,
+    DensityGrid(String),
+    SceneFile(String),
+    Mesh(String),
+    Gltf(String),
 }
 
 impl FromStr for RtScene {
@@ -36,64 +202,552 @@ impl FromStr for RtScene {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "sample" => Ok(Self::Sample),
+            "caustics" => Ok(Self::Caustics),
             "grid" => Ok(Self::Grid),
             "random" => Ok(Self::Random),
-            _ => Err(()),
+            _ if s.ends_with(".json") => Ok(Self::SceneFile(s.to_owned())),
+            _ if s.ends_with(".obj") => Ok(Self::Mesh(s.to_owned())),
+            _ if s.ends_with(".gltf") || s.ends_with(".glb") => Ok(Self::Gltf(s.to_owned())),
+            _ => match s.strip_prefix("voxels:") {
+                Some(filename) => Ok(Self::DensityGrid(filename.to_owned())),
+                None => Err(()),
+            },
         }
     }
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
+// clap wants parsers with descriptive errors, so these wrap up our FromStr implementations.
+
+fn parse_scene(s: &str) -> Result<RtScene, String> {
+    RtScene::from_str(s).map_err(|_| {
+        "expected 'sample', 'caustics', 'grid', 'random', 'voxels:<file>', or a .json, .obj, .gltf \
+         or .glb file"
+            .to_owned()
+    })
+}
+
+fn parse_strategy(s: &str) -> Result<RtStrategy, String> {
+    RtStrategy::from_str(s).map_err(|_| "expected 'naive', 'bvh' or 'bvh_flat'".to_owned())
+}
 
-    if args.len() != 7 {
-        eprintln!("Usage: {} FILE <x_pixels>x<y_pixels> <supersampling_amount> <strategy> <scene> <parallel>", &args[0]);
-        eprintln!("\tWhere <strategy> is one of: 'naive', 'bvh', 'bvh_flat'");
-        eprintln!("\tAnd <scene> is one of: 'sample', 'grid', 'random'");
-        eprintln!("\tAnd <parellel> is 'yes' or 'no'");
-        return;
+fn parse_resolution(s: &str) -> Result<(usize, usize), String> {
+    match parse_pair(s, 'x') {
+        Some((width, height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err("expected <width>x<height>, like 640x480".to_owned()),
     }
+}
 
-    let filename = &args[1];
-    let bounds: (usize, usize) = parse_pair(&args[2], 'x').expect("invalid dimensions");
-    let ss_amt: usize = usize::from_str(&args[3]).expect("invalid ss_amt");
-    let strategy = RtStrategy::from_str(&args[4]).expect("invalid strategy");
-    let scene = RtScene::from_str(&args[5]).expect("invalid scene");
-    let parallel: bool = &args[6] == "yes";
+fn parse_filter(s: &str) -> Result<Filter, String> {
+    Filter::from_str(s).map_err(|_| {
+        "expected 'box', 'tent', 'gaussian' or 'mitchell', optionally followed by ':<radius>'"
+            .to_owned()
+    })
+}
 
-    let fov: f32 = 70.0 * PI / 180.0;
-    let camera = Camera::new(V3::new(0., 0., -5.), V3::z(), V3::y(), fov, bounds);
+fn parse_format(s: &str) -> Result<OutputFormat, String> {
+    OutputFormat::from_str(s)
+        .map_err(|_| "expected 'png', 'jpg', 'bmp', 'tga', 'tiff' or 'exr'".to_owned())
+}
 
-    let elements = match scene {
-        RtScene::Sample => sample_scene(),
-        RtScene::Grid => big_sphere_grid((14, 14), ((-6., -6.), (6., 6.)), 5.),
-        RtScene::Random => random_spheres(
-            256,
-            Bounds {
-                min_point: V3::new(-10., -10., 8.),
-                max_point: V3::new(10., 10., 20.),
-            },
-        ),
+fn parse_tile_order(s: &str) -> Result<TileOrder, String> {
+    TileOrder::from_str(s).map_err(|_| "expected 'scanline', 'spiral' or 'hilbert'".to_owned())
+}
+
+fn parse_integrator(s: &str) -> Result<IntegratorKind, String> {
+    IntegratorKind::from_str(s).map_err(|_| {
+        "expected 'scatter', 'path', 'bdpt', 'photons', 'photons:<count>', 'spectral', 'whitted', 'ao', 'ao:<distance>', 'normals', 'albedo' or 'facing'"
+            .to_owned()
+    })
+}
+
+fn parse_heatmap_metric(s: &str) -> Result<HeatmapMetric, String> {
+    HeatmapMetric::from_str(s).map_err(|_| "expected 'nodes', 'primitives' or 'total'".to_owned())
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Render(args) => render_command(args),
+        Command::Bench(args) => bench_command(args),
+        Command::Inspect(args) => inspect_command(args),
+        Command::Convert(args) => convert_command(args),
+        Command::Diff(args) => diff_command(args),
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// A scene, along with whatever camera and raytracer settings came with it.
+struct LoadedScene {
+    scene: Scene,
+    camera: Option<CameraDescription>,
+    raytracer: Option<Raytracer>,
+}
+
+impl SceneArgs {
+    fn load(&self) -> Result<LoadedScene, String> {
+        if let Some(seed) = self.seed {
+            seed_sampler(seed);
+        }
+
+        let mut imported_camera = None;
+        let scene = match &self.scene {
+            RtScene::Sample => sample_scene(),
+            RtScene::Caustics => caustic_scene(),
+            RtScene::Grid => big_sphere_grid((14, 14), ((-6., -6.), (6., 6.)), 5.),
+            RtScene::Random => random_spheres(
+                256,
+                Bounds {
+                    min_point: V3::new(-10., -10., 8.),
+                    max_point: V3::new(10., 10., 20.),
+                },
+            ),
+            RtScene::DensityGrid(filename) => density_grid_scene(DensityGrid::load(filename)?),
+            RtScene::Mesh(filename) => {
+                let mut materials = MaterialLibrary::new();
+                let material = materials.add(Material::new_diffuse(PixelF::rgb(0.8, 0.8, 0.8)));
+                Scene::new(load_obj(filename, material)?, materials)?
+            }
+            RtScene::Gltf(filename) => {
+                let imported = load_gltf(filename)?;
+                imported_camera = imported.camera.clone();
+                imported.into_scene()?
+            }
+            RtScene::SceneFile(filename) => {
+                let description = SceneDescription::load(filename)?;
+                let camera = description.camera.clone();
+                let raytracer = description.raytracer.clone();
+                return Ok(LoadedScene {
+                    scene: description.flattened()?,
+                    camera: Some(camera),
+                    raytracer: Some(raytracer),
+                });
+            }
+        };
+
+        if scene.primitives.is_empty() {
+            return Err("the scene is empty".to_owned());
+        }
+        let camera = match (&self.scene, imported_camera) {
+            (_, Some(camera)) => camera,
+            // Look down at the mesh from a little above and to the side, far enough away to see all of it.
+            (RtScene::Mesh(_) | RtScene::Gltf(_), None) => {
+                let bounds = BVHBuildNode::new(scene.clone(), 4)?.bounds();
+                let center = (bounds.min_point + bounds.max_point) * 0.5;
+                let radius = (bounds.max_point - bounds.min_point).magnitude() * 0.5;
+                let distance = radius / f32::tan(35f32.to_radians()) * 1.1;
+                let offset = V3::new(0.6, 0.5, -1.).normalized() * distance;
+                CameraDescription {
+                    position: center + offset,
+                    direction: offset * -1.,
+                    ..default_camera()
+                }
+            }
+            _ => default_camera(),
+        };
+
+        Ok(LoadedScene {
+            scene,
+            camera: Some(camera),
+            raytracer: None,
+        })
+    }
+}
+
+fn default_camera() -> CameraDescription {
+    CameraDescription {
+        position: V3::new(0., 0., -5.),
+        direction: V3::z(),
+        up: V3::y(),
+        fov: 70.,
+        bounds: (512, 512),
+    }
+}
+
+impl RenderOptions {
+    /// Work out the camera and raytracer, letting our flags override what the scene came with.
+    fn apply(&self, scene: &LoadedScene) -> Result<(CameraDescription, Raytracer), String> {
+        let mut camera = scene.camera.clone().unwrap_or_else(default_camera);
+        if let Some(resolution) = self.resolution {
+            camera.bounds = resolution;
+        }
+
+        let mut raytracer = scene
+            .raytracer
+            .clone()
+            .unwrap_or_else(|| Raytracer::default().ss_amt(16).max_depth(32));
+        if let Some(samples) = self.samples {
+            if samples == 0 {
+                return Err("we need at least one sample per pixel".to_owned());
+            }
+            raytracer = raytracer.ss_amt(samples);
+        }
+        if let Some(depth) = self.depth {
+            raytracer = raytracer.max_depth(depth);
+        }
+        if let Some(roulette_depth) = self.roulette_depth {
+            raytracer = raytracer.roulette_depth(roulette_depth);
+        }
+        if let Some(filter) = self.filter {
+            raytracer = raytracer.filter(filter);
+        }
+        if let Some(integrator) = self.integrator {
+            raytracer = raytracer.integrator(integrator);
+        }
+        if self.transparent {
+            raytracer = raytracer.transparent_background(true);
+        }
+        if self.photon_radius.is_some() || self.photon_passes.is_some() {
+            let IntegratorKind::PhotonMap {
+                photons,
+                radius,
+                passes,
+            } = raytracer.integrator_kind()
+            else {
+                return Err("photon settings only apply to the photons integrator".to_owned());
+            };
+            let radius = self.photon_radius.unwrap_or(radius);
+            let passes = self.photon_passes.unwrap_or(passes);
+            if radius <= 0. || radius.is_nan() || passes == 0 {
+                return Err("photons need a positive radius and at least one pass".to_owned());
+            }
+            raytracer = raytracer.integrator(IntegratorKind::PhotonMap {
+                photons,
+                radius,
+                passes,
+            });
+        }
+
+        Ok((camera, raytracer))
+    }
+
+    /// Set up rayon's thread pool. Returns whether we should render in parallel at all.
+    fn setup_threads(&self) -> Result<bool, String> {
+        if self.threads > 1 {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(self.threads)
+                .build_global()
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(self.threads != 1)
+    }
+}
+
+/// A scene built for rendering with one of our strategies.
+enum BuiltScene {
+    Naive(Scene),
+    BVHPointers(BVHBuildNode),
+    BVHFlat(BVHFlat),
+}
+
+impl BuiltScene {
+    fn build(scene: Scene, strategy: &RtStrategy) -> Result<Self, String> {
+        Ok(match strategy {
+            RtStrategy::Naive => BuiltScene::Naive(scene),
+            RtStrategy::BVHPointers => BuiltScene::BVHPointers(BVHBuildNode::new(scene, 4)?),
+            RtStrategy::BVHFlat => BuiltScene::BVHFlat(BVHBuildNode::new(scene, 4)?.into()),
+        })
+    }
+
+    fn stats(&self) -> Option<BVHStats> {
+        match self {
+            BuiltScene::Naive(_) => None,
+            BuiltScene::BVHPointers(bvh) => Some(bvh.stats()),
+            BuiltScene::BVHFlat(bvh) => Some(bvh.stats()),
+        }
+    }
+}
+
+impl Drawable for BuiltScene {
+    fn intersect(&self, ray: Ray) -> Option<Collision> {
+        match self {
+            BuiltScene::Naive(scene) => scene.intersect(ray),
+            BuiltScene::BVHPointers(bvh) => bvh.intersect(ray),
+            BuiltScene::BVHFlat(bvh) => bvh.intersect(ray),
+        }
+    }
+
+    fn lights(&self) -> Vec<Light> {
+        match self {
+            BuiltScene::Naive(scene) => scene.lights(),
+            BuiltScene::BVHPointers(bvh) => bvh.lights(),
+            BuiltScene::BVHFlat(bvh) => bvh.lights(),
+        }
+    }
+
+    fn has_mattes(&self) -> bool {
+        match self {
+            BuiltScene::Naive(scene) => scene.has_mattes(),
+            BuiltScene::BVHPointers(bvh) => bvh.has_mattes(),
+            BuiltScene::BVHFlat(bvh) => bvh.has_mattes(),
+        }
+    }
+}
+
+impl Traversable for BuiltScene {
+    fn intersect_with_cost(&self, ray: Ray) -> (Option<Collision>, TraversalCost) {
+        match self {
+            BuiltScene::Naive(scene) => scene.intersect_with_cost(ray),
+            BuiltScene::BVHPointers(bvh) => bvh.intersect_with_cost(ray),
+            BuiltScene::BVHFlat(bvh) => bvh.intersect_with_cost(ray),
+        }
+    }
+}
+
+fn render_command(mut args: RenderArgs) -> Result<ExitCode, String> {
+    let format = match args.format {
+        Some(format) => format,
+        None => OutputFormat::from_filename(&args.output).ok_or_else(|| {
+            format!(
+                "can't tell what format to save {} in, pass --format or use a known extension",
+                args.output
+            )
+        })?,
     };
+    let parallel = args.options.setup_threads()?;
+    let checkpoint_file = format!("{}.checkpoint", args.output);
+    // Randomly generated scenes only come out the same again with the same seed, so checkpointed
+    // renders always get one, and resumed renders pick up the one they started with.
+    if (args.checkpoint || args.resume) && args.scene.seed.is_none() {
+        args.scene.seed = Some(if args.resume {
+            Checkpoint::load_seed(&checkpoint_file)?
+        } else {
+            rand::random()
+        });
+    }
+    let loaded = args.scene.load()?;
+    let (camera_description, raytracer) = args.options.apply(&loaded)?;
+    let camera = camera_description.build();
+    let bounds = camera.bounds();
+
+    // The sample count is left out, so that a finished render can be resumed with more samples.
+    // Only checkpoints need the hash, which is as well, since textures embedded in scenes, like the
+    // ones in .glb files, can't be serialized to hash them.
+    let hash = (args.checkpoint || args.resume)
+        .then(|| {
+            scene_hash(&(
+                &loaded.scene,
+                &camera_description,
+                raytracer.clone().ss_amt(0),
+            ))
+        })
+        .transpose()?;
+    let scene = BuiltScene::build(loaded.scene, &args.scene.strategy)?;
 
-    let raytracer = Raytracer::default().ss_amt(ss_amt).max_depth(32);
+    if let Some(metric) = args.heatmap {
+        let mut heatmap = Heatmap::default().metric(metric);
+        if let Some(max) = args.heatmap_max {
+            heatmap = heatmap.max(max);
+        }
+        let costs = heatmap.costs(&scene, &camera);
+        let mean = costs.iter().sum::<usize>() as f64 / costs.len().max(1) as f64;
+        let max = costs.iter().max().copied().unwrap_or(0);
+        println!("Traversal cost per primary ray: {:.2} mean, {} max", mean, max);
+        heatmap.draw(&costs, bounds).save_as(args.output, format)?;
+        return Ok(ExitCode::SUCCESS);
+    }
 
-    let image = match strategy {
-        RtStrategy::Naive => conditional_render(&raytracer, &camera, &elements, bounds, parallel),
-        RtStrategy::BVHPointers => {
-            println!("Generating Pointer BVH...");
-            let bvh = BVHBuildNode::new(elements, 4);
-            println!("Done.");
-            conditional_render(&raytracer, &camera, &bvh, bounds, parallel)
+    println!(
+        "Rendering {}x{} at {} samples per pixel...",
+        bounds.0,
+        bounds.1,
+        raytracer.samples_per_pixel()
+    );
+    let scheduler = TileScheduler::default()
+        .tile_size(args.tile_size)
+        .order(args.tile_order);
+    let film = if let Some(hash) = hash {
+        let mut checkpointer = Checkpointer::new(&checkpoint_file)
+            .parallel(parallel)
+            .scheduler(scheduler);
+        if let Some(seed) = args.scene.seed {
+            checkpointer = checkpointer.seed(seed);
         }
-        RtStrategy::BVHFlat => {
-            println!("Generating Flat BVH...");
-            let bvh = BVHBuildNode::new(elements, 4);
-            let flat_bvh: BVHFlat = bvh.into();
-            println!("Done.");
-            conditional_render(&raytracer, &camera, &flat_bvh, bounds, parallel)
+        checkpointer
+            .render(&raytracer, &camera, &scene, hash, args.resume, |done, total| {
+                println!("{}/{} samples per pixel", done, total)
+            })
+            .map_err(|e| format!("{}: {}", checkpointer.filename(), e))?
+    } else if parallel {
+        let mut scheduler = scheduler;
+        if let Some(seed) = args.scene.seed {
+            scheduler = scheduler.seed(seed);
         }
+        scheduler.render(&raytracer, &camera, &scene, bounds, |progress| {
+            eprint!("\rTile {}/{}", progress.completed, progress.total);
+            if progress.completed == progress.total {
+                eprintln!();
+            }
+        })
+    } else {
+        let mut film = Film::new(bounds, raytracer.pixel_filter());
+        raytracer.render_film(&scene, &mut film, &camera)?;
+        film
     };
+    if RayStats::ENABLED {
+        println!("{}", film.stats);
+    }
 
-    image.save(filename.to_string()).unwrap();
+    if !args.denoise && !args.passes {
+        film.to_image().save_as(args.output, format)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let mut frame = FrameBuffer::new(bounds.0, bounds.1);
+    raytracer.record_first_hits(&scene, &mut frame, &camera);
+    frame.beauty = film.to_image();
+    if args.passes {
+        frame.save_all(args.output.clone())?;
+    }
+    if args.denoise {
+        println!("Denoising...");
+        let denoised = denoise(&frame);
+        denoised.save_as(args.output, format)?;
+    } else {
+        frame.beauty.save_as(args.output, format)?;
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn bench_command(args: BenchArgs) -> Result<ExitCode, String> {
+    if args.runs == 0 {
+        return Err("we need at least one run".to_owned());
+    }
+    let parallel = args.options.setup_threads()?;
+    let loaded = args.scene.load()?;
+    let (camera_description, raytracer) = args.options.apply(&loaded)?;
+    let camera = camera_description.build();
+    let bounds = camera.bounds();
+
+    let start = Instant::now();
+    let scene = BuiltScene::build(loaded.scene, &args.scene.strategy)?;
+    println!("Built the scene in {:.3} seconds", start.elapsed().as_secs_f32());
+
+    let mut times = Vec::with_capacity(args.runs);
+    for run in 0..args.runs {
+        let start = Instant::now();
+        conditional_render(&raytracer, &camera, &scene, bounds, parallel);
+        times.push(start.elapsed());
+        println!("Run {}: {:.3} seconds", run + 1, times[run].as_secs_f32());
+    }
+
+    let mean = times.iter().sum::<Duration>() / args.runs as u32;
+    let primary_rays = bounds.0 * bounds.1 * raytracer.samples_per_pixel();
+    println!(
+        "Mean {:.3} seconds, {:.0} primary rays per second",
+        mean.as_secs_f32(),
+        primary_rays as f32 / mean.as_secs_f32()
+    );
+    Ok(ExitCode::SUCCESS)
+}
+
+fn inspect_command(args: InspectArgs) -> Result<ExitCode, String> {
+    let loaded = args.scene.load()?;
+    let primitives = &loaded.scene.primitives;
+    let materials = &loaded.scene.materials;
+
+    let count = |f: fn(&Primitive) -> bool| primitives.iter().filter(|p| f(p)).count();
+    println!("Primitives: {}", primitives.len());
+    println!("\tSpheres: {}", count(|p| matches!(p, Primitive::Sphere { .. })));
+    println!("\tTriangles: {}", count(|p| matches!(p, Primitive::Triangle { .. })));
+    println!("\tMedia: {}", count(|p| matches!(p, Primitive::Medium(_))));
+    println!("\tGrid media: {}", count(|p| matches!(p, Primitive::GridMedium(_))));
+    println!("\tNormal or bump mapped: {}", count(|p| matches!(p, Primitive::Mapped { .. })));
+    println!("\tCut out: {}", count(|p| matches!(p, Primitive::Cutout { .. })));
+    let made_of = |f: fn(&Material) -> bool| {
+        let made_of = |p: &&Primitive| p.material().is_some_and(|m| f(&materials[m]));
+        primitives.iter().filter(made_of).count()
+    };
+    println!("\tHoldouts: {}", made_of(Material::is_holdout));
+    println!("\tShadow catchers: {}", made_of(Material::is_shadow_catcher));
+
+    println!("Materials: {}", materials.len());
+    let names: Vec<&str> = materials.iter().filter_map(|(id, _)| materials.name(id)).collect();
+    if !names.is_empty() {
+        println!("\tNamed: {}", names.join(", "));
+    }
+    println!("Lights: {}", find_lights(primitives, materials).len());
+
+    if let Some(camera) = &loaded.camera {
+        println!(
+            "Camera: at {} looking along {}, {}x{}",
+            camera.position, camera.direction, camera.bounds.0, camera.bounds.1
+        );
+    }
+
+    let start = Instant::now();
+    let scene = BuiltScene::build(loaded.scene, &args.scene.strategy)?;
+    let build_time = start.elapsed();
+    if let Some(stats) = scene.stats() {
+        let bounds = match &scene {
+            BuiltScene::BVHPointers(bvh) => bvh.bounds(),
+            BuiltScene::BVHFlat(bvh) => bvh.bounds(),
+            BuiltScene::Naive(_) => unreachable!(),
+        };
+        println!("Scene bounds: {} to {}", bounds.min_point, bounds.max_point);
+        println!("BVH built in {:.3} seconds", build_time.as_secs_f32());
+        println!("\tNodes: {}", stats.nodes);
+        println!("\tInterior nodes: {}", stats.interior_nodes);
+        println!("\tLeaves: {}", stats.leaves);
+        println!("\tMax depth: {}", stats.max_depth);
+        println!(
+            "\tPrimitives per leaf: {:.2} on average, {} at most",
+            stats.mean_leaf_primitives(),
+            stats.max_leaf_primitives
+        );
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn convert_command(args: ConvertArgs) -> Result<ExitCode, String> {
+    let loaded = args.scene.load()?;
+    if args.output.ends_with(".obj") {
+        save_obj(&loaded.scene.primitives, &args.output)?;
+    } else if args.output.ends_with(".json") {
+        let (camera, raytracer) = args.options.apply(&loaded)?;
+        SceneDescription::new(camera, raytracer, loaded.scene).save(&args.output)?;
+    } else {
+        return Err(format!(
+            "can't tell what to convert {} to, use a .json or .obj extension",
+            args.output
+        ));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn diff_command(args: DiffArgs) -> Result<ExitCode, String> {
+    let first = ImageBuffer::load(&args.first)?;
+    let second = ImageBuffer::load(&args.second)?;
+    let comparison = first.compare(&second)?;
+
+    println!("RMSE: {:.6}", comparison.rmse);
+    println!("PSNR: {:.2} dB", comparison.psnr);
+    println!("Largest difference: {:.6}", comparison.max_difference);
+    println!(
+        "Differing pixels: {} of {}",
+        comparison.differing_pixels,
+        first.pixels.len()
+    );
+
+    if let Some(output) = args.output {
+        let mut difference = comparison.difference;
+        for pixel in difference.pixels.iter_mut() {
+            *pixel = pixel.scale(args.amplify);
+        }
+        difference.save(output)?;
+    }
+
+    match args.threshold {
+        Some(threshold) if comparison.rmse > threshold => {
+            println!("The images differ by more than {}", threshold);
+            Ok(ExitCode::FAILURE)
+        }
+        _ => Ok(ExitCode::SUCCESS),
+    }
 }
@@ -1,15 +1,22 @@
 use std::collections::VecDeque;
 
 use crate::{
+    light::Light,
+    material::Material,
+    material_library::{MaterialId, MaterialLibrary},
     primitives::Primitive,
     ray::Ray,
     raytracer::Collision,
-    traits::{intersect_collection, Boundable, Drawable},
+    scene::Scene,
+    stats,
+    traits::{Drawable, Traversable},
     vectors::V3,
 };
 
+use serde::{Deserialize, Serialize};
+
 /// Bounds defines an axis-aligned area in 3d space, bounded between its min_point and max_point
-#[derive(Clone, Copy, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
 pub struct Bounds {
     pub min_point: V3,
     pub max_point: V3,
@@ -87,6 +94,16 @@ impl Bounds {
     /// This version of intersection takes a precomputed inverted direction.
     /// This division is expensive and can be done just once for each ray.
     fn intersects_with_dir_inv(&self, ray: &Ray, d_inv: V3) -> bool {
+        self.clip_with_dir_inv(ray, d_inv).is_some()
+    }
+
+    /// Find the range of t values over which a ray is inside these bounds, if it enters them at all.
+    pub fn clip(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let d_inv = V3::new(1. / ray.dir.x, 1. / ray.dir.y, 1. / ray.dir.z);
+        self.clip_with_dir_inv(ray, d_inv)
+    }
+
+    fn clip_with_dir_inv(&self, ray: &Ray, d_inv: V3) -> Option<(f32, f32)> {
         // We are really looking for the furthest intersection with a near-plane
         //  and the nearest intersection with a far-plane.
         // If the ray passes through the volume, the near-plane intersection
@@ -99,9 +116,7 @@ impl Bounds {
             let mut t_far = (axis.proj(self.max_point) - axis.proj(ray.origin)) * axis.proj(d_inv);
             // Swap if necessary so these are ordered correctly
             if t_near > t_far {
-                let temp = t_near;
-                t_near = t_far;
-                t_far = temp;
+                std::mem::swap(&mut t_near, &mut t_far);
             }
 
             // Update our overall values with our new info.
@@ -116,11 +131,11 @@ impl Bounds {
             }
 
             if overall_t_near > overall_t_far {
-                return false;
+                return None;
             }
         }
 
-        true
+        Some((overall_t_near, overall_t_far))
     }
 }
 
@@ -130,37 +145,25 @@ impl Bounds {
 #[derive(Debug)]
 pub struct BVHPrimitiveInfo {
     primitive: Primitive,
+    /// Where this primitive sat in the list the BVH was built from.
+    index: usize,
     bounds: Bounds,
     centroid: V3,
 }
 
 impl BVHPrimitiveInfo {
-    pub fn new(primitive: Primitive) -> Self {
+    pub fn new(index: usize, primitive: Primitive) -> Self {
         let bounds = primitive.bounds();
         let centroid = bounds.centroid();
         BVHPrimitiveInfo {
             primitive,
+            index,
             bounds,
             centroid,
         }
     }
 }
 
-// While this was intended to be turned into a LinearBVH before rendering,
-// we go ahead and implement Drawable for all our BVH components so that
-// it can also be drawn while just a tree.
-impl Drawable for BVHPrimitiveInfo {
-    fn intersect(&self, ray: Ray) -> Option<Collision> {
-        self.primitive.intersect(ray)
-    }
-}
-
-impl Drawable for &BVHPrimitiveInfo {
-    fn intersect(&self, ray: Ray) -> Option<Collision> {
-        self.primitive.intersect(ray)
-    }
-}
-
 /// A way of referring to axes
 #[derive(Debug)]
 enum SplitAxis {
@@ -188,6 +191,9 @@ pub struct BVHBuildNode {
     n_prims: usize,
     pub n_nodes: usize,
     data: BVHBuildNodeData,
+    /// The materials the primitives in the tree are made of. Only the root holds these, the nodes
+    /// below it leave theirs empty.
+    materials: MaterialLibrary,
 }
 
 /// A BVHBuildNode can either have relevant BVHPrimitiveInfo items to query
@@ -199,13 +205,20 @@ enum BVHBuildNodeData {
 }
 
 impl BVHBuildNode {
-    pub fn new(mut primatives: Vec<Primitive>, prims_per_leaf: usize) -> Self {
-        let prim_infos: Vec<BVHPrimitiveInfo> = primatives
-            .drain(..)
-            .map(|prim| BVHPrimitiveInfo::new(prim))
+    /// Build a tree over a scene's primitives. Every primitive has to be made of a material in the
+    /// scene's library, since rays look their materials up in it without checking.
+    pub fn new(scene: Scene, prims_per_leaf: usize) -> Result<Self, String> {
+        scene.validate()?;
+        let prim_infos: Vec<BVHPrimitiveInfo> = scene
+            .primitives
+            .into_iter()
+            .enumerate()
+            .map(|(index, prim)| BVHPrimitiveInfo::new(index, prim))
             .collect();
 
-        Self::recursive_build_bvh(prim_infos, prims_per_leaf)
+        let mut root = Self::recursive_build_bvh(prim_infos, prims_per_leaf);
+        root.materials = scene.materials;
+        Ok(root)
     }
 
     fn recursive_build_bvh(
@@ -269,6 +282,7 @@ impl BVHBuildNode {
             n_prims: prim_infos.len(),
             n_nodes: 1,
             data: BVHBuildNodeData::PrimInfos(prim_infos),
+            materials: MaterialLibrary::default(),
         }
     }
 
@@ -283,39 +297,185 @@ impl BVHBuildNode {
             n_prims: c1.n_prims + c2.n_prims,
             n_nodes: c1.n_nodes + c2.n_nodes,
             data: BVHBuildNodeData::Children(Box::new((c1, c2))),
+            materials: MaterialLibrary::default(),
         }
     }
 
     fn is_leaf(&self) -> bool {
         matches!(self.data, BVHBuildNodeData::PrimInfos(_))
     }
-}
 
-impl Drawable for BVHBuildNode {
-    fn intersect(&self, ray: Ray) -> Option<Collision> {
-        if self.bounds.intersects(&ray) {
-            match self.data {
-                BVHBuildNodeData::PrimInfos(ref prim_infos) => {
-                    intersect_collection(prim_infos, ray)
+    /// The bounds of everything in the tree.
+    pub fn bounds(&self) -> Bounds {
+        self.bounds
+    }
+
+    /// Measure the shape of the tree.
+    pub fn stats(&self) -> BVHStats {
+        let mut stats = BVHStats::default();
+        let mut stack = vec![(self, 1)];
+        while let Some((node, depth)) = stack.pop() {
+            match node.data {
+                BVHBuildNodeData::PrimInfos(ref prim_infos) => stats.add_leaf(prim_infos.len(), depth),
+                BVHBuildNodeData::Children(ref children) => {
+                    stats.add_interior(depth);
+                    stack.push((&children.0, depth + 1));
+                    stack.push((&children.1, depth + 1));
                 }
+            }
+        }
+        stats
+    }
+}
+
+/// How much work it took to find what a single ray hits. Unlike the ray stats, this is always counted,
+/// since it's just a couple of increments on the stack.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TraversalCost {
+    /// Nodes whose bounds we tested the ray against.
+    pub nodes: usize,
+    /// Primitives we tested the ray against.
+    pub primitives: usize,
+}
+
+impl TraversalCost {
+    pub fn total(&self) -> usize {
+        self.nodes + self.primitives
+    }
+}
+
+/// Some numbers describing the shape of a BVH, to help judge how well it was built.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BVHStats {
+    pub primitives: usize,
+    pub nodes: usize,
+    pub interior_nodes: usize,
+    pub leaves: usize,
+    /// The number of nodes on the longest path from the root to a leaf.
+    pub max_depth: usize,
+    pub max_leaf_primitives: usize,
+}
+
+impl BVHStats {
+    fn add_leaf(&mut self, n_prims: usize, depth: usize) {
+        self.primitives += n_prims;
+        self.nodes += 1;
+        self.leaves += 1;
+        self.max_depth = self.max_depth.max(depth);
+        self.max_leaf_primitives = self.max_leaf_primitives.max(n_prims);
+    }
+
+    fn add_interior(&mut self, depth: usize) {
+        self.nodes += 1;
+        self.interior_nodes += 1;
+        self.max_depth = self.max_depth.max(depth);
+    }
+
+    pub fn mean_leaf_primitives(&self) -> f32 {
+        self.primitives as f32 / self.leaves.max(1) as f32
+    }
+}
+
+impl BVHBuildNode {
+    /// Every primitive in the tree, in no particular order.
+    fn primitive_infos(&self) -> Vec<&BVHPrimitiveInfo> {
+        let mut infos = Vec::new();
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            match node.data {
+                BVHBuildNodeData::PrimInfos(ref prim_infos) => infos.extend(prim_infos),
                 BVHBuildNodeData::Children(ref children) => {
-                    if self.split_axis.proj(ray.dir) < 0. {
-                        intersect_collection([&children.1, &children.0], ray)
-                    } else {
-                        intersect_collection([&children.0, &children.1], ray)
+                    stack.push(&children.0);
+                    stack.push(&children.1);
+                }
+            }
+        }
+        infos
+    }
+
+    /// Intersect a ray with the tree, counting every node and primitive we look at along the way.
+    fn traverse(
+        &self,
+        mut ray: Ray,
+        materials: &MaterialLibrary,
+        cost: &mut TraversalCost,
+    ) -> Option<Collision> {
+        stats::record(|s| s.bvh_nodes_visited += 1);
+        cost.nodes += 1;
+        if !self.bounds.intersects(&ray) {
+            return None;
+        }
+        match self.data {
+            BVHBuildNodeData::PrimInfos(ref prim_infos) => {
+                cost.primitives += prim_infos.len();
+                let mut out = None;
+                for pi in prim_infos {
+                    if let Some(coll) = pi.primitive.intersect(ray, materials) {
+                        ray.max = coll.t;
+                        out = Some(coll.with_primitive_index(pi.index));
                     }
                 }
+                out
+            }
+            BVHBuildNodeData::Children(ref children) => {
+                let (near, far) = if self.split_axis.proj(ray.dir) < 0. {
+                    (&children.1, &children.0)
+                } else {
+                    (&children.0, &children.1)
+                };
+                let out = near.traverse(ray, materials, cost);
+                if let Some(ref coll) = out {
+                    ray.max = coll.t;
+                }
+                far.traverse(ray, materials, cost).or(out)
             }
-        } else {
-            None
         }
     }
 }
 
+// While this was intended to be turned into a LinearBVH before rendering,
+// we go ahead and implement Drawable for the tree so that it can also be drawn as it is.
+impl Drawable for BVHBuildNode {
+    fn intersect(&self, ray: Ray) -> Option<Collision> {
+        self.traverse(ray, &self.materials, &mut TraversalCost::default())
+    }
+
+    fn lights(&self) -> Vec<Light> {
+        let mut lights: Vec<Light> = self
+            .primitive_infos()
+            .into_iter()
+            .filter_map(|pi| Light::from_primitive(&pi.primitive, &self.materials, pi.index))
+            .collect();
+        lights.sort_by_key(|light| light.primitive_index);
+        lights
+    }
+
+    fn has_mattes(&self) -> bool {
+        self.primitive_infos()
+            .iter()
+            .any(|pi| pi.primitive.is_matte(&self.materials))
+    }
+}
+
+impl Traversable for BVHBuildNode {
+    fn intersect_with_cost(&self, ray: Ray) -> (Option<Collision>, TraversalCost) {
+        let mut cost = TraversalCost::default();
+        (self.traverse(ray, &self.materials, &mut cost), cost)
+    }
+}
+
 impl Drawable for &BVHBuildNode {
     fn intersect(&self, ray: Ray) -> Option<Collision> {
         (*self).intersect(ray)
     }
+
+    fn lights(&self) -> Vec<Light> {
+        (*self).lights()
+    }
+
+    fn has_mattes(&self) -> bool {
+        (*self).has_mattes()
+    }
 }
 
 /// The FlatBVH is a flattened BVH tree, eschewing pointers for a contiguous chunk of memory.
@@ -323,12 +483,76 @@ impl Drawable for &BVHBuildNode {
 /// BVHPrimitiveInfos.
 pub struct BVHFlat {
     nodes: Vec<BVHFlatNode>,
+    materials: MaterialLibrary,
+    /// The tree's lights are gathered up front, since every film rendered asks for them.
+    lights: Vec<Light>,
+    has_mattes: bool,
 }
 
-impl BVHFlat {}
+impl BVHFlat {
+    /// The materials the primitives in the tree are made of.
+    pub fn materials(&self) -> &MaterialLibrary {
+        &self.materials
+    }
+
+    /// Swap out one of the tree's materials, changing everything made of it, without having to
+    /// build the tree again. This can turn things into lights or mattes, or back, so the lights
+    /// get gathered up again.
+    pub fn set_material(&mut self, id: MaterialId, material: Material) -> Result<(), String> {
+        self.materials.set(id, material)?;
+        let mut lights = Vec::new();
+        let mut has_mattes = false;
+        for node in &self.nodes {
+            if let BVHFlatNodeData::Prims(ref prims) = node.data {
+                for (index, p) in prims {
+                    lights.extend(Light::from_primitive(p, &self.materials, *index));
+                    has_mattes |= p.is_matte(&self.materials);
+                }
+            }
+        }
+        lights.sort_by_key(|light| light.primitive_index);
+        self.lights = lights;
+        self.has_mattes = has_mattes;
+        Ok(())
+    }
+
+    /// Swap out the material with some name.
+    pub fn set_named_material(&mut self, name: &str, material: Material) -> Result<(), String> {
+        let id = self
+            .materials
+            .find(name)
+            .ok_or_else(|| format!("there's no material called '{}'", name))?;
+        self.set_material(id, material)
+    }
+
+    /// The bounds of everything in the tree.
+    pub fn bounds(&self) -> Bounds {
+        self.nodes[0].bounds
+    }
+
+    /// Measure the shape of the tree.
+    pub fn stats(&self) -> BVHStats {
+        let mut stats = BVHStats::default();
+        let mut stack = vec![(0, 1)];
+        while let Some((offset, depth)) = stack.pop() {
+            match self.nodes[offset].data {
+                BVHFlatNodeData::Prims(ref prims) => stats.add_leaf(prims.len(), depth),
+                BVHFlatNodeData::Children((first, second)) => {
+                    stats.add_interior(depth);
+                    stack.push((first, depth + 1));
+                    stack.push((second, depth + 1));
+                }
+            }
+        }
+        stats
+    }
+}
 
 impl From<BVHBuildNode> for BVHFlat {
-    fn from(root: BVHBuildNode) -> Self {
+    fn from(mut root: BVHBuildNode) -> Self {
+        let lights = root.lights();
+        let has_mattes = root.has_mattes();
+        let materials = std::mem::take(&mut root.materials);
         // Since this is a flattened binary tree, we need our number of nodes to be a
         // power of two for child-getting logic to work out. Here we find the smallest
         // power of two which can contain our data.
@@ -344,8 +568,10 @@ impl From<BVHBuildNode> for BVHFlat {
         loop {
             match current_node.data {
                 BVHBuildNodeData::PrimInfos(mut prim_infos) => {
-                    let prims: Vec<Primitive> =
-                        prim_infos.drain(..).map(|pi| pi.primitive).collect();
+                    let prims: Vec<(usize, Primitive)> = prim_infos
+                        .drain(..)
+                        .map(|pi| (pi.index, pi.primitive))
+                        .collect();
                     array.push(BVHFlatNode {
                         split_axis: current_node.split_axis,
                         bounds: current_node.bounds,
@@ -378,7 +604,12 @@ impl From<BVHBuildNode> for BVHFlat {
             }
         }
 
-        BVHFlat { nodes: array }
+        BVHFlat {
+            nodes: array,
+            materials,
+            lights,
+            has_mattes,
+        }
     }
 }
 
@@ -390,11 +621,13 @@ struct BVHFlatNode {
 
 enum BVHFlatNodeData {
     Children((usize, usize)),
-    Prims(Vec<Primitive>),
+    /// Primitives, alongside their index in the list the BVH was built from.
+    Prims(Vec<(usize, Primitive)>),
 }
 
-impl Drawable for BVHFlat {
-    fn intersect(&self, mut ray: Ray) -> Option<Collision> {
+impl BVHFlat {
+    /// Intersect a ray with the tree, counting every node and primitive we look at along the way.
+    fn traverse(&self, mut ray: Ray, cost: &mut TraversalCost) -> Option<Collision> {
         let mut current_offset = 0;
         let mut offset_stack: Vec<usize> = Vec::with_capacity(128);
         let mut collision: Option<Collision> = None;
@@ -403,26 +636,29 @@ impl Drawable for BVHFlat {
 
         loop {
             let node = &self.nodes[current_offset];
+            stats::record(|s| s.bvh_nodes_visited += 1);
+            cost.nodes += 1;
             if node.bounds.intersects_with_dir_inv(&ray, dir_inv) {
                 match node.data {
                     BVHFlatNodeData::Prims(ref prims) => {
-                        for p in prims {
-                            if let Some(coll) = p.intersect(ray) {
+                        cost.primitives += prims.len();
+                        for (index, p) in prims {
+                            if let Some(coll) = p.intersect(ray, &self.materials) {
                                 ray.max = coll.t;
-                                collision = Some(coll);
+                                collision = Some(coll.with_primitive_index(*index));
                             }
                         }
                     }
                     BVHFlatNodeData::Children(child_offsets) => {
                         // If the direction is negative compared to this axis, visit
                         // the second (more positive) child first, since it's spacially
-                        // closer.
+                        // closer. The stack pops the last push first.
                         if node.split_axis.proj(ray.dir) < 0. {
-                            offset_stack.push(child_offsets.1);
                             offset_stack.push(child_offsets.0);
+                            offset_stack.push(child_offsets.1);
                         } else {
-                            offset_stack.push(child_offsets.0);
                             offset_stack.push(child_offsets.1);
+                            offset_stack.push(child_offsets.0);
                         }
                     }
                 }
@@ -441,3 +677,24 @@ impl Drawable for BVHFlat {
         collision
     }
 }
+
+impl Drawable for BVHFlat {
+    fn intersect(&self, ray: Ray) -> Option<Collision> {
+        self.traverse(ray, &mut TraversalCost::default())
+    }
+
+    fn lights(&self) -> Vec<Light> {
+        self.lights.clone()
+    }
+
+    fn has_mattes(&self) -> bool {
+        self.has_mattes
+    }
+}
+
+impl Traversable for BVHFlat {
+    fn intersect_with_cost(&self, ray: Ray) -> (Option<Collision>, TraversalCost) {
+        let mut cost = TraversalCost::default();
+        (self.traverse(ray, &mut cost), cost)
+    }
+}
@@ -1,4 +1,3 @@
-use rand::rngs::ThreadRng;
 use rand::Rng;
 
 use crate::ray::Ray;
@@ -14,6 +13,8 @@ pub struct Camera {
     horizontal: V3,
     // vector from bottom to top viewport border
     vertical: V3,
+    // unit vector along where we're looking. The viewport is one unit out this way.
+    forward: V3,
     /// Our viewport bounds in pixels
     bounds: (usize, usize),
 }
@@ -22,7 +23,8 @@ impl Camera {
     pub fn new(position: V3, direction: V3, up: V3, fov: f32, bounds: (usize, usize)) -> Camera {
         let aspect_r = bounds.0 as f32 / bounds.1 as f32;
         let z = direction.normalized();
-        let y = up.normalized();
+        // Straighten up out so it's perpendicular to where we're looking, or the image would be skewed.
+        let y = (up - z * up.dot(&z)).normalized();
         let x = z.cross(&y);
         let vertical = y * (2.0 * f32::tan(fov / 2.0));
         let horizontal = x * (-2.0 * f32::tan(fov / 2.0) * aspect_r);
@@ -32,10 +34,16 @@ impl Camera {
             upper_left,
             horizontal,
             vertical,
+            forward: z,
             bounds,
         }
     }
 
+    /// Our viewport bounds in pixels.
+    pub fn bounds(&self) -> (usize, usize) {
+        self.bounds
+    }
+
 	/// Get a ray coming out of the camera at these pixel coordinates.
     pub fn get_ray(&self, x: usize, y: usize) -> Ray {
         let x_frac = x as f32 / self.bounds.0 as f32;
@@ -44,14 +52,51 @@ impl Camera {
     }
 
 	// Get a ray coming out of the camera at these pixel coordinates, with sub-pixel perturbation for supersampling.
-    pub fn get_ray_perturbed(&self, x: usize, y: usize, rand: &mut ThreadRng) -> Ray {
+    pub fn get_ray_perturbed(&self, x: usize, y: usize, rand: &mut impl Rng) -> Ray {
         let x_frac = (x as f32 + rand.gen::<f32>()) / self.bounds.0 as f32;
         let y_frac = (y as f32 + rand.gen::<f32>()) / self.bounds.1 as f32;
         self.get_ray_from_f32(x_frac, y_frac)
     }
 
+	/// Get a ray coming out of the camera at a point in pixel coordinates, which needn't be whole numbers.
+    pub fn get_ray_from_pixel(&self, x: f32, y: f32) -> Ray {
+        self.get_ray_from_f32(x / self.bounds.0 as f32, y / self.bounds.1 as f32)
+    }
+
+    pub fn position(&self) -> V3 {
+        self.position
+    }
+
+	/// Find where a point in the scene shows up in the image, in pixel coordinates, if it's in view at all.
+    pub fn project(&self, point: V3) -> Option<(f32, f32)> {
+        let dir = point - self.position;
+        let distance = dir.dot(&self.forward);
+        if distance <= 0. {
+            return None;
+        }
+        // Follow the direction out to the viewport, then see where that is across and down it.
+        let on_viewport = self.position + dir / distance - self.upper_left;
+        let x = on_viewport.dot(&self.horizontal) / self.horizontal.magnitude_squared();
+        let y = -on_viewport.dot(&self.vertical) / self.vertical.magnitude_squared();
+        if !(0. ..1.).contains(&x) || !(0. ..1.).contains(&y) {
+            return None;
+        }
+        Some((x * self.bounds.0 as f32, y * self.bounds.1 as f32))
+    }
+
+	/// The chance of a camera ray heading along `dir`, per unit solid angle, when rays are spread evenly
+	/// over the whole image. This doesn't check the direction is actually in view; project does that.
+    pub fn direction_pdf(&self, dir: V3) -> f32 {
+        let cos_theta = dir.normalized().dot(&self.forward);
+        if cos_theta <= 0. {
+            return 0.;
+        }
+        let viewport_area = self.horizontal.magnitude() * self.vertical.magnitude();
+        1. / (viewport_area * cos_theta * cos_theta * cos_theta)
+    }
+
     /// takes x, y in [0, 1)x[0, 1)
-    pub fn get_ray_from_f32(self: &Self, x: f32, y: f32) -> Ray {
+    pub fn get_ray_from_f32(&self, x: f32, y: f32) -> Ray {
         let dir = self.upper_left + (self.horizontal * x) - (self.vertical * y) - self.position;
         Ray::new(self.position, dir.normalized())
     }
@@ -0,0 +1,298 @@
+use std::time::{Duration, Instant};
+
+use crate::camera::Camera;
+use crate::film::Film;
+use crate::raytracer::Raytracer;
+use crate::sampler::{seed_sampler, stream_seed};
+use crate::scheduler::TileScheduler;
+use crate::traits::Drawable;
+
+use serde::{Deserialize, Serialize};
+
+// A long render is taken a few samples per pixel at a time, in passes. Between passes we have an
+// unclamped film holding everything accumulated so far, which gets written out every so often.
+// If the process dies, the render can pick up from the last checkpoint instead of starting over.
+//
+// Every pass reseeds the sampler from the render's seed and the number of samples taken so far, so
+// a resumed render takes exactly the samples it would have taken had it never stopped, as long as it
+// keeps the same pass and tile sizes. That means the seed and the sample count are all the sampler
+// state we need to save. Scenes which are generated at random need that same seed to come out the
+// same again, so load_seed can fish it out of a checkpoint before the scene gets built.
+
+/// Everything needed to carry on with a render.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub version: u32,
+    /// A hash of the scene and settings, so we don't resume a render of something else.
+    pub scene_hash: u64,
+    pub seed: u64,
+    /// How many samples per pixel have been taken so far.
+    pub samples_per_pixel: usize,
+    /// The unclamped accumulation buffer, along with how many samples landed in each pixel.
+    pub film: Film,
+}
+
+impl Checkpoint {
+    const VERSION: u32 = 1;
+
+    pub fn new(scene_hash: u64, seed: u64, film: Film) -> Self {
+        Self {
+            version: Self::VERSION,
+            scene_hash,
+            seed,
+            samples_per_pixel: 0,
+            film,
+        }
+    }
+
+    pub fn load(filename: &str) -> Result<Self, String> {
+        let bytes = std::fs::read(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        let checkpoint: Self =
+            serde_json::from_slice(&bytes).map_err(|e| format!("{}: {}", filename, e))?;
+        if checkpoint.version != Self::VERSION {
+            return Err(format!("unsupported checkpoint version {}", checkpoint.version));
+        }
+        Ok(checkpoint)
+    }
+
+    /// Read just the seed from a checkpoint file, skipping over the film.
+    pub fn load_seed(filename: &str) -> Result<u64, String> {
+        #[derive(Deserialize)]
+        struct Header {
+            version: u32,
+            seed: u64,
+        }
+
+        let bytes = std::fs::read(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        let header: Header =
+            serde_json::from_slice(&bytes).map_err(|e| format!("{}: {}", filename, e))?;
+        if header.version != Self::VERSION {
+            return Err(format!("unsupported checkpoint version {}", header.version));
+        }
+        Ok(header.seed)
+    }
+
+    /// Write the checkpoint out. It goes to a temporary file first, which then replaces the old
+    /// checkpoint, so dying partway through a write never leaves us with nothing.
+    pub fn save(&self, filename: &str) -> Result<(), String> {
+        let bytes = serde_json::to_vec(self).map_err(|e| e.to_string())?;
+        let temporary = format!("{}.tmp", filename);
+        std::fs::write(&temporary, bytes)
+            .and_then(|_| std::fs::rename(&temporary, filename))
+            .map_err(|e| format!("{}: {}", filename, e))
+    }
+}
+
+/// Hash anything which can be serialized, like a list of primitives or a raytracer's settings.
+/// This is 64 bit FNV-1a over its JSON, which is plenty to tell scenes apart.
+pub fn scene_hash<T: Serialize + ?Sized>(scene: &T) -> Result<u64, String> {
+    let bytes = serde_json::to_vec(scene).map_err(|e| e.to_string())?;
+    Ok(bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    }))
+}
+
+/// Renders in passes, writing a checkpoint every so often.
+#[derive(Clone, Debug)]
+pub struct Checkpointer {
+    filename: String,
+    interval: Duration,
+    samples_per_pass: usize,
+    seed: Option<u64>,
+    parallel: bool,
+    scheduler: TileScheduler,
+}
+
+impl Checkpointer {
+    pub fn new(filename: &str) -> Self {
+        Self {
+            filename: filename.to_owned(),
+            interval: Duration::from_secs(60),
+            samples_per_pass: 1,
+            seed: None,
+            parallel: true,
+            scheduler: TileScheduler::default(),
+        }
+    }
+
+	/// Builder pattern function to set how long to wait between checkpoints.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+	/// Builder pattern function to set how many samples per pixel each pass takes. Checkpoints can only
+	/// be written between passes.
+    pub fn samples_per_pass(mut self, samples_per_pass: usize) -> Self {
+        self.samples_per_pass = samples_per_pass.max(1);
+        self
+    }
+
+	/// Builder pattern function to set the seed for a new render. Resumed renders use the checkpoint's seed.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+	/// Builder pattern function to choose between rendering each pass in parallel or in series.
+    pub fn parallel(mut self, parallel: bool) -> Self {
+        self.parallel = parallel;
+        self
+    }
+
+	/// Builder pattern function to set the tile size and order for parallel passes. Its seed gets
+	/// replaced with one for each pass.
+    pub fn scheduler(mut self, scheduler: TileScheduler) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+	/// Render until every pixel has the raytracer's supersampling amount of samples.
+	/// When resuming, carry on from the checkpoint file rather than starting from nothing.
+	/// `progress` is called after each pass with the samples per pixel taken so far, and the total.
+    pub fn render<S, P>(
+        &self,
+        rt: &Raytracer,
+        camera: &Camera,
+        scene: &S,
+        scene_hash: u64,
+        resume: bool,
+        mut progress: P,
+    ) -> Result<Film, String>
+    where
+        S: Drawable + Send + Sync,
+        P: FnMut(usize, usize),
+    {
+        let mut checkpoint = if resume {
+            let checkpoint = Checkpoint::load(&self.filename)?;
+            if checkpoint.scene_hash != scene_hash {
+                return Err("the checkpoint is for a different scene".to_owned());
+            }
+            if checkpoint.film.image_bounds != camera.bounds()
+                || checkpoint.film.filter() != rt.pixel_filter()
+            {
+                return Err("the checkpoint's image size or filter doesn't match".to_owned());
+            }
+            checkpoint
+        } else {
+            let seed = self.seed.unwrap_or_else(rand::random);
+            Checkpoint::new(scene_hash, seed, Film::new(camera.bounds(), rt.pixel_filter()))
+        };
+
+        let rt = &rt.prepared(scene);
+        let total = rt.samples_per_pixel();
+        let mut last_save = Instant::now();
+        while checkpoint.samples_per_pixel < total {
+            let samples = self.samples_per_pass.min(total - checkpoint.samples_per_pixel);
+            let pass_rt = rt.clone().ss_amt(samples);
+            let pass_seed = stream_seed(checkpoint.seed, checkpoint.samples_per_pixel as u64);
+
+            let pass_film = if self.parallel {
+                self.scheduler
+                    .clone()
+                    .seed(pass_seed)
+                    .render(&pass_rt, camera, scene, camera.bounds(), |_| {})
+            } else {
+                let mut film = Film::new(camera.bounds(), rt.pixel_filter());
+                seed_sampler(pass_seed);
+                pass_rt.render_film(scene, &mut film, camera)?;
+                film
+            };
+            checkpoint.film.merge(&pass_film);
+            checkpoint.samples_per_pixel += samples;
+            progress(checkpoint.samples_per_pixel, total);
+
+            if last_save.elapsed() >= self.interval {
+                checkpoint.save(&self.filename)?;
+                last_save = Instant::now();
+            }
+        }
+
+        checkpoint.save(&self.filename)?;
+        Ok(checkpoint.film)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_handling::PixelF;
+    use crate::material::Material;
+    use crate::material_library::MaterialLibrary;
+    use crate::primitives::Primitive;
+    use crate::scene::Scene;
+    use crate::vectors::V3;
+
+    fn tiny_scene() -> (Scene, Camera) {
+        let mut materials = MaterialLibrary::new();
+        let grey = materials.add(Material::new_diffuse(PixelF::rgb(0.5, 0.5, 0.5)));
+        let scene =
+            Scene::new(vec![Primitive::new_sphere(V3::zero(), 1., grey)], materials).unwrap();
+        let camera = Camera::new(V3::new(0., 0., -3.), V3::z(), V3::y(), 1., (10, 8));
+        (scene, camera)
+    }
+
+    /// A checkpoint file of our own in the temporary directory, so tests can run side by side.
+    fn checkpoint_file(name: &str) -> String {
+        let file = std::env::temp_dir().join(format!("tracer-r-{}-{}", std::process::id(), name));
+        file.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn resuming_takes_the_same_samples_as_never_stopping() {
+        let (scene, camera) = tiny_scene();
+        let hash = scene_hash(&scene).unwrap();
+        let rt = Raytracer::default().ss_amt(3).max_depth(4);
+        for parallel in [false, true] {
+            let filename = checkpoint_file(&format!("resume-{}", parallel));
+            let checkpointer = Checkpointer::new(&filename)
+                .seed(7)
+                .parallel(parallel)
+                .scheduler(TileScheduler::default().tile_size(3));
+
+            let straight = checkpointer
+                .render(&rt, &camera, &scene, hash, false, |_, _| {})
+                .unwrap();
+            checkpointer
+                .render(&rt.clone().ss_amt(1), &camera, &scene, hash, false, |_, _| {})
+                .unwrap();
+            assert_eq!(Checkpoint::load_seed(&filename).unwrap(), 7);
+            let resumed = checkpointer
+                .render(&rt, &camera, &scene, hash, true, |_, _| {})
+                .unwrap();
+            std::fs::remove_file(&filename).ok();
+
+            assert_eq!(straight.to_image().pixels, resumed.to_image().pixels);
+            assert_eq!(resumed.sample_count(4, 4), 3);
+        }
+    }
+
+    #[test]
+    fn checkpoints_for_other_scenes_are_refused() {
+        let (scene, camera) = tiny_scene();
+        let rt = Raytracer::default().ss_amt(1).max_depth(4);
+        let filename = checkpoint_file("other-scene");
+        let checkpointer = Checkpointer::new(&filename).parallel(false);
+        checkpointer
+            .render(&rt, &camera, &scene, 1, false, |_, _| {})
+            .unwrap();
+
+        let resumed = checkpointer.render(&rt, &camera, &scene, 2, true, |_, _| {});
+        let resized = checkpointer.render(
+            &rt,
+            &Camera::new(V3::new(0., 0., -3.), V3::z(), V3::y(), 1., (4, 4)),
+            &scene,
+            1,
+            true,
+            |_, _| {},
+        );
+        std::fs::remove_file(&filename).ok();
+
+        assert!(resumed.unwrap_err().contains("different scene"));
+        assert!(resized.is_err());
+    }
+}
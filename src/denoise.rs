@@ -0,0 +1,324 @@
+use rayon::prelude::*;
+
+use crate::frame_buffer::FrameBuffer;
+use crate::image_handling::{ImageBuffer, PixelF};
+use crate::vectors::V3;
+
+// This is an edge-avoiding a-trous wavelet filter, as described by Dammertz et al. Each iteration
+// blurs with a 5x5 kernel whose taps get spread further apart (1, 2, 4, 8... pixels), so a handful of
+// cheap passes covers a large footprint. To keep edges sharp, every tap is weighted by how similar
+// its first hit is to the center pixel's, in terms of normal, depth, albedo, and color.
+//
+// Depth is compared by how far a tap's hit sits off the plane of the center's surface, rather than
+// by how far away each one is. Depths change quickly across a floor seen at a grazing angle, but
+// it's all one surface, and should be smoothed out like one.
+//
+// Before filtering we divide out the albedo, so we're really just blurring the lighting. That way
+// texture and color detail survives, and is multiplied back in at the end.
+
+/// The weights of a B3 spline, which make up our 5x5 kernel.
+const KERNEL: [f32; 5] = [1. / 16., 1. / 4., 3. / 8., 1. / 4., 1. / 16.];
+
+/// Denoises a rendered image using the first-hit passes from a FrameBuffer as guides.
+#[derive(Clone, Debug)]
+pub struct Denoiser {
+    iterations: usize,
+    sigma_color: f32,
+    sigma_normal: f32,
+    sigma_depth: f32,
+    sigma_albedo: f32,
+}
+
+impl Denoiser {
+	/// Builder pattern function to set the number of filter passes. Each pass doubles the filter's reach.
+    pub fn iterations(mut self, iterations: usize) -> Self {
+        self.iterations = iterations;
+        self
+    }
+
+	/// Builder pattern function to set how different two colors may be before we stop blurring them together.
+    pub fn sigma_color(mut self, sigma_color: f32) -> Self {
+        self.sigma_color = sigma_color;
+        self
+    }
+
+	/// Builder pattern function to set how sharply differing normals stop the blur. Bigger is sharper.
+    pub fn sigma_normal(mut self, sigma_normal: f32) -> Self {
+        self.sigma_normal = sigma_normal;
+        self
+    }
+
+	/// Builder pattern function to set how far off the center's surface a tap may be, relative to its distance, per pixel of filter reach.
+    pub fn sigma_depth(mut self, sigma_depth: f32) -> Self {
+        self.sigma_depth = sigma_depth;
+        self
+    }
+
+	/// Builder pattern function to set how different two albedos may be before we stop blurring them together.
+    pub fn sigma_albedo(mut self, sigma_albedo: f32) -> Self {
+        self.sigma_albedo = sigma_albedo;
+        self
+    }
+
+	/// Denoise an image, using the passes in `guides` to find edges. They need to be the same size.
+    pub fn denoise(&self, image: &ImageBuffer, guides: &FrameBuffer) -> ImageBuffer {
+        assert_eq!(image.bounds, guides.beauty.bounds);
+        let (width, height) = image.bounds;
+
+        let albedos: Vec<PixelF> = guides
+            .hits
+            .iter()
+            .map(|hit| hit.as_ref().map_or(PixelF::white(), |hit| hit.albedo))
+            .collect();
+
+        // Divide out the albedo. We keep these as plain arrays since PixelF clamps its math.
+        let mut current: Vec<[f32; 3]> = image
+            .pixels
+            .iter()
+            .zip(&albedos)
+            .map(|(p, a)| [p.r / demod(a.r), p.g / demod(a.g), p.b / demod(a.b)])
+            .collect();
+        let mut next = current.clone();
+
+        for iteration in 0..self.iterations {
+            let step = 1 << iteration;
+            // Later passes are working on a smoother image, so they can be pickier about color.
+            let sigma_color = self.sigma_color / (1 << iteration) as f32;
+
+            next.par_chunks_mut(width)
+                .enumerate()
+                .for_each(|(y, row)| {
+                    for (x, out) in row.iter_mut().enumerate() {
+                        *out = self.filter_pixel(
+                            &current,
+                            guides,
+                            &albedos,
+                            (x, y),
+                            (width, height),
+                            step,
+                            sigma_color,
+                        );
+                    }
+                });
+
+            std::mem::swap(&mut current, &mut next);
+        }
+
+        let mut image_out = ImageBuffer::new(width, height);
+        image_out.offset = image.offset;
+        // Only the color gets smoothed out. Each pixel keeps the alpha it started with.
+        image_out.pixels = current
+            .iter()
+            .zip(&albedos)
+            .zip(&image.pixels)
+            .map(|((c, a), p)| {
+                PixelF::rgba(
+                    (c[0] * demod(a.r)).clamp(0., 1.),
+                    (c[1] * demod(a.g)).clamp(0., 1.),
+                    (c[2] * demod(a.b)).clamp(0., 1.),
+                    p.a,
+                )
+            })
+            .collect();
+        image_out
+    }
+
+    /// Run our kernel over one pixel.
+    #[allow(clippy::too_many_arguments)]
+    fn filter_pixel(
+        &self,
+        colors: &[[f32; 3]],
+        guides: &FrameBuffer,
+        albedos: &[PixelF],
+        (x, y): (usize, usize),
+        (width, height): (usize, usize),
+        step: usize,
+        sigma_color: f32,
+    ) -> [f32; 3] {
+        let center = y * width + x;
+        let center_color = colors[center];
+        let center_hit = &guides.hits[center];
+
+        let mut sum = [0.; 3];
+        let mut total_weight = 0.;
+
+        for (j, ky) in KERNEL.iter().enumerate() {
+            let qy = y as isize + (j as isize - 2) * step as isize;
+            if qy < 0 || qy >= height as isize {
+                continue;
+            }
+            for (i, kx) in KERNEL.iter().enumerate() {
+                let qx = x as isize + (i as isize - 2) * step as isize;
+                if qx < 0 || qx >= width as isize {
+                    continue;
+                }
+                let q = qy as usize * width + qx as usize;
+                let color = colors[q];
+
+                let guide_weight = match (center_hit, &guides.hits[q]) {
+                    // Both looking at the sky, which is smooth anyways.
+                    (None, None) => 1.,
+                    (Some(p_hit), Some(q_hit)) => {
+                        let normal_weight =
+                            p_hit.normal.dot(&q_hit.normal).max(0.).powf(self.sigma_normal);
+                        let offset = q_hit.position - p_hit.position;
+                        let off_surface = p_hit.normal.dot(&offset).abs();
+                        let depth_weight = f32::exp(
+                            -off_surface / (self.sigma_depth * step as f32 * p_hit.depth).max(1e-4),
+                        );
+                        let albedo_weight = f32::exp(
+                            -distance_squared(albedos[center], albedos[q])
+                                / (self.sigma_albedo * self.sigma_albedo),
+                        );
+                        normal_weight * depth_weight * albedo_weight
+                    }
+                    // Never blur across the silhouette of an object.
+                    _ => 0.,
+                };
+
+                let color_difference = (0..3)
+                    .map(|c| (center_color[c] - color[c]).powi(2))
+                    .sum::<f32>();
+                let color_weight = f32::exp(-color_difference / (sigma_color * sigma_color));
+
+                let weight = kx * ky * guide_weight * color_weight;
+                for c in 0..3 {
+                    sum[c] += color[c] * weight;
+                }
+                total_weight += weight;
+            }
+        }
+
+        // The center tap always has a weight of at least 9/64, so this never divides by zero.
+        sum.map(|s| s / total_weight)
+    }
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Self {
+            iterations: 5,
+            sigma_color: 0.6,
+            sigma_normal: 64.,
+            sigma_depth: 0.02,
+            sigma_albedo: 0.1,
+        }
+    }
+}
+
+/// Denoise the beauty pass of a FrameBuffer using its own passes as guides, with default settings.
+pub fn denoise(frame: &FrameBuffer) -> ImageBuffer {
+    Denoiser::default().denoise(&frame.beauty, frame)
+}
+
+/// Keep dark albedos from blowing up when we divide by them.
+fn demod(albedo: f32) -> f32 {
+    albedo.max(0.01)
+}
+
+fn distance_squared(a: PixelF, b: PixelF) -> f32 {
+    let d = V3::new(a.r - b.r, a.g - b.g, a.b - b.b);
+    d.dot(&d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::material::Material;
+    use crate::material_library::MaterialLibrary;
+    use crate::primitives::Primitive;
+    use crate::raytracer::Raytracer;
+    use crate::sampler::seed_sampler;
+    use crate::scene::Scene;
+    use crate::traits::Renderer;
+
+    const BOUNDS: (usize, usize) = (48, 32);
+
+    /// A red ball on a white floor under the sky, rendered with some number of samples per pixel.
+    fn render(samples: usize) -> FrameBuffer {
+        let mut materials = MaterialLibrary::new();
+        let red = materials.add(Material::new_diffuse(PixelF::rgb(0.8, 0.1, 0.1)));
+        let white = materials.add(Material::new_diffuse(PixelF::rgb(0.8, 0.8, 0.8)));
+        let primitives = vec![
+            Primitive::new_sphere(V3::new(0., 1., 0.), 1., red),
+            Primitive::new_sphere(V3::new(0., -100., 0.), 100., white),
+        ];
+        let scene = Scene::new(primitives, materials).unwrap();
+        let camera = Camera::new(V3::new(0., 1.5, -5.), V3::z(), V3::y(), 0.8, BOUNDS);
+        let mut frame = FrameBuffer::new(BOUNDS.0, BOUNDS.1);
+        seed_sampler(samples as u64);
+        Raytracer::default()
+            .ss_amt(samples)
+            .max_depth(8)
+            .render(&scene, &mut frame, &camera)
+            .unwrap();
+        frame
+    }
+
+    /// The pixels whose neighbours all see the same material as they do, within some distance.
+    fn surrounded_by_one_material(frame: &FrameBuffer, distance: isize) -> Vec<bool> {
+        let (width, height) = (BOUNDS.0 as isize, BOUNDS.1 as isize);
+        let material = |x: isize, y: isize| {
+            let (x, y) = (x.clamp(0, width - 1), y.clamp(0, height - 1));
+            frame.hits[(y * width + x) as usize].as_ref().map(|hit| hit.material_id)
+        };
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let around = -distance..=distance;
+                around.clone().all(|dy| {
+                    around.clone().all(|dx| material(x + dx, y + dy) == material(x, y))
+                })
+            })
+            .collect()
+    }
+
+    fn rmse(image: &ImageBuffer, reference: &ImageBuffer, pixels: &[bool]) -> f32 {
+        let (mut sum, mut count) = (0., 0);
+        for ((a, b), _) in image.pixels.iter().zip(&reference.pixels).zip(pixels).filter(|p| *p.1) {
+            sum += (a.r - b.r).powi(2) + (a.g - b.g).powi(2) + (a.b - b.b).powi(2);
+            count += 3;
+        }
+        (sum / count as f32).sqrt()
+    }
+
+    #[test]
+    fn denoising_gets_closer_to_a_clean_render_without_blurring_edges() {
+        let noisy = render(4);
+        let reference = render(256).beauty;
+        let denoised = denoise(&noisy);
+
+        let before = noisy.beauty.compare(&reference).unwrap().rmse;
+        let after = denoised.compare(&reference).unwrap().rmse;
+        assert!(after < before * 0.75, "{} only went down to {}", before, after);
+        // Edges get antialiased in the render, but not in the passes, so there's less the
+        // denoiser can do there. Away from them, most of the noise should be gone.
+        let inside = surrounded_by_one_material(&noisy, 1);
+        let before = rmse(&noisy.beauty, &reference, &inside);
+        let after = rmse(&denoised, &reference, &inside);
+        assert!(after < before * 0.6, "{} only went down to {}", before, after);
+
+        // Pixels a couple away from an edge, which blurring without looking at the passes would
+        // smear the other side of the edge into.
+        let near_edges: Vec<bool> = inside
+            .iter()
+            .zip(surrounded_by_one_material(&noisy, 3))
+            .map(|(&inside, clear)| inside && !clear)
+            .collect();
+        let before = rmse(&noisy.beauty, &reference, &near_edges);
+        let after = rmse(&denoised, &reference, &near_edges);
+        assert!(after < before * 0.75, "{} only went down to {} near edges", before, after);
+        // Colors differing stops some blurring across edges too, so leave that out to see what
+        // the passes do on their own.
+        let by_passes = Denoiser::default().sigma_color(1e6);
+        let without_passes = FrameBuffer::new(BOUNDS.0, BOUNDS.1);
+        let guided = by_passes.denoise(&noisy.beauty, &noisy);
+        let blurred = by_passes.denoise(&noisy.beauty, &without_passes);
+        let (guided, blurred) = (
+            rmse(&guided, &reference, &near_edges),
+            rmse(&blurred, &reference, &near_edges),
+        );
+        assert!(guided * 3. < blurred, "{} near edges, but {} without passes", guided, blurred);
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::bounded_volume_hierarchy::{BVHBuildNode, BVHFlat};
+use crate::film::Film;
+use crate::scene::SceneDescription;
+use crate::scheduler::{Tile, TileScheduler};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+// This is the real deal, message passing between separate processes, which may well be on separate
+// machines. A coordinator listens for workers to connect over TCP. Each worker is sent the scene,
+// then handed one tile of the image at a time. Workers render their tile using all of their threads,
+// send back the film for it, and get another tile, until there are none left.
+//
+// If a worker drops off partway through a tile, or takes so long over it that it has probably hung,
+// its tile goes back on the pile for somebody else.
+//
+// Messages are JSON, each prefixed with its length in bytes as a little-endian u32. We don't trust
+// that length any further than we have to, since anything could be on the other end of the socket.
+
+/// The longest message we'll accept. Scenes with big meshes get fairly large, but nothing we send
+/// comes anywhere near this.
+pub const MAX_MESSAGE_LENGTH: usize = 1 << 30;
+
+/// Messages the coordinator sends to workers.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CoordinatorMessage {
+    Scene(Box<SceneDescription>),
+    Render(Tile),
+    Done,
+}
+
+/// Messages workers send to the coordinator.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WorkerMessage {
+    Rendered { tile: Tile, film: Film },
+}
+
+/// Write one length-prefixed message to a stream.
+pub fn send_message<T: Serialize>(stream: &mut TcpStream, message: &T) -> Result<(), String> {
+    let bytes = serde_json::to_vec(message).map_err(|e| e.to_string())?;
+    stream
+        .write_all(&(bytes.len() as u32).to_le_bytes())
+        .and_then(|_| stream.write_all(&bytes))
+        .and_then(|_| stream.flush())
+        .map_err(|e| e.to_string())
+}
+
+/// Block until one length-prefixed message arrives on a stream, or the stream's read timeout runs out.
+pub fn receive_message<T: DeserializeOwned>(stream: &mut TcpStream) -> Result<T, String> {
+    let mut length = [0u8; 4];
+    stream.read_exact(&mut length).map_err(|e| e.to_string())?;
+    let length = u32::from_le_bytes(length) as usize;
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(format!("a message of {} bytes is too long", length));
+    }
+    // Only make room for the bytes as they actually turn up, rather than however many we were promised.
+    let mut bytes = Vec::new();
+    stream
+        .take(length as u64)
+        .read_to_end(&mut bytes)
+        .map_err(|e| e.to_string())?;
+    if bytes.len() < length {
+        return Err("the connection closed partway through a message".to_owned());
+    }
+    serde_json::from_slice(&bytes).map_err(|e| e.to_string())
+}
+
+/// The coordinator's bookkeeping, shared between the threads talking to each worker.
+struct WorkQueue {
+    pending: VecDeque<Tile>,
+    /// Tiles which haven't been merged yet, whether they're pending or out with a worker.
+    remaining: usize,
+}
+
+/// Hands out tiles of a scene to workers over TCP, and assembles what they send back.
+#[derive(Clone, Debug)]
+pub struct Coordinator {
+    tile_size: usize,
+    timeout: Option<Duration>,
+}
+
+impl Coordinator {
+	/// Builder pattern function to set the size of the tiles handed out to workers.
+	/// These should be fairly big, so that workers spend their time rendering rather than waiting on the network.
+    pub fn tile_size(mut self, tile_size: usize) -> Self {
+        self.tile_size = tile_size.max(1);
+        self
+    }
+
+	/// Builder pattern function to set how long a worker gets to send back each tile before we give up on
+	/// it and hand its tile to someone else. None waits forever.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout.filter(|t| !t.is_zero());
+        self
+    }
+
+	/// Render a scene with whichever workers connect to the listener, returning once every tile is in.
+	/// `progress` gets called with the number of finished tiles and the total each time one comes back.
+    pub fn render<P>(
+        &self,
+        listener: TcpListener,
+        scene: &SceneDescription,
+        progress: P,
+    ) -> Result<Film, String>
+    where
+        P: Fn(usize, usize),
+    {
+        let bounds = scene.camera.bounds;
+        let tiles = TileScheduler::default()
+            .tile_size(self.tile_size)
+            .tiles(bounds);
+        let total = tiles.len();
+        let queue = Arc::new(Mutex::new(WorkQueue {
+            remaining: total,
+            pending: tiles.into(),
+        }));
+        let finished = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = mpsc::channel::<(Tile, Film)>();
+
+        // Accept workers in the background, so they can join in at any point during the render.
+        // We poll so that we notice when the render is finished and can stop listening.
+        listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+        let acceptor = {
+            let (queue, finished) = (queue.clone(), finished.clone());
+            let scene = Arc::new(scene.clone());
+            let timeout = self.timeout;
+            thread::spawn(move || {
+                while !finished.load(Ordering::SeqCst) {
+                    match listener.accept() {
+                        Ok((stream, address)) => {
+                            let (queue, scene, sender) = (queue.clone(), scene.clone(), sender.clone());
+                            thread::spawn(move || {
+                                let served = serve_worker(stream, timeout, &scene, &queue, &sender);
+                                if let Err(e) = served {
+                                    eprintln!("lost worker {}: {}", address, e);
+                                }
+                            });
+                        }
+                        Err(_) => thread::sleep(Duration::from_millis(20)),
+                    }
+                }
+            })
+        };
+
+        let mut film = Film::new(bounds, scene.raytracer.pixel_filter());
+        for completed in 1..=total {
+            let (_, tile_film) = receiver.recv().map_err(|e| e.to_string())?;
+            film.merge(&tile_film);
+            progress(completed, total);
+        }
+
+        finished.store(true, Ordering::SeqCst);
+        acceptor.join().map_err(|_| "acceptor thread panicked".to_owned())?;
+        Ok(film)
+    }
+}
+
+impl Default for Coordinator {
+    fn default() -> Self {
+        Self {
+            tile_size: 64,
+            timeout: Some(Duration::from_secs(600)),
+        }
+    }
+}
+
+/// Feed tiles to one worker until we run out, or it goes away. If it goes away or runs out of time,
+/// whatever tile it was working on gets put back for another worker to pick up.
+fn serve_worker(
+    mut stream: TcpStream,
+    timeout: Option<Duration>,
+    scene: &SceneDescription,
+    queue: &Mutex<WorkQueue>,
+    sender: &mpsc::Sender<(Tile, Film)>,
+) -> Result<(), String> {
+    stream.set_nonblocking(false).map_err(|e| e.to_string())?;
+    stream.set_nodelay(true).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(timeout).map_err(|e| e.to_string())?;
+    send_message(&mut stream, &CoordinatorMessage::Scene(Box::new(scene.clone())))?;
+
+    loop {
+        let tile = {
+            let mut queue = queue.lock().unwrap();
+            if queue.remaining == 0 {
+                break;
+            }
+            queue.pending.pop_front()
+        };
+        let tile = match tile {
+            Some(tile) => tile,
+            // Everything is out with other workers. Hang around in case one of them drops out.
+            None => {
+                thread::sleep(Duration::from_millis(50));
+                continue;
+            }
+        };
+
+        let result = send_message(&mut stream, &CoordinatorMessage::Render(tile))
+            .and_then(|_| receive_message::<WorkerMessage>(&mut stream));
+        match result {
+            Ok(WorkerMessage::Rendered { tile: rendered, film }) if rendered == tile => {
+                queue.lock().unwrap().remaining -= 1;
+                sender.send((tile, film)).map_err(|e| e.to_string())?;
+            }
+            Ok(WorkerMessage::Rendered { .. }) => {
+                queue.lock().unwrap().pending.push_front(tile);
+                return Err("worker sent back the wrong tile".to_owned());
+            }
+            Err(e) => {
+                queue.lock().unwrap().pending.push_front(tile);
+                return Err(e);
+            }
+        }
+    }
+
+    // The worker may have already hung up if it saw the end coming, so this is allowed to fail.
+    send_message(&mut stream, &CoordinatorMessage::Done).ok();
+    Ok(())
+}
+
+/// Connect to a coordinator and render tiles for it until it says we're done.
+/// Each tile is rendered using every thread available to this process.
+pub fn run_worker<A: ToSocketAddrs>(address: A) -> Result<(), String> {
+    let mut stream = TcpStream::connect(address).map_err(|e| e.to_string())?;
+    stream.set_nodelay(true).map_err(|e| e.to_string())?;
+
+    let scene = match receive_message(&mut stream)? {
+        CoordinatorMessage::Scene(scene) => scene,
+        _ => return Err("expected the scene first".to_owned()),
+    };
+    let camera = scene.camera.build();
+    let raytracer = scene.raytracer.clone();
+    let bvh: BVHFlat = BVHBuildNode::new(scene.flattened()?, 4)?.into();
+    // Every tile we're sent shares whatever the integrator traces ahead of time.
+    let raytracer = raytracer.prepared(&bvh);
+
+    loop {
+        match receive_message(&mut stream)? {
+            CoordinatorMessage::Render(tile) => {
+                let film = TileScheduler::default().tile_size(16).render_region(
+                    &raytracer,
+                    &camera,
+                    &bvh,
+                    tile.offset,
+                    tile.bounds,
+                    |_| {},
+                );
+                send_message(&mut stream, &WorkerMessage::Rendered { tile, film })?;
+            }
+            CoordinatorMessage::Done => return Ok(()),
+            CoordinatorMessage::Scene(_) => return Err("got a second scene".to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_handling::PixelF;
+    use crate::material::Material;
+    use crate::material_library::MaterialLibrary;
+    use crate::primitives::Primitive;
+    use crate::raytracer::Raytracer;
+    use crate::scene::{CameraDescription, Scene};
+    use crate::vectors::V3;
+
+    fn tiny_scene() -> SceneDescription {
+        let mut materials = MaterialLibrary::new();
+        let grey = materials.add(Material::new_diffuse(PixelF::rgb(0.5, 0.5, 0.5)));
+        SceneDescription::new(
+            CameraDescription {
+                position: V3::new(0., 0., -3.),
+                direction: V3::z(),
+                up: V3::y(),
+                fov: 60.,
+                bounds: (12, 10),
+            },
+            Raytracer::default().ss_amt(2).max_depth(4),
+            Scene::new(vec![Primitive::new_sphere(V3::zero(), 1., grey)], materials).unwrap(),
+        )
+    }
+
+    fn assert_fully_sampled(film: &Film, scene: &SceneDescription) {
+        let (width, height) = scene.camera.bounds;
+        for y in 0..height {
+            for x in 0..width {
+                assert_eq!(film.sample_count(x, y), 2, "pixel ({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn workers_on_localhost_render_every_tile_once() {
+        let scene = tiny_scene();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let workers: Vec<_> = (0..2)
+            .map(|_| thread::spawn(move || run_worker(address)))
+            .collect();
+
+        let film = Coordinator::default()
+            .tile_size(4)
+            .render(listener, &scene, |_, _| {})
+            .unwrap();
+
+        assert_fully_sampled(&film, &scene);
+        // A worker which only turned up after the last tile went out finds nobody listening, which
+        // is fine.
+        for worker in workers {
+            worker.join().unwrap().ok();
+        }
+    }
+
+    #[test]
+    fn tiles_from_stalled_workers_are_handed_out_again() {
+        let scene = tiny_scene();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (took_tile, stalled) = mpsc::channel();
+
+        // This worker takes a tile and then sits on it. Only once it has one does a real worker join.
+        let staller = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let _: CoordinatorMessage = receive_message(&mut stream).unwrap();
+            let _: CoordinatorMessage = receive_message(&mut stream).unwrap();
+            took_tile.send(()).unwrap();
+            // Hold the connection open until the render is over.
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).ok();
+        });
+        let worker = thread::spawn(move || {
+            stalled.recv().unwrap();
+            run_worker(address)
+        });
+
+        let film = Coordinator::default()
+            .tile_size(6)
+            .timeout(Some(Duration::from_millis(200)))
+            .render(listener, &scene, |_, _| {})
+            .unwrap();
+
+        assert_fully_sampled(&film, &scene);
+        worker.join().unwrap().unwrap();
+        staller.join().unwrap();
+    }
+
+    #[test]
+    fn oversized_messages_are_refused_before_reading_them() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut receiver, _) = listener.accept().unwrap();
+
+        sender.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        let error = receive_message::<WorkerMessage>(&mut receiver).unwrap_err();
+        assert!(error.contains("too long"), "{}", error);
+    }
+}
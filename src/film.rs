@@ -0,0 +1,725 @@
+use std::str::FromStr;
+
+use crate::image_handling::{ImageBuffer, PixelF};
+use crate::scheduler::Tile;
+use crate::stats::RayStats;
+use crate::traits::SampleSink;
+
+use serde::{Deserialize, Serialize};
+
+// Rather than averaging each pixel's samples on their own, a Film lets every sample contribute to all
+// of the pixels around it, weighted by a reconstruction filter. Each pixel ends up as the weighted
+// average of every sample which landed near it, which gives much smoother edges on fine geometry.
+//
+// Since samples spill over into neighbouring pixels, a Film covering part of an image keeps an apron
+// of extra pixels around its edges. When pieces of an image are rendered separately, merging their
+// Films adds those aprons into their neighbours, so the result is exactly what one big Film would
+// have produced, without any seams.
+//
+// Light tracing strategies work the other way around: they follow light out from a lamp and land it
+// on whatever pixel it happens to reach. Those splats aren't filtered or averaged, they're just summed
+// up, then scaled by the size of the image over how many camera samples were taken, since each camera
+// sample traces one light path. A splat can land anywhere in the image, so a film covering part of an
+// image keeps its splats in a list, and a film covering the whole image adds them into a buffer.
+//
+// Alpha gets filtered along with the color, so edges of objects in front of a transparent
+// background come out partly covered. Splats are light, and only ever add to the color.
+
+/// A reconstruction filter, describing how much a sample contributes to a pixel some distance away.
+/// Filters are separable, so the weight is the product of the x and y weights.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Filter {
+    /// Weights every sample within the radius equally.
+    /// With a radius of 0.5 this just averages the samples inside each pixel.
+    Box { radius: f32 },
+    /// Weights fall off linearly to zero at the radius.
+    Tent { radius: f32 },
+    /// A gaussian bump, shifted down so that it reaches zero at the radius.
+    Gaussian { radius: f32, alpha: f32 },
+    /// A cubic filter which trades blur against ringing using its b and c parameters.
+    MitchellNetravali { radius: f32, b: f32, c: f32 },
+}
+
+impl Filter {
+    pub fn new_box(radius: f32) -> Self {
+        Filter::Box { radius }
+    }
+
+    pub fn new_tent(radius: f32) -> Self {
+        Filter::Tent { radius }
+    }
+
+    pub fn new_gaussian(radius: f32) -> Self {
+        Filter::Gaussian { radius, alpha: 2. }
+    }
+
+    pub fn new_mitchell_netravali(radius: f32) -> Self {
+        Filter::MitchellNetravali {
+            radius,
+            b: 1. / 3.,
+            c: 1. / 3.,
+        }
+    }
+
+    pub fn radius(&self) -> f32 {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::MitchellNetravali { radius, .. } => radius,
+        }
+    }
+
+    /// How many pixels a sample can reach past the edge of the pixel it landed in.
+    pub fn apron(&self) -> usize {
+        (self.radius() - 0.5).ceil().max(0.) as usize
+    }
+
+    /// The weight of a sample at an offset of (dx, dy) from a pixel's center.
+    pub fn evaluate(&self, dx: f32, dy: f32) -> f32 {
+        self.evaluate_1d(dx) * self.evaluate_1d(dy)
+    }
+
+    fn evaluate_1d(&self, d: f32) -> f32 {
+        let d = d.abs();
+        match *self {
+            Filter::Box { radius } => {
+                if d <= radius {
+                    1.
+                } else {
+                    0.
+                }
+            }
+            Filter::Tent { radius } => (radius - d).max(0.),
+            Filter::Gaussian { radius, alpha } => {
+                (f32::exp(-alpha * d * d) - f32::exp(-alpha * radius * radius)).max(0.)
+            }
+            Filter::MitchellNetravali { radius, b, c } => {
+                // The standard formulation is over [0, 2], so stretch that over our radius.
+                let x = 2. * d / radius;
+                if x >= 2. {
+                    0.
+                } else if x > 1. {
+                    ((-b - 6. * c) * x * x * x
+                        + (6. * b + 30. * c) * x * x
+                        + (-12. * b - 48. * c) * x
+                        + (8. * b + 24. * c))
+                        / 6.
+                } else {
+                    ((12. - 9. * b - 6. * c) * x * x * x
+                        + (-18. + 12. * b + 6. * c) * x * x
+                        + (6. - 2. * b))
+                        / 6.
+                }
+            }
+        }
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::new_box(0.5)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = ();
+
+    /// Parse a filter from its name, optionally followed by a radius, like `gaussian` or `tent:1.5`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, radius) = match s.split_once(':') {
+            Some((name, radius)) => (name, Some(f32::from_str(radius).map_err(|_| ())?)),
+            None => (s, None),
+        };
+        if radius.is_some_and(|r| r <= 0. || r.is_nan()) {
+            return Err(());
+        }
+        match name {
+            "box" => Ok(Filter::new_box(radius.unwrap_or(0.5))),
+            "tent" => Ok(Filter::new_tent(radius.unwrap_or(1.))),
+            "gaussian" => Ok(Filter::new_gaussian(radius.unwrap_or(1.5))),
+            "mitchell" => Ok(Filter::new_mitchell_netravali(radius.unwrap_or(2.))),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A Film accumulates filtered samples for some rectangle of an image.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Film {
+    /// The size of the whole image this film is part of.
+    pub image_bounds: (usize, usize),
+    /// The first pixel this film takes samples for.
+    pub offset: (usize, usize),
+    /// How many pixels this film takes samples for.
+    pub bounds: (usize, usize),
+    filter: Filter,
+    /// The rectangle we actually accumulate into. This is our bounds plus the filter's apron,
+    /// cropped down to the image.
+    origin: (usize, usize),
+    size: (usize, usize),
+    sums: Vec<[f32; 3]>,
+    weights: Vec<f32>,
+    /// Weighted sums of each pixel's sample alphas. Films saved before we kept track of alpha
+    /// don't have any, and were entirely opaque.
+    #[serde(default)]
+    coverage: Vec<f32>,
+    /// How many samples were taken within each pixel.
+    samples: Vec<u32>,
+    /// How many camera samples have been taken altogether, for scaling splats.
+    #[serde(default)]
+    camera_samples: u64,
+    #[serde(default)]
+    splats: Splats,
+    /// What it took to render everything on this film, when the stats feature is on.
+    #[serde(default)]
+    pub stats: RayStats,
+}
+
+impl Film {
+    /// Create a film for a whole image.
+    pub fn new(image_bounds: (usize, usize), filter: Filter) -> Self {
+        Self::region(image_bounds, (0, 0), image_bounds, filter)
+    }
+
+    /// Create a film for the given rectangle of an image.
+    pub fn region(
+        image_bounds: (usize, usize),
+        offset: (usize, usize),
+        bounds: (usize, usize),
+        filter: Filter,
+    ) -> Self {
+        let apron = filter.apron();
+        let origin = (offset.0.saturating_sub(apron), offset.1.saturating_sub(apron));
+        let end = (
+            (offset.0 + bounds.0 + apron).min(image_bounds.0),
+            (offset.1 + bounds.1 + apron).min(image_bounds.1),
+        );
+        let size = (end.0 - origin.0, end.1 - origin.1);
+
+        Film {
+            image_bounds,
+            offset,
+            bounds,
+            filter,
+            origin,
+            size,
+            sums: vec![[0.; 3]; size.0 * size.1],
+            weights: vec![0.; size.0 * size.1],
+            coverage: vec![0.; size.0 * size.1],
+            samples: vec![0; size.0 * size.1],
+            camera_samples: 0,
+            splats: Splats::default(),
+            stats: RayStats::default(),
+        }
+    }
+
+	/// Create a number of films covering bands of an image, just like ImageBuffer::bands.
+	/// Merge them all into a film for the whole image to put it back together.
+    pub fn bands(image_bounds: (usize, usize), rows_per_band: usize, filter: Filter) -> Vec<Film> {
+        (0..image_bounds.1)
+            .step_by(rows_per_band)
+            .map(|y| {
+                let rows = rows_per_band.min(image_bounds.1 - y);
+                Self::region(image_bounds, (0, y), (image_bounds.0, rows), filter)
+            })
+            .collect()
+    }
+
+    pub fn filter(&self) -> Filter {
+        self.filter
+    }
+
+    /// Add a sample taken at (x, y) in the image's pixel coordinates, so that the center of the
+    /// top left pixel is at (0.5, 0.5). It gets splatted onto every pixel within the filter's radius.
+    pub fn add_sample(&mut self, x: f32, y: f32, color: PixelF) {
+        self.camera_samples += 1;
+        self.fill_coverage();
+        let (sx, sy) = (x.floor() as usize, y.floor() as usize);
+        if (self.origin.0..self.origin.0 + self.size.0).contains(&sx)
+            && (self.origin.1..self.origin.1 + self.size.1).contains(&sy)
+        {
+            let i = self.index(sx, sy);
+            self.samples[i] += 1;
+        }
+
+        for (px, py, weight) in filtered(self.filter, x, y, self.origin, self.size) {
+            self.accumulate(px, py, color, weight);
+        }
+    }
+
+    /// Add a sample's weighted contribution to a pixel within our rectangle.
+    fn accumulate(&mut self, x: usize, y: usize, color: PixelF, weight: f32) {
+        let i = self.index(x, y);
+        self.sums[i][0] += color.r * weight;
+        self.sums[i][1] += color.g * weight;
+        self.sums[i][2] += color.b * weight;
+        self.weights[i] += weight;
+        self.coverage[i] += color.a * weight;
+    }
+
+    /// Add light which a light path carried straight to (x, y) in the image's pixel coordinates.
+    /// Splats can land anywhere in the image, not just within this film's bounds.
+    pub fn add_splat(&mut self, x: f32, y: f32, color: PixelF) {
+        let (px, py) = (x.floor() as usize, y.floor() as usize);
+        if x < 0. || y < 0. || px >= self.image_bounds.0 || py >= self.image_bounds.1 {
+            return;
+        }
+        let index = py * self.image_bounds.0 + px;
+        if self.covers_image() {
+            self.splats.densify(self.image_bounds.0 * self.image_bounds.1);
+        }
+        self.splats.add(index, [color.r, color.g, color.b]);
+    }
+
+    /// Whether this film accumulates samples for every pixel of its image.
+    fn covers_image(&self) -> bool {
+        self.offset == (0, 0) && self.bounds == self.image_bounds
+    }
+
+    /// Add everything another film of the same image has accumulated into this one.
+    pub fn merge(&mut self, other: &Film) {
+        assert_eq!(self.image_bounds, other.image_bounds);
+        self.stats.merge(&other.stats);
+        self.camera_samples += other.camera_samples;
+        self.fill_coverage();
+        if self.covers_image() && !other.splats.is_empty() {
+            self.splats.densify(self.image_bounds.0 * self.image_bounds.1);
+        }
+        self.splats.merge(&other.splats);
+        let x_start = self.origin.0.max(other.origin.0);
+        let x_end = (self.origin.0 + self.size.0).min(other.origin.0 + other.size.0);
+        let y_start = self.origin.1.max(other.origin.1);
+        let y_end = (self.origin.1 + self.size.1).min(other.origin.1 + other.size.1);
+
+        for y in y_start..y_end {
+            for x in x_start..x_end {
+                let (i, j) = (self.index(x, y), other.index(x, y));
+                for c in 0..3 {
+                    self.sums[i][c] += other.sums[j][c];
+                }
+                self.weights[i] += other.weights[j];
+                self.coverage[i] += other.coverage.get(j).unwrap_or(&other.weights[j]);
+                self.samples[i] += other.samples[j];
+            }
+        }
+    }
+
+    /// How many samples have been taken within a pixel, given in image coordinates.
+    pub fn sample_count(&self, x: usize, y: usize) -> u32 {
+        self.samples[self.index(x, y)]
+    }
+
+    /// Get the filtered color of a pixel, given in image coordinates.
+    pub fn pixel(&self, x: usize, y: usize) -> PixelF {
+        let i = self.index(x, y);
+        let weight = self.weights[i];
+        // Negative lobes can cancel out everything in rare cases, so don't divide by nothing.
+        let (mut color, alpha) = if weight.abs() < 1e-6 {
+            ([0.; 3], 1.)
+        } else {
+            let coverage = self.coverage.get(i).unwrap_or(&weight);
+            (self.sums[i].map(|sum| sum / weight), coverage / weight)
+        };
+        if !self.splats.dense.is_empty() && self.camera_samples > 0 {
+            let scale =
+                (self.image_bounds.0 * self.image_bounds.1) as f32 / self.camera_samples as f32;
+            let splat = self.splats.dense[y * self.image_bounds.0 + x];
+            for c in 0..3 {
+                color[c] += splat[c] * scale;
+            }
+        }
+        PixelF::rgba(
+            color[0].clamp(0., 1.),
+            color[1].clamp(0., 1.),
+            color[2].clamp(0., 1.),
+            alpha.clamp(0., 1.),
+        )
+    }
+
+    /// Resolve the pixels this film takes samples for into an image.
+    pub fn to_image(&self) -> ImageBuffer {
+        let mut image = ImageBuffer::new(self.bounds.0, self.bounds.1);
+        image.offset = self.offset;
+        for y in 0..self.bounds.1 {
+            for x in 0..self.bounds.0 {
+                image.pixels[y * self.bounds.0 + x] =
+                    self.pixel(x + self.offset.0, y + self.offset.1);
+            }
+        }
+        image
+    }
+
+    /// Films saved before we kept track of alpha were entirely opaque, so fill in their coverage
+    /// before adding anything to it.
+    fn fill_coverage(&mut self) {
+        if self.coverage.is_empty() {
+            self.coverage = self.weights.clone();
+        }
+    }
+
+    fn index(&self, x: usize, y: usize) -> usize {
+        (y - self.origin.1) * self.size.0 + (x - self.origin.0)
+    }
+
+    /// Hand out each tile's pixels of this film, so tiles can be rendered straight into it at the
+    /// same time. Tiles mustn't overlap, and have to lie within our bounds.
+    pub fn tiles_mut(&mut self, tiles: &[Tile]) -> Vec<FilmTile<'_>> {
+        for tile in tiles {
+            assert!(
+                tile.offset.0 >= self.offset.0
+                    && tile.offset.1 >= self.offset.1
+                    && tile.offset.0 + tile.bounds.0 <= self.offset.0 + self.bounds.0
+                    && tile.offset.1 + tile.bounds.1 <= self.offset.1 + self.bounds.1,
+                "{:?} isn't within the film",
+                tile
+            );
+        }
+        self.fill_coverage();
+        let (origin, size) = (self.origin, self.size);
+        let sums = split_tiles(&mut self.sums, origin, size, tiles);
+        let weights = split_tiles(&mut self.weights, origin, size, tiles);
+        let coverage = split_tiles(&mut self.coverage, origin, size, tiles);
+        let samples = split_tiles(&mut self.samples, origin, size, tiles);
+        tiles
+            .iter()
+            .zip(sums.into_iter().zip(weights))
+            .zip(coverage.into_iter().zip(samples))
+            .map(|((tile, (sums, weights)), (coverage, samples))| FilmTile {
+                offset: tile.offset,
+                bounds: tile.bounds,
+                sums,
+                weights,
+                coverage,
+                samples,
+                spill: Film::region(self.image_bounds, tile.offset, tile.bounds, self.filter),
+            })
+            .collect()
+    }
+}
+
+impl SampleSink for Film {
+    fn image_bounds(&self) -> (usize, usize) {
+        self.image_bounds
+    }
+
+    fn offset(&self) -> (usize, usize) {
+        self.offset
+    }
+
+    fn bounds(&self) -> (usize, usize) {
+        self.bounds
+    }
+
+    fn add_sample(&mut self, x: f32, y: f32, color: PixelF) {
+        Film::add_sample(self, x, y, color);
+    }
+
+    fn add_splat(&mut self, x: f32, y: f32, color: PixelF) {
+        Film::add_splat(self, x, y, color);
+    }
+
+    fn add_stats(&mut self, stats: &RayStats) {
+        self.stats.merge(stats);
+    }
+}
+
+/// One tile's pixels of a shared film, which samples within the tile are written straight into.
+/// Samples spill over onto the tile's neighbours, and splats can land anywhere, so those are kept
+/// aside in a film of their own, to be merged into the shared one once every tile is done.
+pub struct FilmTile<'a> {
+    offset: (usize, usize),
+    bounds: (usize, usize),
+    /// The rows of the shared film's buffers that fall within this tile.
+    sums: Vec<&'a mut [[f32; 3]]>,
+    weights: Vec<&'a mut [f32]>,
+    coverage: Vec<&'a mut [f32]>,
+    samples: Vec<&'a mut [u32]>,
+    spill: Film,
+}
+
+impl FilmTile<'_> {
+    /// Everything this tile couldn't write in place, ready to be merged into the shared film.
+    pub fn into_spill(self) -> Film {
+        self.spill
+    }
+
+    fn contains(&self, x: usize, y: usize) -> bool {
+        (self.offset.0..self.offset.0 + self.bounds.0).contains(&x)
+            && (self.offset.1..self.offset.1 + self.bounds.1).contains(&y)
+    }
+}
+
+impl SampleSink for FilmTile<'_> {
+    fn image_bounds(&self) -> (usize, usize) {
+        self.spill.image_bounds
+    }
+
+    fn offset(&self) -> (usize, usize) {
+        self.offset
+    }
+
+    fn bounds(&self) -> (usize, usize) {
+        self.bounds
+    }
+
+    fn add_sample(&mut self, x: f32, y: f32, color: PixelF) {
+        self.spill.camera_samples += 1;
+        let (sx, sy) = (x.floor() as usize, y.floor() as usize);
+        if self.contains(sx, sy) {
+            self.samples[sy - self.offset.1][sx - self.offset.0] += 1;
+        } else if (self.spill.origin.0..self.spill.origin.0 + self.spill.size.0).contains(&sx)
+            && (self.spill.origin.1..self.spill.origin.1 + self.spill.size.1).contains(&sy)
+        {
+            let i = self.spill.index(sx, sy);
+            self.spill.samples[i] += 1;
+        }
+
+        let (origin, size) = (self.spill.origin, self.spill.size);
+        for (px, py, weight) in filtered(self.spill.filter, x, y, origin, size) {
+            if !self.contains(px, py) {
+                self.spill.accumulate(px, py, color, weight);
+                continue;
+            }
+            let (tx, ty) = (px - self.offset.0, py - self.offset.1);
+            self.sums[ty][tx][0] += color.r * weight;
+            self.sums[ty][tx][1] += color.g * weight;
+            self.sums[ty][tx][2] += color.b * weight;
+            self.weights[ty][tx] += weight;
+            self.coverage[ty][tx] += color.a * weight;
+        }
+    }
+
+    fn add_splat(&mut self, x: f32, y: f32, color: PixelF) {
+        self.spill.add_splat(x, y, color);
+    }
+
+    fn add_stats(&mut self, stats: &RayStats) {
+        self.spill.stats.merge(stats);
+    }
+}
+
+/// The pixels a sample at (x, y) contributes to, and its weight in each: every pixel whose center
+/// is within the filter's radius, cropped down to a rectangle.
+fn filtered(
+    filter: Filter,
+    x: f32,
+    y: f32,
+    origin: (usize, usize),
+    size: (usize, usize),
+) -> impl Iterator<Item = (usize, usize, f32)> {
+    let radius = filter.radius();
+    let range = move |s: f32, origin: usize, size: usize| {
+        let first = ((s - radius - 0.5).floor() + 1.).max(origin as f32) as usize;
+        let last = ((s + radius - 0.5).floor() + 1.).clamp(0., (origin + size) as f32) as usize;
+        first..last
+    };
+    range(y, origin.1, size.1).flat_map(move |py| {
+        let dy = py as f32 + 0.5 - y;
+        range(x, origin.0, size.0)
+            .map(move |px| (px, py, filter.evaluate(px as f32 + 0.5 - x, dy)))
+            .filter(|&(_, _, weight)| weight != 0.)
+    })
+}
+
+/// Split a buffer of rows, covering `size` pixels from `origin`, into each tile's piece of each
+/// row. Every piece is borrowed on its own, so tiles can all be written to at once.
+fn split_tiles<'a, T>(
+    buffer: &'a mut [T],
+    origin: (usize, usize),
+    size: (usize, usize),
+    tiles: &[Tile],
+) -> Vec<Vec<&'a mut [T]>> {
+    let mut pieces: Vec<Vec<&mut [T]>> = tiles.iter().map(|_| Vec::new()).collect();
+    let mut across: Vec<usize> = (0..tiles.len()).collect();
+    across.sort_by_key(|&i| tiles[i].offset.0);
+    for (y, row) in (origin.1..).zip(buffer.chunks_mut(size.0)) {
+        let mut rest = row;
+        let mut x = origin.0;
+        for &i in &across {
+            let tile = &tiles[i];
+            if !(tile.offset.1..tile.offset.1 + tile.bounds.1).contains(&y) {
+                continue;
+            }
+            assert!(tile.offset.0 >= x, "tiles overlap at ({}, {})", tile.offset.0, y);
+            let (_, after) = std::mem::take(&mut rest).split_at_mut(tile.offset.0 - x);
+            let (piece, after) = after.split_at_mut(tile.bounds.0);
+            pieces[i].push(piece);
+            rest = after;
+            x = tile.offset.0 + tile.bounds.0;
+        }
+    }
+    pieces
+}
+
+/// Splats for a film. Films covering part of an image list theirs, and films covering the whole image
+/// add theirs up into a buffer with one entry per pixel.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct Splats {
+    dense: Vec<[f32; 3]>,
+    /// Pixel indices, in rows across the whole image, with the light that landed there.
+    sparse: Vec<(usize, [f32; 3])>,
+}
+
+impl Splats {
+    fn is_empty(&self) -> bool {
+        self.dense.is_empty() && self.sparse.is_empty()
+    }
+
+    fn add(&mut self, index: usize, color: [f32; 3]) {
+        if self.dense.is_empty() {
+            self.sparse.push((index, color));
+        } else {
+            for (sum, c) in self.dense[index].iter_mut().zip(color) {
+                *sum += c;
+            }
+        }
+    }
+
+    /// Switch over to a buffer with an entry for each pixel, if we haven't already.
+    fn densify(&mut self, pixels: usize) {
+        if self.dense.is_empty() {
+            self.dense = vec![[0.; 3]; pixels];
+            for (index, color) in std::mem::take(&mut self.sparse) {
+                self.add(index, color);
+            }
+        }
+    }
+
+    fn merge(&mut self, other: &Splats) {
+        if !other.dense.is_empty() {
+            self.densify(other.dense.len());
+            for (splat, other_splat) in self.dense.iter_mut().zip(&other.dense) {
+                for c in 0..3 {
+                    splat[c] += other_splat[c];
+                }
+            }
+        }
+        for &(index, color) in &other.sparse {
+            self.add(index, color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::TileScheduler;
+
+    /// A color which differs from pixel to pixel, so merging the wrong pixels together shows up.
+    fn sample_color(x: f32, y: f32) -> PixelF {
+        PixelF::rgb((x * 0.1).fract(), (y * 0.1).fract(), 0.5)
+    }
+
+    fn sample_points(bounds: (usize, usize)) -> Vec<(f32, f32)> {
+        (0..bounds.1)
+            .flat_map(|y| (0..bounds.0).map(move |x| (x as f32 + 0.3, y as f32 + 0.7)))
+            .collect()
+    }
+
+    #[test]
+    fn merged_bands_match_one_big_film() {
+        let bounds = (9, 7);
+        let filter = Filter::new_mitchell_netravali(2.);
+        let mut whole = Film::new(bounds, filter);
+        let mut merged = Film::new(bounds, filter);
+        for mut band in Film::bands(bounds, 2, filter) {
+            for (x, y) in sample_points(bounds) {
+                // Each band only gets the samples within its own rows, like a renderer would give it.
+                if (band.offset.1..band.offset.1 + band.bounds.1).contains(&(y as usize)) {
+                    band.add_sample(x, y, sample_color(x, y));
+                }
+            }
+            merged.merge(&band);
+        }
+        for (x, y) in sample_points(bounds) {
+            whole.add_sample(x, y, sample_color(x, y));
+        }
+
+        for y in 0..bounds.1 {
+            for x in 0..bounds.0 {
+                let (a, b) = (whole.pixel(x, y), merged.pixel(x, y));
+                assert!((a.r - b.r).abs() < 1e-5 && (a.g - b.g).abs() < 1e-5, "({x}, {y})");
+                assert_eq!(whole.sample_count(x, y), merged.sample_count(x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn tiles_written_in_place_match_one_big_film() {
+        let bounds = (9, 7);
+        let filter = Filter::new_mitchell_netravali(2.);
+        let tiles = TileScheduler::default().tile_size(2).tiles(bounds);
+        let mut whole = Film::new(bounds, filter);
+        let mut tiled = Film::new(bounds, filter);
+        let spills: Vec<Film> = tiled
+            .tiles_mut(&tiles)
+            .into_iter()
+            .map(|mut tile| {
+                for (x, y) in sample_points(bounds) {
+                    let (sx, sy) = (x as usize, y as usize);
+                    if (tile.offset.0..tile.offset.0 + tile.bounds.0).contains(&sx)
+                        && (tile.offset.1..tile.offset.1 + tile.bounds.1).contains(&sy)
+                    {
+                        SampleSink::add_sample(&mut tile, x, y, sample_color(x, y));
+                    }
+                }
+                SampleSink::add_splat(&mut tile, 4.5, 3.5, PixelF::rgb(0.01, 0., 0.));
+                tile.into_spill()
+            })
+            .collect();
+        for spill in &spills {
+            tiled.merge(spill);
+        }
+        for (x, y) in sample_points(bounds) {
+            whole.add_sample(x, y, sample_color(x, y));
+        }
+        for _ in &tiles {
+            whole.add_splat(4.5, 3.5, PixelF::rgb(0.01, 0., 0.));
+        }
+
+        for y in 0..bounds.1 {
+            for x in 0..bounds.0 {
+                let (a, b) = (whole.pixel(x, y), tiled.pixel(x, y));
+                assert!((a.r - b.r).abs() < 1e-5 && (a.g - b.g).abs() < 1e-5, "({x}, {y})");
+                assert_eq!(whole.sample_count(x, y), tiled.sample_count(x, y));
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "overlap")]
+    fn overlapping_tiles_are_refused() {
+        let mut film = Film::new((4, 4), Filter::default());
+        let tile = |index, offset| Tile {
+            index,
+            offset,
+            bounds: (2, 2),
+        };
+        film.tiles_mut(&[tile(0, (0, 0)), tile(1, (1, 1))]);
+    }
+
+    #[test]
+    fn splats_from_bands_are_summed_and_scaled_once_merged() {
+        let bounds = (4, 4);
+        let filter = Filter::default();
+        let mut merged = Film::new(bounds, filter);
+        for mut band in Film::bands(bounds, 2, filter) {
+            for (x, y) in sample_points(bounds) {
+                if (band.offset.1..band.offset.1 + band.bounds.1).contains(&(y as usize)) {
+                    band.add_sample(x, y, PixelF::black());
+                }
+            }
+            // Both bands land light on the same pixel, well outside the first band.
+            band.add_splat(1.5, 3.5, PixelF::rgb(0.25, 0., 0.));
+            merged.merge(&band);
+        }
+
+        // Two splats of 0.25, scaled by 16 pixels over 16 camera samples.
+        assert!((merged.pixel(1, 3).r - 0.5).abs() < 1e-6);
+        assert_eq!(merged.pixel(0, 0).r, 0.);
+        // Splats off the edge of the image are dropped rather than wrapping around.
+        merged.add_splat(4.5, 0.5, PixelF::rgb(1., 1., 1.));
+        assert_eq!(merged.pixel(0, 1).r, 0.);
+    }
+}
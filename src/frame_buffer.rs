@@ -0,0 +1,316 @@
+use std::str::FromStr;
+
+use crate::image_handling::{ImageBuffer, PixelF};
+use crate::material::Material;
+use crate::material_library::MaterialId;
+use crate::traits::Canvas;
+use crate::vectors::V3;
+
+/// Everything we know about the first surface a camera ray runs into.
+/// These make up the extra passes of a FrameBuffer, which compositors and denoisers can make use of.
+/// They're found with a ray through the center of each pixel, separately from the samples that go
+/// into the beauty pass, so they aren't antialiased and can disagree with it along edges.
+#[derive(Clone, Debug)]
+pub struct FirstHit {
+    /// Distance from the camera to the hit.
+    pub depth: f32,
+    /// The shading normal, facing back towards the camera.
+    pub normal: V3,
+    pub albedo: PixelF,
+    pub position: V3,
+    pub primitive_index: Option<usize>,
+    /// Which entry in the scene's material library the surface is made of.
+    pub material_id: Option<MaterialId>,
+    pub material: Material,
+}
+
+/// The different images a FrameBuffer can produce.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pass {
+    Beauty,
+    Depth,
+    Normal,
+    Albedo,
+    PrimitiveId,
+    MaterialId,
+    Position,
+}
+
+impl Pass {
+    pub const ALL: [Pass; 7] = [
+        Pass::Beauty,
+        Pass::Depth,
+        Pass::Normal,
+        Pass::Albedo,
+        Pass::PrimitiveId,
+        Pass::MaterialId,
+        Pass::Position,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pass::Beauty => "beauty",
+            Pass::Depth => "depth",
+            Pass::Normal => "normal",
+            Pass::Albedo => "albedo",
+            Pass::PrimitiveId => "primitive_id",
+            Pass::MaterialId => "material_id",
+            Pass::Position => "position",
+        }
+    }
+}
+
+impl FromStr for Pass {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Pass::ALL.into_iter().find(|p| p.name() == s).ok_or(())
+    }
+}
+
+/// A FrameBuffer holds a rendered image alongside a number of extra passes describing the first
+/// surface seen through each pixel. Just like an ImageBuffer, it can be split into bands for
+/// parallel rendering, and those bands can be appended back together.
+#[derive(Debug)]
+pub struct FrameBuffer {
+    pub beauty: ImageBuffer,
+    pub hits: Vec<Option<FirstHit>>,
+}
+
+impl FrameBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        FrameBuffer {
+            beauty: ImageBuffer::new(width, height),
+            hits: vec![None; width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.beauty.width()
+    }
+
+    pub fn height(&self) -> usize {
+        self.beauty.height()
+    }
+
+	/// Create a number of FrameBuffers representing bands of an image. See ImageBuffer::bands.
+    pub fn bands(bounds: (usize, usize), rows_per_band: usize) -> Vec<FrameBuffer> {
+        ImageBuffer::bands(bounds, rows_per_band)
+            .into_iter()
+            .map(|beauty| {
+                let n_pixels = beauty.pixels.len();
+                FrameBuffer {
+                    beauty,
+                    hits: vec![None; n_pixels],
+                }
+            })
+            .collect()
+    }
+
+    pub fn append_rows(&mut self, other: &mut FrameBuffer) {
+        self.beauty.append_rows(&mut other.beauty);
+        self.hits.append(&mut other.hits);
+    }
+
+	/// The raw values of a pass. Pixels where nothing was hit are all zeroes, and ids are offset by one
+	/// so that they don't get confused with those. Material ids are positions in the scene's material
+	/// library, so they agree from one band of the image to the next.
+    pub fn pass_values(&self, pass: Pass) -> Vec<[f32; 3]> {
+        let v3 = |v: V3| [v.x, v.y, v.z];
+        let px = |p: PixelF| [p.r, p.g, p.b];
+        let id = |i: Option<usize>| {
+            let i = i.map_or(0., |i| (i + 1) as f32);
+            [i, i, i]
+        };
+
+        match pass {
+            Pass::Beauty => self.beauty.pixels.iter().map(|p| px(*p)).collect(),
+            _ => self
+                .hits
+                .iter()
+                .map(|hit| match hit {
+                    None => [0.; 3],
+                    Some(hit) => match pass {
+                        Pass::Depth => [hit.depth; 3],
+                        Pass::Normal => v3(hit.normal),
+                        Pass::Albedo => px(hit.albedo),
+                        Pass::PrimitiveId => id(hit.primitive_index),
+                        Pass::MaterialId => id(hit.material_id.map(|id| id.index())),
+                        Pass::Position => v3(hit.position),
+                        Pass::Beauty => unreachable!(),
+                    },
+                })
+                .collect(),
+        }
+    }
+
+	/// Turn a pass into something that can be looked at. Depths and positions are normalized to the range
+	/// found in the frame, normals are mapped from [-1, 1] to [0, 1], and ids get a unique color each.
+    pub fn pass_image(&self, pass: Pass) -> ImageBuffer {
+        let mut image = ImageBuffer::new(self.width(), self.height());
+        image.offset = self.beauty.offset;
+
+        image.pixels = match pass {
+            Pass::Beauty => self.beauty.pixels.clone(),
+            Pass::Albedo => self
+                .hits
+                .iter()
+                .map(|hit| hit.as_ref().map_or(PixelF::black(), |hit| hit.albedo))
+                .collect(),
+            Pass::Normal => self
+                .hits
+                .iter()
+                .map(|hit| {
+                    hit.as_ref().map_or(PixelF::black(), |hit| {
+                        let n = hit.normal * 0.5 + V3::one() * 0.5;
+                        PixelF::rgb(n.x, n.y, n.z)
+                    })
+                })
+                .collect(),
+            Pass::Depth => {
+                let max_depth = self
+                    .hits
+                    .iter()
+                    .flatten()
+                    .map(|hit| hit.depth)
+                    .fold(0., f32::max);
+                self.hits
+                    .iter()
+                    .map(|hit| {
+                        hit.as_ref().map_or(PixelF::black(), |hit| {
+                            // Near things are bright, far things fade out.
+                            let v = 1. - hit.depth / max_depth.max(f32::EPSILON);
+                            PixelF::rgb(v, v, v)
+                        })
+                    })
+                    .collect()
+            }
+            Pass::Position => {
+                let positions = self.hits.iter().flatten().map(|hit| hit.position);
+                let min = positions.clone().fold(V3::one() * f32::MAX, |a, p| {
+                    V3::new(a.x.min(p.x), a.y.min(p.y), a.z.min(p.z))
+                });
+                let max = positions.fold(V3::one() * f32::MIN, |a, p| {
+                    V3::new(a.x.max(p.x), a.y.max(p.y), a.z.max(p.z))
+                });
+                let extent = max - min;
+                let normalize = |v: f32, lo: f32, ext: f32| (v - lo) / ext.max(f32::EPSILON);
+                self.hits
+                    .iter()
+                    .map(|hit| {
+                        hit.as_ref().map_or(PixelF::black(), |hit| {
+                            PixelF::rgb(
+                                normalize(hit.position.x, min.x, extent.x),
+                                normalize(hit.position.y, min.y, extent.y),
+                                normalize(hit.position.z, min.z, extent.z),
+                            )
+                        })
+                    })
+                    .collect()
+            }
+            Pass::PrimitiveId => self
+                .hits
+                .iter()
+                .map(|hit| id_color(hit.as_ref().and_then(|hit| hit.primitive_index)))
+                .collect(),
+            Pass::MaterialId => self
+                .hits
+                .iter()
+                .map(|hit| {
+                    id_color(hit.as_ref().and_then(|hit| hit.material_id).map(|id| id.index()))
+                })
+                .collect(),
+        };
+
+        image
+    }
+
+	/// Save a single pass. If the filename ends in `.exr`, the raw floating point values are written,
+	/// otherwise we save the viewable version from pass_image.
+    pub fn save_pass(&self, pass: Pass, filename: String) -> Result<(), String> {
+        if filename.ends_with(".exr") {
+            let bytes: Vec<u8> = self
+                .pass_values(pass)
+                .iter()
+                .flatten()
+                .flat_map(|f| f.to_ne_bytes())
+                .collect();
+            image::save_buffer(
+                filename,
+                &bytes,
+                self.width() as u32,
+                self.height() as u32,
+                image::ColorType::Rgb32F,
+            )
+            .map_err(|e| e.to_string())
+        } else {
+            self.pass_image(pass).save(filename)
+        }
+    }
+
+	/// Save every pass next to each other, naming them after the given filename.
+	/// For example, `out.png` produces `out.png`, `out.depth.png`, `out.normal.png`, and so on.
+    pub fn save_all(&self, filename: String) -> Result<(), String> {
+        for pass in Pass::ALL {
+            self.save_pass(pass, pass_filename(&filename, pass))?;
+        }
+        Ok(())
+    }
+}
+
+/// Work out the filename for a pass, by slotting its name in before the extension.
+pub fn pass_filename(filename: &str, pass: Pass) -> String {
+    if pass == Pass::Beauty {
+        return filename.to_owned();
+    }
+    match filename.rfind('.') {
+        Some(dot) => format!("{}.{}{}", &filename[..dot], pass.name(), &filename[dot..]),
+        None => format!("{}.{}", filename, pass.name()),
+    }
+}
+
+/// Give each id a distinct, stable color for mattes. Nothing gets black.
+fn id_color(id: Option<usize>) -> PixelF {
+    match id {
+        None => PixelF::black(),
+        Some(id) => {
+            // Stepping around the color wheel by the golden ratio keeps neighbouring ids far apart,
+            // and the brightness steps keep ids from repeating too soon.
+            let hue = (id as f32 * 0.618_034).fract() * 6.;
+            let value = 1. - 0.3 * ((id / 7) % 3) as f32;
+            let f = hue.fract();
+            let (r, g, b) = match hue as usize {
+                0 => (1., f, 0.),
+                1 => (1. - f, 1., 0.),
+                2 => (0., 1., f),
+                3 => (0., 1. - f, 1.),
+                4 => (f, 0., 1.),
+                _ => (1., 0., 1. - f),
+            };
+            let lift = |c: f32| (0.2 + 0.8 * c) * value;
+            PixelF::rgb(lift(r), lift(g), lift(b))
+        }
+    }
+}
+
+impl Canvas for FrameBuffer {
+    fn put_pixel(&mut self, x: usize, y: usize, pixel: PixelF) {
+        self.beauty.put_pixel(x, y, pixel);
+    }
+
+    fn bounds(&self) -> (usize, usize) {
+        self.beauty.bounds
+    }
+
+    fn offset(&self) -> (usize, usize) {
+        self.beauty.offset
+    }
+
+    fn records_first_hits(&self) -> bool {
+        true
+    }
+
+    fn put_first_hit(&mut self, x: usize, y: usize, hit: Option<FirstHit>) {
+        self.hits[y * self.beauty.bounds.0 + x] = hit;
+    }
+}
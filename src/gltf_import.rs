@@ -0,0 +1,694 @@
+use std::path::Path;
+
+use base64::Engine;
+use gltf::camera::Projection;
+use gltf::image::Source as ImageSource;
+use gltf::material::AlphaMode;
+use gltf::mesh::Mode;
+
+use crate::image_handling::{ImageBuffer, PixelF};
+use crate::material::Material;
+use crate::material_library::{MaterialId, MaterialLibrary, MetallicRoughness};
+use crate::primitives::Primitive;
+use crate::scene::{CameraDescription, Scene};
+use crate::scene_graph::{SceneGraph, SceneNode};
+use crate::texture::{ShadingMap, Texture};
+use crate::vectors::{M4, V3};
+
+// glTF is what most asset pipelines export these days. Unlike OBJ, it keeps the hierarchy of nodes
+// things were modelled in, the materials they were given, and where the cameras were, so we bring
+// all of that across: nodes become scene graph nodes, meshes become prototypes that the nodes place
+// instances of, and metallic-roughness materials go into the material library along with their
+// textures.
+//
+// Both flavours are supported: .gltf files, whose buffers and images sit in files alongside them or
+// are embedded as data URIs, and .glb files, which pack everything into one.
+//
+// glTF is right-handed, and we aren't, so everything gets mirrored front to back on the way in.
+// Cameras get mirrored along with the scene, so nothing comes out flipped in the image.
+//
+// Some things don't come across: skins, morph targets, animation, orthographic cameras, texture
+// transforms and any texture coordinates but the first set. Blended materials are cut out at their
+// cutoff like masked ones, rather than blended. Our lights glow evenly all over and don't reflect
+// anything, so only materials that glow evenly and brightly enough to matter become lights.
+// Emissive textures are ignored, and so is any faint glow, which exporters often leave on ordinary
+// surfaces.
+
+/// How bright the brightest channel of a material's emissive factor has to be for it to become a
+/// light.
+const MIN_EMISSION: f32 = 0.01;
+
+/// How solid a masked material has to be to show up, when it doesn't say.
+const DEFAULT_ALPHA_CUTOFF: f32 = 0.5;
+
+/// What a glTF file holds, ready to render.
+#[derive(Clone, Debug)]
+pub struct GltfScene {
+    pub materials: MaterialLibrary,
+    pub graph: SceneGraph,
+    /// The file's first camera, if it has one.
+    pub camera: Option<CameraDescription>,
+}
+
+impl GltfScene {
+    /// Flatten the scene graph into a Scene.
+    pub fn into_scene(self) -> Result<Scene, String> {
+        Scene::new(self.graph.flatten()?, self.materials)
+    }
+}
+
+/// Load a scene out of a .gltf or .glb file.
+pub fn load_gltf(filename: &str) -> Result<GltfScene, String> {
+    let read = || {
+        let gltf = gltf::Gltf::open(filename).map_err(|e| e.to_string())?;
+        import(&gltf, Path::new(filename).parent().unwrap_or(Path::new("")))
+    };
+    read().map_err(|e| format!("{}: {}", filename, e))
+}
+
+/// Load a scene out of a .gltf or .glb file in memory. Any files it refers to are looked for
+/// relative to the working directory.
+pub fn load_gltf_from_memory(bytes: &[u8]) -> Result<GltfScene, String> {
+    let gltf = gltf::Gltf::from_slice(bytes).map_err(|e| e.to_string())?;
+    import(&gltf, Path::new(""))
+}
+
+fn import(gltf: &gltf::Gltf, directory: &Path) -> Result<GltfScene, String> {
+    let buffers = load_buffers(gltf, directory)?;
+    let mut importer = Importer {
+        directory,
+        buffers: &buffers,
+        textures: vec![None; gltf.images().len()],
+        materials: MaterialLibrary::new(),
+        material_ids: vec![None; gltf.materials().len()],
+        default_material: None,
+    };
+
+    let scene = gltf
+        .default_scene()
+        .or_else(|| gltf.scenes().next())
+        .ok_or("there's no scene in the file")?;
+
+    // Mirroring the z axis takes us from glTF's right-handed coordinates to our left-handed ones.
+    let flip = M4::scaling(V3::new(1., 1., -1.));
+    let mut root = SceneNode::new().matrix(flip);
+    let mut camera = None;
+    for node in scene.nodes() {
+        root = root.child(convert_node(&node, flip, &mut camera));
+    }
+
+    let mut graph = SceneGraph::new(vec![root]);
+    for mesh in gltf.meshes() {
+        let primitives = importer.mesh_primitives(&mesh)?;
+        graph = graph.prototype(
+            &mesh_prototype(&mesh),
+            SceneNode::new().primitives(primitives),
+        );
+    }
+
+    Ok(GltfScene {
+        materials: importer.materials,
+        graph,
+        camera,
+    })
+}
+
+/// The name of the prototype a mesh gets turned into. glTF names don't have to be unique, so we go
+/// by position instead.
+fn mesh_prototype(mesh: &gltf::Mesh) -> String {
+    format!("mesh {}", mesh.index())
+}
+
+/// Turn a node, and everything under it, into a scene graph node. `parent` is where the node above
+/// it sits in the world, so the first camera we come across can be placed.
+fn convert_node(
+    node: &gltf::Node,
+    parent: M4,
+    camera: &mut Option<CameraDescription>,
+) -> SceneNode {
+    // glTF matrices are stored column by column.
+    let local = M4(node.transform().matrix()).transpose();
+    let world = parent * local;
+    let mut converted = SceneNode::new().matrix(local);
+    if let Some(name) = node.name() {
+        converted = converted.named(name);
+    }
+    if let Some(mesh) = node.mesh() {
+        converted = converted.instance(&mesh_prototype(&mesh));
+    }
+    if camera.is_none() {
+        *camera = node.camera().and_then(|c| convert_camera(&c, &world));
+    }
+    for child in node.children() {
+        converted = converted.child(convert_node(&child, world, camera));
+    }
+    converted
+}
+
+/// glTF cameras look down their -z axis, with +y up. Orthographic ones are skipped, since ours are
+/// all perspective.
+fn convert_camera(camera: &gltf::Camera, world: &M4) -> Option<CameraDescription> {
+    let Projection::Perspective(perspective) = camera.projection() else {
+        return None;
+    };
+    let bounds = match perspective.aspect_ratio() {
+        Some(aspect) if aspect > 0. => (((512. * aspect).round() as usize).max(1), 512),
+        _ => (512, 512),
+    };
+    Some(CameraDescription {
+        position: world.transform_point(V3::zero()),
+        direction: world.transform_vector(V3::new(0., 0., -1.)).normalized(),
+        up: world.transform_vector(V3::y()).normalized(),
+        fov: perspective.yfov().to_degrees(),
+        bounds,
+    })
+}
+
+/// Read in every buffer, from the binary chunk of a .glb, a data URI or a file next to the glTF.
+fn load_buffers(gltf: &gltf::Gltf, directory: &Path) -> Result<Vec<Vec<u8>>, String> {
+    let mut buffers = Vec::new();
+    for buffer in gltf.buffers() {
+        let mut data = match buffer.source() {
+            gltf::buffer::Source::Bin => gltf
+                .blob
+                .clone()
+                .ok_or("a buffer refers to a binary chunk that isn't there")?,
+            gltf::buffer::Source::Uri(uri) => load_uri(uri, directory)?,
+        };
+        if data.len() < buffer.length() {
+            return Err(format!(
+                "buffer {} should be {} bytes long, but it's only {}",
+                buffer.index(),
+                buffer.length(),
+                data.len()
+            ));
+        }
+        // The binary chunk gets padded out to a multiple of four bytes.
+        data.truncate(buffer.length());
+        buffers.push(data);
+    }
+    Ok(buffers)
+}
+
+/// Read the bytes a URI refers to, either embedded in it or in a file relative to the glTF.
+fn load_uri(uri: &str, directory: &Path) -> Result<Vec<u8>, String> {
+    match uri.strip_prefix("data:") {
+        Some(data) => {
+            let (_, encoded) = data
+                .split_once(";base64,")
+                .ok_or("only base64 data URIs are supported")?;
+            base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .map_err(|e| format!("invalid data URI: {}", e))
+        }
+        None => {
+            let path = directory.join(percent_decode(uri));
+            std::fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))
+        }
+    }
+}
+
+/// URIs escape characters like spaces as %20.
+fn percent_decode(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| uri.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Everything we keep track of while turning meshes into primitives. Textures and materials are
+/// shared between meshes, so each one is only converted the first time it's used.
+struct Importer<'a> {
+    directory: &'a Path,
+    buffers: &'a [Vec<u8>],
+    textures: Vec<Option<Texture>>,
+    materials: MaterialLibrary,
+    material_ids: Vec<Option<MaterialId>>,
+    /// What primitives without a material are made of, the same plain grey as OBJ meshes.
+    default_material: Option<MaterialId>,
+}
+
+impl Importer<'_> {
+    fn mesh_primitives(&mut self, mesh: &gltf::Mesh) -> Result<Vec<Primitive>, String> {
+        let mut primitives = Vec::new();
+        for primitive in mesh.primitives() {
+            self.convert_primitive(&primitive, &mut primitives)
+                .map_err(|e| format!("mesh {}: {}", mesh.index(), e))?;
+        }
+        Ok(primitives)
+    }
+
+    /// Turn one of a mesh's primitives into triangles. Points and lines have nothing to hit, so
+    /// they're skipped.
+    fn convert_primitive(
+        &mut self,
+        primitive: &gltf::Primitive,
+        triangles: &mut Vec<Primitive>,
+    ) -> Result<(), String> {
+        let buffers = self.buffers;
+        let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+        let positions: Vec<V3> = reader
+            .read_positions()
+            .ok_or("a primitive has no positions")?
+            .map(|[x, y, z]| V3::new(x, y, z))
+            .collect();
+        let normals: Option<Vec<V3>> = reader
+            .read_normals()
+            .map(|normals| normals.map(|[x, y, z]| V3::new(x, y, z)).collect());
+        // glTF's v runs down the image, and ours runs up it.
+        let uvs: Option<Vec<(f32, f32)>> = reader
+            .read_tex_coords(0)
+            .map(|uvs| uvs.into_f32().map(|[u, v]| (u, 1. - v)).collect());
+        let indices: Vec<usize> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().map(|i| i as usize).collect(),
+            None => (0..positions.len()).collect(),
+        };
+        if let Some(&index) = indices.iter().find(|&&index| index >= positions.len()) {
+            return Err(format!("there's no vertex number {}", index));
+        }
+        let corners: Vec<[usize; 3]> = match primitive.mode() {
+            Mode::Triangles => indices
+                .chunks_exact(3)
+                .map(|corners| [corners[0], corners[1], corners[2]])
+                .collect(),
+            // Every other triangle in a strip is wound backwards.
+            Mode::TriangleStrip => indices
+                .windows(3)
+                .enumerate()
+                .map(|(i, w)| {
+                    if i % 2 == 0 {
+                        [w[0], w[1], w[2]]
+                    } else {
+                        [w[1], w[0], w[2]]
+                    }
+                })
+                .collect(),
+            Mode::TriangleFan => indices
+                .windows(2)
+                .skip(1)
+                .map(|w| [indices[0], w[0], w[1]])
+                .collect(),
+            Mode::Points | Mode::Lines | Mode::LineLoop | Mode::LineStrip => return Ok(()),
+        };
+
+        let material = primitive.material();
+        let id = self.material(&material)?;
+        let normal_map = match material.normal_texture() {
+            Some(normal) => Some(ShadingMap::Normal {
+                texture: self.texture(&normal.texture())?,
+                strength: normal.scale(),
+            }),
+            None => None,
+        };
+        // Alpha is the base colour texture's alpha times the base colour factor's, and textures
+        // without any see-through pixels count as solid. Rather than scale the texture, we scale
+        // the cutoff to match. We can't blend, so blended materials get cut out like masked ones.
+        let cutout = match material.alpha_mode() {
+            AlphaMode::Opaque => None,
+            AlphaMode::Mask | AlphaMode::Blend => {
+                let pbr = material.pbr_metallic_roughness();
+                let alpha = pbr.base_color_factor()[3];
+                let cutoff = material.alpha_cutoff().unwrap_or(DEFAULT_ALPHA_CUTOFF);
+                let texture = match pbr.base_color_texture() {
+                    Some(info) => Some(self.texture(&info.texture())?),
+                    None => None,
+                };
+                match texture.filter(Texture::has_alpha) {
+                    Some(texture) if alpha > 0. => Some((texture, cutoff / alpha)),
+                    _ if alpha >= cutoff => None,
+                    // None of it is solid enough to see.
+                    _ => return Ok(()),
+                }
+            }
+        };
+
+        for [a, b, c] in corners {
+            let vertices = [positions[a], positions[b], positions[c]];
+            let mut triangle = match &normals {
+                Some(normals) => Primitive::new_smooth_triangle(
+                    vertices,
+                    [normals[a], normals[b], normals[c]],
+                    id,
+                ),
+                None => Primitive::new_triangle(vertices, id),
+            };
+            if let Some(uvs) = &uvs {
+                triangle = triangle.with_uvs([uvs[a], uvs[b], uvs[c]]);
+            }
+            if let Some(map) = &normal_map {
+                triangle = Primitive::new_mapped(triangle, map.clone());
+            }
+            if let Some((opacity, cutoff)) = &cutout {
+                triangle = Primitive::new_cutout(triangle, opacity.clone()).with_cutoff(*cutoff);
+            }
+            triangles.push(triangle);
+        }
+        Ok(())
+    }
+
+    /// Find or add the library entry for a glTF material.
+    fn material(&mut self, material: &gltf::Material) -> Result<MaterialId, String> {
+        let Some(index) = material.index() else {
+            let grey = Material::new_diffuse(PixelF::rgb(0.8, 0.8, 0.8));
+            return Ok(*self
+                .default_material
+                .get_or_insert_with(|| self.materials.add(grey)));
+        };
+        if let Some(id) = self.material_ids[index] {
+            return Ok(id);
+        }
+
+        // Names in glTF files don't have to be unique, so only the first of each gets its name.
+        let name = material
+            .name()
+            .filter(|&name| self.materials.find(name).is_none());
+        let [r, g, b] = material.emissive_factor();
+        let glows = material.emissive_texture().is_none() && r.max(g).max(b) >= MIN_EMISSION;
+        let id = if glows {
+            let emissive = Material::new_emissive(PixelF::rgb(r, g, b));
+            match name {
+                Some(name) => self.materials.add_named(name, emissive)?,
+                None => self.materials.add(emissive),
+            }
+        } else {
+            let pbr = material.pbr_metallic_roughness();
+            let [r, g, b, _] = pbr.base_color_factor();
+            let mut surface = MetallicRoughness::new(
+                PixelF::rgb(r, g, b),
+                pbr.metallic_factor(),
+                pbr.roughness_factor(),
+            );
+            if let Some(info) = pbr.base_color_texture() {
+                surface.base_color_texture = Some(self.texture(&info.texture())?);
+            }
+            if let Some(info) = pbr.metallic_roughness_texture() {
+                surface.metallic_roughness_texture = Some(self.texture(&info.texture())?);
+            }
+            self.materials.add_metallic_roughness(name, surface)?
+        };
+        self.material_ids[index] = Some(id);
+        Ok(id)
+    }
+
+    /// Find or load the image behind a glTF texture. Images in files of their own are loaded as
+    /// textures from those files, so they can still be saved to scene files. Embedded ones can't.
+    fn texture(&mut self, texture: &gltf::Texture) -> Result<Texture, String> {
+        let image = texture.source();
+        if let Some(texture) = &self.textures[image.index()] {
+            return Ok(texture.clone());
+        }
+        let loaded = match image.source() {
+            ImageSource::Uri { uri, .. } if !uri.starts_with("data:") => {
+                let path = self.directory.join(percent_decode(uri));
+                Texture::load(&path.to_string_lossy())?
+            }
+            ImageSource::Uri { uri, .. } => {
+                let bytes = load_uri(uri, self.directory)?;
+                Texture::from_image(ImageBuffer::load_from_memory(&bytes)?)?
+            }
+            ImageSource::View { view, .. } => {
+                let bytes = self.buffers[view.buffer().index()]
+                    .get(view.offset()..view.offset() + view.length())
+                    .ok_or_else(|| {
+                        format!("image {} runs off the end of its buffer", image.index())
+                    })?;
+                Texture::from_image(ImageBuffer::load_from_memory(bytes)?)?
+            }
+        };
+        self.textures[image.index()] = Some(loaded.clone());
+        Ok(loaded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::light::find_lights;
+
+    /// A unit square in the xy plane: its corners, their texture coordinates, the indices of its
+    /// two triangles, and the same corners in strip order and in fan order.
+    fn square_buffer() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for x in [0f32, 0., 0., 1., 0., 0., 1., 1., 0., 0., 1., 0.] {
+            bytes.extend_from_slice(&x.to_le_bytes());
+        }
+        for x in [0f32, 1., 1., 1., 1., 0., 0., 0.] {
+            bytes.extend_from_slice(&x.to_le_bytes());
+        }
+        for i in [0u16, 1, 2, 0, 2, 3, 0, 1, 3, 2, 0, 1, 2, 3] {
+            bytes.extend_from_slice(&i.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// A glTF document holding the square, with whatever else the test needs added on. Indices
+    /// are accessor 2 for triangles, 3 for a strip and 4 for a fan.
+    fn document(extra: Value) -> Value {
+        let mut document = json!({
+            "asset": {"version": "2.0"},
+            "scenes": [{"nodes": [0]}],
+            "nodes": [{"mesh": 0}],
+            "accessors": [
+                {"bufferView": 0, "componentType": 5126, "count": 4, "type": "VEC3",
+                    "min": [0, 0, 0], "max": [1, 1, 0]},
+                {"bufferView": 1, "componentType": 5126, "count": 4, "type": "VEC2"},
+                {"bufferView": 2, "componentType": 5123, "count": 6, "type": "SCALAR"},
+                {"bufferView": 2, "byteOffset": 12, "componentType": 5123, "count": 4,
+                    "type": "SCALAR"},
+                {"bufferView": 2, "byteOffset": 20, "componentType": 5123, "count": 4,
+                    "type": "SCALAR"},
+            ],
+            "bufferViews": [
+                {"buffer": 0, "byteOffset": 0, "byteLength": 48},
+                {"buffer": 0, "byteOffset": 48, "byteLength": 32},
+                {"buffer": 0, "byteOffset": 80, "byteLength": 28},
+            ],
+            "buffers": [{"byteLength": 108}],
+        });
+        if let (Some(document), Value::Object(extra)) = (document.as_object_mut(), extra) {
+            document.extend(extra);
+        }
+        document
+    }
+
+    fn data_uri(mime_type: &str, bytes: &[u8]) -> String {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(bytes);
+        format!("data:{};base64,{}", mime_type, encoded)
+    }
+
+    /// A .gltf file with its buffer embedded as a data URI.
+    fn embedded(mut document: Value) -> Vec<u8> {
+        let uri = data_uri("application/octet-stream", &square_buffer());
+        document["buffers"][0]["uri"] = uri.into();
+        document.to_string().into_bytes()
+    }
+
+    /// A .glb file, with its buffer in the binary chunk.
+    fn binary(document: Value) -> Vec<u8> {
+        let mut json = document.to_string().into_bytes();
+        // Chunks have to be a multiple of four bytes long.
+        json.resize(json.len().next_multiple_of(4), b' ');
+        let bin = square_buffer();
+        let mut glb = Vec::new();
+        let length = 12 + 8 + json.len() + 8 + bin.len();
+        for n in [0x4654_6C67, 2, length as u32, json.len() as u32, 0x4E4F_534A] {
+            glb.extend_from_slice(&n.to_le_bytes());
+        }
+        glb.extend_from_slice(&json);
+        for n in [bin.len() as u32, 0x004E_4942] {
+            glb.extend_from_slice(&n.to_le_bytes());
+        }
+        glb.extend_from_slice(&bin);
+        glb
+    }
+
+    /// A one row PNG, as a glTF image.
+    fn png(pixels: &[[u8; 4]]) -> Value {
+        let image = image::RgbaImage::from_raw(pixels.len() as u32, 1, pixels.concat()).unwrap();
+        let mut bytes = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut bytes), image::ImageOutputFormat::Png)
+            .unwrap();
+        json!({"uri": data_uri("image/png", &bytes)})
+    }
+
+    /// The corners of a triangle, whatever it's wrapped in.
+    fn corners(primitive: &Primitive) -> [V3; 3] {
+        match primitive {
+            Primitive::Triangle { vertices, .. } => *vertices,
+            Primitive::Mapped { primitive, .. } | Primitive::Cutout { primitive, .. } => {
+                corners(primitive)
+            }
+            _ => panic!("{:?} isn't a triangle", primitive),
+        }
+    }
+
+    fn facing(primitive: &Primitive) -> V3 {
+        let [a, b, c] = corners(primitive);
+        (b - a).cross(&(c - a)).normalized()
+    }
+
+    fn assert_near(a: V3, b: V3) {
+        assert!((a - b).magnitude() < 1e-5, "{:?} isn't {:?}", a, b);
+    }
+
+    #[test]
+    fn nodes_are_placed_and_mirrored_front_to_back() {
+        let document = document(json!({
+            "scenes": [{"nodes": [0, 1, 3]}],
+            "nodes": [
+                {"camera": 0, "translation": [0, 0, 5]},
+                {"translation": [1, 0, 0], "children": [2]},
+                {"mesh": 0, "translation": [0, 0, 2], "scale": [2, 2, 2]},
+                {"camera": 1, "translation": [9, 9, 9]},
+            ],
+            "cameras": [
+                {"type": "perspective",
+                    "perspective": {"yfov": 0.5, "aspectRatio": 2, "znear": 0.1}},
+                {"type": "perspective", "perspective": {"yfov": 1, "znear": 0.1}},
+            ],
+            "meshes": [{"primitives": [{"attributes": {"POSITION": 0}, "indices": 2}]}],
+        }));
+
+        for file in [embedded(document.clone()), binary(document)] {
+            let imported = load_gltf_from_memory(&file).unwrap();
+            let camera = imported.camera.clone().unwrap();
+            assert_near(camera.position, V3::new(0., 0., -5.));
+            assert_near(camera.direction, V3::z());
+            assert_near(camera.up, V3::y());
+            assert!((camera.fov - 0.5f32.to_degrees()).abs() < 1e-4);
+            assert_eq!(camera.bounds, (1024, 512));
+
+            let scene = imported.into_scene().unwrap();
+            assert_eq!(scene.primitives.len(), 2);
+            // Mirroring turns the triangle inside out, so it gets wound the other way.
+            let expected = [V3::new(1., 0., -2.), V3::new(3., 2., -2.), V3::new(3., 0., -2.)];
+            for (corner, expected) in corners(&scene.primitives[0]).into_iter().zip(expected) {
+                assert_near(corner, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn strips_and_fans_become_triangles_facing_the_same_way() {
+        let document = document(json!({
+            "meshes": [{"primitives": [
+                {"attributes": {"POSITION": 0}, "indices": 2},
+                {"attributes": {"POSITION": 0}, "indices": 3, "mode": 5},
+                {"attributes": {"POSITION": 0}, "indices": 4, "mode": 6},
+            ]}],
+        }));
+        let scene = load_gltf_from_memory(&embedded(document))
+            .unwrap()
+            .into_scene()
+            .unwrap();
+        assert_eq!(scene.primitives.len(), 6);
+        let front = facing(&scene.primitives[0]);
+        for primitive in &scene.primitives {
+            assert_near(facing(primitive), front);
+        }
+        // The strip's second triangle is the far corner and the two either side of it.
+        let strip_corners = corners(&scene.primitives[3]);
+        for corner in [V3::new(1., 0., 0.), V3::new(0., 1., 0.), V3::new(1., 1., 0.)] {
+            assert!(strip_corners.iter().any(|&c| (c - corner).magnitude() < 1e-5));
+        }
+    }
+
+    #[test]
+    fn metallic_roughness_materials_come_across_and_only_bright_ones_glow() {
+        let document = document(json!({
+            "meshes": [{"primitives": [
+                {"attributes": {"POSITION": 0}, "indices": 2, "material": 0},
+                {"attributes": {"POSITION": 0}, "indices": 2, "material": 1},
+                {"attributes": {"POSITION": 0}, "indices": 2, "material": 2},
+                {"attributes": {"POSITION": 0}, "indices": 2, "material": 3},
+                {"attributes": {"POSITION": 0}, "indices": 2},
+            ]}],
+            "materials": [
+                {"name": "gold", "pbrMetallicRoughness": {
+                    "baseColorFactor": [1, 0.8, 0.2, 1], "metallicFactor": 1,
+                    "roughnessFactor": 0.3}},
+                {"pbrMetallicRoughness": {
+                    "baseColorFactor": [0.2, 0.4, 0.6, 1], "metallicFactor": 0,
+                    "roughnessFactor": 0.1}},
+                {"emissiveFactor": [4, 2, 1]},
+                {"emissiveFactor": [0.005, 0, 0], "pbrMetallicRoughness": {"metallicFactor": 0}},
+            ],
+        }));
+        let scene = load_gltf_from_memory(&embedded(document))
+            .unwrap()
+            .into_scene()
+            .unwrap();
+        let material = |i: usize| scene.materials[scene.primitives[i].material().unwrap()].clone();
+
+        assert_eq!(scene.materials.find("gold"), scene.primitives[0].material());
+        let gold = Material::new_metallic_roughness(PixelF::rgb(1., 0.8, 0.2), 1., 0.3);
+        assert_eq!(material(0), gold);
+        let coated = Material::new_metallic_roughness(PixelF::rgb(0.2, 0.4, 0.6), 0., 0.1);
+        assert_eq!(material(2), coated);
+        assert_eq!(material(4), Material::new_emissive(PixelF::rgb(4., 2., 1.)));
+        assert_eq!(material(6), Material::new_diffuse(PixelF::white()));
+        assert_eq!(material(8), Material::new_diffuse(PixelF::rgb(0.8, 0.8, 0.8)));
+        assert_eq!(find_lights(&scene.primitives, &scene.materials).len(), 2);
+    }
+
+    #[test]
+    fn cutouts_go_by_alpha_and_the_materials_cutoff() {
+        let see_through = png(&[[255, 255, 255, 255], [255, 255, 255, 0]]);
+        let black = png(&[[0, 0, 0, 255], [0, 0, 0, 255]]);
+        let document = document(json!({
+            "meshes": [{"primitives": (0..6)
+                .map(|i| json!({"attributes": {"POSITION": 0, "TEXCOORD_0": 1}, "indices": 2,
+                    "material": i}))
+                .collect::<Vec<_>>()}],
+            "materials": [
+                {"alphaMode": "MASK", "alphaCutoff": 0.25,
+                    "pbrMetallicRoughness": {"baseColorTexture": {"index": 0}}},
+                {"alphaMode": "MASK", "pbrMetallicRoughness": {
+                    "baseColorTexture": {"index": 0}, "baseColorFactor": [1, 1, 1, 0.5]}},
+                // Without any alpha, the texture is solid, however dark it is.
+                {"alphaMode": "MASK", "pbrMetallicRoughness": {"baseColorTexture": {"index": 1}}},
+                {"alphaMode": "MASK", "pbrMetallicRoughness": {"baseColorFactor": [1, 1, 1, 0.2]}},
+                {"alphaMode": "BLEND", "pbrMetallicRoughness": {"baseColorFactor": [1, 1, 1, 0.8]}},
+                {"pbrMetallicRoughness": {"baseColorTexture": {"index": 0}}},
+            ],
+            "textures": [{"source": 0}, {"source": 1}],
+            "images": [see_through, black],
+        }));
+        let scene = load_gltf_from_memory(&embedded(document))
+            .unwrap()
+            .into_scene()
+            .unwrap();
+        let cutoffs: Vec<Option<f32>> = scene
+            .primitives
+            .iter()
+            .map(|primitive| match primitive {
+                Primitive::Cutout { cutoff, .. } => Some(*cutoff),
+                _ => None,
+            })
+            .collect();
+        // The fourth material is never solid enough to show up, so its triangles are left out.
+        let expected = [Some(0.25), Some(1.), None, None, None];
+        assert_eq!(cutoffs, expected.iter().flat_map(|&c| [c, c]).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,214 @@
+use std::str::FromStr;
+
+use rayon::prelude::*;
+
+use crate::bounded_volume_hierarchy::TraversalCost;
+use crate::camera::Camera;
+use crate::image_handling::{ImageBuffer, PixelF};
+use crate::traits::Traversable;
+
+// A debug view of how hard the acceleration structure works for each pixel. We cast one primary ray
+// through the middle of every pixel, count the BVH nodes and primitives it gets tested against, and
+// colour the pixel by that count, from dark blue for cheap to red for expensive. Nothing is shaded.
+//
+// A good tree shows the outlines of the objects in the scene, getting warmer around edges and
+// where things overlap. A bad one shows up as big hot blobs, or as rays which are expensive even
+// when they miss everything.
+//
+// There's a legend drawn under the image, with the colour scale and the counts it runs between, in a
+// tiny built-in font so we don't need to pull in a font renderer for a handful of digits.
+
+/// Which of the counts the heatmap shows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeatmapMetric {
+    Nodes,
+    Primitives,
+    /// Nodes and primitives added together.
+    Total,
+}
+
+impl HeatmapMetric {
+    fn measure(&self, cost: TraversalCost) -> usize {
+        match self {
+            HeatmapMetric::Nodes => cost.nodes,
+            HeatmapMetric::Primitives => cost.primitives,
+            HeatmapMetric::Total => cost.total(),
+        }
+    }
+}
+
+impl FromStr for HeatmapMetric {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nodes" => Ok(HeatmapMetric::Nodes),
+            "primitives" => Ok(HeatmapMetric::Primitives),
+            "total" => Ok(HeatmapMetric::Total),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Renders traversal-cost heatmaps.
+#[derive(Clone, Debug)]
+pub struct Heatmap {
+    metric: HeatmapMetric,
+    max: Option<usize>,
+    legend: bool,
+}
+
+impl Default for Heatmap {
+    fn default() -> Self {
+        Self {
+            metric: HeatmapMetric::Total,
+            max: None,
+            legend: true,
+        }
+    }
+}
+
+impl Heatmap {
+	/// Builder pattern function to set which count gets drawn.
+    pub fn metric(mut self, metric: HeatmapMetric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+	/// Builder pattern function to fix the count at the top of the colour scale, so heatmaps of different
+	/// trees can be compared. By default the scale goes up to the most expensive pixel.
+    pub fn max(mut self, max: usize) -> Self {
+        self.max = Some(max.max(1));
+        self
+    }
+
+	/// Builder pattern function to choose whether to draw the legend under the image.
+    pub fn legend(mut self, legend: bool) -> Self {
+        self.legend = legend;
+        self
+    }
+
+	/// Count the cost of the primary ray through the middle of each pixel, in rows from the top left.
+    pub fn costs<S: Traversable + Sync>(&self, scene: &S, camera: &Camera) -> Vec<usize> {
+        let (width, height) = camera.bounds();
+        (0..height)
+            .into_par_iter()
+            .flat_map_iter(|y| {
+                (0..width).map(move |x| {
+                    let ray = camera.get_ray_from_pixel(x as f32 + 0.5, y as f32 + 0.5);
+                    self.metric.measure(scene.intersect_with_cost(ray).1)
+                })
+            })
+            .collect()
+    }
+
+	/// Colour in a set of costs, as measured by `costs`.
+    pub fn draw(&self, costs: &[usize], bounds: (usize, usize)) -> ImageBuffer {
+        let (width, height) = bounds;
+        assert_eq!(costs.len(), width * height);
+        let max = self
+            .max
+            .unwrap_or_else(|| costs.iter().copied().max().unwrap_or(0).max(1));
+
+        let scale = 1 + width / 512;
+        let legend_height = if self.legend { 17 * scale } else { 0 };
+        let mut image = ImageBuffer::new(width, height + legend_height);
+        for (pixel, &cost) in image.pixels.iter_mut().zip(costs) {
+            *pixel = false_color(cost as f32 / max as f32);
+        }
+        if self.legend {
+            draw_legend(&mut image, height, scale, max);
+        }
+        image
+    }
+
+	/// Render a heatmap of a scene as seen by a camera.
+    pub fn render<S: Traversable + Sync>(&self, scene: &S, camera: &Camera) -> ImageBuffer {
+        self.draw(&self.costs(scene, camera), camera.bounds())
+    }
+}
+
+/// Map a value in [0, 1] onto our colour scale. Values outside of that are clamped.
+pub fn false_color(t: f32) -> PixelF {
+    const STOPS: [(f32, f32, f32); 6] = [
+        (0.0, 0.0, 0.3),
+        (0.0, 0.3, 1.0),
+        (0.0, 0.9, 0.9),
+        (0.5, 1.0, 0.2),
+        (1.0, 0.6, 0.0),
+        (1.0, 0.0, 0.0),
+    ];
+    let t = if t.is_nan() { 0. } else { t.clamp(0., 1.) };
+    let position = t * (STOPS.len() - 1) as f32;
+    let index = (position as usize).min(STOPS.len() - 2);
+    let frac = position - index as f32;
+    let (a, b) = (STOPS[index], STOPS[index + 1]);
+    PixelF::rgb(
+        a.0 + (b.0 - a.0) * frac,
+        a.1 + (b.1 - a.1) * frac,
+        a.2 + (b.2 - a.2) * frac,
+    )
+}
+
+/// Draw the colour scale and its labels into the rows of an image from `top` down.
+fn draw_legend(image: &mut ImageBuffer, top: usize, scale: usize, max: usize) {
+    let width = image.width();
+    let margin = 2 * scale;
+    let bar_width = width.saturating_sub(2 * margin).max(1);
+
+    for y in top + margin..top + margin + 6 * scale {
+        for x in margin..(margin + bar_width).min(width) {
+            let t = (x - margin) as f32 / bar_width.saturating_sub(1).max(1) as f32;
+            image.pixels[y * width + x] = false_color(t);
+        }
+    }
+
+    let text_top = top + 10 * scale;
+    let right = margin + bar_width;
+    let labels = [max / 2, max].map(|n| n.to_string());
+    draw_number(image, "0", margin, text_top, scale);
+    let middle_x = (margin + bar_width / 2).saturating_sub(text_width(&labels[0], scale) / 2);
+    draw_number(image, &labels[0], middle_x, text_top, scale);
+    let right_x = right.saturating_sub(text_width(&labels[1], scale));
+    draw_number(image, &labels[1], right_x, text_top, scale);
+}
+
+/// Digits in a 3x5 pixel font, one row per byte, with the leftmost pixel in the highest bit.
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+fn text_width(text: &str, scale: usize) -> usize {
+    (text.len() * 4).saturating_sub(1) * scale
+}
+
+/// Write out a string of digits in white, clipping anything that falls off the image.
+fn draw_number(image: &mut ImageBuffer, text: &str, left: usize, top: usize, scale: usize) {
+    let (width, height) = image.bounds;
+    for (i, digit) in text.bytes().filter(u8::is_ascii_digit).enumerate() {
+        let glyph = DIGITS[(digit - b'0') as usize];
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) == 0 {
+                    continue;
+                }
+                let x0 = left + (i * 4 + column) * scale;
+                let y0 = top + row * scale;
+                for y in y0..(y0 + scale).min(height) {
+                    for x in x0..(x0 + scale).min(width) {
+                        image.pixels[y * width + x] = PixelF::white();
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,7 +1,9 @@
-use image;
+use std::str::FromStr;
+
 use rand::Rng;
 
 use crate::traits::Canvas;
+use crate::sampler::rng;
 
 use serde::{Serialize, Deserialize};
 
@@ -68,38 +70,6 @@ impl ImageBuffer {
         chunks
     }
 
-	/// This creates bands from an existing image. These bands can be edited in-place, and don't need to be reassembled.
-	/// However, Rust cannot guarantee safety when passing references like this between threads, so this cannot be used
-	/// with conventional multithreading.
-    pub fn bands_in_place(&mut self, rows_per_band: usize) -> Vec<InPlaceSubBuffer<'_>> {
-        let remainder_band_rows = self.bounds.1 % rows_per_band;
-        let num_bands = self.bounds.1 / rows_per_band + if remainder_band_rows > 0 { 1 } else { 0 };
-        let mut chunks = Vec::with_capacity(num_bands);
-
-        for (b, chunk) in self
-            .pixels
-            .chunks_mut(self.bounds.0 * rows_per_band)
-            .enumerate()
-        {
-            let is_remainder_band = remainder_band_rows > 0 && b == num_bands - 1;
-
-            chunks.push(InPlaceSubBuffer {
-                bounds: (
-                    self.bounds.0,
-                    if is_remainder_band {
-                        remainder_band_rows
-                    } else {
-                        rows_per_band
-                    },
-                ),
-                offset: (0, b * rows_per_band),
-                pixels: chunk,
-            })
-        }
-
-        chunks
-    }
-
     pub fn append_rows(&mut self, other: &mut ImageBuffer) {
         assert!(other.offset.1 == self.bounds.1);
         self.pixels.append(&mut other.pixels);
@@ -114,43 +84,220 @@ impl ImageBuffer {
         bytes
     }
 
+    /// Like to_bytes, but with an alpha byte after each pixel's color.
+    pub fn to_rgba_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::<u8>::with_capacity(self.bounds.0 * self.bounds.1 * 4);
+        for px in &self.pixels {
+            bytes.extend_from_slice(&px.to_rgba_bytes());
+        }
+        bytes
+    }
+
+    /// Whether any pixel is less than fully opaque. Images without any transparency are saved
+    /// without an alpha channel.
+    pub fn has_alpha(&self) -> bool {
+        self.pixels.iter().any(|p| p.a < 1.)
+    }
+
     pub fn save(&self, filename: String) -> Result<(), String> {
         let (width, height) = self.bounds;
+        let (bytes, color_type) = if self.has_alpha() {
+            (self.to_rgba_bytes(), image::ColorType::Rgba8)
+        } else {
+            (self.to_bytes(), image::ColorType::Rgb8)
+        };
         match image::save_buffer(
             filename,
-            &self.to_bytes(),
+            &bytes,
             width as u32,
             height as u32,
-            image::ColorType::Rgb8,
+            color_type,
         ) {
             Err(error) => Err(error.to_string()),
             Ok(()) => Ok(()),
         }
     }
-}
 
-impl Canvas for ImageBuffer {
-    fn put_pixel(self: &mut Self, x: usize, y: usize, pixel: PixelF) {
-        self.pixels[y * self.bounds.0 + x] = pixel;
+	/// Save in a particular format, whatever the filename's extension says.
+	/// OpenEXR gets the full floating point values, rather than rounding them down to bytes.
+	/// Transparency is kept in formats that can hold it, and dropped from the rest.
+    pub fn save_as(&self, filename: String, format: OutputFormat) -> Result<(), String> {
+        let (width, height) = (self.bounds.0 as u32, self.bounds.1 as u32);
+        let alpha = format.supports_alpha() && self.has_alpha();
+        let result = if format == OutputFormat::Exr {
+            let bytes: Vec<u8> = self
+                .pixels
+                .iter()
+                .flat_map(|p| [p.r, p.g, p.b, p.a].into_iter().take(if alpha { 4 } else { 3 }))
+                .flat_map(|f| f.to_ne_bytes())
+                .collect();
+            image::save_buffer_with_format(
+                filename,
+                &bytes,
+                width,
+                height,
+                if alpha {
+                    image::ColorType::Rgba32F
+                } else {
+                    image::ColorType::Rgb32F
+                },
+                format.image_format(),
+            )
+        } else if alpha {
+            image::save_buffer_with_format(
+                filename,
+                &self.to_rgba_bytes(),
+                width,
+                height,
+                image::ColorType::Rgba8,
+                format.image_format(),
+            )
+        } else {
+            image::save_buffer_with_format(
+                filename,
+                &self.to_bytes(),
+                width,
+                height,
+                image::ColorType::Rgb8,
+                format.image_format(),
+            )
+        };
+        result.map_err(|e| e.to_string())
     }
 
-    fn bounds(self: &Self) -> (usize, usize) {
-        self.bounds
+    /// Read an image from a file, in any format the image crate understands. Images without an
+    /// alpha channel come out fully opaque.
+    pub fn load(filename: &str) -> Result<Self, String> {
+        let loaded = image::open(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        Ok(Self::from_dynamic_image(loaded))
     }
 
-    fn offset(&self) -> (usize, usize) {
-        self.offset
+    /// Read an image from an encoded file held in memory, like a PNG embedded in another file.
+    pub fn load_from_memory(bytes: &[u8]) -> Result<Self, String> {
+        let loaded = image::load_from_memory(bytes).map_err(|e| e.to_string())?;
+        Ok(Self::from_dynamic_image(loaded))
+    }
+
+    fn from_dynamic_image(loaded: image::DynamicImage) -> Self {
+        let loaded = loaded.into_rgba32f();
+        let mut image = ImageBuffer::new(loaded.width() as usize, loaded.height() as usize);
+        for (pixel, loaded_pixel) in image.pixels.iter_mut().zip(loaded.pixels()) {
+            let [r, g, b, a] = loaded_pixel.0;
+            *pixel = PixelF::rgba(r, g, b, a);
+        }
+        image
+    }
+
+	/// Measure how different another image of the same size is from this one.
+    pub fn compare(&self, other: &ImageBuffer) -> Result<ImageComparison, String> {
+        if self.bounds != other.bounds {
+            return Err(format!(
+                "the images are different sizes, {}x{} and {}x{}",
+                self.bounds.0, self.bounds.1, other.bounds.0, other.bounds.1
+            ));
+        }
+
+        let mut difference = ImageBuffer::new(self.bounds.0, self.bounds.1);
+        let (mut squared_error, mut max_difference, mut differing_pixels) = (0., 0f32, 0);
+        for (i, (a, b)) in self.pixels.iter().zip(&other.pixels).enumerate() {
+            let delta = [(a.r - b.r).abs(), (a.g - b.g).abs(), (a.b - b.b).abs()];
+            squared_error += delta.iter().map(|d| (d * d) as f64).sum::<f64>();
+            let largest = delta[0].max(delta[1]).max(delta[2]);
+            max_difference = max_difference.max(largest);
+            // Anything under one step of an 8 bit channel is just rounding.
+            if largest > 1. / 255. {
+                differing_pixels += 1;
+            }
+            difference.pixels[i] = PixelF::rgb(delta[0], delta[1], delta[2]);
+        }
+
+        let rmse = (squared_error / (self.pixels.len().max(1) * 3) as f64).sqrt() as f32;
+        Ok(ImageComparison {
+            rmse,
+            psnr: -20. * rmse.log10(),
+            max_difference,
+            differing_pixels,
+            difference,
+        })
     }
 }
 
+/// How different two images are. Colors are in [0, 1], so PSNR is measured against a peak of 1.
+/// PSNR is infinite for identical images.
 #[derive(Debug)]
-pub struct InPlaceSubBuffer<'parent> {
-    bounds: (usize, usize),
-    offset: (usize, usize),
-    pixels: &'parent mut [PixelF],
+pub struct ImageComparison {
+    pub rmse: f32,
+    pub psnr: f32,
+    pub max_difference: f32,
+    /// How many pixels differ by more than rounding error.
+    pub differing_pixels: usize,
+    /// The absolute difference between the images, per channel.
+    pub difference: ImageBuffer,
+}
+
+/// The file formats images can be saved in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Bmp,
+    Tga,
+    Tiff,
+    Exr,
+}
+
+impl OutputFormat {
+    /// Work out the format from a filename's extension.
+    pub fn from_filename(filename: &str) -> Option<Self> {
+        let (_, extension) = filename.rsplit_once('.')?;
+        Self::from_str(&extension.to_lowercase()).ok()
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Bmp => "bmp",
+            OutputFormat::Tga => "tga",
+            OutputFormat::Tiff => "tiff",
+            OutputFormat::Exr => "exr",
+        }
+    }
+
+    /// Whether images saved in this format can be partly transparent.
+    pub fn supports_alpha(&self) -> bool {
+        !matches!(self, OutputFormat::Jpeg | OutputFormat::Bmp)
+    }
+
+    fn image_format(&self) -> image::ImageFormat {
+        match self {
+            OutputFormat::Png => image::ImageFormat::Png,
+            OutputFormat::Jpeg => image::ImageFormat::Jpeg,
+            OutputFormat::Bmp => image::ImageFormat::Bmp,
+            OutputFormat::Tga => image::ImageFormat::Tga,
+            OutputFormat::Tiff => image::ImageFormat::Tiff,
+            OutputFormat::Exr => image::ImageFormat::OpenExr,
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "png" => Ok(Self::Png),
+            "jpg" | "jpeg" => Ok(Self::Jpeg),
+            "bmp" => Ok(Self::Bmp),
+            "tga" => Ok(Self::Tga),
+            "tif" | "tiff" => Ok(Self::Tiff),
+            "exr" => Ok(Self::Exr),
+            _ => Err(()),
+        }
+    }
 }
 
-impl<'parent> Canvas for InPlaceSubBuffer<'parent> {
+impl Canvas for ImageBuffer {
     fn put_pixel(&mut self, x: usize, y: usize, pixel: PixelF) {
         self.pixels[y * self.bounds.0 + x] = pixel;
     }
@@ -167,11 +314,24 @@ impl<'parent> Canvas for InPlaceSubBuffer<'parent> {
 /// PixelF represents a single pixel whose r, g, and b values are f32s in [0, 1]
 /// These are used in processing, since they have high accuracy, and are then
 /// converted to u8s for export to file.
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+/// Alpha is how much of the pixel is covered, for compositing renders over other images. Colors
+/// are premultiplied by it, so a transparent pixel is black. Light and material colors are always
+/// opaque, and arithmetic on colors gives opaque results.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub struct PixelF {
     pub r: f32,
     pub g: f32,
     pub b: f32,
+    #[serde(default = "opaque", skip_serializing_if = "is_opaque")]
+    pub a: f32,
+}
+
+fn opaque() -> f32 {
+    1.
+}
+
+fn is_opaque(a: &f32) -> bool {
+    *a == 1.
 }
 
 impl PixelF {
@@ -180,11 +340,34 @@ impl PixelF {
             r: 0.0,
             g: 0.0,
             b: 0.0,
+            a: 1.0,
+        }
+    }
+
+    pub fn white() -> Self {
+        Self {
+            r: 1.0,
+            g: 1.0,
+            b: 1.0,
+            a: 1.0,
         }
     }
 
+    /// A pixel that's completely see-through.
+    pub fn transparent() -> Self {
+        Self::rgba(0., 0., 0., 0.)
+    }
+
     pub fn rgb(r: f32, g: f32, b: f32) -> Self {
-        Self { r, g, b }
+        Self { r, g, b, a: 1. }
+    }
+
+    pub fn rgba(r: f32, g: f32, b: f32, a: f32) -> Self {
+        Self { r, g, b, a }
+    }
+
+    pub fn with_alpha(self, a: f32) -> Self {
+        Self { a, ..self }
     }
 
     pub fn rgb_u8(r: u8, g: u8, b: u8) -> Self {
@@ -207,6 +390,15 @@ impl PixelF {
         Self::rgb(self.r * other.r, self.g * other.g, self.b * other.b)
     }
 
+    /// Raise each channel to some power.
+    pub fn powf(self, exponent: f32) -> Self {
+        Self::rgb(
+            self.r.powf(exponent),
+            self.g.powf(exponent),
+            self.b.powf(exponent),
+        )
+    }
+
     pub fn scale(self, scalar: f32) -> Self {
         Self::rgb(
             (self.r * scalar).clamp(0., 1.),
@@ -223,8 +415,13 @@ impl PixelF {
         ]
     }
 
+    pub fn to_rgba_bytes(self) -> [u8; 4] {
+        let [r, g, b] = self.to_bytes();
+        [r, g, b, Self::color_f32_to_u8(self.a)]
+    }
+
     pub fn random() -> Self {
-        let mut r = rand::thread_rng();
+        let mut r = rng();
         Self::rgb(r.gen(), r.gen(), r.gen())
     }
 }
@@ -0,0 +1,604 @@
+use std::cell::RefCell;
+use std::str::FromStr;
+
+use rand::Rng;
+
+use crate::bidirectional::BidirectionalIntegrator;
+use crate::camera::Camera;
+use crate::image_handling::PixelF;
+use crate::light::Light;
+use crate::medium::Fog;
+use crate::photon_map::{PhotonMap, PhotonMapIntegrator};
+use crate::ray::Ray;
+use crate::raytracer::Collision;
+use crate::sampler::rng;
+use crate::spectral::SpectralIntegrator;
+use crate::stats;
+use crate::traits::Drawable;
+use crate::vectors::V3;
+
+use serde::{Deserialize, Serialize};
+
+// An integrator is what works out how much light comes back along a ray from the camera. The
+// raytracer takes care of generating rays and filtering the results onto a film, and hands each ray
+// over to its integrator. Different integrators trade quality for speed in different ways:
+//
+// - scatter follows whatever direction each material scatters in until the path flies off into the
+//   sky. This is how the raytracer has always worked. It's simple, but small lights are very noisy,
+//   since paths only find them by chance.
+// - path does the same, but also picks a point on a light at every diffuse bounce and checks whether
+//   it can be seen (next event estimation). The two ways of finding a light are blended with multiple
+//   importance sampling, so small lights converge quickly without big ones getting noisier.
+// - photons path traces too, but takes caustics from photon maps traced out from the lights before
+//   rendering, since paths from the camera almost never find them. See photon_map.rs.
+// - spectral path traces at a few wavelengths at once rather than in RGB, so glass can bend each
+//   colour by a different amount and split light into rainbows. See spectral.rs.
+// - whitted only follows mirrors and glass. At the first rough surface it adds up the direct light
+//   from every light, plus the sky as a flat ambient term, and stops. Fast, but no bounced light.
+// - bdpt traces a path out from a light as well as from the camera, and joins up every pair of points
+//   along the two. It's the best at light which only arrives after bouncing off of something, like
+//   a lamp inside a shade, or light focused through glass. See bidirectional.rs.
+// - ambient occlusion shades first hits by how much of the nearby hemisphere is open.
+// - debug shows normals, albedos or which side of each surface we hit, for checking geometry.
+//
+// Integrators are picked with IntegratorKind, which can be saved in scene files. Anything implementing
+// the Integrator trait can be used through the same TraceContext though.
+
+/// How far shadow rays keep clear of the points at either end, in world units.
+pub(crate) const SHADOW_EPSILON: f32 = 1e-3;
+
+/// Works out how much light comes back along a ray.
+pub trait Integrator {
+    fn radiance(&self, ray: Ray, context: &TraceContext) -> PixelF;
+}
+
+/// Everything an integrator gets to look at while tracing a ray.
+pub struct TraceContext<'a> {
+    pub scene: &'a dyn Drawable,
+    /// The lights in the scene, sorted by primitive index.
+    pub lights: &'a [Light],
+    pub fog: Option<&'a Fog>,
+    /// The most bounces a path can take.
+    pub max_depth: usize,
+    /// How many bounces a path gets before Russian roulette may end it early.
+    pub roulette_depth: usize,
+    /// The camera, when the film being rendered takes splats. Without one, integrators can't land
+    /// light paths on the image directly.
+    pub camera: Option<&'a Camera>,
+    /// Photon maps for the photon mapping integrator, one for each pass. Empty for
+    /// everything else.
+    pub photon_maps: &'a [PhotonMap],
+    /// Splats an integrator has made while tracing, in the image's pixel coordinates, waiting for
+    /// the raytracer to add them to the film.
+    pub splats: RefCell<Vec<(f32, f32, PixelF)>>,
+    /// Whatever the scene had in the way of the first ray intersected since this was last emptied,
+    /// leaving fog aside. Every integrator intersects the camera ray first, so this is how the raytracer finds
+    /// out what the camera saw without intersecting the ray all over again.
+    pub first_collision: RefCell<Option<Option<Collision>>>,
+}
+
+impl<'a> TraceContext<'a> {
+    /// Intersect a ray with the scene, and with the fog if there is any. Fog might scatter the ray
+    /// before it gets to whatever it would have hit.
+    pub fn intersect(&self, ray: Ray) -> Option<Collision> {
+        let mut collision = self.scene.intersect(ray);
+        self.first_collision
+            .borrow_mut()
+            .get_or_insert_with(|| collision.clone());
+        if let Some(fog) = self.fog {
+            let mut fog_ray = ray;
+            if let Some(ref c) = collision {
+                fog_ray.max = c.t;
+            }
+            if let Some(fog_collision) = fog.intersect(fog_ray) {
+                collision = Some(fog_collision);
+            }
+        }
+        collision
+    }
+
+    /// The same context, but with no camera to splat through. Integrators tracing rays that didn't
+    /// come from the camera use this, so light paths don't get splatted more than once per sample.
+    pub fn without_camera(&self) -> TraceContext<'a> {
+        TraceContext {
+            scene: self.scene,
+            lights: self.lights,
+            fog: self.fog,
+            max_depth: self.max_depth,
+            roulette_depth: self.roulette_depth,
+            camera: None,
+            photon_maps: self.photon_maps,
+            splats: RefCell::new(Vec::new()),
+            first_collision: RefCell::new(None),
+        }
+    }
+
+    /// Cast a shadow ray to check whether nothing's in the way between two points.
+    pub fn visible(&self, from: V3, to: V3) -> bool {
+        stats::record(|s| s.shadow_rays += 1);
+        let mut ray = Ray::from_to(from, to);
+        // Points on big surfaces, like the huge spheres we use for floors, aren't stored very
+        // precisely. Skip a little way out from both ends, so we don't shadow ourselves or the light.
+        let skip = SHADOW_EPSILON / ray.dir.magnitude();
+        ray.min = skip;
+        ray.max = 1. - skip;
+        ray.min < ray.max && self.intersect(ray).is_none()
+    }
+
+    /// Find the light made out of a primitive, if it's one of ours.
+    pub fn light(&self, primitive_index: Option<usize>) -> Option<&'a Light> {
+        let index = primitive_index?;
+        self.lights
+            .binary_search_by_key(&index, |light| light.primitive_index)
+            .ok()
+            .map(|i| &self.lights[i])
+    }
+
+    /// Land some light at (x, y) in the image's pixel coordinates, wherever the ray being traced
+    /// happens to be. Nothing happens if there's no camera to splat through.
+    pub fn splat(&self, x: f32, y: f32, color: PixelF) {
+        if self.camera.is_some() {
+            self.splats.borrow_mut().push((x, y, color));
+        }
+    }
+
+    /// Russian roulette. Once a path has gone a few bounces, we randomly stop following it with a
+    /// probability that rises as less light gets carried along it. The paths that survive make up for
+    /// the ones that didn't by carrying proportionally more, so on average we get the same answer, we
+    /// just stop wasting time on paths that hardly contribute anything.
+    ///
+    /// Gives back the path's new throughput, or None if it was ended.
+    pub fn roulette(&self, depth: usize, throughput: PixelF, rand: &mut impl Rng) -> Option<PixelF> {
+        let largest = throughput.r.max(throughput.g).max(throughput.b);
+        let scale = self.roulette_scale(depth, largest, rand)?;
+        Some(scaled(throughput, scale))
+    }
+
+    /// Russian roulette for paths which don't carry their light as a PixelF, going by the largest
+    /// part of their throughput. Gives back how much to scale the throughput by, or None if the path
+    /// was ended.
+    pub fn roulette_scale(&self, depth: usize, largest: f32, rand: &mut impl Rng) -> Option<f32> {
+        if depth < self.roulette_depth {
+            return Some(1.);
+        }
+        let survival = largest.min(1.);
+        if rand.gen::<f32>() >= survival {
+            stats::record(|s| {
+                s.roulette_terminations += 1;
+                s.path_ended(depth);
+            });
+            return None;
+        }
+        Some(1. / survival)
+    }
+}
+
+/// Determine the color of the sky depending on what direction we flew off.
+pub fn sky_color(ray: Ray) -> PixelF {
+    let unit_direction = ray.dir.normalized();
+    let t = 0.5 * (unit_direction.y + 1.0);
+    let lerp = |t: f32, start: f32, end: f32| -> f32 { start * (1.0 - t) + end * t };
+    PixelF::rgb_u8(
+        lerp(t, 255.0, 120.0) as u8,
+        lerp(t, 255.0, 200.0) as u8,
+        255,
+    )
+}
+
+/// The integrators we ship, in a form that can be saved to a scene file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum IntegratorKind {
+    #[default]
+    Scatter,
+    Path,
+    Bidirectional,
+    /// Path tracing, with caustics from photon maps. Photons are traced `passes` times over, each
+    /// time gathered over a smaller radius than the last.
+    PhotonMap {
+        photons: usize,
+        radius: f32,
+        passes: usize,
+    },
+    Spectral,
+    Whitted,
+    AmbientOcclusion { distance: f32, samples: usize },
+    Debug { view: DebugView },
+}
+
+/// What the debug integrator shows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DebugView {
+    /// Normals, mapped from [-1, 1] onto [0, 1].
+    Normals,
+    Albedo,
+    /// Green for the fronts of surfaces and red for their backs.
+    Facing,
+}
+
+impl Integrator for IntegratorKind {
+    fn radiance(&self, ray: Ray, context: &TraceContext) -> PixelF {
+        match *self {
+            IntegratorKind::Scatter => ScatterIntegrator.radiance(ray, context),
+            IntegratorKind::Path => PathIntegrator.radiance(ray, context),
+            IntegratorKind::Bidirectional => BidirectionalIntegrator.radiance(ray, context),
+            IntegratorKind::PhotonMap { .. } => PhotonMapIntegrator.radiance(ray, context),
+            IntegratorKind::Spectral => SpectralIntegrator.radiance(ray, context),
+            IntegratorKind::Whitted => WhittedIntegrator.radiance(ray, context),
+            IntegratorKind::AmbientOcclusion { distance, samples } => {
+                AmbientOcclusionIntegrator { distance, samples }.radiance(ray, context)
+            }
+            IntegratorKind::Debug { view } => DebugIntegrator { view }.radiance(ray, context),
+        }
+    }
+}
+
+impl IntegratorKind {
+    /// Photon mapping with some number of photons, a gathering radius that suits a scene a few units
+    /// across, and a single pass.
+    pub fn photon_map(photons: usize) -> Self {
+        IntegratorKind::PhotonMap {
+            photons,
+            radius: 0.05,
+            passes: 1,
+        }
+    }
+}
+
+impl FromStr for IntegratorKind {
+    type Err = ();
+
+    /// Parse an integrator from its name. Ambient occlusion can be followed by how far to look for
+    /// occluders, like `ao:2`, and photon mapping by how many photons to trace, like `photons:50000`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("photons", photons)) => {
+                let photons = usize::from_str(photons).map_err(|_| ())?;
+                if photons == 0 {
+                    return Err(());
+                }
+                Ok(IntegratorKind::photon_map(photons))
+            }
+            Some(("ao", distance)) => {
+                let distance = f32::from_str(distance).map_err(|_| ())?;
+                if distance <= 0. || distance.is_nan() {
+                    return Err(());
+                }
+                Ok(IntegratorKind::AmbientOcclusion {
+                    distance,
+                    samples: 1,
+                })
+            }
+            Some(_) => Err(()),
+            None => match s {
+                "scatter" => Ok(IntegratorKind::Scatter),
+                "path" => Ok(IntegratorKind::Path),
+                "bdpt" => Ok(IntegratorKind::Bidirectional),
+                "photons" => Ok(IntegratorKind::photon_map(200_000)),
+                "spectral" => Ok(IntegratorKind::Spectral),
+                "whitted" => Ok(IntegratorKind::Whitted),
+                "ao" => Ok(IntegratorKind::AmbientOcclusion {
+                    distance: 1.,
+                    samples: 1,
+                }),
+                "normals" => Ok(IntegratorKind::Debug {
+                    view: DebugView::Normals,
+                }),
+                "albedo" => Ok(IntegratorKind::Debug {
+                    view: DebugView::Albedo,
+                }),
+                "facing" => Ok(IntegratorKind::Debug {
+                    view: DebugView::Facing,
+                }),
+                _ => Err(()),
+            },
+        }
+    }
+}
+
+/// Follows scattered rays until they reach the sky. Lights are only found by running into them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ScatterIntegrator;
+
+impl Integrator for ScatterIntegrator {
+    fn radiance(&self, mut ray: Ray, context: &TraceContext) -> PixelF {
+        let mut rand = rng();
+        let mut throughput = PixelF::white();
+
+        for depth in 0..=context.max_depth {
+            if depth > 0 {
+                stats::record(|s| s.secondary_rays += 1);
+            }
+            let collision = match context.intersect(ray) {
+                Some(collision) => collision,
+                None => {
+                    stats::record(|s| s.path_ended(depth));
+                    return sky_color(ray).attenuate(throughput);
+                }
+            };
+            stats::record(|s| s.hits += 1);
+
+            if collision.material.emission().is_some() {
+                stats::record(|s| s.path_ended(depth));
+                let emitted = collision.material.emitted(collision.front_facing);
+                return emitted.attenuate(throughput);
+            }
+
+            throughput = throughput.attenuate(collision.color);
+            ray = collision.ray_out;
+            throughput = match context.roulette(depth + 1, throughput, &mut rand) {
+                Some(throughput) => throughput,
+                None => return PixelF::black(),
+            };
+        }
+
+        stats::record(|s| {
+            s.max_depth_terminations += 1;
+            s.path_ended(context.max_depth + 1);
+        });
+        PixelF::black()
+    }
+}
+
+/// A path tracer which samples lights directly at every rough bounce, and weighs that against
+/// running into them by chance with multiple importance sampling.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PathIntegrator;
+
+impl Integrator for PathIntegrator {
+    fn radiance(&self, ray: Ray, context: &TraceContext) -> PixelF {
+        path_radiance(ray, context, None)
+    }
+}
+
+/// Path trace a ray. Given a photon map, caustics at the first rough surface come from that instead,
+/// and light arriving there by way of mirrors and glass is skipped so it doesn't get counted twice.
+pub(crate) fn path_radiance(
+    mut ray: Ray,
+    context: &TraceContext,
+    caustics: Option<&PhotonMap>,
+) -> PixelF {
+    let mut rand = rng();
+    let mut throughput = PixelF::white();
+    let mut radiance = PixelF::black();
+    // Where the last bounce happened, and how likely it was to pick the direction it did. This is
+    // None when the last bounce couldn't have sampled a light, like the camera or a mirror.
+    let mut last_bounce: Option<(V3, f32)> = None;
+    let mut caustics = caustics;
+    // Once caustics have been looked up, how many mirrors and glass the path has gone through since.
+    // This goes back to None at the next rough bounce.
+    let mut caustic_chain: Option<usize> = None;
+
+    for depth in 0..=context.max_depth {
+        if depth > 0 {
+            stats::record(|s| s.secondary_rays += 1);
+        }
+        let collision = match context.intersect(ray) {
+            Some(collision) => collision,
+            None => {
+                stats::record(|s| s.path_ended(depth));
+                return accumulated(radiance, sky_color(ray).attenuate(throughput));
+            }
+        };
+        stats::record(|s| s.hits += 1);
+        let point = collision.point();
+
+        if collision.material.emission().is_some() {
+            stats::record(|s| s.path_ended(depth));
+            let light = context.light(collision.primitive_index);
+            if light.is_some() && matches!(caustic_chain, Some(n) if n > 0) {
+                // The photons already brought this light in.
+                return radiance;
+            }
+            let emitted = collision.material.emitted(collision.front_facing);
+            // We might have found this light by sampling it at the last bounce too.
+            let weight = match (last_bounce, light) {
+                (Some((from, scatter_pdf)), Some(light)) => {
+                    let light_pdf =
+                        light.pdf(from, point, collision.normal) / context.lights.len() as f32;
+                    power_heuristic(scatter_pdf, light_pdf)
+                }
+                _ => 1.,
+            };
+            return accumulated(radiance, scaled(emitted.attenuate(throughput), weight));
+        }
+
+        // Next event estimation, for materials that scatter light every which way.
+        let dir_in = ray.dir;
+        let lobe = collision.evaluate(collision.ray_out.dir);
+        if lobe.is_some() && !context.lights.is_empty() {
+            let light = &context.lights[rand.gen_range(0..context.lights.len())];
+            if let Some(sample) = light.sample(point, &mut rand) {
+                let dir_out = sample.point - point;
+                if let Some((value, scatter_pdf)) = collision.evaluate(dir_out) {
+                    let light_pdf = sample.pdf / context.lights.len() as f32;
+                    if scatter_pdf > 0. && context.visible(point, sample.point) {
+                        let weight = power_heuristic(light_pdf, scatter_pdf) / light_pdf;
+                        let direct = value.attenuate(sample.emission).attenuate(throughput);
+                        radiance = accumulated(radiance, scaled(direct, weight));
+                    }
+                }
+            }
+        }
+        // Coated materials sometimes glance off of their coat like a mirror instead, and bounces
+        // like that count as mirrors.
+        let rough = lobe.filter(|_| !collision.scattered_specularly());
+        last_bounce = rough.map(|(_, scatter_pdf)| (point, scatter_pdf));
+
+        let mut looked_up = false;
+        if lobe.is_some() && !collision.material.is_volumetric() {
+            if let Some(map) = caustics.take() {
+                let caustic = map.estimate(point, collision.normal, dir_in, &collision.material);
+                radiance = accumulated(radiance, caustic.attenuate(throughput));
+                looked_up = true;
+            }
+        }
+        if rough.is_some() {
+            caustic_chain = looked_up.then_some(0);
+        } else if let Some(n) = caustic_chain {
+            caustic_chain = Some(n + 1);
+        }
+
+        throughput = throughput.attenuate(collision.color);
+        ray = collision.ray_out;
+        throughput = match context.roulette(depth + 1, throughput, &mut rand) {
+            Some(throughput) => throughput,
+            None => return radiance,
+        };
+    }
+
+    stats::record(|s| {
+        s.max_depth_terminations += 1;
+        s.path_ended(context.max_depth + 1);
+    });
+    radiance
+}
+
+/// Follows mirrors and glass, then lights the first rough surface directly from every light, with
+/// the sky as an unshadowed ambient term.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WhittedIntegrator;
+
+impl Integrator for WhittedIntegrator {
+    fn radiance(&self, mut ray: Ray, context: &TraceContext) -> PixelF {
+        let mut rand = rng();
+        let mut throughput = PixelF::white();
+
+        for depth in 0..=context.max_depth {
+            if depth > 0 {
+                stats::record(|s| s.secondary_rays += 1);
+            }
+            let collision = match context.intersect(ray) {
+                Some(collision) => collision,
+                None => {
+                    stats::record(|s| s.path_ended(depth));
+                    return sky_color(ray).attenuate(throughput);
+                }
+            };
+            stats::record(|s| s.hits += 1);
+
+            if collision.material.emission().is_some() {
+                stats::record(|s| s.path_ended(depth));
+                return collision
+                    .material
+                    .emitted(collision.front_facing)
+                    .attenuate(throughput);
+            }
+
+            if collision.scattered_specularly() {
+                // Mirrors and glass just pass us along, as does a coat when light glances off of it.
+                throughput = throughput.attenuate(collision.color);
+                ray = collision.ray_out;
+                continue;
+            }
+
+            stats::record(|s| s.path_ended(depth));
+            let point = collision.point();
+            let mut light = collision
+                .material
+                .albedo()
+                .attenuate(sky_color(Ray::new(point, collision.normal)));
+            for source in context.lights {
+                let sample = match source.sample(point, &mut rand) {
+                    Some(sample) => sample,
+                    None => continue,
+                };
+                if let Some((value, scatter_pdf)) = collision.evaluate(sample.point - point) {
+                    if scatter_pdf > 0. && context.visible(point, sample.point) {
+                        let direct = value.attenuate(sample.emission);
+                        light = accumulated(light, scaled(direct, 1. / sample.pdf));
+                    }
+                }
+            }
+            return light.attenuate(throughput);
+        }
+
+        stats::record(|s| {
+            s.max_depth_terminations += 1;
+            s.path_ended(context.max_depth + 1);
+        });
+        PixelF::black()
+    }
+}
+
+/// Shades first hits by the fraction of rays sent off from them which travel `distance` without
+/// running into anything. Fog counts as something, both for first hits and for blocking rays.
+#[derive(Clone, Copy, Debug)]
+pub struct AmbientOcclusionIntegrator {
+    pub distance: f32,
+    pub samples: usize,
+}
+
+impl Integrator for AmbientOcclusionIntegrator {
+    fn radiance(&self, ray: Ray, context: &TraceContext) -> PixelF {
+        stats::record(|s| s.path_ended(0));
+        let collision = match context.intersect(ray) {
+            Some(collision) => collision,
+            None => return PixelF::white(),
+        };
+        stats::record(|s| s.hits += 1);
+
+        let point = collision.point();
+        let samples = self.samples.max(1);
+        let open = (0..samples)
+            .filter(|_| {
+                stats::record(|s| s.shadow_rays += 1);
+                let mut dir = collision.normal + V3::random_on_unit_sphere();
+                if dir.near_zero() {
+                    dir = collision.normal;
+                }
+                let mut occlusion_ray = Ray::new(point, dir);
+                occlusion_ray.min = SHADOW_EPSILON / dir.magnitude();
+                occlusion_ray.max = self.distance / dir.magnitude();
+                context.intersect(occlusion_ray).is_none()
+            })
+            .count();
+        let v = open as f32 / samples as f32;
+        PixelF::rgb(v, v, v)
+    }
+}
+
+/// Shows something about the first thing each ray hits, rather than lighting it. That might be fog.
+#[derive(Clone, Copy, Debug)]
+pub struct DebugIntegrator {
+    pub view: DebugView,
+}
+
+impl Integrator for DebugIntegrator {
+    fn radiance(&self, ray: Ray, context: &TraceContext) -> PixelF {
+        stats::record(|s| s.path_ended(0));
+        let collision = match context.intersect(ray) {
+            Some(collision) => collision,
+            None => return PixelF::black(),
+        };
+        stats::record(|s| s.hits += 1);
+        match self.view {
+            DebugView::Normals => {
+                let n = collision.normal.normalized();
+                PixelF::rgb(0.5 * (n.x + 1.), 0.5 * (n.y + 1.), 0.5 * (n.z + 1.))
+            }
+            DebugView::Albedo => collision.material.albedo(),
+            DebugView::Facing if collision.front_facing => PixelF::rgb(0.1, 0.8, 0.1),
+            DebugView::Facing => PixelF::rgb(0.8, 0.1, 0.1),
+        }
+    }
+}
+
+/// Weigh one way of sampling something against another, by the power heuristic.
+pub fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b == 0. {
+        0.
+    } else {
+        a / (a + b)
+    }
+}
+
+// PixelF's own addition and scaling clamp to [0, 1], which won't do for light, since lights and
+// roulette survivors carry well over 1.
+pub(crate) fn accumulated(a: PixelF, b: PixelF) -> PixelF {
+    PixelF::rgb(a.r + b.r, a.g + b.g, a.b + b.b)
+}
+
+pub(crate) fn scaled(p: PixelF, scalar: f32) -> PixelF {
+    PixelF::rgb(p.r * scalar, p.g * scalar, p.b * scalar)
+}
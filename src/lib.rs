@@ -1,15 +1,36 @@
 #![allow(dead_code)]
 
 mod image_handling;
+mod integrator;
 mod traits;
 
+mod bidirectional;
 mod bounded_volume_hierarchy;
 mod camera;
+mod checkpoint;
+mod film;
+mod denoise;
+mod distributed;
+mod frame_buffer;
+mod gltf_import;
+mod heatmap;
+mod light;
 mod material;
+mod material_library;
+mod medium;
+mod mesh;
+mod photon_map;
 mod primitives;
 // mod partitionable;
 mod ray;
 mod raytracer;
+mod sampler;
+mod scene;
+mod scene_graph;
+mod scheduler;
+mod spectral;
+mod stats;
+mod texture;
 mod utils;
 mod vectors;
 
@@ -17,6 +38,7 @@ pub mod prelude;
 use prelude::*;
 use rand::Rng;
 use rayon::prelude::*;
+use sampler::rng;
 
 // This file orchestrates all the modules in the project, and exports some utility methods which make
 // rendering a bit easier for our binaries.
@@ -39,14 +61,32 @@ where
     }
 }
 
+/// Render a scene and its extra passes either in parallel or in series depending on a passed bool.
+pub fn conditional_render_passes<S>(
+    rt: &Raytracer,
+    camera: &Camera,
+    scene: &S,
+    bounds: (usize, usize),
+    parallel: bool,
+) -> FrameBuffer
+where
+    S: Drawable + Send + Sync,
+{
+    if parallel {
+        par_render_passes(rt, camera, scene, bounds)
+    } else {
+        render_passes(rt, camera, scene, bounds)
+    }
+}
+
 /// Render a scene in series.
 pub fn render<S>(rt: &Raytracer, camera: &Camera, scene: &S, bounds: (usize, usize)) -> ImageBuffer
 where
     S: Drawable,
 {
-    let mut image_out = ImageBuffer::new(bounds.0, bounds.1);
-    rt.render(scene, &mut image_out, camera).unwrap();
-    image_out
+    let mut film = Film::new(bounds, rt.pixel_filter());
+    rt.render_film(scene, &mut film, camera).unwrap();
+    film.to_image()
 }
 
 /// Render a scene in parallel.
@@ -59,22 +99,65 @@ pub fn par_render<S>(
 where
     S: Drawable + Send + Sync,
 {
-    let mut chunks = ImageBuffer::bands(bounds, 16);
-    chunks.par_iter_mut().for_each(|chunk| {
-        rt.render(scene, chunk, camera).unwrap();
-    });
-
-    let mut image_out = ImageBuffer::new(bounds.0, 0);
-    for mut chunk in chunks {
-        image_out.append_rows(&mut chunk);
-    }
-    image_out
+    par_render_film(rt, camera, scene, bounds).to_image()
+}
+
+/// Render a scene in parallel, returning the film with all of the accumulated samples.
+/// Each tile's film overlaps its neighbours by the filter's apron, and merging them all together
+/// sums up those overlaps, so there are no seams between the tiles.
+pub fn par_render_film<S>(rt: &Raytracer, camera: &Camera, scene: &S, bounds: (usize, usize)) -> Film
+where
+    S: Drawable + Send + Sync,
+{
+    TileScheduler::default().render(rt, camera, scene, bounds, |_| {})
+}
+
+/// Render a scene in series, recording extra passes alongside the image.
+pub fn render_passes<S>(
+    rt: &Raytracer,
+    camera: &Camera,
+    scene: &S,
+    bounds: (usize, usize),
+) -> FrameBuffer
+where
+    S: Drawable,
+{
+    let mut frame_out = FrameBuffer::new(bounds.0, bounds.1);
+    rt.render(scene, &mut frame_out, camera).unwrap();
+    frame_out
+}
+
+/// Render a scene in parallel, recording extra passes alongside the image.
+pub fn par_render_passes<S>(
+    rt: &Raytracer,
+    camera: &Camera,
+    scene: &S,
+    bounds: (usize, usize),
+) -> FrameBuffer
+where
+    S: Drawable + Send + Sync,
+{
+    let mut frame_out = FrameBuffer::new(bounds.0, bounds.1);
+    // Rows of the first hits can be filled in place, since they don't overlap.
+    frame_out
+        .hits
+        .par_chunks_mut(bounds.0)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, hit) in row.iter_mut().enumerate() {
+                let center = camera.get_ray_from_pixel(x as f32 + 0.5, y as f32 + 0.5);
+                *hit = rt.first_hit(center, scene);
+            }
+        });
+    frame_out.beauty = par_render(rt, camera, scene, bounds);
+    frame_out
 }
 
 /// Generate a scene with random spheres.
-pub fn random_spheres(num: usize, bounds: Bounds) -> Vec<Primitive> {
-    let mut rand = rand::thread_rng();
+pub fn random_spheres(num: usize, bounds: Bounds) -> Scene {
+    let mut rand = rng();
     let mut elements: Vec<Primitive> = Vec::with_capacity(num);
+    let mut materials = MaterialLibrary::new();
 
     for _ in 0..num {
         let x: f32 = rand.gen_range(bounds.min_point.x..bounds.max_point.x);
@@ -91,10 +174,11 @@ pub fn random_spheres(num: usize, bounds: Bounds) -> Vec<Primitive> {
             _ => Material::new_dielectric(color, 1. + param * param, 0.005),
         };
 
+        let mat = materials.add(mat);
         elements.push(Primitive::new_sphere(V3::new(x, y, z), radius, mat));
     }
 
-    elements
+    Scene::new(elements, materials).unwrap()
 }
 
 /// Generate a scene with a grid of spheres, ligtly perturbed in the z axis.
@@ -102,9 +186,10 @@ pub fn big_sphere_grid(
     grid_dims: (usize, usize),
     world_dims: ((f32, f32), (f32, f32)),
     z: f32,
-) -> Vec<Primitive> {
-    let mut rand = rand::thread_rng();
+) -> Scene {
+    let mut rand = rng();
     let mut elements: Vec<Primitive> = Vec::with_capacity(grid_dims.0 * grid_dims.1);
+    let mut materials = MaterialLibrary::new();
 
     for y in 0..grid_dims.1 {
         for x in 0..grid_dims.0 {
@@ -117,6 +202,7 @@ pub fn big_sphere_grid(
             } else {
                 Material::new_specular(color, 0.1)
             };
+            let mat = materials.add(mat);
 
             let sphere = Primitive::new_sphere(
                 V3::new(
@@ -130,17 +216,22 @@ pub fn big_sphere_grid(
             elements.push(sphere);
         }
     }
-    elements
+    Scene::new(elements, materials).unwrap()
 }
 
-/// Build a preset scene with six spheres.
-pub fn sample_scene() -> Vec<Primitive> {
-    let diffuse_orange = Material::new_diffuse(PixelF::rgb_u8(200, 120, 30));
-    let diffuse_dark_blue = Material::new_diffuse(PixelF::rgb(0.08, 0.1, 0.4));
-    let specular_gold = Material::new_specular(PixelF::rgb(1., 0.8, 0.4), 0.2);
-    let specular_red = Material::new_specular(PixelF::rgb(0.8, 0.2, 0.3), 0.);
-    let specular_mirror = Material::new_specular(PixelF::rgb(0.9, 0.8, 1.), 0.05);
-    let dielectric_teal = Material::new_dielectric(PixelF::rgb(0.5, 0.8, 1.), 1.16, 0.);
+/// Build a preset scene with six spheres. Each of their materials is named after how it looks.
+pub fn sample_scene() -> Scene {
+    let mut materials = MaterialLibrary::new();
+    let mut add = |name: &str, material: Material| materials.add_named(name, material).unwrap();
+    let diffuse_orange = add("orange", Material::new_diffuse(PixelF::rgb_u8(200, 120, 30)));
+    let diffuse_dark_blue = add("dark blue", Material::new_diffuse(PixelF::rgb(0.08, 0.1, 0.4)));
+    let specular_gold = add("gold", Material::new_specular(PixelF::rgb(1., 0.8, 0.4), 0.2));
+    let specular_red = add("red", Material::new_specular(PixelF::rgb(0.8, 0.2, 0.3), 0.));
+    let specular_mirror = add("mirror", Material::new_specular(PixelF::rgb(0.9, 0.8, 1.), 0.05));
+    let dielectric_teal = add(
+        "teal glass",
+        Material::new_dielectric(PixelF::rgb(0.5, 0.8, 1.), 1.16, 0.),
+    );
 
     let sphere = Primitive::new_sphere(V3::new(0.0, 0.0, 0.), 0.9, specular_gold);
     let sphere2 = Primitive::new_sphere(V3::new(2.1, 0.0, 0.), 1.1, diffuse_orange);
@@ -148,5 +239,54 @@ pub fn sample_scene() -> Vec<Primitive> {
     let sphere4 = Primitive::new_sphere(V3::new(0.3, 0.3, -2.), 0.6, dielectric_teal);
     let sphere5 = Primitive::new_sphere(V3::new(0., -100.8, 0.), 100., specular_mirror);
     let sphere6 = Primitive::new_sphere(V3::new(-2.3, 3.2, 3.3), 2.2, specular_red);
-    vec![sphere, sphere2, sphere3, sphere4, sphere5, sphere6]
+    let primitives = vec![sphere, sphere2, sphere3, sphere4, sphere5, sphere6];
+    Scene::new(primitives, materials).unwrap()
+}
+
+/// Build a scene for showing off caustics: a glass ball on a matte floor, lit by a small lamp off to
+/// one side. It's all inside a big matte sphere, so the sky doesn't drown out the lamp.
+pub fn caustic_scene() -> Scene {
+    let mut materials = MaterialLibrary::new();
+    let mut add = |name: &str, material: Material| materials.add_named(name, material).unwrap();
+    let walls = add("walls", Material::new_diffuse(PixelF::rgb(0.6, 0.6, 0.6)));
+    let floor = add("floor", Material::new_diffuse(PixelF::rgb(0.8, 0.75, 0.7)));
+    let glass = add("glass", Material::new_dielectric(PixelF::white(), 1.5, 0.));
+    let lamp = add("lamp", Material::new_emissive(PixelF::rgb(40., 40., 36.)));
+
+    let primitives = vec![
+        Primitive::new_sphere(V3::zero(), 12., walls),
+        Primitive::new_sphere(V3::new(0., -1001., 0.), 1000., floor),
+        Primitive::new_sphere(V3::new(0., 0., 1.5), 1., glass),
+        Primitive::new_sphere(V3::new(-2.5, 2.5, 1.), 0.5, lamp),
+    ];
+    Scene::new(primitives, materials).unwrap()
+}
+
+/// Build a scene for looking at a density grid. The grid fills a box sitting on a matte floor,
+/// scaled so that its longest side is four units long.
+pub fn density_grid_scene(grid: DensityGrid) -> Scene {
+    let (nx, ny, nz) = grid.dims;
+    let longest = nx.max(ny).max(nz) as f32;
+    let half_extent = V3::new(nx as f32, ny as f32, nz as f32) * (2. / longest);
+    let center = V3::new(0., half_extent.y - 0.8, 2.);
+
+    // Densities come in whatever units the simulation used, so normalize them so that the densest
+    // voxel is reasonably opaque.
+    let max_density = grid.max_density().max(f32::EPSILON);
+    let medium = HomogeneousMedium::new(0.5 / max_density, 6. / max_density, PixelF::white(), 0.);
+
+    let volume = Primitive::new_grid_medium(
+        grid,
+        Bounds {
+            min_point: center - half_extent,
+            max_point: center + half_extent,
+        },
+        medium,
+    );
+    let mut materials = MaterialLibrary::new();
+    let floor_material = materials
+        .add_named("floor", Material::new_diffuse(PixelF::rgb(0.4, 0.4, 0.45)))
+        .unwrap();
+    let floor = Primitive::new_sphere(V3::new(0., -100.8, 0.), 100., floor_material);
+    Scene::new(vec![volume, floor], materials).unwrap()
 }
@@ -0,0 +1,204 @@
+use std::f32::consts::PI;
+
+use rand::Rng;
+
+use crate::image_handling::PixelF;
+use crate::material_library::MaterialLibrary;
+use crate::primitives::Primitive;
+use crate::vectors::V3;
+
+// Lights are just primitives with an emissive material. Integrators which sample lights directly
+// want to pick points on them, so here we pull the emissive spheres and triangles out of a scene
+// into a list, remembering which primitive each came from. That way when a ray happens to hit a
+// light on its own, we can look the light back up and work out how likely we'd have been to pick
+// that same point, which multiple importance sampling needs.
+//
+// Spheres are sampled over the cone of directions they cover as seen from the point being lit, so
+// we never pick a point on the far side of the sphere. Triangles are sampled uniformly by area.
+// Either way, the pdfs we hand out are per unit solid angle at the point being lit.
+
+/// A primitive which gives off light.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Light {
+    pub shape: LightShape,
+    /// The light given off by the front of the surface.
+    pub emission: PixelF,
+    /// Index of the primitive this light came from, in the list the scene was built from.
+    pub primitive_index: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightShape {
+    Sphere { center: V3, radius: f32 },
+    Triangle { vertices: [V3; 3] },
+}
+
+/// A point picked on a light.
+#[derive(Clone, Copy, Debug)]
+pub struct LightSample {
+    pub point: V3,
+    /// The outward normal of the light at that point.
+    pub normal: V3,
+    /// The light given off towards the point being lit.
+    pub emission: PixelF,
+    /// The chance of picking this point, per unit solid angle at the point being lit.
+    pub pdf: f32,
+}
+
+impl Light {
+    /// Make a light out of a primitive, if it's emissive and a shape we know how to sample.
+    pub fn from_primitive(
+        primitive: &Primitive,
+        materials: &MaterialLibrary,
+        primitive_index: usize,
+    ) -> Option<Self> {
+        let (shape, material) = match *primitive {
+            Primitive::Sphere {
+                center,
+                radius,
+                material,
+            } => (LightShape::Sphere { center, radius }, material),
+            Primitive::Triangle {
+                vertices, material, ..
+            } => (LightShape::Triangle { vertices }, material),
+            Primitive::Mapped { ref primitive, .. } => {
+                return Light::from_primitive(primitive, materials, primitive_index)
+            }
+            // We'd pick points in the holes, so cut out lights only get found by running into them.
+            Primitive::Cutout { .. } => return None,
+            _ => return None,
+        };
+        let emission = materials.get(material)?.emission()?;
+        Some(Light {
+            shape,
+            emission,
+            primitive_index,
+        })
+    }
+
+    /// Pick a point on this light which can be seen from `from`, if there is one.
+    pub fn sample(&self, from: V3, rand: &mut impl Rng) -> Option<LightSample> {
+        match self.shape {
+            LightShape::Sphere { center, radius } => {
+                let to_center = center - from;
+                let distance_squared = to_center.magnitude_squared();
+                // We don't bother lighting the inside of a light.
+                if distance_squared <= radius * radius {
+                    return None;
+                }
+                let distance = distance_squared.sqrt();
+                let cos_theta_max = (1. - radius * radius / distance_squared).max(0.).sqrt();
+
+                // Pick a direction inside the cone the sphere covers.
+                let cos_theta = 1. - rand.gen::<f32>() * (1. - cos_theta_max);
+                let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+                let phi = rand.gen::<f32>() * 2. * PI;
+                let forward = to_center / distance;
+                let (tangent, bitangent) = forward.orthonormal_basis();
+                let dir = forward * cos_theta + (tangent * phi.cos() + bitangent * phi.sin()) * sin_theta;
+
+                // Then find where it lands on the sphere. Right at the edge of the cone rounding can
+                // make it miss, in which case we take the closest point instead.
+                let t = distance * cos_theta
+                    - (radius * radius - distance_squared * sin_theta * sin_theta)
+                        .max(0.)
+                        .sqrt();
+                let point = from + dir * t;
+                Some(LightSample {
+                    point,
+                    normal: (point - center).normalized(),
+                    emission: self.emission,
+                    pdf: 1. / (2. * PI * (1. - cos_theta_max)).max(f32::EPSILON),
+                })
+            }
+            LightShape::Triangle { vertices: [a, b, c] } => {
+                let root = rand.gen::<f32>().sqrt();
+                let (u, v) = (1. - root, rand.gen::<f32>() * root);
+                let point = a * u + b * v + c * (1. - u - v);
+                let cross = (b - a).cross(&(c - a));
+                let normal = cross.normalized();
+                let pdf = self.area_to_solid_angle(from, point, normal, 0.5 * cross.magnitude())?;
+                Some(LightSample {
+                    point,
+                    normal,
+                    emission: self.emission,
+                    pdf,
+                })
+            }
+        }
+    }
+
+    /// The surface area of the light.
+    pub fn area(&self) -> f32 {
+        match self.shape {
+            LightShape::Sphere { radius, .. } => 4. * PI * radius * radius,
+            LightShape::Triangle { vertices: [a, b, c] } => 0.5 * (b - a).cross(&(c - a)).magnitude(),
+        }
+    }
+
+    /// Pick a point anywhere on the light, evenly by area, for light paths to start from. Gives the
+    /// point and the outward normal there. The chance of picking it is one over the area.
+    pub fn sample_surface(&self, rand: &mut impl Rng) -> (V3, V3) {
+        match self.shape {
+            LightShape::Sphere { center, radius } => {
+                let normal = V3::random_on_unit_sphere();
+                (center + normal * radius, normal)
+            }
+            LightShape::Triangle { vertices: [a, b, c] } => {
+                let root = rand.gen::<f32>().sqrt();
+                let (u, v) = (1. - root, rand.gen::<f32>() * root);
+                (a * u + b * v + c * (1. - u - v), (b - a).cross(&(c - a)).normalized())
+            }
+        }
+    }
+
+    /// The light given off from a point with this outward normal, heading along `dir`. Lights only
+    /// shine out of their fronts.
+    pub fn emitted_towards(&self, normal: V3, dir: V3) -> PixelF {
+        if normal.dot(&dir) > 0. {
+            self.emission
+        } else {
+            PixelF::black()
+        }
+    }
+
+    /// The chance `sample` would pick a given point on this light from `from`, per unit solid angle.
+    /// The point's assumed to be on the light and visible from `from`.
+    pub fn pdf(&self, from: V3, point: V3, normal: V3) -> f32 {
+        match self.shape {
+            LightShape::Sphere { center, radius } => {
+                let distance_squared = (center - from).magnitude_squared();
+                if distance_squared <= radius * radius {
+                    return 0.;
+                }
+                let cos_theta_max = (1. - radius * radius / distance_squared).max(0.).sqrt();
+                1. / (2. * PI * (1. - cos_theta_max)).max(f32::EPSILON)
+            }
+            LightShape::Triangle { vertices: [a, b, c] } => {
+                let area = 0.5 * (b - a).cross(&(c - a)).magnitude();
+                self.area_to_solid_angle(from, point, normal, area)
+                    .unwrap_or(0.)
+            }
+        }
+    }
+
+    /// Turn a pdf of one over the area into one per unit solid angle, if the point is facing `from`.
+    fn area_to_solid_angle(&self, from: V3, point: V3, normal: V3, area: f32) -> Option<f32> {
+        let to_light = point - from;
+        let distance_squared = to_light.magnitude_squared();
+        let cos_light = -normal.dot(&to_light) / distance_squared.sqrt();
+        if cos_light <= 0. || area <= 0. {
+            return None;
+        }
+        Some(distance_squared / (cos_light * area))
+    }
+}
+
+/// Find every light among a list of primitives, made of materials from a library.
+pub fn find_lights(primitives: &[Primitive], materials: &MaterialLibrary) -> Vec<Light> {
+    primitives
+        .iter()
+        .enumerate()
+        .filter_map(|(index, primitive)| Light::from_primitive(primitive, materials, index))
+        .collect()
+}
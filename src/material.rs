@@ -1,14 +1,20 @@
-use rand::{thread_rng, Rng};
+use std::f32::consts::PI;
+
+use rand::Rng;
 
 use crate::image_handling::PixelF;
+use crate::integrator::scaled;
+use crate::medium::{henyey_greenstein, sample_henyey_greenstein};
 use crate::ray::Ray;
+use crate::sampler::rng;
+use crate::spectral::spectrum_to_rgb;
 use crate::utils::lerp;
 use crate::vectors::V3;
 
 use serde::{Serialize, Deserialize};
 
 /// A Material defines ways to react to light and propogate color.
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Material {
 	/// This material reflects in roughly random directions, creating a matte surface.
     Diffuse {
@@ -20,13 +26,178 @@ pub enum Material {
         fuzz: f32,
     },
 	/// This material refracts and reflects light, like glass or water.
+	/// Rather than tinting light at each bounce, the albedo is the color light takes on after travelling
+	/// one unit through the material, so thick glass comes out darker than thin glass.
+	/// With dispersion, the spectral integrator bends each wavelength by a different amount, splitting
+	/// white light into rainbows. Everything else sticks to `r_index_ratio`.
     Dielectric {
         albedo: PixelF,
         r_index_ratio: f32,
         fuzz: f32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dispersion: Option<Dispersion>,
+    },
+	/// This material puts a clear coat over any other material, like lacquer or car paint. Light
+	/// either glances off of the coat, or goes through it to the base and back out again. Each colour
+	/// fades by `absorption` per unit travelled through the coat, which is `thickness` units thick.
+	/// A thin film on top of the coat makes it shimmer, like oil on a wet road.
+	/// Light can be sampled directly off of the base if it could be without the coat, so a coat over
+	/// a mirror or glass is treated like a mirror too. Whatever the base lets through carries on into
+	/// the surface underneath.
+    Coated {
+        base: Box<Material>,
+        ior: f32,
+        thickness: f32,
+        absorption: PixelF,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        film: Option<ThinFilm>,
+    },
+	/// This material is a thin film hanging in the air, like a soap bubble. Whatever it doesn't reflect
+	/// carries straight on through.
+    Iridescent {
+        film: ThinFilm,
+    },
+	/// This material is translucent, like wax, marble, jade or skin. Light goes through its surface
+	/// in a random direction, then wanders around inside, scattering off of whatever it's made of,
+	/// until it finds its way back out or gets absorbed. `mean_free_path` is how far each colour gets
+	/// between scattering, on average, and `albedo` is how much of it survives each time it scatters.
+	/// Only closed shapes have an inside to wander around in.
+    Subsurface {
+        albedo: PixelF,
+        mean_free_path: PixelF,
+        anisotropy: f32,
+    },
+	/// This material scatters light off of particles in a participating medium, like smoke or fog.
+	/// It isn't meant for surfaces - media hand it out for their scattering events.
+    Volumetric {
+        albedo: PixelF,
+        anisotropy: f32,
     },
+	/// This material gives off light from its front, and doesn't scatter anything. Spheres and triangles
+	/// made of it can be sampled directly as lights. Emission can go well past 1 for bright lights.
+    Emissive {
+        emission: PixelF,
+    },
+	/// This material cuts a hole in the image wherever the camera sees it directly, leaving it black
+	/// and fully transparent, so something else can be composited in there later. It still hides
+	/// whatever's behind it, and soaks up any light that lands on it, like a black surface.
+    Holdout,
+	/// This material is an invisible floor for compositing renders over photographs. Wherever the
+	/// camera sees it directly, it shows whatever's behind it, darkened by the shadows falling on it,
+	/// along with any light bouncing onto it off of everything else. Everything but the camera sees a
+	/// matte surface, so objects still pick up light bouncing off of the floor.
+    ShadowCatcher {
+        albedo: PixelF,
+    },
+}
+
+/// How a dielectric's index of refraction changes with the wavelength of light. Published coefficients
+/// take wavelengths in micrometres, so these do too.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Dispersion {
+    /// Cauchy's equation, n = a + b / λ². Good enough for most glass across visible light.
+    Cauchy { a: f32, b: f32 },
+    /// The Sellmeier equation, n² = 1 + Σ bᵢλ² / (λ² - cᵢ), which is what glass catalogues list.
+    Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+impl Dispersion {
+    /// Schott's BK7, the most common optical glass.
+    pub const BK7: Dispersion = Dispersion::Sellmeier {
+        b: [1.039_612, 0.231_792_3, 1.010_469_4],
+        c: [0.006_000_699, 0.020_017_914, 103.560_65],
+    };
+
+    /// Schott's SF11, a dense flint glass which splits light much more than BK7.
+    pub const SF11: Dispersion = Dispersion::Sellmeier {
+        b: [1.737_597, 0.313_747_35, 1.898_781],
+        c: [0.013_188_707, 0.062_306_814, 155.236_3],
+    };
+
+    /// Diamond, which gets its fire from splitting light even more than flint glass.
+    pub const DIAMOND: Dispersion = Dispersion::Sellmeier {
+        b: [0.3306, 4.3356, 0.],
+        c: [0.030_625, 0.011_236, 0.],
+    };
+
+    /// The index of refraction for light of some wavelength, in nanometres.
+    pub fn ior(&self, wavelength: f32) -> f32 {
+        let micrometres = wavelength / 1000.;
+        let l2 = micrometres * micrometres;
+        match *self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                let sum: f32 = (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum();
+                (1. + sum).max(1.).sqrt()
+            }
+        }
+    }
+}
+
+/// A film only a few hundred nanometres thick, like soap or oil. Light reflecting off of its front
+/// interferes with light reflecting off of its back, cancelling out some wavelengths and
+/// reinforcing others depending on the film's thickness and the angle it's seen at. That's where
+/// the colours in soap bubbles and oil slicks come from.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ThinFilm {
+    /// In nanometres.
+    pub thickness: f32,
+    pub ior: f32,
+}
+
+impl ThinFilm {
+    pub fn new(thickness: f32, ior: f32) -> Self {
+        ThinFilm { thickness, ior }
+    }
+
+    /// How much light of some wavelength, in nanometres, the film reflects, arriving at an angle
+    /// whose cosine is `cos_theta` from a material with index `outside`, when the film sits on one
+    /// with index `below`.
+    pub fn reflectance(&self, cos_theta: f32, outside: f32, below: f32, wavelength: f32) -> f32 {
+        let film = self.ior;
+        let sin_squared = 1. - cos_theta * cos_theta;
+        let cos_film_squared = 1. - sin_squared * (outside / film).powi(2);
+        let cos_below_squared = 1. - sin_squared * (outside / below).powi(2);
+        if cos_film_squared <= 0. || cos_below_squared <= 0. {
+            // Total internal reflection, so everything comes back.
+            return 1.;
+        }
+        let cos_film = cos_film_squared.sqrt();
+        let (s_top, p_top) = fresnel_amplitudes(outside, cos_theta, film, cos_film);
+        let (s_bottom, p_bottom) =
+            fresnel_amplitudes(film, cos_film, below, cos_below_squared.sqrt());
+        // How far out of step light coming off the back is with light coming off the front.
+        let cos_phase = (4. * PI * film * self.thickness * cos_film / wavelength).cos();
+        // Airy's formula, which adds up every trip back and forth inside the film.
+        let airy = |top: f32, bottom: f32| {
+            let cross = 2. * top * bottom * cos_phase;
+            (top * top + bottom * bottom + cross) / (1. + top * top * bottom * bottom + cross)
+        };
+        ((airy(s_top, s_bottom) + airy(p_top, p_bottom)) / 2.).clamp(0., 1.)
+    }
+
+    /// The film's reflectance across all of visible light, as a colour.
+    pub fn reflectance_rgb(&self, cos_theta: f32, outside: f32, below: f32) -> PixelF {
+        spectrum_to_rgb(|wavelength| self.reflectance(cos_theta, outside, below, wavelength))
+    }
 }
 
+/// How much of a wave's amplitude reflects going from index `n1` to `n2`, for light polarised
+/// perpendicular to and parallel with the plane it travels in. The cosines are of the angles either
+/// side of the boundary.
+fn fresnel_amplitudes(n1: f32, cos1: f32, n2: f32, cos2: f32) -> (f32, f32) {
+    (
+        (n1 * cos1 - n2 * cos2) / (n1 * cos1 + n2 * cos2),
+        (n2 * cos1 - n1 * cos2) / (n2 * cos1 + n1 * cos2),
+    )
+}
+
+/// The wavelength glass catalogues quote indices of refraction at, the yellow helium d line.
+const D_LINE: f32 = 587.56;
+
+/// How many times light can bounce around inside a coat before we give up on it.
+const COAT_BOUNCES: usize = 8;
+
 impl Material {
     pub fn new_diffuse(albedo: PixelF) -> Self {
         Material::Diffuse { albedo }
@@ -41,13 +212,388 @@ impl Material {
             albedo,
             r_index_ratio: 1. / r_index,
             fuzz,
+            dispersion: None,
+        }
+    }
+
+    /// Glass whose index of refraction changes with wavelength. Outside of spectral rendering it
+    /// refracts like the dispersion's index at the middle of the visible spectrum.
+    pub fn new_dispersive(albedo: PixelF, dispersion: Dispersion, fuzz: f32) -> Self {
+        Material::Dielectric {
+            albedo,
+            r_index_ratio: 1. / dispersion.ior(D_LINE),
+            fuzz,
+            dispersion: Some(dispersion),
+        }
+    }
+
+    /// A clear coat over a base. `absorption` is how much of each colour the coat soaks up per unit
+    /// travelled through it, so black makes for a perfectly clear coat.
+    pub fn new_coated(base: Material, ior: f32, thickness: f32, absorption: PixelF) -> Self {
+        Material::Coated {
+            base: Box::new(base),
+            ior,
+            thickness,
+            absorption,
+            film: None,
+        }
+    }
+
+    /// Put a thin film over a coated material's coat. Anything else is left as it is.
+    pub fn with_film(self, film: ThinFilm) -> Self {
+        match self {
+            Material::Coated {
+                base,
+                ior,
+                thickness,
+                absorption,
+                ..
+            } => Material::Coated {
+                base,
+                ior,
+                thickness,
+                absorption,
+                film: Some(film),
+            },
+            other => other,
+        }
+    }
+
+    /// A soap bubble, or any other thin film floating in the air.
+    pub fn new_iridescent(film: ThinFilm) -> Self {
+        Material::Iridescent { film }
+    }
+
+    pub fn new_subsurface(albedo: PixelF, mean_free_path: PixelF, anisotropy: f32) -> Self {
+        Material::Subsurface {
+            albedo,
+            mean_free_path,
+            anisotropy: anisotropy.clamp(-0.999, 0.999),
+        }
+    }
+
+    pub fn new_volumetric(albedo: PixelF, anisotropy: f32) -> Self {
+        Material::Volumetric { albedo, anisotropy }
+    }
+
+    pub fn new_emissive(emission: PixelF) -> Self {
+        Material::Emissive { emission }
+    }
+
+    pub fn new_holdout() -> Self {
+        Material::Holdout
+    }
+
+    pub fn new_shadow_catcher(albedo: PixelF) -> Self {
+        Material::ShadowCatcher { albedo }
+    }
+
+    /// The closest we can get to a physically based metallic-roughness surface, like the ones in
+    /// glTF files. Metals become fuzzy mirrors tinted by the base color, shiny non-metals become a
+    /// clear coat over a matte base, and rough non-metals are just matte.
+    pub fn new_metallic_roughness(base_color: PixelF, metallic: f32, roughness: f32) -> Self {
+        let base_color = base_color.with_alpha(1.);
+        if metallic >= 0.5 {
+            Material::new_specular(base_color, roughness * roughness)
+        } else if roughness < 0.4 {
+            Material::new_coated(Material::new_diffuse(base_color), 1.5, 0., PixelF::black())
+        } else {
+            Material::new_diffuse(base_color)
+        }
+    }
+
+    /// The base color of this material, for render passes.
+    pub fn albedo(&self) -> PixelF {
+        match self {
+            Material::Diffuse { albedo }
+            | Material::ShadowCatcher { albedo }
+            | Material::Specular { albedo, .. }
+            | Material::Dielectric { albedo, .. }
+            | Material::Subsurface { albedo, .. }
+            | Material::Volumetric { albedo, .. } => *albedo,
+            Material::Coated { base, .. } => base.albedo(),
+            Material::Iridescent { .. } => PixelF::white(),
+            Material::Emissive { emission } => *emission,
+            Material::Holdout => PixelF::black(),
+        }
+    }
+
+    /// The light this material gives off, if it's a light at all.
+    pub fn emission(&self) -> Option<PixelF> {
+        match self {
+            Material::Emissive { emission } => Some(*emission),
+            _ => None,
+        }
+    }
+
+    /// The light given off from the side of the surface we hit.
+    pub fn emitted(&self, front_facing: bool) -> PixelF {
+        match self {
+            Material::Emissive { emission } if front_facing => *emission,
+            _ => PixelF::black(),
+        }
+    }
+
+    /// For materials which scatter light all over the place, work out how much of the light arriving
+    /// from `dir_out` gets sent back along `dir_in`, cosine included, along with the chance `scatter`
+    /// would have picked `dir_out` (per unit solid angle). Dividing the first by the second gives what
+    /// `scatter` hands back as the color.
+    ///
+    /// Mirrors and glass only ever send light off in one direction, which we'd never hit by choosing
+    /// directions some other way, so they give None.
+    pub fn evaluate(&self, dir_in: V3, dir_out: V3, normal: V3) -> Option<(PixelF, f32)> {
+        let f = self.bsdf(dir_in, dir_out, normal)?;
+        let cos_theta = if self.is_volumetric() {
+            1.
+        } else {
+            // The bsdf is zero on whichever side light doesn't go, so this only matters for
+            // materials which let light through.
+            normal.dot(&dir_out.normalized()).abs()
+        };
+        Some((
+            f.attenuate(PixelF::rgb(cos_theta, cos_theta, cos_theta)),
+            self.scatter_pdf(dir_in, dir_out, normal),
+        ))
+    }
+
+    /// Like evaluate, but without the cosine: just the ratio of light leaving along `dir_out` to light
+    /// arriving along `dir_in`. None for mirrors and glass.
+    pub fn bsdf(&self, dir_in: V3, dir_out: V3, normal: V3) -> Option<PixelF> {
+        match self {
+            Material::Diffuse { albedo } | Material::ShadowCatcher { albedo } => {
+                // Light has to arrive and leave on the side the normal's facing.
+                if normal.dot(&dir_out) <= 0. || normal.dot(&dir_in) >= 0. {
+                    return Some(PixelF::black());
+                }
+                Some(albedo.attenuate(PixelF::rgb(1. / PI, 1. / PI, 1. / PI)))
+            }
+            Material::Subsurface { .. } => {
+                // The surface passes light through to the other side, evenly in every direction.
+                // The colour comes from what happens inside.
+                if normal.dot(&dir_out) * normal.dot(&dir_in) <= 0. {
+                    return Some(PixelF::black());
+                }
+                Some(PixelF::rgb(1. / PI, 1. / PI, 1. / PI))
+            }
+            Material::Coated {
+                base,
+                ior,
+                thickness,
+                absorption,
+                film,
+            } => Self::coated_bsdf(
+                base,
+                *ior,
+                *thickness,
+                *absorption,
+                *film,
+                dir_in,
+                dir_out,
+                normal,
+            ),
+            Material::Volumetric { albedo, .. } => {
+                let p = self.scatter_pdf(dir_in, dir_out, normal);
+                Some(albedo.attenuate(PixelF::rgb(p, p, p)))
+            }
+            _ => None,
+        }
+    }
+
+    /// The chance, per unit solid angle, that `scatter` sends light arriving along `dir_in` off along
+    /// `dir_out`. Zero for mirrors and glass, since they never pick a direction at random.
+    pub fn scatter_pdf(&self, dir_in: V3, dir_out: V3, normal: V3) -> f32 {
+        match self {
+            Material::Diffuse { .. } | Material::ShadowCatcher { .. } => {
+                normal.dot(&dir_out.normalized()).max(0.) / PI
+            }
+            Material::Subsurface { .. } => {
+                if normal.dot(&dir_out) * normal.dot(&dir_in) <= 0. {
+                    return 0.;
+                }
+                normal.dot(&dir_out.normalized()).abs() / PI
+            }
+            Material::Coated {
+                base, ior, film, ..
+            } => {
+                // Light has to get through the coat before the base sends it off anywhere. Where it
+                // comes back out is close enough to how the base scatters it.
+                let cos_in = -normal.dot(&dir_in.normalized());
+                if cos_in <= 0. {
+                    return 0.;
+                }
+                let r = Self::reflectance(*film, cos_in, 1., *ior, None);
+                (1. - (r.r + r.g + r.b) / 3.) * base.scatter_pdf(dir_in, dir_out, normal)
+            }
+            Material::Volumetric { anisotropy, .. } => {
+                let cos_theta = dir_in.normalized().dot(&dir_out.normalized());
+                henyey_greenstein(cos_theta, *anisotropy)
+            }
+            _ => 0.,
+        }
+    }
+
+    /// Whether `scatter` sent light arriving along `dir_in` off along `dir_out` the way a mirror or
+    /// glass would, rather than picking a direction at random. Coated materials over a rough base do
+    /// either, depending on whether the light glanced off of the coat, and this tells the two apart.
+    pub fn scattered_specularly(&self, dir_in: V3, dir_out: V3, normal: V3) -> bool {
+        match self {
+            Material::Coated { base, .. } => {
+                let dir_in = dir_in.normalized();
+                let mirror = dir_in - normal * (2. * dir_in.dot(&normal));
+                base.bsdf(dir_in, dir_out, normal).is_none()
+                    || mirror.dot(&dir_out.normalized()) > 1. - 1e-5
+            }
+            _ => self.bsdf(dir_in, dir_out, normal).is_none(),
         }
     }
 
+    /// Whether this is a participating medium rather than a surface, so has no normal to speak of.
+    pub fn is_volumetric(&self) -> bool {
+        matches!(self, Material::Volumetric { .. })
+    }
+
+    /// Whether the camera sees straight through this, to whatever gets composited behind the image.
+    pub fn is_holdout(&self) -> bool {
+        matches!(self, Material::Holdout)
+    }
+
+    /// Whether this is an invisible floor that only shows the shadows and light falling on it.
+    pub fn is_shadow_catcher(&self) -> bool {
+        matches!(self, Material::ShadowCatcher { .. })
+    }
+
+    /// Whether this is only there for compositing, so the camera has to treat it specially.
+    pub fn is_matte(&self) -> bool {
+        self.is_holdout() || self.is_shadow_catcher()
+    }
+
+    /// Whether this splits light up by wavelength.
+    pub fn is_dispersive(&self) -> bool {
+        match self {
+            Material::Dielectric {
+                dispersion: Some(_),
+                ..
+            }
+            | Material::Coated { film: Some(_), .. }
+            | Material::Iridescent { .. } => true,
+            Material::Coated { base, .. } => base.is_dispersive(),
+            _ => false,
+        }
+    }
+
+    /// Like scatter, but for light of a single wavelength, in nanometres, so dispersive glass can
+    /// bend it by exactly the right amount.
+    pub fn scatter_wavelength(
+        &self,
+        ray_in: &Ray,
+        point: V3,
+        normal: V3,
+        front_facing: bool,
+        wavelength: f32,
+    ) -> (Ray, PixelF) {
+        match *self {
+            Material::Dielectric {
+                albedo,
+                fuzz,
+                dispersion: Some(dispersion),
+                ..
+            } => Material::Dielectric {
+                albedo,
+                r_index_ratio: 1. / dispersion.ior(wavelength),
+                fuzz,
+                dispersion: None,
+            }
+            .scatter(ray_in, point, normal, front_facing),
+            Material::Coated {
+                ref base,
+                ior,
+                thickness,
+                absorption,
+                film,
+            } => Self::scatter_coated(
+                base,
+                ior,
+                thickness,
+                absorption,
+                film,
+                ray_in,
+                point,
+                normal,
+                front_facing,
+                Some(wavelength),
+            ),
+            Material::Iridescent { film } => {
+                Self::scatter_film(film, ray_in, point, normal, Some(wavelength))
+            }
+            _ => self.scatter(ray_in, point, normal, front_facing),
+        }
+    }
+
+    /// How much light survives travelling some distance through the inside of this material.
+    /// This follows the Beer-Lambert law, so it falls off exponentially with distance.
+    ///
+    /// For translucent materials, this is how much survives without scattering, divided by the
+    /// chance sample_interior let it get that far.
+    pub fn transmittance(&self, distance: f32) -> PixelF {
+        match self {
+            Material::Dielectric { albedo, .. } => albedo.powf(distance),
+            Material::Subsurface { mean_free_path, .. } => {
+                let survived = Self::extinction(*mean_free_path).map(|e| (-e * distance).exp());
+                let chance = survived.iter().sum::<f32>() / 3.;
+                if chance <= 0. {
+                    return PixelF::black();
+                }
+                scaled(PixelF::rgb(survived[0], survived[1], survived[2]), 1. / chance)
+            }
+            _ => PixelF::white(),
+        }
+    }
+
+    /// For translucent materials, maybe pick somewhere for light travelling `distance` through the
+    /// inside to scatter before it gets to the other side. Gives back how far it got, and the
+    /// material to scatter it with there.
+    ///
+    /// Each colour gets a different distance between scatterings, so we pick a colour at random to
+    /// choose a distance with, and weight the colours by how likely each would have been to stop
+    /// there, against how likely we were to.
+    pub fn sample_interior(&self, distance: f32) -> Option<(f32, Material)> {
+        let Material::Subsurface {
+            albedo,
+            mean_free_path,
+            anisotropy,
+            ..
+        } = *self
+        else {
+            return None;
+        };
+        let extinction = Self::extinction(mean_free_path);
+        let mut rand = rng();
+        let travelled = -f32::ln(1. - rand.gen::<f32>()) / extinction[rand.gen_range(0..3)];
+        if travelled >= distance {
+            return None;
+        }
+        let density = extinction.map(|e| e * (-e * travelled).exp());
+        let chance = density.iter().sum::<f32>() / 3.;
+        let weight = scaled(PixelF::rgb(density[0], density[1], density[2]), 1. / chance);
+        Some((
+            travelled,
+            Material::Volumetric {
+                albedo: albedo.attenuate(weight),
+                anisotropy,
+            },
+        ))
+    }
+
+    /// How often each colour scatters per unit travelled, from how far it gets between scatterings.
+    fn extinction(mean_free_path: PixelF) -> [f32; 3] {
+        [mean_free_path.r, mean_free_path.g, mean_free_path.b].map(|d| 1. / d.max(1e-6))
+    }
+
     ///returns (reflection, albedo)
-    pub fn scatter(&self, ray_in: &Ray, point: V3, normal: V3) -> (Ray, PixelF) {
+    /// `normal` faces back against the incoming ray, and `front_facing` says whether we hit the outside.
+    pub fn scatter(&self, ray_in: &Ray, point: V3, normal: V3, front_facing: bool) -> (Ray, PixelF) {
         match self {
-            Material::Diffuse { albedo } => {
+            Material::Diffuse { albedo } | Material::ShadowCatcher { albedo } => {
                 let mut scatter_direction = normal + V3::random_on_unit_sphere();
                 //correct some wierdness that might happen when our random offset ~= -normal
                 if scatter_direction.near_zero() {
@@ -62,25 +608,278 @@ impl Material {
                 (Ray::new(point, reflect_direction), *albedo)
             }
             Material::Dielectric {
-                albedo,
                 r_index_ratio,
                 fuzz,
+                ..
             } => {
-                let cos_theta = (ray_in.dir * -1.).dot(&normal);
+                // On the way back out, we go from the material's index back to air.
+                let r_index_ratio = if front_facing {
+                    *r_index_ratio
+                } else {
+                    1. / r_index_ratio
+                };
+                // Scattered rays aren't always unit length, and the angles below rely on that.
+                let dir_in = ray_in.dir.normalized();
+                let cos_theta = (dir_in * -1.).dot(&normal).min(1.);
                 let sin_theta = f32::sqrt(1. - (cos_theta * cos_theta));
 
                 let dir = if sin_theta * r_index_ratio > 1.
-                    || thread_rng().gen::<f32>() < Self::schlick(cos_theta, *r_index_ratio)
+                    || rng().gen::<f32>() < Self::schlick(cos_theta, r_index_ratio)
                 {
                     // Reflect
-                    Self::reflect(ray_in.dir, normal, *fuzz)
+                    Self::reflect(dir_in, normal, *fuzz)
                 } else {
                     // Refract
-                    Self::refract(ray_in.dir, normal, cos_theta, *r_index_ratio)
+                    Self::refract(dir_in, normal, cos_theta, r_index_ratio)
                 };
 
-                (Ray::new(point, dir), *albedo)
+                // Absorption is handled along the path through the material, see transmittance.
+                (Ray::new(point, dir), PixelF::white())
+            }
+            Material::Coated {
+                base,
+                ior,
+                thickness,
+                absorption,
+                film,
+            } => Self::scatter_coated(
+                base,
+                *ior,
+                *thickness,
+                *absorption,
+                *film,
+                ray_in,
+                point,
+                normal,
+                front_facing,
+                None,
+            ),
+            Material::Iridescent { film } => Self::scatter_film(*film, ray_in, point, normal, None),
+            Material::Subsurface { .. } => {
+                // Go through the surface, rather than bouncing off of it like Diffuse.
+                let mut scatter_direction = normal * -1. + V3::random_on_unit_sphere();
+                if scatter_direction.near_zero() {
+                    scatter_direction = normal * -1.;
+                }
+
+                (Ray::new(point, scatter_direction), PixelF::white())
+            }
+            Material::Volumetric { albedo, anisotropy } => {
+                let scatter_direction = sample_henyey_greenstein(ray_in.dir, *anisotropy);
+
+                (Ray::new(point, scatter_direction), *albedo)
+            }
+            // Lights and holdouts absorb everything that lands on them.
+            Material::Emissive { .. } | Material::Holdout => {
+                (Ray::new(point, normal), PixelF::black())
+            }
+        }
+    }
+
+    /// Scatter off of a coated material, for light of a single wavelength or all of them at once.
+    /// The coat is thin enough that we treat it as having no thickness as far as where light goes,
+    /// and only use its thickness for how much it absorbs.
+    #[allow(clippy::too_many_arguments)]
+    fn scatter_coated(
+        base: &Material,
+        ior: f32,
+        thickness: f32,
+        absorption: PixelF,
+        film: Option<ThinFilm>,
+        ray_in: &Ray,
+        point: V3,
+        normal: V3,
+        front_facing: bool,
+        wavelength: Option<f32>,
+    ) -> (Ray, PixelF) {
+        let mut rand = rng();
+        let dir_in = ray_in.dir.normalized();
+        let cos_theta = (dir_in * -1.).dot(&normal).clamp(0., 1.);
+        let (reflected, mut color) = Self::choose(
+            Self::reflectance(film, cos_theta, 1., ior, wavelength),
+            &mut rand,
+        );
+        if reflected {
+            return (Ray::new(point, Self::reflect(dir_in, normal, 0.)), color);
+        }
+
+        let through_coat = |cos_theta: f32| {
+            let distance = thickness / cos_theta.max(1e-3);
+            PixelF::rgb(
+                (-absorption.r * distance).exp(),
+                (-absorption.g * distance).exp(),
+                (-absorption.b * distance).exp(),
+            )
+        };
+        let mut dir = Self::refract(dir_in, normal, cos_theta, 1. / ior);
+        // Light can bounce between the base and the underside of the coat a few times before it
+        // gets out.
+        for _ in 0..COAT_BOUNCES {
+            color = color.attenuate(through_coat((dir * -1.).dot(&normal)));
+            let ray = Ray::new(point, dir);
+            let (ray, base_color) = match wavelength {
+                Some(wavelength) => {
+                    base.scatter_wavelength(&ray, point, normal, front_facing, wavelength)
+                }
+                None => base.scatter(&ray, point, normal, front_facing),
+            };
+            color = color.attenuate(base_color);
+            dir = ray.dir.normalized();
+            let cos_theta = dir.dot(&normal);
+            // The base let the light through, so it carries on into the surface.
+            if cos_theta <= 0. {
+                return (Ray::new(point, dir), color);
+            }
+            color = color.attenuate(through_coat(cos_theta));
+
+            let (reflected, weight) = Self::choose(
+                Self::reflectance(film, cos_theta, ior, 1., wavelength),
+                &mut rand,
+            );
+            color = color.attenuate(weight);
+            if !reflected {
+                let dir_out = Self::refract(dir, normal * -1., cos_theta, ior);
+                return (Ray::new(point, dir_out), color);
+            }
+            dir = Self::reflect(dir, normal * -1., 0.);
+        }
+        // Whatever's still stuck in the coat is absorbed.
+        (Ray::new(point, normal), PixelF::black())
+    }
+
+    /// The light which goes through a coat, off of the base underneath, and back out again, leaving
+    /// out whatever glances straight off of the coat. The base's own bsdf is used for the directions
+    /// light travels in inside the coat, so this is None whenever the base's is. Light bouncing back
+    /// and forth between the base and the underside of the coat is added up as if the base sent its
+    /// albedo's worth off evenly in every direction each time, and the thin film is left out of
+    /// those bounces, since they'd take far too long to add up exactly.
+    #[allow(clippy::too_many_arguments)]
+    fn coated_bsdf(
+        base: &Material,
+        ior: f32,
+        thickness: f32,
+        absorption: PixelF,
+        film: Option<ThinFilm>,
+        dir_in: V3,
+        dir_out: V3,
+        normal: V3,
+    ) -> Option<PixelF> {
+        let (dir_in, dir_out) = (dir_in.normalized(), dir_out.normalized());
+        let cos_in = -normal.dot(&dir_in);
+        let cos_out = normal.dot(&dir_out);
+        if cos_in <= 0. {
+            return base.bsdf(dir_in, dir_out, normal).map(|_| PixelF::black());
+        }
+        // The cosine of the angle light travels at inside the coat, from Snell's law.
+        let inside = |cos: f32| (1. - (1. - cos * cos) / (ior * ior)).max(0.).sqrt();
+        let through_coat = |cos_inside: f32| {
+            let distance = thickness / cos_inside.max(1e-3);
+            [absorption.r, absorption.g, absorption.b].map(|a| (-a * distance).exp())
+        };
+        let transmitted = |cos: f32| {
+            let r = Self::reflectance(film, cos, 1., ior, None);
+            [1. - r.r, 1. - r.g, 1. - r.b]
+        };
+
+        // How much of the light the base sends up comes back down to it, averaged over every
+        // direction the base sends it in. Light any further from the normal than the critical angle
+        // can't get out at all, so we add that part up separately.
+        const STEPS: usize = 8;
+        let critical = if ior > 1. {
+            (1. - 1. / (ior * ior)).sqrt()
+        } else {
+            0.
+        };
+        let mut bounced_back = [0.; 3];
+        for (from, to) in [(0., critical), (critical, 1.)] {
+            for i in 0..STEPS {
+                let cos = from + (to - from) * (i as f32 + 0.5) / STEPS as f32;
+                let r = Self::reflectance(None, cos, ior, 1., None).r;
+                let t = through_coat(cos);
+                for c in 0..3 {
+                    bounced_back[c] += t[c] * t[c] * r * 2. * cos * (to - from) / STEPS as f32;
+                }
+            }
+        }
+
+        let inside_in = Self::refract(dir_in, normal, cos_in, 1. / ior);
+        let (t_in, f_in) = (through_coat(inside(cos_in)), transmitted(cos_in));
+        if cos_out <= 0. {
+            // Whatever the base lets through goes on into the surface without crossing the coat
+            // again.
+            let f = base.bsdf(inside_in, dir_out, normal)?;
+            let f = [f.r, f.g, f.b];
+            let f = |c: usize| f_in[c] * t_in[c] * f[c];
+            return Some(PixelF::rgb(f(0), f(1), f(2)));
+        }
+        let inside_out = Self::refract(dir_out * -1., normal, cos_out, 1. / ior) * -1.;
+        let f = base.bsdf(inside_in, inside_out, normal)?;
+        let (t_out, f_out) = (through_coat(inside(cos_out)), transmitted(cos_out));
+        let (f, albedo) = ([f.r, f.g, f.b], base.albedo());
+        let albedo = [albedo.r, albedo.g, albedo.b];
+        // Light coming out of the coat spreads out over a wider angle than it had inside, hence ior².
+        let f = |c: usize| {
+            f_in[c] * t_in[c] * f[c] * t_out[c] * f_out[c]
+                / (ior * ior * (1. - albedo[c] * bounced_back[c]))
+        };
+        Some(PixelF::rgb(f(0), f(1), f(2)))
+    }
+
+    /// Scatter off of a thin film floating in the air, for light of a single wavelength or all of
+    /// them at once.
+    fn scatter_film(
+        film: ThinFilm,
+        ray_in: &Ray,
+        point: V3,
+        normal: V3,
+        wavelength: Option<f32>,
+    ) -> (Ray, PixelF) {
+        let dir_in = ray_in.dir.normalized();
+        let cos_theta = (dir_in * -1.).dot(&normal).clamp(0., 1.);
+        let (reflected, color) = Self::choose(
+            Self::reflectance(Some(film), cos_theta, 1., 1., wavelength),
+            &mut rng(),
+        );
+        let dir = if reflected {
+            Self::reflect(dir_in, normal, 0.)
+        } else {
+            dir_in
+        };
+        (Ray::new(point, dir), color)
+    }
+
+    /// How much light reflects off of the boundary between two indices of refraction, with a thin
+    /// film on it or not, for one wavelength or all of them.
+    fn reflectance(
+        film: Option<ThinFilm>,
+        cos_theta: f32,
+        outside: f32,
+        below: f32,
+        wavelength: Option<f32>,
+    ) -> PixelF {
+        let grey = |r: f32| PixelF::rgb(r, r, r);
+        match (film, wavelength) {
+            (Some(film), None) => film.reflectance_rgb(cos_theta, outside, below),
+            (Some(film), Some(wavelength)) => {
+                grey(film.reflectance(cos_theta, outside, below, wavelength))
             }
+            // A film with no thickness is the same as no film at all.
+            (None, _) => {
+                grey(ThinFilm::new(0., outside).reflectance(cos_theta, outside, below, 1.))
+            }
+        }
+    }
+
+    /// Pick between reflecting and transmitting, in proportion to how much light does each. Returns
+    /// whether we reflected, and what to weight the light by to make up for the choice.
+    fn choose(reflectance: PixelF, rand: &mut impl Rng) -> (bool, PixelF) {
+        let chance = (reflectance.r + reflectance.g + reflectance.b) / 3.;
+        if chance >= 1. || (chance > 0. && rand.gen::<f32>() < chance) {
+            (true, scaled(reflectance, 1. / chance))
+        } else {
+            let transmitted =
+                PixelF::rgb(1. - reflectance.r, 1. - reflectance.g, 1. - reflectance.b);
+            (false, scaled(transmitted, 1. / (1. - chance)))
         }
     }
 
@@ -102,3 +901,152 @@ impl Material {
         lerp(1., (1. - cos_theta).powi(5), t)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Add up how much light a coat over a matte base scatters into directions within some angle of
+    /// the normal, once by scattering lots of rays and once by integrating its bsdf.
+    fn coat_scattered_within(material: &Material, dir_in: V3, min_cos: f32) -> (PixelF, PixelF) {
+        let normal = V3::y();
+        let ray = Ray::new(V3::zero(), dir_in);
+        let samples = 100_000;
+        let mut scattered = [0.; 3];
+        for _ in 0..samples {
+            let (ray_out, color) = material.scatter(&ray, V3::zero(), normal, true);
+            let dir_out = ray_out.dir.normalized();
+            if dir_out.dot(&normal) >= min_cos
+                && !material.scattered_specularly(dir_in, dir_out, normal)
+            {
+                scattered[0] += color.r / samples as f32;
+                scattered[1] += color.g / samples as f32;
+                scattered[2] += color.b / samples as f32;
+            }
+        }
+
+        // The integral over directions, in rings of equal cosine.
+        let (rings, segments) = (200, 64);
+        let mut integrated = [0.; 3];
+        for i in 0..rings {
+            let cos = min_cos + (1. - min_cos) * (i as f32 + 0.5) / rings as f32;
+            let sin = (1. - cos * cos).sqrt();
+            for j in 0..segments {
+                let phi = 2. * PI * (j as f32 + 0.5) / segments as f32;
+                let dir_out = V3::new(sin * phi.cos(), cos, sin * phi.sin());
+                let (value, _) = material.evaluate(dir_in, dir_out, normal).unwrap();
+                let solid_angle = 2. * PI * (1. - min_cos) / (rings * segments) as f32;
+                integrated[0] += value.r * solid_angle;
+                integrated[1] += value.g * solid_angle;
+                integrated[2] += value.b * solid_angle;
+            }
+        }
+        let rgb = |c: [f32; 3]| PixelF::rgb(c[0], c[1], c[2]);
+        (rgb(scattered), rgb(integrated))
+    }
+
+    #[test]
+    fn coated_bsdf_matches_what_scatter_does() {
+        let material = Material::new_coated(
+            Material::new_diffuse(PixelF::rgb(0.9, 0.5, 0.2)),
+            1.5,
+            0.2,
+            PixelF::rgb(0.1, 0.5, 1.),
+        );
+        for dir_in in [V3::new(0., -1., 0.), V3::new(0.8, -0.5, 0.)] {
+            for min_cos in [0., 0.7] {
+                let (scattered, integrated) =
+                    coat_scattered_within(&material, dir_in.normalized(), min_cos);
+                for (s, i) in [
+                    (scattered.r, integrated.r),
+                    (scattered.g, integrated.g),
+                    (scattered.b, integrated.b),
+                ] {
+                    assert!((s - i).abs() < 0.02 * i.max(0.05), "{s} vs {i}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn only_the_coat_reflection_counts_as_specular() {
+        let material = Material::new_coated(
+            Material::new_diffuse(PixelF::white()),
+            1.5,
+            0.,
+            PixelF::black(),
+        );
+        let (normal, dir_in) = (V3::y(), V3::new(1., -1., 0.));
+        assert!(material.scattered_specularly(dir_in, V3::new(2., 2., 0.), normal));
+        assert!(!material.scattered_specularly(dir_in, V3::new(-1., 1., 0.), normal));
+        assert!(material.bsdf(dir_in, V3::new(-1., 1., 0.), normal).is_some());
+        assert!(material.scatter_pdf(dir_in, V3::new(-1., 1., 0.), normal) > 0.);
+
+        let metallic = Material::new_coated(
+            Material::new_specular(PixelF::white(), 0.1),
+            1.5,
+            0.,
+            PixelF::black(),
+        );
+        assert!(metallic.bsdf(dir_in, V3::new(-1., 1., 0.), normal).is_none());
+        assert!(metallic.scattered_specularly(dir_in, V3::new(-1., 1., 0.), normal));
+    }
+
+    #[test]
+    fn a_coat_with_nothing_to_it_leaves_the_base_as_it_is() {
+        let bases = [
+            Material::new_diffuse(PixelF::rgb(0.9, 0.5, 0.2)),
+            Material::new_subsurface(PixelF::white(), PixelF::rgb(1., 1., 1.), 0.),
+            Material::new_coated(
+                Material::new_diffuse(PixelF::rgb(0.2, 0.4, 0.6)),
+                1.5,
+                0.1,
+                PixelF::rgb(0.5, 0.5, 0.5),
+            ),
+            Material::new_specular(PixelF::white(), 0.1),
+        ];
+        let normal = V3::y();
+        let dir_in = V3::new(0.6, -0.8, 0.);
+        for base in bases {
+            let coated = Material::new_coated(base.clone(), 1., 0., PixelF::black());
+            for dir_out in [V3::new(0., 1., 0.), V3::new(-0.6, 0.8, 0.), V3::new(0.3, -0.9, 0.4)] {
+                let dir_out = dir_out.normalized();
+                match (base.bsdf(dir_in, dir_out, normal), coated.bsdf(dir_in, dir_out, normal)) {
+                    (Some(b), Some(c)) => {
+                        for (b, c) in [(b.r, c.r), (b.g, c.g), (b.b, c.b)] {
+                            assert!((b - c).abs() < 1e-4, "{base:?}: {b} vs {c}");
+                        }
+                    }
+                    (None, None) => {
+                        assert!(coated.scattered_specularly(dir_in, dir_out, normal));
+                    }
+                    other => panic!("{base:?}: {other:?}"),
+                }
+                let pdf = base.scatter_pdf(dir_in, dir_out, normal);
+                assert!((pdf - coated.scatter_pdf(dir_in, dir_out, normal)).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn a_coat_over_glass_lets_light_through() {
+        let glass = Material::new_dielectric(PixelF::white(), 1.5, 0.);
+        let coated = Material::new_coated(glass, 1.5, 0., PixelF::black());
+        let (normal, dir_in) = (V3::y(), V3::new(0.6, -0.8, 0.));
+        assert!(coated.bsdf(dir_in, V3::new(0., -1., 0.), normal).is_none());
+
+        let ray = Ray::new(V3::zero(), dir_in);
+        let (mut through, mut back) = (0, 0);
+        for _ in 0..1000 {
+            let (ray_out, color) = coated.scatter(&ray, V3::zero(), normal, true);
+            assert!(color.r > 0.);
+            if ray_out.dir.dot(&normal) < 0. {
+                through += 1;
+            } else {
+                back += 1;
+            }
+        }
+        // Glass lets most light through, and the coat and the glass each reflect some of it.
+        assert!(through > 700 && back > 20, "{through} through, {back} back");
+    }
+}
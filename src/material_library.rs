@@ -0,0 +1,217 @@
+use std::ops::Index;
+
+use crate::image_handling::PixelF;
+use crate::material::Material;
+use crate::texture::Texture;
+
+use serde::{Deserialize, Serialize};
+
+// Primitives don't carry their materials around with them. Instead, every scene has a table of
+// materials, and each sphere or triangle just says which entry in the table it's made of. A mesh
+// with a million triangles then only needs the one copy of its material, and changing an entry
+// changes everything made of it at once, without having to touch the primitives at all.
+//
+// Entries can also be given names, so scene files can refer to "gold" rather than to whatever
+// position gold happens to be at in the table.
+//
+// Materials get copied out to every hit, so they can't hold textures. Entries can, though. An
+// entry made from a metallic-roughness surface, like the ones glTF files describe, works out its
+// material afresh wherever a ray hits, going by the textures at that spot.
+
+/// Which entry in a MaterialLibrary a primitive is made of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MaterialId(u32);
+
+impl MaterialId {
+    /// Where this material sits in its library.
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// A surface described the way most asset pipelines describe them: a base color, how metallic it
+/// is and how rough it is, each of which can vary over the surface with a texture.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MetallicRoughness {
+    pub base_color: PixelF,
+    pub metallic: f32,
+    pub roughness: f32,
+    /// Multiplied by `base_color`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_color_texture: Option<Texture>,
+    /// Roughness in the green channel and metallic in the blue one, multiplied by `roughness`
+    /// and `metallic`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metallic_roughness_texture: Option<Texture>,
+}
+
+impl MetallicRoughness {
+    pub fn new(base_color: PixelF, metallic: f32, roughness: f32) -> Self {
+        MetallicRoughness {
+            base_color,
+            metallic,
+            roughness,
+            base_color_texture: None,
+            metallic_roughness_texture: None,
+        }
+    }
+
+    /// The material the surface is made of, ignoring its textures.
+    pub fn material(&self) -> Material {
+        Material::new_metallic_roughness(self.base_color, self.metallic, self.roughness)
+    }
+
+    /// The material the surface is made of at some texture coordinates.
+    pub fn material_at(&self, uv: (f32, f32)) -> Material {
+        let base_color = match &self.base_color_texture {
+            Some(texture) => self.base_color.attenuate(texture.sample(uv)),
+            None => self.base_color,
+        };
+        let (metallic, roughness) = match &self.metallic_roughness_texture {
+            Some(texture) => {
+                let texel = texture.sample(uv);
+                (self.metallic * texel.b, self.roughness * texel.g)
+            }
+            None => (self.metallic, self.roughness),
+        };
+        Material::new_metallic_roughness(base_color, metallic, roughness)
+    }
+
+    fn is_textured(&self) -> bool {
+        self.base_color_texture.is_some() || self.metallic_roughness_texture.is_some()
+    }
+}
+
+/// A material in a library, and the name it can be looked up by, if it has one. Entries made from
+/// textured surfaces keep the surface around, so hits can look up their textures.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct LibraryEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    material: Material,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    surface: Option<MetallicRoughness>,
+}
+
+/// The table of materials which the primitives in a scene are made of.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MaterialLibrary {
+    entries: Vec<LibraryEntry>,
+}
+
+impl MaterialLibrary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a material to the library, without a name.
+    pub fn add(&mut self, material: Material) -> MaterialId {
+        self.push(None, material)
+    }
+
+    /// Add a material to the library under a name. Names have to be unique.
+    pub fn add_named(&mut self, name: &str, material: Material) -> Result<MaterialId, String> {
+        if self.find(name).is_some() {
+            return Err(format!("there's already a material called '{}'", name));
+        }
+        Ok(self.push(Some(name.to_owned()), material))
+    }
+
+    /// Add a metallic-roughness surface to the library, under a name if it has one. Untextured
+    /// surfaces just become their material.
+    pub fn add_metallic_roughness(
+        &mut self,
+        name: Option<&str>,
+        surface: MetallicRoughness,
+    ) -> Result<MaterialId, String> {
+        let id = match name {
+            Some(name) => self.add_named(name, surface.material())?,
+            None => self.add(surface.material()),
+        };
+        if surface.is_textured() {
+            self.entries[id.index()].surface = Some(surface);
+        }
+        Ok(id)
+    }
+
+    fn push(&mut self, name: Option<String>, material: Material) -> MaterialId {
+        self.entries.push(LibraryEntry {
+            name,
+            material,
+            surface: None,
+        });
+        MaterialId(self.entries.len() as u32 - 1)
+    }
+
+    /// Look up a material by its id.
+    pub fn get(&self, id: MaterialId) -> Option<Material> {
+        self.entries.get(id.index()).map(|entry| entry.material.clone())
+    }
+
+    /// The material at some texture coordinates on a primitive made of some entry. Only textured
+    /// entries change from one spot to the next.
+    pub fn at(&self, id: MaterialId, uv: (f32, f32)) -> Material {
+        let entry = &self.entries[id.index()];
+        match &entry.surface {
+            Some(surface) => surface.material_at(uv),
+            None => entry.material.clone(),
+        }
+    }
+
+    /// Swap out the material behind an id, changing everything made of it. Any textures the entry
+    /// had go with it.
+    pub fn set(&mut self, id: MaterialId, material: Material) -> Result<(), String> {
+        match self.entries.get_mut(id.index()) {
+            Some(entry) => {
+                entry.material = material;
+                entry.surface = None;
+                Ok(())
+            }
+            None => Err(format!("there's no material number {}", id.index())),
+        }
+    }
+
+    /// Find the id of the material with some name.
+    pub fn find(&self, name: &str) -> Option<MaterialId> {
+        self.entries
+            .iter()
+            .position(|entry| entry.name.as_deref() == Some(name))
+            .map(|index| MaterialId(index as u32))
+    }
+
+    /// The id of the material at some position in the table, if there's one there.
+    pub fn id(&self, index: usize) -> Option<MaterialId> {
+        (index < self.entries.len()).then_some(MaterialId(index as u32))
+    }
+
+    /// The name of a material, if it was given one.
+    pub fn name(&self, id: MaterialId) -> Option<&str> {
+        self.entries.get(id.index())?.name.as_deref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Every material in the library, alongside its id.
+    pub fn iter(&self) -> impl Iterator<Item = (MaterialId, &Material)> + '_ {
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| (MaterialId(index as u32), &entry.material))
+    }
+}
+
+impl Index<MaterialId> for MaterialLibrary {
+    type Output = Material;
+
+    fn index(&self, id: MaterialId) -> &Material {
+        &self.entries[id.index()].material
+    }
+}
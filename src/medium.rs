@@ -0,0 +1,433 @@
+use rand::Rng;
+
+use crate::bounded_volume_hierarchy::Bounds;
+use crate::image_handling::PixelF;
+use crate::material::Material;
+use crate::ray::{Ray, RAY_MAX, RAY_MIN};
+use crate::raytracer::Collision;
+use crate::sampler::rng;
+use crate::traits::{Boundable, Boundary, Drawable};
+use crate::utils::lerp;
+use crate::vectors::V3;
+
+use serde::{Deserialize, Serialize};
+
+// Participating media are things like fog, smoke, or murky water, where light can get scattered or
+// absorbed anywhere along its path instead of just at surfaces. We handle them by randomly picking
+// how far a ray gets before it hits a particle, which (for a constant density) follows an exponential
+// distribution. If that distance is shorter than the path through the volume, we produce a Collision
+// right there and let a Volumetric material pick a new direction.
+//
+// Translucent materials, like wax or marble, work the same way, except the medium is only inside of
+// their surfaces. Whenever a ray hits the back of one, it's been travelling through the inside, so
+// the Collision picks whether it scattered somewhere along the way instead. See
+// Material::sample_interior.
+
+/// A HomogeneousMedium describes a participating medium with the same density everywhere.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct HomogeneousMedium {
+    /// How much light gets absorbed per unit of distance.
+    pub absorption: f32,
+    /// How much light gets scattered per unit of distance.
+    pub scattering: f32,
+    /// Tints the light scattered by the medium.
+    pub color: PixelF,
+    /// Henyey-Greenstein asymmetry in (-1, 1). Negative values scatter backwards, positive
+    /// values scatter forwards, and 0 scatters evenly in every direction.
+    pub anisotropy: f32,
+}
+
+impl HomogeneousMedium {
+    pub fn new(absorption: f32, scattering: f32, color: PixelF, anisotropy: f32) -> Self {
+        HomogeneousMedium {
+            absorption,
+            scattering,
+            color,
+            anisotropy: anisotropy.clamp(-0.999, 0.999),
+        }
+    }
+
+    /// Total rate at which light is removed from a ray, by either absorption or scattering.
+    pub fn extinction(&self) -> f32 {
+        self.absorption + self.scattering
+    }
+
+    /// Sample how far a ray travels through this medium before it interacts with a particle.
+    pub fn sample_distance(&self) -> f32 {
+        let extinction = self.extinction();
+        if extinction <= 0. {
+            return f32::INFINITY;
+        }
+        -f32::ln(1. - rng().gen::<f32>()) / extinction
+    }
+
+    /// The fraction of light which makes it some distance through this medium without interacting.
+    pub fn transmittance(&self, distance: f32) -> f32 {
+        f32::exp(-self.extinction() * distance)
+    }
+
+    /// The material used for scattering events inside this medium. Its albedo is the fraction of
+    /// interactions which scatter rather than absorb.
+    pub fn material(&self) -> Material {
+        Material::new_volumetric(
+            self.color.scale(self.scattering / self.extinction()),
+            self.anisotropy,
+        )
+    }
+
+    /// Sample a scattering event along the given parametric range of a ray.
+    fn sample_collision(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<Collision> {
+        if t_min >= t_max {
+            return None;
+        }
+        let ray_length = ray.dir.magnitude();
+        let distance = self.sample_distance();
+        if distance >= (t_max - t_min) * ray_length {
+            return None;
+        }
+
+        let t = t_min + distance / ray_length;
+        // The normal is meaningless in a volume, so just point it back along the ray.
+        Some(Collision::new(ray, ray.dir * -1., t, self.material()))
+    }
+}
+
+/// A ConstantMedium fills the inside of some boundary with a HomogeneousMedium.
+/// The boundary should be a closed shape, otherwise we can't tell where the inside is.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConstantMedium<B> {
+    pub boundary: B,
+    pub medium: HomogeneousMedium,
+}
+
+impl<B: Boundary> ConstantMedium<B> {
+    pub fn new(boundary: B, medium: HomogeneousMedium) -> Self {
+        ConstantMedium { boundary, medium }
+    }
+}
+
+impl<B: Boundary> Drawable for ConstantMedium<B> {
+    fn intersect(&self, ray: Ray) -> Option<Collision> {
+        // Find where the whole line enters and exits the boundary, even behind the ray's origin,
+        // since the ray may well start inside of the volume.
+        let mut probe = ray;
+        probe.min = -RAY_MAX;
+        probe.max = RAY_MAX;
+        let entry = self.boundary.crossing(probe)?;
+        probe.min = entry + RAY_MIN;
+        let exit = self.boundary.crossing(probe)?;
+
+        self.medium
+            .sample_collision(ray, entry.max(ray.min), exit.min(ray.max))
+    }
+}
+
+impl<B: Boundary> Boundable for ConstantMedium<B> {
+    fn bounds(&self) -> Bounds {
+        self.boundary.bounds()
+    }
+}
+
+/// Fog fills the whole scene with a HomogeneousMedium. Fog only extends `extent` units out from
+/// the origin of a ray, so rays which fly off into the distance still reach the sky.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Fog {
+    pub medium: HomogeneousMedium,
+    pub extent: f32,
+}
+
+impl Fog {
+    pub fn new(medium: HomogeneousMedium, extent: f32) -> Self {
+        Fog { medium, extent }
+    }
+}
+
+impl Drawable for Fog {
+    fn intersect(&self, ray: Ray) -> Option<Collision> {
+        let t_extent = self.extent / ray.dir.magnitude();
+        self.medium
+            .sample_collision(ray, ray.min, ray.max.min(t_extent))
+    }
+}
+
+/// A DensityGrid is a dense block of voxels, each holding the density of a medium at that spot.
+/// Densities are stored with x varying fastest, then y, then z.
+///
+/// On disk, a grid is a little-endian binary file laid out as:
+/// - the four magic bytes `TRVG`
+/// - a u32 format version, which is currently 1
+/// - three u32s giving the number of voxels along x, y, and z
+/// - one f32 density per voxel
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DensityGrid {
+    pub dims: (usize, usize, usize),
+    densities: Vec<f32>,
+    max_density: f32,
+}
+
+impl DensityGrid {
+    const MAGIC: &'static [u8; 4] = b"TRVG";
+    const VERSION: u32 = 1;
+    const HEADER_LEN: usize = 20;
+
+    pub fn new(dims: (usize, usize, usize), densities: Vec<f32>) -> Result<Self, String> {
+        let voxels = Self::voxel_count(dims)?;
+        if densities.len() != voxels {
+            return Err(format!(
+                "a {}x{}x{} grid needs {} densities, but got {}",
+                dims.0,
+                dims.1,
+                dims.2,
+                voxels,
+                densities.len()
+            ));
+        }
+        if densities.iter().any(|d| !d.is_finite() || *d < 0.) {
+            return Err("grid densities must be finite and non-negative".to_owned());
+        }
+        let max_density = densities.iter().cloned().fold(0., f32::max);
+        Ok(DensityGrid {
+            dims,
+            densities,
+            max_density,
+        })
+    }
+
+    /// How many voxels a grid of some size holds. Sizes come straight out of files, so they could
+    /// be anything, including empty or too big to count.
+    fn voxel_count(dims: (usize, usize, usize)) -> Result<usize, String> {
+        if dims.0 == 0 || dims.1 == 0 || dims.2 == 0 {
+            return Err(format!("a {}x{}x{} grid is empty", dims.0, dims.1, dims.2));
+        }
+        dims.0
+            .checked_mul(dims.1)
+            .and_then(|n| n.checked_mul(dims.2))
+            .ok_or_else(|| format!("a {}x{}x{} grid is too big", dims.0, dims.1, dims.2))
+    }
+
+    /// Read a grid from a file in the format described above.
+    pub fn load(filename: &str) -> Result<Self, String> {
+        let bytes = std::fs::read(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < Self::HEADER_LEN || &bytes[0..4] != Self::MAGIC {
+            return Err("not a density grid file".to_owned());
+        }
+        let read_u32 = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let version = read_u32(4);
+        if version != Self::VERSION {
+            return Err(format!("unsupported density grid version {}", version));
+        }
+        let dims = (
+            read_u32(8) as usize,
+            read_u32(12) as usize,
+            read_u32(16) as usize,
+        );
+
+        let body = &bytes[Self::HEADER_LEN..];
+        let body_len = Self::voxel_count(dims)?
+            .checked_mul(4)
+            .ok_or_else(|| format!("a {}x{}x{} grid is too big", dims.0, dims.1, dims.2))?;
+        if body.len() != body_len {
+            return Err(format!(
+                "a {}x{}x{} grid needs {} bytes of densities, but the file has {}",
+                dims.0,
+                dims.1,
+                dims.2,
+                body_len,
+                body.len()
+            ));
+        }
+        let densities = body
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+
+        Self::new(dims, densities)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::HEADER_LEN + self.densities.len() * 4);
+        bytes.extend_from_slice(Self::MAGIC);
+        bytes.extend_from_slice(&Self::VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(self.dims.0 as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.dims.1 as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.dims.2 as u32).to_le_bytes());
+        for d in &self.densities {
+            bytes.extend_from_slice(&d.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn save(&self, filename: &str) -> Result<(), String> {
+        std::fs::write(filename, self.to_bytes()).map_err(|e| format!("{}: {}", filename, e))
+    }
+
+    pub fn max_density(&self) -> f32 {
+        self.max_density
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize) -> f32 {
+        self.densities[(z * self.dims.1 + y) * self.dims.0 + x]
+    }
+
+    /// Look up the density at a point in [0, 1]^3, trilinearly interpolating between voxel centers.
+    pub fn density(&self, p: V3) -> f32 {
+        // Find the voxel to the lower corner of this point, and how far we are towards the next one.
+        let axis = |u: f32, n: usize| -> (usize, usize, f32) {
+            let g = (u * n as f32 - 0.5).clamp(0., (n - 1) as f32);
+            let i = (g as usize).min(n - 1);
+            (i, (i + 1).min(n - 1), g - i as f32)
+        };
+        let (x0, x1, tx) = axis(p.x, self.dims.0);
+        let (y0, y1, ty) = axis(p.y, self.dims.1);
+        let (z0, z1, tz) = axis(p.z, self.dims.2);
+
+        // lerp is (x0, x1, t) -> x1 at t=0, so our arguments go far-then-near.
+        let along_x = |y: usize, z: usize| lerp(self.voxel(x1, y, z), self.voxel(x0, y, z), tx);
+        let along_y = |z: usize| lerp(along_x(y1, z), along_x(y0, z), ty);
+        lerp(along_y(z1), along_y(z0), tz)
+    }
+}
+
+/// A GridMedium is a participating medium whose density varies through space according to a
+/// DensityGrid, stretched to fill some bounds. The medium's coefficients are given for a density of 1.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GridMedium {
+    pub bounds: Bounds,
+    pub grid: DensityGrid,
+    pub medium: HomogeneousMedium,
+}
+
+impl GridMedium {
+    pub fn new(grid: DensityGrid, bounds: Bounds, medium: HomogeneousMedium) -> Self {
+        GridMedium {
+            bounds,
+            grid,
+            medium,
+        }
+    }
+
+    /// The density at a point in world space.
+    pub fn density(&self, point: V3) -> f32 {
+        let extent = self.bounds.max_point - self.bounds.min_point;
+        let local = point - self.bounds.min_point;
+        self.grid.density(V3::new(
+            local.x / extent.x,
+            local.y / extent.y,
+            local.z / extent.z,
+        ))
+    }
+
+    /// The highest extinction anywhere in the medium. Tracking samples distances as if the whole
+    /// volume had this density, then throws out the "null" collisions where the real medium is thinner.
+    fn majorant(&self) -> f32 {
+        self.grid.max_density() * self.medium.extinction()
+    }
+}
+
+impl Drawable for GridMedium {
+    /// Find a real collision in the volume with delta tracking.
+    fn intersect(&self, ray: Ray) -> Option<Collision> {
+        let majorant = self.majorant();
+        if majorant <= 0. {
+            return None;
+        }
+        let (t_min, t_max) = self.bounds.clip(&ray)?;
+        let ray_length = ray.dir.magnitude();
+        let mut rand = rng();
+
+        let mut t = t_min;
+        loop {
+            t += -f32::ln(1. - rand.gen::<f32>()) / majorant / ray_length;
+            if t >= t_max {
+                return None;
+            }
+            let point = ray.destination(t);
+            let extinction = self.density(point) * self.medium.extinction();
+            if rand.gen::<f32>() * majorant < extinction {
+                return Some(Collision::new(ray, ray.dir * -1., t, self.medium.material()));
+            }
+        }
+    }
+}
+
+impl Boundable for GridMedium {
+    fn bounds(&self) -> Bounds {
+        self.bounds
+    }
+}
+
+/// The Henyey-Greenstein phase function: how likely light travelling along some direction is to
+/// scatter off at an angle with this cosine, per unit solid angle.
+pub fn henyey_greenstein(cos_theta: f32, anisotropy: f32) -> f32 {
+    let g = anisotropy;
+    let denominator = (1. + g * g - 2. * g * cos_theta).max(f32::EPSILON);
+    (1. - g * g) / (4. * std::f32::consts::PI * denominator * denominator.sqrt())
+}
+
+/// Pick a new direction for light scattered by a particle, according to the Henyey-Greenstein
+/// phase function. `anisotropy` is the mean cosine between the incoming and outgoing directions.
+pub fn sample_henyey_greenstein(dir_in: V3, anisotropy: f32) -> V3 {
+    let mut rand = rng();
+    let u: f32 = rand.gen();
+    let phi: f32 = rand.gen::<f32>() * 2. * std::f32::consts::PI;
+
+    let g = anisotropy;
+    let cos_theta = if g.abs() < 1e-3 {
+        1. - 2. * u
+    } else {
+        let t = (1. - g * g) / (1. + g - 2. * g * u);
+        (1. + g * g - t * t) / (2. * g)
+    };
+    let sin_theta = f32::sqrt((1. - cos_theta * cos_theta).max(0.));
+
+    let forward = dir_in.normalized();
+    let (tangent, bitangent) = forward.orthonormal_basis();
+    forward * cos_theta + (tangent * phi.cos() + bitangent * phi.sin()) * sin_theta
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(magic: &[u8; 4], version: u32, dims: (u32, u32, u32)) -> Vec<u8> {
+        let mut bytes = magic.to_vec();
+        for n in [version, dims.0, dims.1, dims.2] {
+            bytes.extend_from_slice(&n.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn grids_survive_a_round_trip_through_bytes() {
+        let grid = DensityGrid::new((2, 1, 2), vec![0., 0.5, 1., 2.]).unwrap();
+        let loaded = DensityGrid::from_bytes(&grid.to_bytes()).unwrap();
+        assert_eq!(loaded.dims, (2, 1, 2));
+        assert_eq!(loaded.densities, grid.densities);
+        assert_eq!(loaded.max_density(), 2.);
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        assert!(DensityGrid::from_bytes(b"TRVG").is_err());
+        assert!(DensityGrid::from_bytes(&header(b"NOPE", 1, (1, 1, 1))).is_err());
+        assert!(DensityGrid::from_bytes(&header(b"TRVG", 2, (1, 1, 1))).is_err());
+        // One voxel needs four bytes of density after the header.
+        let mut truncated = header(b"TRVG", 1, (1, 1, 1));
+        truncated.extend_from_slice(&[0, 0]);
+        assert!(DensityGrid::from_bytes(&truncated).is_err());
+        // Empty grids have no voxels to look densities up in.
+        assert!(DensityGrid::from_bytes(&header(b"TRVG", 1, (0, 1, 1))).is_err());
+        assert!(DensityGrid::new((1, 0, 1), Vec::new()).is_err());
+    }
+
+    #[test]
+    fn huge_sizes_are_rejected_rather_than_overflowing() {
+        let bytes = header(b"TRVG", 1, (u32::MAX, u32::MAX, u32::MAX));
+        assert!(DensityGrid::from_bytes(&bytes).is_err());
+        assert!(DensityGrid::new((usize::MAX, 2, 1), Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use std::fmt::Write;
+
+use crate::material_library::MaterialId;
+use crate::primitives::Primitive;
+use crate::vectors::V3;
+
+// Meshes come in and out as Wavefront OBJ, which just about everything can export. We only care
+// about the geometry: vertex positions, texture coordinates, vertex normals, and faces. Faces with
+// more than three vertices are split into fans of triangles. Everything else, like groups and .mtl
+// materials, is skipped over, and the whole mesh is made of the one material we're given.
+
+/// Load the triangles out of an OBJ file.
+pub fn load_obj(filename: &str, material: MaterialId) -> Result<Vec<Primitive>, String> {
+    let text = std::fs::read_to_string(filename).map_err(|e| format!("{}: {}", filename, e))?;
+    parse_obj(&text, material).map_err(|e| format!("{}: {}", filename, e))
+}
+
+/// Parse the triangles out of the text of an OBJ file.
+pub fn parse_obj(text: &str, material: MaterialId) -> Result<Vec<Primitive>, String> {
+    let mut positions: Vec<V3> = Vec::new();
+    let mut normals: Vec<V3> = Vec::new();
+    let mut uvs: Vec<(f32, f32)> = Vec::new();
+    let mut triangles = Vec::new();
+
+    for (line_index, line) in text.lines().enumerate() {
+        let error = |message: &str| format!("line {}: {}", line_index + 1, message);
+        let mut words = line.split_whitespace();
+
+        match words.next() {
+            Some("v") => positions.push(parse_v3(&mut words).ok_or_else(|| error("invalid vertex"))?),
+            Some("vn") => normals.push(parse_v3(&mut words).ok_or_else(|| error("invalid normal"))?),
+            Some("vt") => {
+                uvs.push(parse_uv(&mut words).ok_or_else(|| error("invalid texture coordinate"))?)
+            }
+            Some("f") => {
+                // Each corner is position/texture/normal, where everything but the position is optional.
+                let mut corners: Vec<Corner> = Vec::new();
+                for word in words {
+                    let mut indices = word.split('/');
+                    let position = indices
+                        .next()
+                        .and_then(|i| lookup(&positions, i))
+                        .ok_or_else(|| error(&format!("invalid vertex reference '{}'", word)))?;
+                    let uv = match indices.next() {
+                        Some(i) if !i.is_empty() => Some(lookup(&uvs, i).ok_or_else(|| {
+                            error(&format!("invalid texture reference '{}'", word))
+                        })?),
+                        _ => None,
+                    };
+                    let normal = match indices.next() {
+                        Some(i) if !i.is_empty() => Some(
+                            lookup(&normals, i)
+                                .ok_or_else(|| error(&format!("invalid normal reference '{}'", word)))?,
+                        ),
+                        _ => None,
+                    };
+                    corners.push((position, uv, normal));
+                }
+                if corners.len() < 3 {
+                    return Err(error("faces need at least three vertices"));
+                }
+
+                for i in 1..corners.len() - 1 {
+                    let (a, b, c) = (corners[0], corners[i], corners[i + 1]);
+                    let vertices = [a.0, b.0, c.0];
+                    let triangle = match (a.2, b.2, c.2) {
+                        (Some(n_a), Some(n_b), Some(n_c)) => {
+                            Primitive::new_smooth_triangle(vertices, [n_a, n_b, n_c], material)
+                        }
+                        _ => Primitive::new_triangle(vertices, material),
+                    };
+                    triangles.push(match (a.1, b.1, c.1) {
+                        (Some(uv_a), Some(uv_b), Some(uv_c)) => {
+                            triangle.with_uvs([uv_a, uv_b, uv_c])
+                        }
+                        _ => triangle,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(triangles)
+}
+
+/// Write the triangles in a list of primitives to an OBJ file. Anything that isn't a triangle can't
+/// be written out, so this fails rather than silently leaving things behind.
+pub fn save_obj(primitives: &[Primitive], filename: &str) -> Result<(), String> {
+    let text = to_obj(primitives)?;
+    std::fs::write(filename, text).map_err(|e| format!("{}: {}", filename, e))
+}
+
+/// Produce the text of an OBJ file holding the triangles in a list of primitives.
+pub fn to_obj(primitives: &[Primitive]) -> Result<String, String> {
+    let mut text = String::new();
+    let (mut n_vertices, mut n_uvs, mut n_normals) = (0, 0, 0);
+    for (index, primitive) in primitives.iter().enumerate() {
+        let (vertices, uvs, normals) = match primitive {
+            Primitive::Triangle {
+                vertices,
+                uvs,
+                normals,
+                ..
+            } => (vertices, uvs, normals),
+            _ => {
+                return Err(format!(
+                    "primitive {} isn't a triangle, and only triangles can be written to OBJ",
+                    index
+                ))
+            }
+        };
+
+        for v in vertices {
+            writeln!(text, "v {} {} {}", v.x, v.y, v.z).unwrap();
+        }
+        let (a, b, c) = (n_vertices + 1, n_vertices + 2, n_vertices + 3);
+        // Each corner is written as position/texture/normal, leaving out whatever we don't have.
+        let mut corners = [a.to_string(), b.to_string(), c.to_string()];
+        if let Some(uvs) = uvs {
+            for (corner, (u, v)) in corners.iter_mut().zip(uvs) {
+                n_uvs += 1;
+                writeln!(text, "vt {} {}", u, v).unwrap();
+                write!(corner, "/{}", n_uvs).unwrap();
+            }
+        }
+        if let Some(normals) = normals {
+            for (corner, n) in corners.iter_mut().zip(normals) {
+                n_normals += 1;
+                writeln!(text, "vn {} {} {}", n.x, n.y, n.z).unwrap();
+                if uvs.is_none() {
+                    corner.push('/');
+                }
+                write!(corner, "/{}", n_normals).unwrap();
+            }
+        }
+        writeln!(text, "f {} {} {}", corners[0], corners[1], corners[2]).unwrap();
+        n_vertices += 3;
+    }
+    Ok(text)
+}
+
+fn parse_v3<'a>(words: &mut impl Iterator<Item = &'a str>) -> Option<V3> {
+    let mut next = || words.next()?.parse::<f32>().ok();
+    Some(V3::new(next()?, next()?, next()?))
+}
+
+/// A face corner's position, and its texture coordinates and normal if it has them.
+type Corner = (V3, Option<(f32, f32)>, Option<V3>);
+
+fn parse_uv<'a>(words: &mut impl Iterator<Item = &'a str>) -> Option<(f32, f32)> {
+    let mut next = || words.next()?.parse::<f32>().ok();
+    Some((next()?, next()?))
+}
+
+/// Look up a one-based OBJ index, where negative numbers count back from the end.
+fn lookup<T: Copy>(list: &[T], index: &str) -> Option<T> {
+    let index: isize = index.parse().ok()?;
+    let position = if index < 0 {
+        list.len().checked_sub(index.unsigned_abs())?
+    } else {
+        (index as usize).checked_sub(1)?
+    };
+    list.get(position).copied()
+}
@@ -0,0 +1,295 @@
+use std::f32::consts::PI;
+
+use rand::Rng;
+
+use crate::image_handling::PixelF;
+use crate::integrator::{
+    accumulated, path_radiance, scaled, Integrator, TraceContext, SHADOW_EPSILON,
+};
+use crate::light::Light;
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::sampler::{rng, stream_seed, with_seed};
+use crate::vectors::V3;
+
+// Caustics are light focused onto a rough surface by glass or a mirror, like the bright spot under
+// a glass ball. Tracing paths from the camera, the only way to find one is to bounce off the floor,
+// through the glass, and straight into a light, which almost never happens for small lights.
+// Following light out from the lights finds them easily though.
+//
+// So before rendering, we fire photons out from the lights. Only emissive primitives count as lights
+// here, not the sky, so a scene lit by nothing but the sky gets no photons at all, and renders just
+// like it would with the path integrator. Any that go through glass or off a
+// mirror and then land on something rough get stored, and the rest are thrown away. When a camera
+// path reaches its first rough surface, we gather up the photons which landed nearby and work out
+// how bright the caustic is there from how densely they're packed. Everything else is path traced
+// as usual, except for light that reaches that surface by way of glass, since that's exactly what
+// the photons already counted.
+//
+// Density estimation blurs caustics over the gathering radius, so they come out a little soft and
+// a little too dim around their edges. Borrowing from progressive photon mapping helps: we trace
+// several sets of photons, each gathered over a smaller radius than the last, and average over all
+// of them. The radii shrink like in Knaus and Zwicker's probabilistic take on progressive photon
+// mapping, so no pass has to remember anything about the ones before it. Unlike the real thing, the
+// number of passes is fixed before rendering rather than growing with the samples, so caustics get
+// sharper with more passes, but taking more samples only makes them less noisy, never less blurry.
+//
+// Photons are kept in a kd-tree for finding the ones near a point quickly. It's stored flat, with
+// each node in the middle of the range of photons under it, so it needs no pointers at all.
+
+/// How quickly the gathering radius shrinks between progressive passes. Smaller shrinks faster.
+const PROGRESSIVE_ALPHA: f32 = 2. / 3.;
+
+/// Photons are always traced from the same seeds, so every tile and every worker traces the same ones.
+const PHOTON_SEED: u64 = 0x7068_6f74_6f6e;
+
+/// Some light which landed on a rough surface after being focused by glass or a mirror.
+#[derive(Clone, Copy, Debug)]
+pub struct Photon {
+    pub position: V3,
+    /// The normal of the surface it landed on, facing the side it arrived from.
+    pub normal: V3,
+    /// The direction it was travelling in.
+    pub dir: V3,
+    /// How much light it carries.
+    pub power: PixelF,
+}
+
+/// Photons stored in a kd-tree, along with the radius to gather them over.
+#[derive(Clone, Debug)]
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    /// The axis each node splits the photons under it along.
+    axes: Vec<u8>,
+    radius: f32,
+}
+
+impl PhotonMap {
+    /// Build a kd-tree out of some photons.
+    pub fn new(mut photons: Vec<Photon>, radius: f32) -> Self {
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        PhotonMap {
+            photons,
+            axes,
+            radius,
+        }
+    }
+
+    /// Fire photons out from a scene's lights, and keep the ones which make caustics.
+    pub fn trace(context: &TraceContext, photons: usize, radius: f32) -> Self {
+        let mut stored = Vec::new();
+        if !context.lights.is_empty() {
+            let mut rand = rng();
+            for _ in 0..photons {
+                let light = &context.lights[rand.gen_range(0..context.lights.len())];
+                trace_photon(context, light, photons, &mut rand, &mut stored);
+            }
+        }
+        PhotonMap::new(stored, radius)
+    }
+
+    /// Trace photons for every pass of progressive photon mapping, shrinking the radius as we go.
+    pub fn trace_progressive(
+        context: &TraceContext,
+        photons: usize,
+        radius: f32,
+        passes: usize,
+    ) -> Vec<Self> {
+        let mut radius_squared = radius * radius;
+        (0..passes.max(1))
+            .map(|pass| {
+                let map = with_seed(stream_seed(PHOTON_SEED, pass as u64), || {
+                    PhotonMap::trace(context, photons, radius_squared.sqrt())
+                });
+                let n = (pass + 1) as f32;
+                radius_squared *= (n + PROGRESSIVE_ALPHA) / (n + 1.);
+                map
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    /// Call `f` on every photon within `radius` of a point.
+    pub fn for_each_within(&self, point: V3, radius: f32, mut f: impl FnMut(&Photon)) {
+        within(&self.photons, &self.axes, point, radius * radius, &mut f);
+    }
+
+    /// Work out the light caustics send back along `dir_in` from a point on a rough surface, from
+    /// the photons which landed nearby.
+    pub fn estimate(&self, point: V3, normal: V3, dir_in: V3, material: &Material) -> PixelF {
+        let mut sum = PixelF::black();
+        self.for_each_within(point, self.radius, |photon| {
+            // Photons on the other side of a thin wall don't light this side.
+            if photon.normal.dot(&normal) <= 0. {
+                return;
+            }
+            if let Some(f) = material.bsdf(dir_in, photon.dir * -1., normal) {
+                sum = accumulated(sum, f.attenuate(photon.power));
+            }
+        });
+        scaled(sum, 1. / (PI * self.radius * self.radius))
+    }
+}
+
+/// Follow one photon out from a light until it lands on something rough. Only photons which went
+/// through glass or off a mirror on the way are worth keeping. A coat over something rough keeps
+/// the photon, but it might glance off of the coat and carry on to land somewhere else as well.
+fn trace_photon(
+    context: &TraceContext,
+    light: &Light,
+    photons: usize,
+    rand: &mut impl Rng,
+    stored: &mut Vec<Photon>,
+) {
+    let (point, normal) = light.sample_surface(rand);
+    let mut dir = normal + V3::random_on_unit_sphere();
+    if dir.near_zero() {
+        dir = normal;
+    }
+    // Each light puts out pi times its area times its emission, shared between the photons which
+    // happen to pick it.
+    let share = PI * light.area() * context.lights.len() as f32 / photons as f32;
+    let mut power = scaled(light.emission, share);
+    let mut ray = Ray::new(point, dir);
+    ray.min = SHADOW_EPSILON / dir.magnitude();
+
+    let mut focused = false;
+    for _ in 0..=context.max_depth {
+        let Some(collision) = context.intersect(ray) else {
+            return;
+        };
+        let material = &collision.material;
+        if material.emission().is_some() || material.is_volumetric() {
+            return;
+        }
+        let rough = material
+            .bsdf(ray.dir, collision.ray_out.dir, collision.normal)
+            .is_some();
+        if rough && focused {
+            stored.push(Photon {
+                position: collision.point(),
+                normal: collision.normal,
+                dir: ray.dir.normalized(),
+                power,
+            });
+        }
+        if !collision.scattered_specularly() {
+            return;
+        }
+        focused = true;
+        power = power.attenuate(collision.color);
+        ray = collision.ray_out;
+    }
+}
+
+fn axis_value(v: V3, axis: u8) -> f32 {
+    match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+/// Arrange photons into a kd-tree, splitting each range at its median along its longest side.
+fn build(photons: &mut [Photon], axes: &mut [u8]) {
+    if photons.len() <= 1 {
+        return;
+    }
+    let (mut min, mut max) = (photons[0].position, photons[0].position);
+    for photon in photons.iter() {
+        let p = photon.position;
+        min = V3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z));
+        max = V3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z));
+    }
+    let extent = max - min;
+    let axis = if extent.x > extent.y && extent.x > extent.z {
+        0
+    } else if extent.y > extent.z {
+        1
+    } else {
+        2
+    };
+
+    let middle = photons.len() / 2;
+    photons.select_nth_unstable_by(middle, |a, b| {
+        axis_value(a.position, axis).total_cmp(&axis_value(b.position, axis))
+    });
+    axes[middle] = axis;
+    let (left, right) = photons.split_at_mut(middle);
+    let (left_axes, right_axes) = axes.split_at_mut(middle);
+    build(left, left_axes);
+    build(&mut right[1..], &mut right_axes[1..]);
+}
+
+fn within(
+    photons: &[Photon],
+    axes: &[u8],
+    point: V3,
+    radius_squared: f32,
+    f: &mut impl FnMut(&Photon),
+) {
+    if photons.is_empty() {
+        return;
+    }
+    let middle = photons.len() / 2;
+    let photon = &photons[middle];
+    if (photon.position - point).magnitude_squared() <= radius_squared {
+        f(photon);
+    }
+    if photons.len() == 1 {
+        return;
+    }
+    // Only look on the far side of the split if the sphere we're gathering over crosses it.
+    let axis = axes[middle];
+    let offset = axis_value(point, axis) - axis_value(photon.position, axis);
+    let (near, far) = if offset < 0. {
+        ((0, middle), (middle + 1, photons.len()))
+    } else {
+        ((middle + 1, photons.len()), (0, middle))
+    };
+    within(
+        &photons[near.0..near.1],
+        &axes[near.0..near.1],
+        point,
+        radius_squared,
+        f,
+    );
+    if offset * offset <= radius_squared {
+        within(
+            &photons[far.0..far.1],
+            &axes[far.0..far.1],
+            point,
+            radius_squared,
+            f,
+        );
+    }
+}
+
+/// Path traces everything but caustics, which come from photon maps instead.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PhotonMapIntegrator;
+
+impl Integrator for PhotonMapIntegrator {
+    fn radiance(&self, ray: Ray, context: &TraceContext) -> PixelF {
+        // With several passes, each sample uses one of them, so on average we get the average over
+        // all of them.
+        let map = match context.photon_maps.len() {
+            0 => None,
+            1 => context.photon_maps.first(),
+            passes => context.photon_maps.get(rng().gen_range(0..passes)),
+        };
+        path_radiance(ray, context, map)
+    }
+}
@@ -1,13 +1,40 @@
 pub use crate::{
-    bounded_volume_hierarchy::{BVHBuildNode, Bounds, BVHFlat},
+    bidirectional::BidirectionalIntegrator,
+    bounded_volume_hierarchy::{BVHBuildNode, BVHStats, Bounds, BVHFlat, TraversalCost},
     camera::Camera,
-    image_handling::{ImageBuffer, PixelF},
-    material::Material,
+    checkpoint::{scene_hash, Checkpoint, Checkpointer},
+    denoise::{denoise, Denoiser},
+    distributed::{run_worker, Coordinator},
+    film::{Film, FilmTile, Filter},
+    frame_buffer::{FirstHit, FrameBuffer, Pass},
+    gltf_import::{load_gltf, load_gltf_from_memory, GltfScene},
+    heatmap::{false_color, Heatmap, HeatmapMetric},
+    image_handling::{ImageBuffer, ImageComparison, OutputFormat, PixelF},
+    integrator::{
+        sky_color, AmbientOcclusionIntegrator, DebugIntegrator, DebugView, Integrator,
+        IntegratorKind, PathIntegrator, ScatterIntegrator, TraceContext, WhittedIntegrator,
+    },
+    light::{find_lights, Light, LightSample, LightShape},
+    material::{Dispersion, Material, ThinFilm},
+    material_library::{MaterialId, MaterialLibrary, MetallicRoughness},
+    medium::{ConstantMedium, DensityGrid, Fog, GridMedium, HomogeneousMedium},
+    mesh::{load_obj, parse_obj, save_obj, to_obj},
+    photon_map::{Photon, PhotonMap, PhotonMapIntegrator},
     primitives::Primitive,
-    raytracer::Raytracer,
+    ray::Ray,
+    raytracer::{Collision, Raytracer, SurfaceHit},
+    scene::{CameraDescription, Scene, SceneDescription},
+    scene_graph::{SceneGraph, SceneNode, Transform},
+    sampler::{seed_sampler, stream_seed},
+    scheduler::{Tile, TileOrder, TileProgress, TileScheduler},
+    spectral::{
+        cie_xyz, rgb_to_spectrum, xyz_to_rgb, SpectralIntegrator, MAX_WAVELENGTH, MIN_WAVELENGTH,
+    },
+    stats::RayStats,
+    texture::{ShadingMap, Texture},
     traits::*,
     utils::{lerp, parse_pair},
-    vectors::V3,
+    vectors::{M4, V3},
 };
 
 // If you're not familiar with a prelude, it re-exports an essential set of the most commonly needed
@@ -1,39 +1,268 @@
+use std::f32::consts::PI;
+
 use crate::{
     bounded_volume_hierarchy::Bounds,
-    material::Material,
+    material_library::{MaterialId, MaterialLibrary},
+    medium::{ConstantMedium, DensityGrid, GridMedium, HomogeneousMedium},
     ray::Ray,
-    raytracer::Collision,
-    traits::{Boundable, Drawable},
+    raytracer::{Collision, SurfaceHit},
+    stats,
+    texture::{ShadingMap, Texture},
+    traits::{Boundable, Boundary, Drawable},
     vectors::*,
 };
 
 use serde::{Serialize, Deserialize};
 
 
+/// How solid a cut out surface has to be for rays to hit it, unless it says otherwise.
+const CUTOUT_THRESHOLD: f32 = 0.5;
+
+fn cutout_threshold() -> f32 {
+    CUTOUT_THRESHOLD
+}
+
+/// How many times a ray gets to pass through the holes in a cut out primitive, looking for a solid
+/// part. Spheres only ever need two goes, but this keeps rounding error from looping forever.
+const CUTOUT_RETRIES: usize = 4;
+
 /// This represents a primitive object which can be rendered.
-/// It's an enum to leave room for triangles, quads, meshes, etc.
-/// Those don't exist in the project yet, but I may add them later.
+/// It's an enum to leave room for quads, meshes, etc.
+/// Spheres and triangles don't hold their materials themselves, just which entry they are in the
+/// scene's MaterialLibrary.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Primitive {
     Sphere {
         center: V3,
         radius: f32,
-        material: Material,
+        material: MaterialId,
+    },
+    /// A flat triangle, wound counter-clockwise when looking at its front. Meshes are made of lots of
+    /// these. When vertex normals are given, the shading normal is blended between them. Without
+    /// texture coordinates, the corners get (0, 0), (1, 0) and (0, 1).
+    Triangle {
+        vertices: [V3; 3],
+        normals: Option<[V3; 3]>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        uvs: Option<[(f32, f32); 3]>,
+        material: MaterialId,
+    },
+    /// A sphere or triangle with a normal or bump map over it.
+    Mapped {
+        primitive: Box<Primitive>,
+        map: ShadingMap,
     },
+    /// A sphere or triangle with shapes cut out of it, like a leaf out of a flat card. Rays go
+    /// straight through wherever `opacity` is less solid than `cutoff`, half solid by default, as
+    /// if nothing was there. Materials can't hold textures, so the cut out shape lives here rather
+    /// than on the material.
+    Cutout {
+        primitive: Box<Primitive>,
+        opacity: Texture,
+        #[serde(default = "cutout_threshold")]
+        cutoff: f32,
+    },
+    /// A participating medium filling the inside of another primitive.
+    Medium(Box<ConstantMedium<Primitive>>),
+    /// A participating medium whose density comes from a voxel grid.
+    GridMedium(Box<GridMedium>),
 }
 
 impl Primitive {
-    pub fn new_sphere(center: V3, radius: f32, material: Material) -> Self {
+    pub fn new_sphere(center: V3, radius: f32, material: MaterialId) -> Self {
         Primitive::Sphere {
             center,
             radius,
             material,
         }
     }
-}
 
-impl Drawable for Primitive {
-    fn intersect(self: &Self, ray: Ray) -> Option<Collision> {
+    pub fn new_triangle(vertices: [V3; 3], material: MaterialId) -> Self {
+        Primitive::Triangle {
+            vertices,
+            normals: None,
+            uvs: None,
+            material,
+        }
+    }
+
+    pub fn new_smooth_triangle(
+        vertices: [V3; 3],
+        normals: [V3; 3],
+        material: MaterialId,
+    ) -> Self {
+        Primitive::Triangle {
+            vertices,
+            normals: Some(normals),
+            uvs: None,
+            material,
+        }
+    }
+
+    /// Give a triangle texture coordinates at each of its corners. Anything else is left as it is.
+    pub fn with_uvs(mut self, corner_uvs: [(f32, f32); 3]) -> Self {
+        if let Primitive::Triangle { ref mut uvs, .. } = self {
+            *uvs = Some(corner_uvs);
+        }
+        self
+    }
+
+    /// Put a normal or bump map over a sphere or triangle.
+    pub fn new_mapped(primitive: Primitive, map: ShadingMap) -> Self {
+        Primitive::Mapped {
+            primitive: Box::new(primitive),
+            map,
+        }
+    }
+
+    /// Cut shapes out of a sphere or triangle, going by a texture's opacity.
+    pub fn new_cutout(primitive: Primitive, opacity: Texture) -> Self {
+        Primitive::Cutout {
+            primitive: Box::new(primitive),
+            opacity,
+            cutoff: CUTOUT_THRESHOLD,
+        }
+    }
+
+    /// Change how solid a cut out primitive has to be for rays to hit it.
+    pub fn with_cutoff(mut self, new_cutoff: f32) -> Self {
+        if let Primitive::Cutout { ref mut cutoff, .. } = self {
+            *cutoff = new_cutoff;
+        }
+        self
+    }
+
+    pub fn new_medium(boundary: Primitive, medium: HomogeneousMedium) -> Self {
+        Primitive::Medium(Box::new(ConstantMedium::new(boundary, medium)))
+    }
+
+    pub fn new_grid_medium(grid: DensityGrid, bounds: Bounds, medium: HomogeneousMedium) -> Self {
+        Primitive::GridMedium(Box::new(GridMedium::new(grid, bounds, medium)))
+    }
+
+    /// Move, turn and stretch this primitive with a matrix. Spheres have to stay round, so they
+    /// can only be scaled evenly, and density grids stay lined up with the axes, so they can only
+    /// be moved and stretched.
+    pub fn transformed(&self, matrix: &M4) -> Result<Primitive, String> {
+        Ok(match *self {
+            Primitive::Sphere {
+                center,
+                radius,
+                material,
+            } => {
+                let [x, y, z] = [V3::x(), V3::y(), V3::z()].map(|v| matrix.transform_vector(v));
+                let scale = x.magnitude();
+                let tolerance = scale * 1e-4;
+                let even = (y.magnitude() - scale).abs() <= tolerance
+                    && (z.magnitude() - scale).abs() <= tolerance
+                    && x.dot(&y).abs() <= tolerance * scale
+                    && y.dot(&z).abs() <= tolerance * scale
+                    && z.dot(&x).abs() <= tolerance * scale;
+                if !even {
+                    return Err("spheres can only be scaled evenly along every axis".to_owned());
+                }
+                Primitive::new_sphere(matrix.transform_point(center), radius * scale, material)
+            }
+            Primitive::Triangle {
+                vertices,
+                normals,
+                uvs,
+                material,
+            } => {
+                let normal_matrix = match normals {
+                    Some(_) => Some(
+                        matrix
+                            .inverse()
+                            .ok_or("can't place a triangle with a matrix that squashes it flat")?
+                            .transpose(),
+                    ),
+                    None => None,
+                };
+                let mut vertices = vertices.map(|vertex| matrix.transform_point(vertex));
+                let mut normals = normals.zip(normal_matrix).map(|(normals, normal_matrix)| {
+                    normals.map(|normal| normal_matrix.transform_vector(normal).normalized())
+                });
+                let mut uvs = uvs;
+                // Mirroring a triangle turns it inside out, so wind it the other way to keep its
+                // front facing the same way as its normals.
+                if matrix.determinant_3x3() < 0. {
+                    vertices.swap(1, 2);
+                    if let Some(ref mut normals) = normals {
+                        normals.swap(1, 2);
+                    }
+                    if let Some(ref mut uvs) = uvs {
+                        uvs.swap(1, 2);
+                    }
+                }
+                Primitive::Triangle {
+                    vertices,
+                    normals,
+                    uvs,
+                    material,
+                }
+            }
+            Primitive::Mapped {
+                ref primitive,
+                ref map,
+            } => Primitive::new_mapped(primitive.transformed(matrix)?, map.clone()),
+            Primitive::Cutout {
+                ref primitive,
+                ref opacity,
+                cutoff,
+            } => Primitive::new_cutout(primitive.transformed(matrix)?, opacity.clone())
+                .with_cutoff(cutoff),
+            Primitive::Medium(ref medium) => {
+                Primitive::new_medium(medium.boundary.transformed(matrix)?, medium.medium)
+            }
+            Primitive::GridMedium(ref medium) => {
+                let m = &matrix.0;
+                let lined_up = (0..3).all(|i| (0..3).all(|j| i == j || m[i][j] == 0.));
+                if !lined_up || (0..3).any(|i| m[i][i] <= 0.) {
+                    return Err("density grids can only be moved and stretched".to_owned());
+                }
+                let bounds = Bounds {
+                    min_point: matrix.transform_point(medium.bounds.min_point),
+                    max_point: matrix.transform_point(medium.bounds.max_point),
+                };
+                Primitive::new_grid_medium(medium.grid.clone(), bounds, medium.medium)
+            }
+        })
+    }
+
+    /// Which material a sphere or triangle is made of. Media don't have one.
+    pub fn material(&self) -> Option<MaterialId> {
+        match self {
+            Primitive::Sphere { material, .. } | Primitive::Triangle { material, .. } => {
+                Some(*material)
+            }
+            Primitive::Mapped { primitive, .. } | Primitive::Cutout { primitive, .. } => {
+                primitive.material()
+            }
+            Primitive::Medium(_) | Primitive::GridMedium(_) => None,
+        }
+    }
+
+    /// Whether this is a sphere or triangle with a matte material, like a holdout.
+    pub fn is_matte(&self, materials: &MaterialLibrary) -> bool {
+        self.material().is_some_and(|material| materials[material].is_matte())
+    }
+
+    /// Intersect a ray with this primitive, looking up what it's made of in a library.
+    pub fn intersect(&self, ray: Ray, materials: &MaterialLibrary) -> Option<Collision> {
+        stats::record(|s| s.primitive_tests += 1);
+        match self {
+            Primitive::Medium(medium) => medium.intersect(ray),
+            Primitive::GridMedium(medium) => medium.intersect(ray),
+            _ => {
+                let (hit, id) = self.surface_hit(ray)?;
+                let material = materials.at(id, hit.uv);
+                Some(Collision::on_surface(ray, hit, material).with_material_id(id))
+            }
+        }
+    }
+
+    /// Find where a ray hits a sphere or triangle, and what the surface is like there.
+    fn surface_hit(&self, ray: Ray) -> Option<(SurfaceHit, MaterialId)> {
         match *self {
             Primitive::Sphere {
                 center,
@@ -65,16 +294,149 @@ impl Drawable for Primitive {
 
                 let point = ray.destination(root);
                 let raw_normal = (point - center) / radius;
-                let faced_normal = ray.get_faced_normal(raw_normal);
 
-                Option::Some(Collision::new(ray, faced_normal, root, material))
+                // Texture coordinates go around the equator and up from the bottom, like
+                // longitude and latitude.
+                let offset = point - center;
+                let (x, y, z) = (raw_normal.x, raw_normal.y, raw_normal.z);
+                let theta = (-y).clamp(-1., 1.).acos();
+                let phi = (-z).atan2(x) + PI;
+                let uv = (phi / (2. * PI), theta / PI);
+                let dpdu = V3::new(offset.z, 0., -offset.x) * (2. * PI);
+                let sin_theta = theta.sin();
+                let (dpdu, dpdv) = if sin_theta < 1e-4 {
+                    // Right at the poles, u doesn't go anywhere.
+                    raw_normal.orthonormal_basis()
+                } else {
+                    let cot_theta = theta.cos() / sin_theta;
+                    let dpdv = V3::new(
+                        offset.x * cot_theta,
+                        radius * sin_theta,
+                        offset.z * cot_theta,
+                    ) * PI;
+                    (dpdu, dpdv)
+                };
+
+                // Collision works out which side we hit from the outward normal.
+                Some((
+                    SurfaceHit {
+                        t: root,
+                        normal: raw_normal,
+                        geometric_normal: raw_normal,
+                        uv,
+                        dpdu,
+                        dpdv,
+                    },
+                    material,
+                ))
+            }
+            Primitive::Triangle {
+                vertices: [a, b, c],
+                normals,
+                uvs,
+                material,
+            } => {
+                // Möller-Trumbore: solve for the distance along the ray and the barycentric
+                // coordinates of the hit all at once.
+                let edge_1 = b - a;
+                let edge_2 = c - a;
+                let p = ray.dir.cross(&edge_2);
+                let determinant = edge_1.dot(&p);
+                // The ray runs parallel to the triangle.
+                if determinant.abs() < 1e-9 {
+                    return None;
+                }
+                let inverse_determinant = 1. / determinant;
+
+                let a_to_origin = ray.origin - a;
+                let u = a_to_origin.dot(&p) * inverse_determinant;
+                if !(0. ..=1.).contains(&u) {
+                    return None;
+                }
+                let q = a_to_origin.cross(&edge_1);
+                let v = ray.dir.dot(&q) * inverse_determinant;
+                if v < 0. || u + v > 1. {
+                    return None;
+                }
+                let t = edge_2.dot(&q) * inverse_determinant;
+                if t < ray.min || t > ray.max {
+                    return None;
+                }
+
+                let geometric_normal = edge_1.cross(&edge_2).normalized();
+                let normal = match normals {
+                    Some([n_a, n_b, n_c]) => (n_a * (1. - u - v) + n_b * u + n_c * v).normalized(),
+                    None => geometric_normal,
+                };
+                let (uv, dpdu, dpdv) = match uvs {
+                    Some([uv_a, uv_b, uv_c]) => {
+                        let uv = (
+                            uv_a.0 * (1. - u - v) + uv_b.0 * u + uv_c.0 * v,
+                            uv_a.1 * (1. - u - v) + uv_b.1 * u + uv_c.1 * v,
+                        );
+                        // Solve for how the position changes with the texture coordinates along
+                        // both edges at once.
+                        let (du_1, dv_1) = (uv_b.0 - uv_a.0, uv_b.1 - uv_a.1);
+                        let (du_2, dv_2) = (uv_c.0 - uv_a.0, uv_c.1 - uv_a.1);
+                        let uv_determinant = du_1 * dv_2 - dv_1 * du_2;
+                        let (dpdu, dpdv) = if uv_determinant.abs() < 1e-12 {
+                            geometric_normal.orthonormal_basis()
+                        } else {
+                            let inverse = 1. / uv_determinant;
+                            (
+                                (edge_1 * dv_2 - edge_2 * dv_1) * inverse,
+                                (edge_2 * du_1 - edge_1 * du_2) * inverse,
+                            )
+                        };
+                        (uv, dpdu, dpdv)
+                    }
+                    None => ((u, v), edge_1, edge_2),
+                };
+                Some((
+                    SurfaceHit {
+                        t,
+                        normal,
+                        geometric_normal,
+                        uv,
+                        dpdu,
+                        dpdv,
+                    },
+                    material,
+                ))
             }
+            Primitive::Mapped {
+                ref primitive,
+                ref map,
+            } => {
+                let (mut hit, material) = primitive.surface_hit(ray)?;
+                hit.normal = map.perturb(hit.normal, hit.uv, hit.dpdu, hit.dpdv);
+                Some((hit, material))
+            }
+            Primitive::Cutout {
+                ref primitive,
+                ref opacity,
+                cutoff,
+            } => {
+                // Spheres can be hit twice, so if the near side's cut away, try again from just
+                // past it.
+                let mut ray = ray;
+                for _ in 0..CUTOUT_RETRIES {
+                    let (hit, material) = primitive.surface_hit(ray)?;
+                    if opacity.opacity(hit.uv) >= cutoff {
+                        return Some((hit, material));
+                    }
+                    ray.min = hit.t + hit.t.abs().max(1.) * 1e-5;
+                }
+                None
+            }
+            Primitive::Medium(_) | Primitive::GridMedium(_) => None,
         }
     }
 }
 
-impl Boundable for Primitive {
-    fn bounds(&self) -> Bounds {
+impl Primitive {
+    /// A box around the whole primitive.
+    pub fn bounds(&self) -> Bounds {
         match *self {
             Primitive::Sphere {
                 center,
@@ -87,19 +449,98 @@ impl Boundable for Primitive {
                     max_point: center + radius_offset,
                 }
             }
+            Primitive::Triangle {
+                vertices: [a, b, c],
+                ..
+            } => {
+                // Pad the box out a little, since a triangle lying flat along an axis would
+                // otherwise give it no thickness at all.
+                let padding = V3::new(1e-4, 1e-4, 1e-4);
+                let min = |f: fn(&V3) -> f32| f(&a).min(f(&b)).min(f(&c));
+                let max = |f: fn(&V3) -> f32| f(&a).max(f(&b)).max(f(&c));
+                Bounds {
+                    min_point: V3::new(min(|v| v.x), min(|v| v.y), min(|v| v.z)) - padding,
+                    max_point: V3::new(max(|v| v.x), max(|v| v.y), max(|v| v.z)) + padding,
+                }
+            }
+            Primitive::Mapped { ref primitive, .. } | Primitive::Cutout { ref primitive, .. } => {
+                primitive.bounds()
+            }
+            Primitive::Medium(ref medium) => medium.bounds(),
+            Primitive::GridMedium(ref medium) => medium.bounds(),
         }
     }
 }
 
-impl Drawable for Vec<Primitive> {
-    fn intersect(&self, mut ray: Ray) -> Option<Collision> {
-        let mut out = None;
-        for ref el in self {
-            if let Some(coll) = el.intersect(ray) {
-                ray.max = coll.t;
-                out = Some(coll);
+// Media fill the inside of other primitives, and only care about where rays cross into and out of
+// them, not what they're made of.
+impl Boundary for Primitive {
+    fn crossing(&self, ray: Ray) -> Option<f32> {
+        stats::record(|s| s.primitive_tests += 1);
+        self.surface_hit(ray).map(|(hit, _)| hit.t)
+    }
+
+    fn bounds(&self) -> Bounds {
+        Primitive::bounds(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_handling::PixelF;
+    use crate::material::Material;
+    use std::f32::consts::FRAC_PI_2;
+
+    fn material() -> MaterialId {
+        MaterialLibrary::new().add(Material::new_diffuse(PixelF::white()))
+    }
+
+    #[test]
+    fn mirrored_triangles_are_wound_the_other_way() {
+        let triangle = Primitive::Triangle {
+            vertices: [V3::zero(), V3::x(), V3::y()],
+            normals: Some([V3::z(), (V3::z() + V3::x()).normalized(), V3::z()]),
+            uvs: Some([(0., 0.), (1., 0.), (0., 1.)]),
+            material: material(),
+        };
+        let mirrored = triangle.transformed(&M4::scaling(V3::new(-1., 1., 1.))).unwrap();
+        let Primitive::Triangle {
+            vertices: [a, b, c],
+            normals: Some(normals),
+            uvs: Some(uvs),
+            ..
+        } = mirrored
+        else {
+            panic!("expected a triangle, got {:?}", mirrored);
+        };
+
+        // The front still faces the same way as the normals.
+        assert!((b - a).cross(&(c - a)).dot(&V3::z()) > 0.);
+        // Each vertex keeps its own normal and texture coordinates.
+        assert_eq!([a, b, c], [V3::zero(), V3::y(), V3::x() * -1.]);
+        assert_eq!(uvs, [(0., 0.), (0., 1.), (1., 0.)]);
+        assert_eq!(normals[1], V3::z());
+        assert!((normals[2] - (V3::z() - V3::x()).normalized()).near_zero());
+    }
+
+    #[test]
+    fn spheres_only_scale_evenly() {
+        let sphere = Primitive::new_sphere(V3::x(), 1., material());
+        assert!(sphere.transformed(&M4::scaling(V3::new(1., 2., 1.))).is_err());
+        assert!(sphere
+            .transformed(&(M4::rotation(V3::z(), 0.5) * M4::scaling(V3::new(1., 1., 3.))))
+            .is_err());
+
+        let placed = M4::translation(V3::y())
+            * M4::rotation(V3::z(), FRAC_PI_2)
+            * M4::scaling(V3::one() * 2.);
+        match sphere.transformed(&placed).unwrap() {
+            Primitive::Sphere { center, radius, .. } => {
+                assert!((center - V3::new(0., 3., 0.)).near_zero(), "{}", center);
+                assert!((radius - 2.).abs() < 1e-5);
             }
+            other => panic!("expected a sphere, got {:?}", other),
         }
-        out
     }
 }
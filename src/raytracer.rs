@@ -1,17 +1,44 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+
+use rand::Rng;
+
 use crate::camera::Camera;
+use crate::film::{Film, Filter};
+use crate::frame_buffer::FirstHit;
 use crate::image_handling::PixelF;
+use crate::integrator::{
+    accumulated, scaled, sky_color, Integrator, IntegratorKind, TraceContext,
+};
+use crate::light::Light;
 use crate::material::Material;
+use crate::material_library::MaterialId;
+use crate::medium::Fog;
+use crate::photon_map::PhotonMap;
 use crate::ray::Ray;
+use crate::sampler::rng;
+use crate::stats;
 use crate::traits::Drawable;
-use crate::traits::{Canvas, Renderer};
+use crate::traits::{Canvas, Renderer, SampleSink};
 use crate::vectors::*;
 
+use serde::{Deserialize, Serialize};
+
 /// The raytracer does all our, well, raytracing. It turns a drawable into an image by intersecting
 /// a ton of rays with it.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Raytracer {
     ss_amt: usize,
     max_depth: usize,
+    roulette_depth: usize,
+    fog: Option<Fog>,
+    filter: Filter,
+    integrator: IntegratorKind,
+    transparent_background: bool,
+    /// Photon maps traced ahead of time by prepared, for whichever scene it was given.
+    #[serde(skip)]
+    photon_maps: Option<Arc<Vec<PhotonMap>>>,
 }
 
 impl Raytracer {
@@ -21,75 +48,312 @@ impl Raytracer {
         self
     }
 
-	/// Builder pattern function to set max recursion depth.
+	/// Builder pattern function to set the most bounces a path can take.
     pub fn max_depth(mut self, max_depth: usize) -> Self {
         self.max_depth = max_depth;
+        self.photon_maps = None;
+        self
+    }
+
+	/// Builder pattern function to set how many bounces a path gets before Russian roulette may end it early.
+    pub fn roulette_depth(mut self, roulette_depth: usize) -> Self {
+        self.roulette_depth = roulette_depth;
+        self
+    }
+
+	/// Builder pattern function to fill the scene with fog.
+    pub fn fog(mut self, fog: Fog) -> Self {
+        self.fog = Some(fog);
+        self.photon_maps = None;
+        self
+    }
+
+	/// Builder pattern function to set the reconstruction filter used to combine samples into pixels.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+	/// Builder pattern function to choose how light gets worked out for each ray.
+    pub fn integrator(mut self, integrator: IntegratorKind) -> Self {
+        self.integrator = integrator;
+        self.photon_maps = None;
         self
     }
 
-	/// Intersect a ray with a drawable, resolving the correct color.
+	/// Builder pattern function to leave the sky transparent wherever the camera sees it directly, so
+	/// renders can be composited over other images. The sky still lights the scene.
+    pub fn transparent_background(mut self, transparent: bool) -> Self {
+        self.transparent_background = transparent;
+        self
+    }
+
+    pub fn integrator_kind(&self) -> IntegratorKind {
+        self.integrator
+    }
+
+    pub fn pixel_filter(&self) -> Filter {
+        self.filter
+    }
+
+    pub fn samples_per_pixel(&self) -> usize {
+        self.ss_amt
+    }
+
+	/// Trace anything our integrator needs ahead of time for a scene, like photon maps, so that every
+	/// film rendered with the raytracer we hand back shares it rather than tracing its own. That
+	/// raytracer is only good for rendering this scene. Preparing it again hands it back as it is.
+    pub fn prepared(&self, scene: &dyn Drawable) -> Raytracer {
+        let mut prepared = self.clone();
+        if prepared.photon_maps.is_none() {
+            prepared.photon_maps = Some(self.photon_maps(scene, &scene.lights()));
+        }
+        prepared
+    }
+
+	/// Take samples for every pixel a film, or a tile of one, covers, splatting them onto it.
+    pub fn render_film<F: SampleSink>(
+        &self,
+        scene: &dyn Drawable,
+        film: &mut F,
+        camera: &Camera,
+    ) -> Result<(), String> {
+        if film.image_bounds() != camera.bounds() {
+            return Err("film and camera disagree on the image size".to_owned());
+        }
+        // Only count what happens while rendering this film, not whatever this thread did before.
+        stats::take_thread_stats();
+        let lights = scene.lights();
+        let photon_maps = self.photon_maps(scene, &lights);
+        let context = self.context(scene, &lights, &photon_maps, Some(camera));
+        let composite = self.transparent_background || scene.has_mattes();
+        let mut rand = rng();
+        let (offset, bounds) = (film.offset(), film.bounds());
+		// For each pixel in our film...
+        for x in offset.0..offset.0 + bounds.0 {
+            for y in offset.1..offset.1 + bounds.1 {
+                for _ in 0..self.ss_amt {
+					// Generate a ray from our camera, somewhere within this pixel
+                    let sample_x = x as f32 + rand.gen::<f32>();
+                    let sample_y = y as f32 + rand.gen::<f32>();
+                    let ray = camera.get_ray_from_pixel(sample_x, sample_y);
+                    stats::record(|s| s.primary_rays += 1);
+					// Perform the intersection
+                    let color = if composite {
+                        self.camera_sample(ray, &context)
+                    } else {
+                        self.integrator.radiance(ray, &context)
+                    };
+
+					// The film takes care of weighting and averaging our samples.
+                    film.add_sample(sample_x, sample_y, color);
+                    for (x, y, splat) in context.splats.borrow_mut().drain(..) {
+                        film.add_splat(x, y, splat);
+                    }
+                }
+            }
+        }
+
+        // Hand over everything this thread counted while rendering the film.
+        film.add_stats(&stats::take_thread_stats());
+        Ok(())
+    }
+
+	/// Work out the color and alpha for a ray from the camera, for scenes that are going to be
+	/// composited over something else.
+    fn camera_sample(&self, ray: Ray, context: &TraceContext) -> PixelF {
+        // The integrator gets to trace every ray regardless, since it might splat light paths
+        // traced alongside it onto the rest of the image. Whatever it hit first is what we composite
+        // by, so we don't intersect the ray twice.
+        context.first_collision.take();
+        let color = self.integrator.radiance(ray, context);
+        let collision = context
+            .first_collision
+            .take()
+            .unwrap_or_else(|| context.scene.intersect(ray));
+        self.composite(ray, collision, context).unwrap_or(color)
+    }
+
+	/// What the camera sees along a ray if it's a matte or a transparent background, rather than
+	/// anything the integrator would work out, given the first surface the ray hits. Mattes go by
+	/// that surface alone, so any fog in front of them gets left out.
+    fn composite(
+        &self,
+        ray: Ray,
+        collision: Option<Collision>,
+        context: &TraceContext,
+    ) -> Option<PixelF> {
+        match collision {
+            None if self.transparent_background => Some(PixelF::transparent()),
+            Some(collision) if collision.material.is_holdout() => Some(PixelF::transparent()),
+            Some(collision) if collision.material.is_shadow_catcher() => {
+                Some(self.catch_shadows(ray, &collision, context))
+            }
+            _ => None,
+        }
+    }
+
+	/// Work out what the camera sees where it looks at a shadow catcher. We light the catcher from
+	/// the sky in one direction and from one point on a light, once as if there was nothing else
+	/// around and once with everything in the way. How much light gets through is how much of
+	/// whatever's behind the catcher shows through, and light that bounces off of something onto the
+	/// catcher gets added on top.
+    fn catch_shadows(&self, ray: Ray, collision: &Collision, context: &TraceContext) -> PixelF {
+        let mut rand = rng();
+        let uncaught = context.without_camera();
+        let point = collision.point();
+        let (mut unoccluded, mut lit, mut bounced) =
+            (PixelF::black(), PixelF::black(), PixelF::black());
+
+        // The sky, in whichever direction the catcher scattered the ray. Lights and other catchers
+        // don't count as being in the way.
+        let sky = sky_color(collision.ray_out).attenuate(collision.color);
+        unoccluded = accumulated(unoccluded, sky);
+        match context.scene.intersect(collision.ray_out) {
+            Some(hit)
+                if !hit.material.is_shadow_catcher()
+                    && context.light(hit.primitive_index).is_none() =>
+            {
+                let light = self.integrator.radiance(collision.ray_out, &uncaught);
+                bounced = light.attenuate(collision.color);
+            }
+            _ => lit = accumulated(lit, sky),
+        }
+
+        if !context.lights.is_empty() {
+            let light = &context.lights[rand.gen_range(0..context.lights.len())];
+            if let Some(sample) = light.sample(point, &mut rand) {
+                if let Some((value, _)) = collision.evaluate(sample.point - point) {
+                    let pdf = sample.pdf / context.lights.len() as f32;
+                    let direct = scaled(value.attenuate(sample.emission), 1. / pdf);
+                    unoccluded = accumulated(unoccluded, direct);
+                    if context.visible(point, sample.point) {
+                        lit = accumulated(lit, direct);
+                    }
+                }
+            }
+        }
+
+        let brightness = |p: PixelF| p.r + p.g + p.b;
+        let shown = if brightness(unoccluded) > 0. {
+            (brightness(lit) / brightness(unoccluded)).min(1.)
+        } else {
+            1.
+        };
+
+        // Carry on past the catcher to see what's behind it.
+        let mut behind_ray = ray;
+        behind_ray.min = collision.t + collision.t.abs().max(1.) * 1e-5;
+        let behind = self
+            .composite(behind_ray, context.scene.intersect(behind_ray), context)
+            .unwrap_or_else(|| self.integrator.radiance(behind_ray, &uncaught));
+        accumulated(scaled(behind, shown), bounced).with_alpha(1. - shown * (1. - behind.a))
+    }
+
+	/// Record the first hit through the center of each pixel of a canvas. This is one ray on top of
+	/// the samples render_film takes, so thin things a pixel's samples caught can be missed here.
+    pub fn record_first_hits<C: Canvas>(&self, scene: &dyn Drawable, canvas: &mut C, camera: &Camera) {
+        let (offset, bounds) = (canvas.offset(), canvas.bounds());
+        for x in 0..bounds.0 {
+            for y in 0..bounds.1 {
+                let center = camera
+                    .get_ray_from_pixel((x + offset.0) as f32 + 0.5, (y + offset.1) as f32 + 0.5);
+                canvas.put_first_hit(x, y, self.first_hit(center, scene));
+            }
+        }
+    }
+
+	/// Intersect a ray with a drawable, resolving the correct color. This gathers up the scene's lights
+	/// every time, so prefer render_film for anything more than the odd ray.
     pub fn get_color(&self, ray: Ray, scene: &dyn Drawable) -> PixelF {
-        self.get_color_recursive(ray, scene, 0)
+        let lights = scene.lights();
+        let photon_maps = self.photon_maps(scene, &lights);
+        self.integrator
+            .radiance(ray, &self.context(scene, &lights, &photon_maps, None))
     }
 
-	/// Get color, but recurse on reflected rays until we hit nothing.
-    fn get_color_recursive(&self, ray: Ray, scene: &dyn Drawable, depth: usize) -> PixelF {
-        if depth > self.max_depth {
-            return PixelF::black();
+	/// Bundle up what our integrator needs to trace rays through a scene. Pass the camera if the
+	/// integrator's splats have somewhere to go.
+    fn context<'a>(
+        &'a self,
+        scene: &'a dyn Drawable,
+        lights: &'a [Light],
+        photon_maps: &'a [PhotonMap],
+        camera: Option<&'a Camera>,
+    ) -> TraceContext<'a> {
+        TraceContext {
+            scene,
+            lights,
+            fog: self.fog.as_ref(),
+            max_depth: self.max_depth,
+            roulette_depth: self.roulette_depth,
+            camera,
+            photon_maps,
+            splats: RefCell::new(Vec::new()),
+            first_collision: RefCell::new(None),
         }
+    }
 
-        match scene.intersect(ray) {
-            Some(collision) => self
-                .get_color_recursive(collision.ray_out, scene, depth + 1)
-                .attenuate(collision.color),
-            _ => Self::get_sky_color(ray),
+	/// Get the photon maps our integrator needs for a scene, if it needs any. Unless they were traced
+	/// ahead of time by prepared, we trace them now.
+    fn photon_maps(&self, scene: &dyn Drawable, lights: &[Light]) -> Arc<Vec<PhotonMap>> {
+        if let Some(maps) = &self.photon_maps {
+            return maps.clone();
+        }
+        match self.integrator {
+            IntegratorKind::PhotonMap {
+                photons,
+                radius,
+                passes,
+            } => {
+                let context = self.context(scene, lights, &[], None);
+                Arc::new(PhotonMap::trace_progressive(
+                    &context, photons, radius, passes,
+                ))
+            }
+            _ => Arc::default(),
         }
     }
 
-	/// Determine the color of the sky depending on what direction we flew off.
-    fn get_sky_color(ray: Ray) -> PixelF {
-        let unit_direction = ray.dir.normalized();
-        let t = 0.5 * (unit_direction.y + 1.0);
-        let lerp = |t: f32, start: f32, end: f32| -> f32 { start * (1.0 - t) + end * t };
-        PixelF::rgb_u8(
-            lerp(t, 255.0, 120.0) as u8,
-            lerp(t, 255.0, 200.0) as u8,
-            255,
-        )
+	/// Gather information about the first thing a ray hits, for our extra render passes.
+	/// Fog is ignored here, since it isn't really a surface.
+    pub fn first_hit(&self, ray: Ray, scene: &dyn Drawable) -> Option<FirstHit> {
+        scene.intersect(ray).map(|collision| FirstHit {
+            depth: collision.t * ray.dir.magnitude(),
+            normal: collision.normal,
+            albedo: collision.material.albedo(),
+            position: collision.point(),
+            primitive_index: collision.primitive_index,
+            material_id: collision.material_id,
+            material: collision.material,
+        })
     }
 }
 
 impl Renderer for Raytracer {
+    /// Render onto any canvas. Samples near the edges of the canvas which would spill over into
+    /// neighbouring pixels are lost, so use render_film and merge films together for seamless
+    /// results when rendering pieces of an image with wide filters.
     fn render<C: Canvas>(
         &self,
         scene: &dyn Drawable,
         canvas: &mut C,
         camera: &Camera,
     ) -> Result<(), String> {
-        let mut rand = rand::thread_rng();
-        let bounds = canvas.bounds();
-		// For each pixel in our canvas...
+        let (offset, bounds) = (canvas.offset(), canvas.bounds());
+        let mut film = Film::region(camera.bounds(), offset, bounds, self.filter);
+        self.render_film(scene, &mut film, camera)?;
+
         for x in 0..bounds.0 {
             for y in 0..bounds.1 {
-                let mut pixel = PixelF::black();
-                for _ in 0..self.ss_amt {
-					// Generate a ray from our camera
-                    let ray = camera.get_ray_perturbed(
-                        x + canvas.offset().0,
-                        y + canvas.offset().1,
-                        &mut rand,
-                    );
-					// Perform the intersection
-                    let color = self.get_color(ray, scene);
-
-					// Add the color on to our output pixel. This performs our ss averaging by
-					// scaling down each sample when it's added.
-                    pixel = pixel + color.scale(1.0 / self.ss_amt as f32);
-                }
-                canvas.put_pixel(x, y, pixel);
+                canvas.put_pixel(x, y, film.pixel(x + offset.0, y + offset.1));
             }
         }
 
+        if canvas.records_first_hits() {
+            self.record_first_hits(scene, canvas, camera);
+        }
+
         Ok(())
     }
 }
@@ -99,6 +363,12 @@ impl Default for Raytracer {
         Self {
             ss_amt: 8,
             max_depth: 256,
+            roulette_depth: 4,
+            fog: None,
+            filter: Filter::default(),
+            integrator: IntegratorKind::default(),
+            transparent_background: false,
+            photon_maps: None,
         }
     }
 }
@@ -108,28 +378,190 @@ impl Default for Raytracer {
 pub struct Collision {
     pub ray_in: Ray,
     pub ray_out: Ray,
+    /// The normal to shade with, facing back against the incoming ray. Normal and bump maps lean
+    /// this over, away from the geometric normal.
     pub normal: V3,
+    /// The surface's real normal, also facing back against the incoming ray.
+    pub geometric_normal: V3,
     pub t: f32,
     pub front_facing: bool,
     pub color: PixelF,
+    pub material: Material,
+    /// Index of the primitive we hit, in the list the scene was built from, if we know it.
+    pub primitive_index: Option<usize>,
+    /// The entry in the scene's material library the primitive is made of. Media don't have one.
+    pub material_id: Option<MaterialId>,
+    /// Whether this is light scattering around inside a translucent material. Nothing outside of
+    /// the material can be seen from in there.
+    pub subsurface: bool,
+    /// Texture coordinates where we hit.
+    pub uv: (f32, f32),
+    /// How the position on the surface changes as u and v do, which makes up its tangent frame.
+    pub dpdu: V3,
+    pub dpdv: V3,
 }
 
+/// Everything a primitive works out about where a ray hit its surface.
+#[derive(Clone, Copy, Debug)]
+pub struct SurfaceHit {
+    pub t: f32,
+    /// The outward normal to shade with.
+    pub normal: V3,
+    /// The outward normal of the surface itself.
+    pub geometric_normal: V3,
+    pub uv: (f32, f32),
+    pub dpdu: V3,
+    pub dpdv: V3,
+}
+
+/// How squarely a shading normal has to face the incoming ray, at the least.
+const MIN_SHADING_COSINE: f32 = 0.01;
+
 impl Collision {
+    /// A collision with something that has nothing more to say about its surface than its outward
+    /// normal.
     pub fn new(ray: Ray, raw_normal: V3, t: f32, material: Material) -> Self {
-        let front_facing = ray.dir.dot(&raw_normal) < 0f32;
-        let normal = if front_facing {
-            raw_normal
+        let (dpdu, dpdv) = raw_normal.normalized().orthonormal_basis();
+        Collision::on_surface(
+            ray,
+            SurfaceHit {
+                t,
+                normal: raw_normal,
+                geometric_normal: raw_normal,
+                uv: (0., 0.),
+                dpdu,
+                dpdv,
+            },
+            material,
+        )
+    }
+
+    pub fn on_surface(ray: Ray, hit: SurfaceHit, material: Material) -> Self {
+        let t = hit.t;
+        // Which side we hit goes by the real surface, but it's whichever side the shading normal
+        // is on that counts as the front.
+        let geometric = if hit.geometric_normal.dot(&hit.normal) < 0. {
+            hit.geometric_normal * -1.
+        } else {
+            hit.geometric_normal
+        };
+        let front_facing = ray.dir.dot(&geometric) < 0f32;
+        let (mut normal, geometric_normal) = if front_facing {
+            (hit.normal, geometric)
         } else {
-            raw_normal * -1f32
+            (hit.normal * -1f32, geometric * -1f32)
         };
-        let (ray_out, color) = material.scatter(&ray, ray.destination(t), normal);
+        // Hitting the back of a surface means we've been travelling through the inside of it, where
+        // translucent materials might have scattered the light before it got here.
+        if !front_facing {
+            let length = ray.dir.magnitude();
+            if let Some((travelled, interior)) = material.sample_interior(t * length) {
+                // Like in a medium, there's no normal, so point it back along the ray.
+                return Collision {
+                    subsurface: true,
+                    ..Collision::new(ray, ray.dir * -1., travelled / length, interior)
+                };
+            }
+        }
+        // A shading normal leaning away from the ray would have us shade the back of the surface,
+        // so lean it back until it faces us.
+        let dir = ray.dir.normalized();
+        let facing = (dir * -1.).dot(&normal);
+        if facing < MIN_SHADING_COSINE && !(normal - geometric_normal).near_zero() {
+            normal = (normal - dir * (MIN_SHADING_COSINE - facing)).normalized();
+        }
+
+        let (mut ray_out, mut color) =
+            material.scatter(&ray, ray.destination(t), normal, front_facing);
+        ray_out.dir = keep_on_side(ray_out.dir, normal, geometric_normal);
+        if !front_facing {
+            color = color.attenuate(material.transmittance(t * ray.dir.magnitude()));
+        }
         Collision {
             ray_in: ray,
             ray_out,
             normal,
+            geometric_normal,
             t,
             front_facing,
             color,
+            material,
+            primitive_index: None,
+            material_id: None,
+            subsurface: false,
+            uv: hit.uv,
+            dpdu: hit.dpdu,
+            dpdv: hit.dpdv,
+        }
+    }
+
+    pub fn with_primitive_index(mut self, index: usize) -> Self {
+        self.primitive_index = Some(index);
+        self
+    }
+
+    pub fn with_material_id(mut self, id: MaterialId) -> Self {
+        self.material_id = Some(id);
+        self
+    }
+
+    /// The point in space where this collision happened.
+    pub fn point(&self) -> V3 {
+        self.ray_in.destination(self.t)
+    }
+
+    /// Scatter again, for light of a single wavelength in nanometres, for materials that treat each
+    /// wavelength differently.
+    pub fn scatter_wavelength(&self, wavelength: f32) -> (Ray, PixelF) {
+        let (ray_out, mut color) = self.material.scatter_wavelength(
+            &self.ray_in,
+            self.point(),
+            self.normal,
+            self.front_facing,
+            wavelength,
+        );
+        let ray_out = Ray {
+            dir: keep_on_side(ray_out.dir, self.normal, self.geometric_normal),
+            ..ray_out
+        };
+        if !self.front_facing {
+            color = color.attenuate(
+                self.material
+                    .transmittance(self.t * self.ray_in.dir.magnitude()),
+            );
+        }
+        (ray_out, color)
+    }
+
+    /// How much light arriving from `dir_out` gets sent back along the incoming ray, as in
+    /// Material::evaluate. Light from beyond the real surface is cut off, even where the shading
+    /// normal leans over towards it.
+    pub fn evaluate(&self, dir_out: V3) -> Option<(PixelF, f32)> {
+        let (value, pdf) = self
+            .material
+            .evaluate(self.ray_in.dir, dir_out, self.normal)?;
+        if dir_out.dot(&self.normal) * dir_out.dot(&self.geometric_normal) < 0. {
+            return Some((PixelF::black(), pdf));
         }
+        Some((value, pdf))
+    }
+
+    /// Whether the ray scattered off in the one direction a mirror or glass would send it, as in
+    /// Material::scattered_specularly. Paths can't be weighed against sampling lights at bounces
+    /// like these.
+    pub fn scattered_specularly(&self) -> bool {
+        self.material
+            .scattered_specularly(self.ray_in.dir, self.ray_out.dir, self.normal)
+    }
+}
+
+/// A leaning shading normal can have light leave through the wrong side of the real surface. Send
+/// any that does back off the real surface, onto the side the material meant it for.
+fn keep_on_side(dir: V3, normal: V3, geometric_normal: V3) -> V3 {
+    let geometric = dir.dot(&geometric_normal);
+    if dir.dot(&normal) * geometric < 0. {
+        dir - geometric_normal * (2. * geometric)
+    } else {
+        dir
     }
 }
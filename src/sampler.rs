@@ -0,0 +1,67 @@
+use std::cell::RefCell;
+
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+
+// Everything random in the renderer pulls from one generator per thread. rand's thread_rng can't be
+// seeded, which makes renders impossible to reproduce, and impossible to pick back up from a
+// checkpoint without repeating the exact same samples. This generator can be reseeded whenever we
+// start a new piece of work, so the samples for a tile only depend on the seed and the tile.
+
+thread_local! {
+    static SAMPLER: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+}
+
+/// A handle to the current thread's sampler. It works just like rand's ThreadRng.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SamplerRng;
+
+/// Get a handle to the current thread's sampler.
+pub fn rng() -> SamplerRng {
+    SamplerRng
+}
+
+/// Restart the current thread's sampler from a seed.
+pub fn seed_sampler(seed: u64) {
+    SAMPLER.with(|sampler| *sampler.borrow_mut() = StdRng::seed_from_u64(seed));
+}
+
+/// Run something with the current thread's sampler restarted from a seed, then put the sampler back
+/// the way it was. Whatever the thread was doing before carries on exactly as if nothing happened,
+/// which keeps renders reproducible when some shared piece of work gets done by whichever tile
+/// happens to need it first.
+pub(crate) fn with_seed<T>(seed: u64, f: impl FnOnce() -> T) -> T {
+    let saved = SAMPLER.with(|sampler| {
+        std::mem::replace(&mut *sampler.borrow_mut(), StdRng::seed_from_u64(seed))
+    });
+    let result = f();
+    SAMPLER.with(|sampler| *sampler.borrow_mut() = saved);
+    result
+}
+
+/// Derive an independent seed for some numbered piece of work, like a pass or a tile.
+/// This is the finalizer from SplitMix64, which scrambles nearby inputs into unrelated outputs.
+pub fn stream_seed(seed: u64, stream: u64) -> u64 {
+    let mut z = seed ^ stream.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+impl RngCore for SamplerRng {
+    fn next_u32(&mut self) -> u32 {
+        SAMPLER.with(|sampler| sampler.borrow_mut().next_u32())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        SAMPLER.with(|sampler| sampler.borrow_mut().next_u64())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        SAMPLER.with(|sampler| sampler.borrow_mut().fill_bytes(dest))
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        SAMPLER.with(|sampler| sampler.borrow_mut().try_fill_bytes(dest))
+    }
+}
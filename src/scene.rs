@@ -0,0 +1,362 @@
+use std::f32::consts::PI;
+
+use crate::bounded_volume_hierarchy::TraversalCost;
+use crate::camera::Camera;
+use crate::light::{find_lights, Light};
+use crate::material_library::MaterialLibrary;
+use crate::primitives::Primitive;
+use crate::ray::Ray;
+use crate::raytracer::{Collision, Raytracer};
+use crate::scene_graph::SceneGraph;
+use crate::traits::{Drawable, Traversable};
+use crate::vectors::V3;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// The things in a scene, and the library of materials they're made of.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Scene {
+    pub primitives: Vec<Primitive>,
+    #[serde(default)]
+    pub materials: MaterialLibrary,
+}
+
+impl Scene {
+    /// Put together a scene, as long as everything in it is made of a material in the library.
+    pub fn new(primitives: Vec<Primitive>, materials: MaterialLibrary) -> Result<Self, String> {
+        let scene = Scene {
+            primitives,
+            materials,
+        };
+        scene.validate()?;
+        Ok(scene)
+    }
+
+    /// Make sure every primitive is made of a material in the library.
+    pub fn validate(&self) -> Result<(), String> {
+        let missing = self
+            .primitives
+            .iter()
+            .filter_map(Primitive::material)
+            .find(|&material| self.materials.get(material).is_none());
+        match missing {
+            Some(material) => Err(format!("there's no material number {}", material.index())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drawable for Scene {
+    fn intersect(&self, mut ray: Ray) -> Option<Collision> {
+        let mut out = None;
+        for (index, el) in self.primitives.iter().enumerate() {
+            if let Some(coll) = el.intersect(ray, &self.materials) {
+                ray.max = coll.t;
+                out = Some(coll.with_primitive_index(index));
+            }
+        }
+        out
+    }
+
+    fn lights(&self) -> Vec<Light> {
+        find_lights(&self.primitives, &self.materials)
+    }
+
+    fn has_mattes(&self) -> bool {
+        self.primitives
+            .iter()
+            .any(|primitive| primitive.is_matte(&self.materials))
+    }
+}
+
+// Without any structure to speed things up, every ray gets tested against every primitive.
+impl Traversable for Scene {
+    fn intersect_with_cost(&self, ray: Ray) -> (Option<Collision>, TraversalCost) {
+        let cost = TraversalCost {
+            nodes: 0,
+            primitives: self.primitives.len(),
+        };
+        (self.intersect(ray), cost)
+    }
+}
+
+/// Everything needed to set up a Camera, in a form that can be written to a file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CameraDescription {
+    pub position: V3,
+    pub direction: V3,
+    pub up: V3,
+    /// Vertical field of view, in degrees.
+    pub fov: f32,
+    /// Image size in pixels.
+    pub bounds: (usize, usize),
+}
+
+impl CameraDescription {
+    pub fn build(&self) -> Camera {
+        Camera::new(
+            self.position,
+            self.direction,
+            self.up,
+            self.fov * PI / 180.0,
+            self.bounds,
+        )
+    }
+}
+
+/// A SceneDescription is a complete, self contained description of a render: what's in the scene,
+/// where we're looking at it from, and how the raytracer is set up. These can be saved to and loaded
+/// from JSON scene files, or sent off to other processes.
+///
+/// In scene files, the materials are a list of `{ "name": ..., "material": ... }` entries, where
+/// names are optional. Primitives refer to them either by their position in the list or by name.
+///
+/// Alongside the loose primitives, there can be a scene graph of `nodes`, and `prototypes` for them
+/// to place, which get flattened into the rest of the primitives before rendering.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SceneDescription {
+    pub camera: CameraDescription,
+    #[serde(default)]
+    pub raytracer: Raytracer,
+    #[serde(flatten)]
+    pub scene: Scene,
+    #[serde(flatten)]
+    pub graph: SceneGraph,
+}
+
+impl SceneDescription {
+    pub fn new(camera: CameraDescription, raytracer: Raytracer, scene: Scene) -> Self {
+        SceneDescription {
+            camera,
+            raytracer,
+            scene,
+            graph: SceneGraph::default(),
+        }
+    }
+
+    pub fn with_graph(mut self, graph: SceneGraph) -> Self {
+        self.graph = graph;
+        self
+    }
+
+    /// Everything in the scene, with the scene graph flattened out into the loose primitives.
+    pub fn flattened(self) -> Result<Scene, String> {
+        let mut scene = self.scene;
+        scene.primitives.extend(self.graph.flatten()?);
+        scene.validate()?;
+        Ok(scene)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let mut json: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        resolve_materials(&mut json)?;
+        let description: Self = serde_json::from_value(json).map_err(|e| e.to_string())?;
+        description.scene.validate()?;
+        Ok(description)
+    }
+
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self).map_err(|e| e.to_string())
+    }
+
+    pub fn load(filename: &str) -> Result<Self, String> {
+        let json = std::fs::read_to_string(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        Self::from_json(&json).map_err(|e| format!("{}: {}", filename, e))
+    }
+
+    pub fn save(&self, filename: &str) -> Result<(), String> {
+        std::fs::write(filename, self.to_json()?).map_err(|e| format!("{}: {}", filename, e))
+    }
+}
+
+// Primitives in scene files, including the ones in the scene graph, can refer to materials by
+// position or by name, and older files have each primitive's material written out in full. Before
+// handing the file to serde, we turn all of those into positions in the table, adding any written
+// out materials to the table as we go.
+
+/// Turn every material reference among a scene file's primitives into a position in its table.
+fn resolve_materials(json: &mut Value) -> Result<(), String> {
+    let Some(fields) = json.as_object_mut() else {
+        // Not our problem, serde will complain about it.
+        return Ok(());
+    };
+    let mut materials = match fields.remove("materials") {
+        Some(Value::Array(materials)) => materials,
+        Some(_) => return Err("the materials should be a list".to_owned()),
+        None => Vec::new(),
+    };
+    let mut names: Vec<&str> = materials
+        .iter()
+        .filter_map(|entry| entry.get("name")?.as_str())
+        .collect();
+    names.sort_unstable();
+    if let Some(name) = names.windows(2).find(|pair| pair[0] == pair[1]) {
+        return Err(format!("there's more than one material called '{}'", name[0]));
+    }
+    for key in ["primitives", "nodes", "prototypes"] {
+        if let Some(primitives) = fields.get_mut(key) {
+            resolve_references(primitives, &mut materials)?;
+        }
+    }
+    fields.insert("materials".to_owned(), Value::Array(materials));
+    Ok(())
+}
+
+/// Find every material reference somewhere inside a primitive or scene graph node, or a list of
+/// them.
+fn resolve_references(json: &mut Value, materials: &mut Vec<Value>) -> Result<(), String> {
+    match json {
+        Value::Array(items) => {
+            for item in items {
+                resolve_references(item, materials)?;
+            }
+        }
+        Value::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                if key == "material" {
+                    *value = Value::from(resolve_reference(value, materials)?);
+                } else {
+                    resolve_references(value, materials)?;
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Find the position in the table of a single material reference.
+fn resolve_reference(reference: &Value, materials: &mut Vec<Value>) -> Result<usize, String> {
+    match reference {
+        Value::Number(number) => number
+            .as_u64()
+            .map(|index| index as usize)
+            .filter(|&index| index < materials.len())
+            .ok_or_else(|| format!("there's no material number {}", number)),
+        Value::String(name) => materials
+            .iter()
+            .position(|entry| entry.get("name") == Some(reference))
+            .ok_or_else(|| format!("there's no material called '{}'", name)),
+        // Lots of primitives tend to share the same material, so they share the same entry too.
+        inline => {
+            let existing = materials.iter().position(|entry| {
+                entry.get("name").is_none() && entry.get("material") == Some(inline)
+            });
+            Ok(existing.unwrap_or_else(|| {
+                materials.push(json!({ "material": inline }));
+                materials.len() - 1
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bounded_volume_hierarchy::BVHBuildNode;
+    use crate::image_handling::PixelF;
+    use crate::material::Material;
+    use crate::material_library::MaterialId;
+
+    /// A scene file with one sphere for each of the material references given.
+    fn scene_file(materials: &str, references: &[&str]) -> String {
+        let spheres: Vec<String> = references
+            .iter()
+            .map(|reference| {
+                let center = r#"{"x": 0.0, "y": 0.0, "z": 0.0}"#;
+                format!(
+                    r#"{{"Sphere": {{"center": {}, "radius": 1.0, "material": {}}}}}"#,
+                    center, reference
+                )
+            })
+            .collect();
+        format!(
+            r#"{{
+                "camera": {{
+                    "position": {{"x": 0.0, "y": 0.0, "z": -5.0}},
+                    "direction": {{"x": 0.0, "y": 0.0, "z": 1.0}},
+                    "up": {{"x": 0.0, "y": 1.0, "z": 0.0}},
+                    "fov": 70.0,
+                    "bounds": [8, 8]
+                }},
+                "materials": {},
+                "primitives": [{}]
+            }}"#,
+            materials,
+            spheres.join(", ")
+        )
+    }
+
+    const RED: &str = r#"{"Diffuse": {"albedo": {"r": 1.0, "g": 0.0, "b": 0.0}}}"#;
+    const BLUE: &str = r#"{"Diffuse": {"albedo": {"r": 0.0, "g": 0.0, "b": 1.0}}}"#;
+
+    #[test]
+    fn materials_are_found_by_name_or_position() {
+        let materials = format!(
+            r#"[{{"material": {}}}, {{"name": "red", "material": {}}}]"#,
+            BLUE, RED
+        );
+        let json = scene_file(&materials, &["\"red\"", "0", "1"]);
+        let scene = SceneDescription::from_json(&json).unwrap().scene;
+
+        let ids: Vec<MaterialId> =
+            scene.primitives.iter().filter_map(Primitive::material).collect();
+        assert_eq!(ids[0], ids[2]);
+        assert_eq!(scene.materials.find("red"), Some(ids[0]));
+        assert_eq!(scene.materials.name(ids[0]), Some("red"));
+        assert_eq!(scene.materials.name(ids[1]), None);
+        assert_eq!(scene.materials[ids[0]], Material::new_diffuse(PixelF::rgb(1., 0., 0.)));
+        assert_eq!(scene.materials[ids[1]], Material::new_diffuse(PixelF::rgb(0., 0., 1.)));
+    }
+
+    #[test]
+    fn materials_written_out_in_full_share_entries() {
+        let json = scene_file("[]", &[RED, BLUE, RED]);
+        let scene = SceneDescription::from_json(&json).unwrap().scene;
+
+        let ids: Vec<MaterialId> =
+            scene.primitives.iter().filter_map(Primitive::material).collect();
+        assert_eq!(scene.materials.len(), 2);
+        assert_eq!(ids[0], ids[2]);
+        assert_ne!(ids[0], ids[1]);
+        assert_eq!(scene.materials[ids[1]], Material::new_diffuse(PixelF::rgb(0., 0., 1.)));
+    }
+
+    #[test]
+    fn missing_materials_are_errors() {
+        let materials = format!(r#"[{{"name": "red", "material": {}}}]"#, RED);
+        let unknown = SceneDescription::from_json(&scene_file(&materials, &["\"green\""]));
+        assert!(unknown.unwrap_err().contains("'green'"));
+        let out_of_range = SceneDescription::from_json(&scene_file(&materials, &["1"]));
+        assert!(out_of_range.unwrap_err().contains("number 1"));
+
+        let twice = format!(
+            r#"[{{"name": "red", "material": {}}}, {{"name": "red", "material": {}}}]"#,
+            RED, BLUE
+        );
+        let duplicated = SceneDescription::from_json(&scene_file(&twice, &["0"]));
+        assert!(duplicated.unwrap_err().contains("more than one"));
+    }
+
+    #[test]
+    fn trees_are_only_built_over_materials_in_the_library() {
+        let mut elsewhere = MaterialLibrary::new();
+        elsewhere.add(Material::new_diffuse(PixelF::white()));
+        let id = elsewhere.add(Material::new_diffuse(PixelF::black()));
+        let sphere = Primitive::new_sphere(V3::zero(), 1., id);
+
+        let mut materials = MaterialLibrary::new();
+        materials.add(Material::new_diffuse(PixelF::white()));
+        assert!(Scene::new(vec![sphere.clone()], materials.clone()).is_err());
+        // Scenes with public fields can still be put together by hand, so trees check for
+        // themselves.
+        let scene = Scene {
+            primitives: vec![sphere.clone()],
+            materials: materials.clone(),
+        };
+        assert!(BVHBuildNode::new(scene, 4).is_err());
+        materials.add(Material::new_diffuse(PixelF::black()));
+        assert!(BVHBuildNode::new(Scene::new(vec![sphere], materials).unwrap(), 4).is_ok());
+    }
+}
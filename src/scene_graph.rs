@@ -0,0 +1,212 @@
+use std::collections::BTreeMap;
+
+use crate::primitives::Primitive;
+use crate::vectors::{M4, V3};
+
+use serde::{Deserialize, Serialize};
+
+// Scenes are often built out of assemblies of parts, like a table with its legs, which want to be
+// moved about as a whole. A scene graph is a tree of nodes, each placed relative to the one above
+// it. Moving a node moves everything underneath it along with it.
+//
+// Nodes can also be instances of prototypes, which are nodes kept off to the side that aren't drawn
+// on their own. Placing the same prototype under lots of nodes puts a copy everywhere it's placed.
+//
+// Our BVHs only know about primitives in the world, so before rendering we flatten the graph,
+// working out where everything ends up and copying it there. Instances get copied as well, so they
+// save on writing scenes, not on memory.
+
+/// One of the steps which place a node relative to the node above it.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Transform {
+    Translate(V3),
+    /// Rotate counter-clockwise around an axis through the origin, looking back down the axis.
+    Rotate {
+        axis: V3,
+        degrees: f32,
+    },
+    /// Stretch along each axis. Spheres can only be scaled evenly.
+    Scale(V3),
+    Matrix(M4),
+}
+
+impl Transform {
+    pub fn matrix(&self) -> M4 {
+        match *self {
+            Transform::Translate(offset) => M4::translation(offset),
+            Transform::Rotate { axis, degrees } => M4::rotation(axis, degrees.to_radians()),
+            Transform::Scale(scale) => M4::scaling(scale),
+            Transform::Matrix(matrix) => matrix,
+        }
+    }
+}
+
+/// A node in a scene graph. Its transforms place it relative to the node above it, and its own
+/// primitives and children are placed relative to it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SceneNode {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Applied in order, so `[Scale, Rotate, Translate]` scales things before turning them and
+    /// then moving them into place.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transforms: Vec<Transform>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub primitives: Vec<Primitive>,
+    /// The name of a prototype to place at this node, along with anything else it holds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<SceneNode>,
+}
+
+impl SceneNode {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn named(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    pub fn translate(mut self, offset: V3) -> Self {
+        self.transforms.push(Transform::Translate(offset));
+        self
+    }
+
+    pub fn rotate(mut self, axis: V3, degrees: f32) -> Self {
+        self.transforms.push(Transform::Rotate { axis, degrees });
+        self
+    }
+
+    pub fn scale(mut self, scale: V3) -> Self {
+        self.transforms.push(Transform::Scale(scale));
+        self
+    }
+
+    pub fn matrix(mut self, matrix: M4) -> Self {
+        self.transforms.push(Transform::Matrix(matrix));
+        self
+    }
+
+    pub fn primitive(mut self, primitive: Primitive) -> Self {
+        self.primitives.push(primitive);
+        self
+    }
+
+    pub fn primitives(mut self, primitives: impl IntoIterator<Item = Primitive>) -> Self {
+        self.primitives.extend(primitives);
+        self
+    }
+
+    pub fn child(mut self, child: SceneNode) -> Self {
+        self.children.push(child);
+        self
+    }
+
+    pub fn instance(mut self, prototype: &str) -> Self {
+        self.instance = Some(prototype.to_owned());
+        self
+    }
+
+    /// Where this node sits relative to the node above it.
+    pub fn local_matrix(&self) -> M4 {
+        self.transforms
+            .iter()
+            .fold(M4::identity(), |matrix, transform| {
+                transform.matrix() * matrix
+            })
+    }
+
+    /// Find a node by name, here or anywhere underneath.
+    pub fn find_mut(&mut self, name: &str) -> Option<&mut SceneNode> {
+        if self.name.as_deref() == Some(name) {
+            return Some(self);
+        }
+        self.children
+            .iter_mut()
+            .find_map(|child| child.find_mut(name))
+    }
+}
+
+/// A tree of nodes, and the prototypes they can place copies of.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SceneGraph {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nodes: Vec<SceneNode>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub prototypes: BTreeMap<String, SceneNode>,
+}
+
+impl SceneGraph {
+    pub fn new(nodes: Vec<SceneNode>) -> Self {
+        SceneGraph {
+            nodes,
+            prototypes: BTreeMap::new(),
+        }
+    }
+
+    pub fn prototype(mut self, name: &str, node: SceneNode) -> Self {
+        self.prototypes.insert(name.to_owned(), node);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty() && self.prototypes.is_empty()
+    }
+
+    /// Find a node by name, so it can be moved about along with everything under it. Prototypes
+    /// aren't searched.
+    pub fn find_mut(&mut self, name: &str) -> Option<&mut SceneNode> {
+        self.nodes.iter_mut().find_map(|node| node.find_mut(name))
+    }
+
+    /// Work out where every primitive in the graph ends up in the world.
+    pub fn flatten(&self) -> Result<Vec<Primitive>, String> {
+        let mut primitives = Vec::new();
+        for node in &self.nodes {
+            self.flatten_node(node, M4::identity(), &mut Vec::new(), &mut primitives)?;
+        }
+        Ok(primitives)
+    }
+
+    /// Place a node's primitives, and everything under it, given where the node above it sits.
+    /// `placing` holds the prototypes we're in the middle of placing, so one can't end up inside
+    /// itself.
+    fn flatten_node<'a>(
+        &'a self,
+        node: &'a SceneNode,
+        parent: M4,
+        placing: &mut Vec<&'a str>,
+        primitives: &mut Vec<Primitive>,
+    ) -> Result<(), String> {
+        let matrix = parent * node.local_matrix();
+        for primitive in &node.primitives {
+            primitives.push(
+                primitive
+                    .transformed(&matrix)
+                    .map_err(|e| match &node.name {
+                        Some(name) => format!("{}: {}", name, e),
+                        None => e,
+                    })?,
+            );
+        }
+        if let Some(ref name) = node.instance {
+            if placing.contains(&name.as_str()) {
+                return Err(format!("the prototype '{}' ends up inside itself", name));
+            }
+            let prototype = self
+                .prototypes
+                .get(name)
+                .ok_or_else(|| format!("there's no prototype called '{}'", name))?;
+            placing.push(name);
+            self.flatten_node(prototype, matrix, placing, primitives)?;
+            placing.pop();
+        }
+        for child in &node.children {
+            self.flatten_node(child, matrix, placing, primitives)?;
+        }
+        Ok(())
+    }
+}
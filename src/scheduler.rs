@@ -0,0 +1,275 @@
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use rayon::prelude::*;
+
+use crate::camera::Camera;
+use crate::film::Film;
+use crate::raytracer::Raytracer;
+use crate::sampler::{seed_sampler, stream_seed};
+use crate::traits::Drawable;
+
+use serde::{Deserialize, Serialize};
+
+// Splitting an image into full-width bands means a band across the busy middle of a frame can take far
+// longer than the rest, leaving every other thread idle while it finishes. Small square tiles spread
+// that work out much more evenly. Tiles are handed out to rayon's thread pool one at a time, in
+// whatever order we like, and each one is rendered in place, straight into its own pixels of the
+// one shared film.
+//
+// Tiles can't have quite everything to themselves, though. A tile's filter apron lands on pixels
+// belonging to its neighbours, and splats can land anywhere at all, so each tile keeps those aside
+// and they're all added in once every tile is done. That's only a thin border of pixels per tile,
+// so no tile ever has to wait on another to write.
+
+/// The order in which tiles get rendered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TileOrder {
+    /// Left to right, top to bottom.
+    Scanline,
+    /// Outwards from the center of the image, where the interesting stuff usually is.
+    Spiral,
+    /// Along a Hilbert curve, which keeps consecutive tiles next to each other.
+    Hilbert,
+}
+
+impl FromStr for TileOrder {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scanline" => Ok(Self::Scanline),
+            "spiral" => Ok(Self::Spiral),
+            "hilbert" => Ok(Self::Hilbert),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A rectangle of the image to be rendered as one piece of work.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tile {
+    /// Where this tile falls in the render order.
+    pub index: usize,
+    pub offset: (usize, usize),
+    pub bounds: (usize, usize),
+}
+
+/// Reported each time a tile finishes.
+#[derive(Clone, Copy, Debug)]
+pub struct TileProgress {
+    pub tile: Tile,
+    pub completed: usize,
+    pub total: usize,
+}
+
+/// Renders an image in parallel, one tile at a time.
+#[derive(Clone, Debug)]
+pub struct TileScheduler {
+    tile_size: usize,
+    order: TileOrder,
+    seed: Option<u64>,
+}
+
+impl TileScheduler {
+	/// Builder pattern function to set the width and height of each tile, in pixels.
+    pub fn tile_size(mut self, tile_size: usize) -> Self {
+        self.tile_size = tile_size.max(1);
+        self
+    }
+
+	/// Builder pattern function to set the order tiles are rendered in.
+    pub fn order(mut self, order: TileOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+	/// Builder pattern function to make renders repeatable. Each tile reseeds its thread's sampler from
+	/// this seed and the tile's position, so it doesn't matter which thread picks it up, or when.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+	/// Split an image into tiles, sorted into our render order.
+    pub fn tiles(&self, bounds: (usize, usize)) -> Vec<Tile> {
+        self.tiles_in((0, 0), bounds)
+    }
+
+	/// Split a rectangle of an image into tiles, sorted into our render order.
+    pub fn tiles_in(&self, region_offset: (usize, usize), bounds: (usize, usize)) -> Vec<Tile> {
+        let size = self.tile_size;
+        let n_tiles = (bounds.0.div_ceil(size), bounds.1.div_ceil(size));
+
+        let mut grid: Vec<(usize, usize)> = (0..n_tiles.1)
+            .flat_map(|ty| (0..n_tiles.0).map(move |tx| (tx, ty)))
+            .collect();
+
+        match self.order {
+            TileOrder::Scanline => {}
+            TileOrder::Spiral => {
+                // Work outwards in rings around the center tile, going around each ring by angle.
+                let center = (
+                    (n_tiles.0 as f32 - 1.) / 2.,
+                    (n_tiles.1 as f32 - 1.) / 2.,
+                );
+                let key = |&(tx, ty): &(usize, usize)| {
+                    let (dx, dy) = (tx as f32 - center.0, ty as f32 - center.1);
+                    (dx.abs().max(dy.abs()), dy.atan2(dx))
+                };
+                grid.sort_by(|a, b| {
+                    let (ka, kb) = (key(a), key(b));
+                    ka.0.total_cmp(&kb.0).then(ka.1.total_cmp(&kb.1))
+                });
+            }
+            TileOrder::Hilbert => {
+                let mut side = 1;
+                while side < n_tiles.0.max(n_tiles.1) {
+                    side <<= 1;
+                }
+                grid.sort_by_key(|&(tx, ty)| hilbert_index(side, tx, ty));
+            }
+        }
+
+        grid.into_iter()
+            .enumerate()
+            .map(|(index, (tx, ty))| {
+                let local = (tx * size, ty * size);
+                Tile {
+                    index,
+                    offset: (region_offset.0 + local.0, region_offset.1 + local.1),
+                    bounds: (size.min(bounds.0 - local.0), size.min(bounds.1 - local.1)),
+                }
+            })
+            .collect()
+    }
+
+	/// Render a scene into a film for the whole image. Each tile is rendered straight into the film,
+	/// and `progress` is called as each one finishes.
+    pub fn render<S, P>(
+        &self,
+        rt: &Raytracer,
+        camera: &Camera,
+        scene: &S,
+        bounds: (usize, usize),
+        progress: P,
+    ) -> Film
+    where
+        S: Drawable + Send + Sync,
+        P: Fn(TileProgress) + Send + Sync,
+    {
+        self.render_region(rt, camera, scene, (0, 0), bounds, progress)
+    }
+
+	/// Render a scene into a film covering just one rectangle of the image, the same way as render.
+    pub fn render_region<S, P>(
+        &self,
+        rt: &Raytracer,
+        camera: &Camera,
+        scene: &S,
+        offset: (usize, usize),
+        bounds: (usize, usize),
+        progress: P,
+    ) -> Film
+    where
+        S: Drawable + Send + Sync,
+        P: Fn(TileProgress) + Send + Sync,
+    {
+        let image_bounds = camera.bounds();
+        // Tiles share anything the integrator traces ahead of time, rather than each tracing their own.
+        let rt = &rt.prepared(scene);
+        let mut film = Film::region(image_bounds, offset, bounds, rt.pixel_filter());
+        let tiles = self.tiles_in(offset, bounds);
+        let total = tiles.len();
+        let completed = Mutex::new(0);
+
+        // par_bridge pulls tiles off of the iterator in order as threads free up, so we keep our
+        // render order while rayon keeps every thread busy.
+        let spills: Vec<Film> = tiles
+            .iter()
+            .zip(film.tiles_mut(&tiles))
+            .par_bridge()
+            .map(|(&tile, mut tile_film)| {
+                if let Some(seed) = self.seed {
+                    let position = tile.offset.1 * image_bounds.0 + tile.offset.0;
+                    seed_sampler(stream_seed(seed, position as u64));
+                }
+                rt.render_film(scene, &mut tile_film, camera).unwrap();
+
+                let mut completed = completed.lock().unwrap();
+                *completed += 1;
+                progress(TileProgress {
+                    tile,
+                    completed: *completed,
+                    total,
+                });
+                tile_film.into_spill()
+            })
+            .collect();
+
+        for spill in &spills {
+            film.merge(spill);
+        }
+        film
+    }
+}
+
+impl Default for TileScheduler {
+    fn default() -> Self {
+        Self {
+            tile_size: 32,
+            order: TileOrder::Spiral,
+            seed: None,
+        }
+    }
+}
+
+/// Find how far along a Hilbert curve filling a side x side square the point (x, y) is.
+/// The side needs to be a power of two.
+fn hilbert_index(side: usize, mut x: usize, mut y: usize) -> usize {
+    let mut index = 0;
+    let mut s = side / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as usize;
+        let ry = (y & s > 0) as usize;
+        index += s * s * ((3 * rx) ^ ry);
+        // Rotate the quadrant so the curve lines up with the next level down.
+        if ry == 0 {
+            if rx == 1 {
+                x = side - 1 - x;
+                y = side - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_order_visits_every_tile_once() {
+        // Neither side is a whole number of tiles, or a power of two.
+        let bounds = (23, 13);
+        let scanline = TileScheduler::default()
+            .tile_size(4)
+            .order(TileOrder::Scanline)
+            .tiles(bounds);
+        assert_eq!(scanline.len(), 6 * 4);
+        let area: usize = scanline.iter().map(|t| t.bounds.0 * t.bounds.1).sum();
+        assert_eq!(area, bounds.0 * bounds.1);
+
+        for order in [TileOrder::Spiral, TileOrder::Hilbert] {
+            let tiles = TileScheduler::default().tile_size(4).order(order).tiles(bounds);
+            assert!(tiles.iter().enumerate().all(|(i, tile)| tile.index == i));
+            let mut visited: Vec<_> = tiles.iter().map(|t| (t.offset, t.bounds)).collect();
+            visited.sort();
+            let mut expected: Vec<_> = scanline.iter().map(|t| (t.offset, t.bounds)).collect();
+            expected.sort();
+            assert_eq!(visited, expected, "{:?}", order);
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use std::sync::OnceLock;
+
+use rand::Rng;
+
+use crate::image_handling::PixelF;
+use crate::integrator::{accumulated, power_heuristic, sky_color, Integrator, TraceContext};
+use crate::ray::Ray;
+use crate::sampler::rng;
+use crate::stats;
+use crate::vectors::V3;
+
+// Everything else in the renderer works in RGB, which can't describe glass bending red light less than
+// blue, so prisms and diamonds can't split white light up. The spectral integrator is a path tracer
+// which carries light at a handful of wavelengths instead.
+//
+// Each path picks a hero wavelength at random, plus three more spaced evenly across the visible
+// spectrum from it, and carries all four along, which costs barely more than carrying one. When the
+// path goes through dispersive glass, only the hero wavelength gets refracted by the right amount, so
+// the other three are dropped from then on, and the hero carries the path by itself.
+//
+// Materials and lights still have RGB colours, so we spread those out into smooth spectra. Every
+// wavelength gets a blend of three overlapping bumps, for the blue, green and red ends of the
+// spectrum, which always add up to one, so white comes out perfectly flat. We work out once how each
+// bump looks in RGB, and blend them in whatever amounts give back the colour we started with.
+//
+// At the end, each path's light gets weighted by the CIE 1931 colour matching functions into XYZ,
+// and from there into the linear sRGB the film holds. We use the analytic fit to the matching
+// functions from Wyman, Sloan and Shirley rather than a big table. sRGB expects a D65 white, but our
+// light has no particular illuminant in mind, so we white balance such that light which is equally
+// bright at every wavelength comes out white. That keeps RGB and spectral renders of the same scene
+// looking the same, apart from the rainbows.
+
+/// The shortest wavelength we trace, in nanometres.
+pub const MIN_WAVELENGTH: f32 = 380.;
+/// The longest wavelength we trace, in nanometres.
+pub const MAX_WAVELENGTH: f32 = 780.;
+
+/// How many wavelengths each path carries.
+const WAVELENGTHS: usize = 4;
+
+/// How many wavelengths spectrum_to_rgb looks at.
+const COARSE_WAVELENGTHS: usize = 32;
+
+/// The CIE 1931 colour matching functions at a wavelength in nanometres, as XYZ.
+pub fn cie_xyz(wavelength: f32) -> [f32; 3] {
+    let lobe = |mean: f32, below: f32, above: f32| {
+        let t = (wavelength - mean) / if wavelength < mean { below } else { above };
+        (-0.5 * t * t).exp()
+    };
+    [
+        1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7)
+            - 0.065 * lobe(501.1, 20.4, 26.2),
+        0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+        1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8),
+    ]
+}
+
+/// Turn XYZ into the linear RGB our films hold, white balanced so that a flat spectrum comes out
+/// white.
+pub fn xyz_to_rgb(xyz: [f32; 3]) -> PixelF {
+    let [r, g, b] = xyz_to_srgb(xyz);
+    let white = tables().white;
+    PixelF::rgb(r / white[0], g / white[1], b / white[2])
+}
+
+/// How bright an RGB colour is at some wavelength, in nanometres, once it's spread out into a
+/// smooth spectrum.
+pub fn rgb_to_spectrum(color: PixelF, wavelength: f32) -> f32 {
+    let weights = tables().basis_weights;
+    let rgb = [color.r, color.g, color.b];
+    let bumps = bumps(wavelength);
+    (0..3)
+        .map(|i| bumps[i] * (0..3).map(|j| weights[i][j] * rgb[j]).sum::<f32>())
+        .sum::<f32>()
+        .max(0.)
+}
+
+/// The colour of a whole spectrum, given how bright it is at each wavelength, in nanometres. A flat
+/// spectrum comes out grey. This only looks at a few wavelengths, so it's for spectra which don't
+/// change too sharply, like the reflectance of a thin film.
+pub(crate) fn spectrum_to_rgb(spectrum: impl Fn(f32) -> f32) -> PixelF {
+    let tables = tables();
+    let rgb = xyz_to_srgb(sum_xyz(&tables.coarse, spectrum));
+    let white = tables.coarse_white;
+    PixelF::rgb(
+        (rgb[0] / white[0]).max(0.),
+        (rgb[1] / white[1]).max(0.),
+        (rgb[2] / white[2]).max(0.),
+    )
+}
+
+fn sum_xyz(matching: &[(f32, [f32; 3])], spectrum: impl Fn(f32) -> f32) -> [f32; 3] {
+    let mut xyz = [0.; 3];
+    for &(wavelength, matching) in matching {
+        let value = spectrum(wavelength);
+        for c in 0..3 {
+            xyz[c] += matching[c] * value;
+        }
+    }
+    xyz
+}
+
+/// The sRGB primaries' matrix, without any white balancing.
+fn xyz_to_srgb([x, y, z]: [f32; 3]) -> [f32; 3] {
+    [
+        3.240_454_2 * x - 1.537_138_5 * y - 0.498_531_4 * z,
+        -0.969_266 * x + 1.876_010_8 * y + 0.041_556 * z,
+        0.055_643_4 * x - 0.204_025_9 * y + 1.057_225_2 * z,
+    ]
+}
+
+/// How much of each of our blue, green and red bumps goes into a wavelength. They always add up to
+/// one.
+fn bumps(wavelength: f32) -> [f32; 3] {
+    let smoothstep = |start: f32, end: f32| {
+        let t = ((wavelength - start) / (end - start)).clamp(0., 1.);
+        t * t * (3. - 2. * t)
+    };
+    let blue = 1. - smoothstep(485., 505.);
+    let red = smoothstep(575., 595.);
+    [blue, 1. - blue - red, red]
+}
+
+/// Everything we work out once about the matching functions and our bumps.
+struct Tables {
+    /// The integral of the Y matching function, so a flat spectrum at one has a Y of one.
+    y_integral: f32,
+    /// The unbalanced RGB of a flat spectrum.
+    white: [f32; 3],
+    /// How much of each bump, blue to red, to use for each of red, green and blue.
+    basis_weights: [[f32; 3]; 3],
+    /// The matching functions at a few evenly spaced wavelengths, for spectrum_to_rgb.
+    coarse: [(f32, [f32; 3]); COARSE_WAVELENGTHS],
+    /// The unbalanced RGB of a flat spectrum, added up over just those wavelengths.
+    coarse_white: [f32; 3],
+}
+
+fn tables() -> &'static Tables {
+    static TABLES: OnceLock<Tables> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        // Integrate everything a nanometre at a time.
+        let steps = (MAX_WAVELENGTH - MIN_WAVELENGTH) as usize;
+        let mut flat = [0.; 3];
+        let mut bumped = [[0.; 3]; 3];
+        for step in 0..steps {
+            let wavelength = MIN_WAVELENGTH + step as f32 + 0.5;
+            let xyz = cie_xyz(wavelength);
+            let bumps = bumps(wavelength);
+            for c in 0..3 {
+                flat[c] += xyz[c];
+                for (bump, weight) in bumped.iter_mut().zip(bumps) {
+                    bump[c] += weight * xyz[c];
+                }
+            }
+        }
+        let y_integral = flat[1];
+        let normalize = |xyz: [f32; 3]| xyz.map(|v| v / y_integral);
+        let white = xyz_to_srgb(normalize(flat));
+
+        // The balanced RGB of each bump makes up a column of a matrix which turns amounts of bumps
+        // into colours. Its inverse turns colours back into amounts of bumps.
+        let mut bump_colors = [[0.; 3]; 3];
+        for (bump, xyz) in bumped.into_iter().enumerate() {
+            let rgb = xyz_to_srgb(normalize(xyz));
+            for c in 0..3 {
+                bump_colors[c][bump] = rgb[c] / white[c];
+            }
+        }
+
+        let coarse = std::array::from_fn(|i| {
+            let wavelength = MIN_WAVELENGTH
+                + (i as f32 + 0.5) / COARSE_WAVELENGTHS as f32 * (MAX_WAVELENGTH - MIN_WAVELENGTH);
+            (wavelength, cie_xyz(wavelength))
+        });
+        let coarse_white = xyz_to_srgb(sum_xyz(&coarse, |_| 1.));
+        Tables {
+            y_integral,
+            white,
+            basis_weights: invert(bump_colors),
+            coarse,
+            coarse_white,
+        }
+    })
+}
+
+fn invert(m: [[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    let cofactor =
+        |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    let determinant = m[0][0] * cofactor(1, 2, 1, 2) - m[0][1] * cofactor(1, 2, 0, 2)
+        + m[0][2] * cofactor(1, 2, 0, 1);
+    let inverse = [
+        [
+            cofactor(1, 2, 1, 2),
+            -cofactor(0, 2, 1, 2),
+            cofactor(0, 1, 1, 2),
+        ],
+        [
+            -cofactor(1, 2, 0, 2),
+            cofactor(0, 2, 0, 2),
+            -cofactor(0, 1, 0, 2),
+        ],
+        [
+            cofactor(1, 2, 0, 1),
+            -cofactor(0, 2, 0, 1),
+            cofactor(0, 1, 0, 1),
+        ],
+    ];
+    inverse.map(|row| row.map(|v| v / determinant))
+}
+
+/// Light carried at each of a path's wavelengths.
+#[derive(Clone, Copy, Debug)]
+struct Spectrum([f32; WAVELENGTHS]);
+
+impl Spectrum {
+    fn splat(value: f32) -> Self {
+        Spectrum([value; WAVELENGTHS])
+    }
+
+    fn mul(self, other: Spectrum) -> Self {
+        Spectrum(std::array::from_fn(|i| self.0[i] * other.0[i]))
+    }
+
+    fn scale(self, scalar: f32) -> Self {
+        Spectrum(self.0.map(|v| v * scalar))
+    }
+
+    fn max(&self) -> f32 {
+        self.0.iter().copied().fold(0., f32::max)
+    }
+}
+
+/// The wavelengths a path carries, in nanometres, with the hero first.
+#[derive(Clone, Copy, Debug)]
+struct Wavelengths {
+    wavelengths: [f32; WAVELENGTHS],
+    /// How many are still being carried. Only the hero is left after dispersion.
+    count: usize,
+}
+
+impl Wavelengths {
+    /// Pick a hero wavelength, and space the rest evenly from it, wrapping around.
+    fn sample(rand: &mut impl Rng) -> Self {
+        let range = MAX_WAVELENGTH - MIN_WAVELENGTH;
+        let hero = rand.gen::<f32>();
+        Wavelengths {
+            wavelengths: std::array::from_fn(|i| {
+                MIN_WAVELENGTH + (hero + i as f32 / WAVELENGTHS as f32).fract() * range
+            }),
+            count: WAVELENGTHS,
+        }
+    }
+
+    fn hero(&self) -> f32 {
+        self.wavelengths[0]
+    }
+
+    fn terminate_secondary(&mut self) {
+        self.count = 1;
+    }
+
+    /// Spread an RGB colour out over our wavelengths.
+    fn upsample(&self, color: PixelF) -> Spectrum {
+        Spectrum(
+            self.wavelengths
+                .map(|wavelength| rgb_to_spectrum(color, wavelength)),
+        )
+    }
+
+    /// Turn light carried at our wavelengths into RGB. Every wavelength was equally likely to be
+    /// picked, so this averages over the ones still being carried.
+    fn to_rgb(self, light: Spectrum) -> PixelF {
+        let mut xyz = [0.; 3];
+        for i in 0..self.count {
+            let matching = cie_xyz(self.wavelengths[i]);
+            for c in 0..3 {
+                xyz[c] += matching[c] * light.0[i];
+            }
+        }
+        let scale = (MAX_WAVELENGTH - MIN_WAVELENGTH) / (self.count as f32 * tables().y_integral);
+        xyz_to_rgb(xyz.map(|v| v * scale))
+    }
+}
+
+/// A path tracer which carries light at a few wavelengths rather than in RGB, so dispersive glass
+/// can split light into rainbows.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SpectralIntegrator;
+
+impl Integrator for SpectralIntegrator {
+    fn radiance(&self, mut ray: Ray, context: &TraceContext) -> PixelF {
+        let mut rand = rng();
+        let mut wavelengths = Wavelengths::sample(&mut rand);
+        let mut throughput = Spectrum::splat(1.);
+        // Light found so far is turned into RGB as soon as it's found, since dropping wavelengths
+        // later on changes how it would be weighted.
+        let mut radiance = PixelF::black();
+        let mut last_bounce: Option<(V3, f32)> = None;
+
+        for depth in 0..=context.max_depth {
+            if depth > 0 {
+                stats::record(|s| s.secondary_rays += 1);
+            }
+            let collision = match context.intersect(ray) {
+                Some(collision) => collision,
+                None => {
+                    stats::record(|s| s.path_ended(depth));
+                    let sky = wavelengths.upsample(sky_color(ray)).mul(throughput);
+                    return accumulated(radiance, wavelengths.to_rgb(sky));
+                }
+            };
+            stats::record(|s| s.hits += 1);
+            let point = collision.point();
+
+            if collision.material.emission().is_some() {
+                stats::record(|s| s.path_ended(depth));
+                let emitted = collision.material.emitted(collision.front_facing);
+                let weight = match (last_bounce, context.light(collision.primitive_index)) {
+                    (Some((from, scatter_pdf)), Some(light)) => {
+                        let light_pdf =
+                            light.pdf(from, point, collision.normal) / context.lights.len() as f32;
+                        power_heuristic(scatter_pdf, light_pdf)
+                    }
+                    _ => 1.,
+                };
+                let light = wavelengths.upsample(emitted).mul(throughput).scale(weight);
+                return accumulated(radiance, wavelengths.to_rgb(light));
+            }
+
+            // Next event estimation, just like the path integrator.
+            if collision.evaluate(collision.ray_out.dir).is_some() && !context.lights.is_empty() {
+                let light = &context.lights[rand.gen_range(0..context.lights.len())];
+                if let Some(sample) = light.sample(point, &mut rand) {
+                    let dir_out = sample.point - point;
+                    if let Some((value, scatter_pdf)) = collision.evaluate(dir_out) {
+                        let light_pdf = sample.pdf / context.lights.len() as f32;
+                        if scatter_pdf > 0. && context.visible(point, sample.point) {
+                            let weight = power_heuristic(light_pdf, scatter_pdf) / light_pdf;
+                            let direct = wavelengths
+                                .upsample(value)
+                                .mul(wavelengths.upsample(sample.emission))
+                                .mul(throughput)
+                                .scale(weight);
+                            radiance = accumulated(radiance, wavelengths.to_rgb(direct));
+                        }
+                    }
+                }
+            }
+            // Dispersive glass sends every wavelength off in a different direction, and thin films
+            // reflect each one differently, so we follow the hero and leave the rest behind.
+            let color = if collision.material.is_dispersive() {
+                wavelengths.terminate_secondary();
+                let (ray_out, color) = collision.scatter_wavelength(wavelengths.hero());
+                ray = ray_out;
+                wavelengths.upsample(color)
+            } else {
+                ray = collision.ray_out;
+                wavelengths.upsample(collision.color)
+            };
+            // Only bounces which picked a direction at random can be weighed against sampling lights.
+            let specular = collision.material.scattered_specularly(
+                collision.ray_in.dir,
+                ray.dir,
+                collision.normal,
+            );
+            last_bounce = collision
+                .evaluate(ray.dir)
+                .filter(|_| !specular)
+                .map(|(_, scatter_pdf)| (point, scatter_pdf));
+            throughput = throughput.mul(color);
+            throughput = match context.roulette_scale(depth + 1, throughput.max(), &mut rand) {
+                Some(scale) => throughput.scale(scale),
+                None => return radiance,
+            };
+        }
+
+        stats::record(|s| {
+            s.max_depth_terminations += 1;
+            s.path_ended(context.max_depth + 1);
+        });
+        radiance
+    }
+}
@@ -0,0 +1,123 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+// Counters for what the renderer gets up to, for working out why a scene is slow. They're only
+// collected when the `stats` feature is turned on. Otherwise `record` does nothing at all, and the
+// compiler throws away every call to it.
+//
+// Each thread counts into its own RayStats, so threads never fight over them. Whenever a thread
+// finishes rendering onto a film, it hands its counts over to the film, and merging films together
+// merges their counts too.
+
+/// Counts of everything interesting that happened while rendering.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RayStats {
+    /// Rays cast from the camera.
+    pub primary_rays: u64,
+    /// Rays cast after scattering off of something.
+    pub secondary_rays: u64,
+    /// Rays cast towards lights to check whether they're blocked.
+    pub shadow_rays: u64,
+    pub bvh_nodes_visited: u64,
+    /// Ray-primitive intersection tests, whether or not they hit.
+    pub primitive_tests: u64,
+    /// Rays which hit something.
+    pub hits: u64,
+    /// Paths cut off for reaching the raytracer's max depth.
+    pub max_depth_terminations: u64,
+    /// Paths ended early by Russian roulette.
+    #[serde(default)]
+    pub roulette_terminations: u64,
+    /// How many paths ended after each number of bounces.
+    pub depth_histogram: Vec<u64>,
+}
+
+impl RayStats {
+    /// Whether ray statistics are being collected.
+    pub const ENABLED: bool = cfg!(feature = "stats");
+
+    pub fn total_rays(&self) -> u64 {
+        self.primary_rays + self.secondary_rays + self.shadow_rays
+    }
+
+    /// Note that a path ended after some number of bounces.
+    pub fn path_ended(&mut self, depth: usize) {
+        if self.depth_histogram.len() <= depth {
+            self.depth_histogram.resize(depth + 1, 0);
+        }
+        self.depth_histogram[depth] += 1;
+    }
+
+    /// Add another set of counts to these ones.
+    pub fn merge(&mut self, other: &RayStats) {
+        self.primary_rays += other.primary_rays;
+        self.secondary_rays += other.secondary_rays;
+        self.shadow_rays += other.shadow_rays;
+        self.bvh_nodes_visited += other.bvh_nodes_visited;
+        self.primitive_tests += other.primitive_tests;
+        self.hits += other.hits;
+        self.max_depth_terminations += other.max_depth_terminations;
+        self.roulette_terminations += other.roulette_terminations;
+        if self.depth_histogram.len() < other.depth_histogram.len() {
+            self.depth_histogram.resize(other.depth_histogram.len(), 0);
+        }
+        for (count, other_count) in self.depth_histogram.iter_mut().zip(&other.depth_histogram) {
+            *count += other_count;
+        }
+    }
+}
+
+impl fmt::Display for RayStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let per_ray = |count: u64| count as f64 / self.total_rays().max(1) as f64;
+        writeln!(f, "Rays: {}", self.total_rays())?;
+        writeln!(f, "\tPrimary: {}", self.primary_rays)?;
+        writeln!(f, "\tSecondary: {}", self.secondary_rays)?;
+        writeln!(f, "\tShadow: {}", self.shadow_rays)?;
+        writeln!(f, "Hits: {} ({:.1}% of rays)", self.hits, per_ray(self.hits) * 100.)?;
+        writeln!(
+            f,
+            "BVH nodes visited: {} ({:.2} per ray)",
+            self.bvh_nodes_visited,
+            per_ray(self.bvh_nodes_visited)
+        )?;
+        writeln!(
+            f,
+            "Primitive tests: {} ({:.2} per ray)",
+            self.primitive_tests,
+            per_ray(self.primitive_tests)
+        )?;
+        writeln!(f, "Paths cut off at max depth: {}", self.max_depth_terminations)?;
+        writeln!(f, "Paths ended by Russian roulette: {}", self.roulette_terminations)?;
+        write!(f, "Path lengths:")?;
+        for (depth, count) in self.depth_histogram.iter().enumerate() {
+            if *count > 0 {
+                write!(f, "\n\t{} bounces: {}", depth, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "stats")]
+thread_local! {
+    static THREAD_STATS: std::cell::RefCell<RayStats> = std::cell::RefCell::new(RayStats::default());
+}
+
+/// Update the current thread's counts.
+#[inline(always)]
+pub fn record<F: FnOnce(&mut RayStats)>(update: F) {
+    #[cfg(feature = "stats")]
+    THREAD_STATS.with(|stats| update(&mut stats.borrow_mut()));
+    #[cfg(not(feature = "stats"))]
+    let _ = update;
+}
+
+/// Take everything the current thread has counted so far, leaving it to start again from zero.
+pub fn take_thread_stats() -> RayStats {
+    #[cfg(feature = "stats")]
+    return THREAD_STATS.with(|stats| stats.take());
+    #[cfg(not(feature = "stats"))]
+    RayStats::default()
+}
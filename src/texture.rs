@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::image_handling::{ImageBuffer, PixelF};
+use crate::vectors::V3;
+
+// Textures are images wrapped over surfaces. Spheres and triangles work out texture coordinates
+// (u, v) wherever a ray hits them, along with how the surface runs as u and v change, which gives
+// each hit a tangent frame to go with its normal.
+//
+// Textures can add fine detail to how surfaces are lit, without changing their shape. Normal maps
+// store a normal for every pixel, relative to the tangent frame. Bump maps store a height, and we
+// work out which way the raised surface would face by seeing how the height changes from one pixel
+// to the next.
+//
+// They can also cut shapes out of surfaces, like leaves out of a flat card, by letting rays carry
+// straight on through wherever the texture's transparent.
+//
+// Leaning the normal over can make a surface look like it faces somewhere the real one doesn't, so
+// light could end up bouncing off through the back of it. Collision keeps the real normal around as
+// well, and keeps everything on the right side of it.
+//
+// Lots of triangles share each texture, so loading the same file twice hands back the same pixels.
+// Scene files just hold the path a texture was loaded from.
+
+/// An image wrapped over a surface, looked up by texture coordinates. Coordinates wrap around, so
+/// (1.25, 0.5) is the same spot as (0.25, 0.5), and v runs up the image.
+#[derive(Clone)]
+pub struct Texture {
+    path: Option<String>,
+    image: Arc<ImageBuffer>,
+    /// Whether any of the image is see-through, in which case its opacity comes from its alpha.
+    has_alpha: bool,
+}
+
+impl Texture {
+    /// Load a texture from an image file, or share the one already loaded from there.
+    pub fn load(filename: &str) -> Result<Self, String> {
+        static LOADED: OnceLock<Mutex<HashMap<String, Weak<ImageBuffer>>>> = OnceLock::new();
+        let mut loaded = LOADED.get_or_init(Default::default).lock().unwrap();
+        let image = match loaded.get(filename).and_then(Weak::upgrade) {
+            Some(image) => image,
+            None => {
+                let image = ImageBuffer::load(filename)?;
+                if image.pixels.is_empty() {
+                    return Err(format!("{}: the image is empty", filename));
+                }
+                let image = Arc::new(image);
+                loaded.insert(filename.to_owned(), Arc::downgrade(&image));
+                image
+            }
+        };
+        Ok(Texture {
+            path: Some(filename.to_owned()),
+            has_alpha: image.has_alpha(),
+            image,
+        })
+    }
+
+    /// Make a texture out of an image in memory. These can't be saved to scene files, since there's
+    /// nowhere to load them back from.
+    pub fn from_image(image: ImageBuffer) -> Result<Self, String> {
+        if image.pixels.is_empty() {
+            return Err("textures can't be empty".to_owned());
+        }
+        Ok(Texture {
+            path: None,
+            has_alpha: image.has_alpha(),
+            image: Arc::new(image),
+        })
+    }
+
+    /// The file this texture was loaded from, if it came from one.
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    pub fn resolution(&self) -> (usize, usize) {
+        self.image.bounds
+    }
+
+    /// Whether any of the texture is see-through.
+    pub fn has_alpha(&self) -> bool {
+        self.has_alpha
+    }
+
+    /// The color at some texture coordinates, blended between the nearest four pixels. Alpha gets
+    /// blended along with it.
+    pub fn sample(&self, (u, v): (f32, f32)) -> PixelF {
+        let (width, height) = self.image.bounds;
+        let x = u * width as f32 - 0.5;
+        let y = (1. - v) * height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let pixel = |x: f32, y: f32| {
+            let x = (x as isize).rem_euclid(width as isize) as usize;
+            let y = (y as isize).rem_euclid(height as isize) as usize;
+            self.image.pixels[y * width + x]
+        };
+        let lerp = |a: PixelF, b: PixelF, t: f32| {
+            PixelF::rgba(
+                a.r + (b.r - a.r) * t,
+                a.g + (b.g - a.g) * t,
+                a.b + (b.b - a.b) * t,
+                a.a + (b.a - a.a) * t,
+            )
+        };
+        lerp(
+            lerp(pixel(x0, y0), pixel(x0 + 1., y0), fx),
+            lerp(pixel(x0, y0 + 1.), pixel(x0 + 1., y0 + 1.), fx),
+            fy,
+        )
+    }
+
+    /// How bright the texture is at some texture coordinates, for textures that hold a single value
+    /// rather than a color.
+    pub fn value(&self, uv: (f32, f32)) -> f32 {
+        let color = self.sample(uv);
+        (color.r + color.g + color.b) / 3.
+    }
+
+    /// How solid a surface is at some texture coordinates, when it's cut out with this texture.
+    /// Images with transparency in them go by their alpha, like leaves painted onto a transparent
+    /// background, and ones without go by their brightness, like a black and white mask.
+    pub fn opacity(&self, uv: (f32, f32)) -> f32 {
+        if self.has_alpha {
+            self.sample(uv).a
+        } else {
+            self.value(uv)
+        }
+    }
+}
+
+impl fmt::Debug for Texture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (width, height) = self.image.bounds;
+        match &self.path {
+            Some(path) => write!(f, "Texture({}, {}x{})", path, width, height),
+            None => write!(f, "Texture({}x{})", width, height),
+        }
+    }
+}
+
+/// Textures are the same if they share their pixels.
+impl PartialEq for Texture {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.image, &other.image)
+    }
+}
+
+impl Serialize for Texture {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.path {
+            Some(path) => serializer.serialize_str(path),
+            None => Err(serde::ser::Error::custom(
+                "textures made in memory can't be saved, only ones loaded from files",
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Texture {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let path = String::deserialize(deserializer)?;
+        Texture::load(&path).map_err(D::Error::custom)
+    }
+}
+
+/// A way of changing which way a surface seems to face, without changing its shape.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ShadingMap {
+    /// A tangent-space normal map. Red, green and blue hold how far the normal leans along the
+    /// surface's u and v directions and how far it points out, squeezed from [-1, 1] into [0, 1].
+    /// `strength` scales the lean, with 1 being the map as it was painted.
+    Normal { texture: Texture, strength: f32 },
+    /// A bump map. The texture's brightness is how far the surface is raised, with white being
+    /// `height` units up.
+    Bump { texture: Texture, height: f32 },
+}
+
+impl ShadingMap {
+    /// Lean a unit normal over according to this map, at some texture coordinates, given how the
+    /// surface's position changes as they do.
+    pub fn perturb(&self, normal: V3, uv: (f32, f32), dpdu: V3, dpdv: V3) -> V3 {
+        let perturbed = match self {
+            ShadingMap::Normal { texture, strength } => {
+                let (tangent, bitangent) = tangent_frame(normal, dpdu, dpdv);
+                let color = texture.sample(uv);
+                tangent * ((color.r * 2. - 1.) * strength)
+                    + bitangent * ((color.g * 2. - 1.) * strength)
+                    + normal * (color.b * 2. - 1.)
+            }
+            ShadingMap::Bump { texture, height } => {
+                // Step about half a pixel each way to see how the height changes.
+                let (width, rows) = texture.resolution();
+                let (du, dv) = (0.5 / width as f32, 0.5 / rows as f32);
+                let (u, v) = uv;
+                let here = texture.value(uv) * height;
+                let slope_u = (texture.value((u + du, v)) * height - here) / du;
+                let slope_v = (texture.value((u, v + dv)) * height - here) / dv;
+                let raised_dpdu = dpdu + normal * slope_u;
+                let raised_dpdv = dpdv + normal * slope_v;
+                let raised = raised_dpdu.cross(&raised_dpdv);
+                // Which way the cross product points depends on how u and v run over the surface.
+                if raised.dot(&normal) < 0. {
+                    raised * -1.
+                } else {
+                    raised
+                }
+            }
+        };
+        if perturbed.near_zero() || !perturbed.x.is_finite() {
+            return normal;
+        }
+        perturbed.normalized()
+    }
+}
+
+/// Unit vectors along the surface, one following u and the other following v as closely as they
+/// can while staying at right angles.
+fn tangent_frame(normal: V3, dpdu: V3, dpdv: V3) -> (V3, V3) {
+    let tangent = dpdu - normal * normal.dot(&dpdu);
+    if tangent.near_zero() {
+        return normal.orthonormal_basis();
+    }
+    let tangent = tangent.normalized();
+    let bitangent = normal.cross(&tangent);
+    if bitangent.dot(&dpdv) < 0. {
+        (tangent, bitangent * -1.)
+    } else {
+        (tangent, bitangent)
+    }
+}
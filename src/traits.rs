@@ -1,16 +1,42 @@
 use std::fmt::Debug;
 
-use crate::bounded_volume_hierarchy::Bounds;
+use crate::bounded_volume_hierarchy::{Bounds, TraversalCost};
 use crate::camera::Camera;
+use crate::frame_buffer::FirstHit;
 use crate::image_handling::PixelF;
+use crate::light::Light;
 use crate::ray::Ray;
 use crate::raytracer::Collision;
+use crate::stats::RayStats;
 
 /// relatively generic way of using canvases, so that we can adapt to use a variety of output methods.
 pub trait Canvas {
     fn put_pixel(&mut self, x: usize, y: usize, pixel: PixelF);
     fn bounds(&self) -> (usize, usize);
     fn offset(&self) -> (usize, usize);
+
+    /// Whether this canvas wants to know about the first thing hit through each pixel.
+    /// Renderers can skip working that out when it isn't wanted.
+    fn records_first_hits(&self) -> bool {
+        false
+    }
+
+    fn put_first_hit(&mut self, _x: usize, _y: usize, _hit: Option<FirstHit>) {}
+}
+
+/// Somewhere a renderer can put filtered samples and splats: a whole Film, or one tile's share of
+/// one.
+pub trait SampleSink {
+    /// The size of the whole image the samples are for.
+    fn image_bounds(&self) -> (usize, usize);
+    /// The first pixel to take samples for.
+    fn offset(&self) -> (usize, usize);
+    /// How many pixels to take samples for.
+    fn bounds(&self) -> (usize, usize);
+    fn add_sample(&mut self, x: f32, y: f32, color: PixelF);
+    fn add_splat(&mut self, x: f32, y: f32, color: PixelF);
+    /// Keep track of what it took to render the samples, when the stats feature is on.
+    fn add_stats(&mut self, stats: &RayStats);
 }
 
 /// This didn't really need to be a trait, but I do have dreams of implementing a rasterizer to be used
@@ -25,10 +51,28 @@ pub trait Renderer {
 }
 
 /// This trait describes anything that can be intersected with, and as such drawn by our raytracer.
-/// Notable items that fit this are Primitives, collections of Primitives (there's a helper method here for exactly that),
-/// and our BVH and LinearBVH.
+/// Notable items that fit this are Scenes, which are collections of Primitives alongside their
+/// materials, and our BVH and LinearBVH.
 pub trait Drawable {
     fn intersect(&self, ray: Ray) -> Option<Collision>;
+
+    /// The lights among whatever's being drawn, sorted by primitive index. Integrators sample
+    /// these directly. Most drawables don't know about primitive indices, so they have none.
+    fn lights(&self) -> Vec<Light> {
+        Vec::new()
+    }
+
+    /// Whether anything being drawn is a matte, like a holdout, which the camera treats specially.
+    /// Renderers skip checking what each camera ray hits first when there aren't any.
+    fn has_mattes(&self) -> bool {
+        false
+    }
+}
+
+/// Something drawable which can also say how much work it took to intersect a ray. This is what
+/// the traversal-cost heatmap draws, to show off badly built acceleration structures.
+pub trait Traversable: Drawable {
+    fn intersect_with_cost(&self, ray: Ray) -> (Option<Collision>, TraversalCost);
 }
 
 /// Intersect a collection of Drawables. This should be a generic trait implementation, but I can't 
@@ -52,6 +96,14 @@ pub trait Boundable: Drawable {
     fn bounds(&self) -> Bounds;
 }
 
+/// Something with an inside, like the boundary of a medium. It only needs to say where rays cross
+/// its surface, not what the surface is made of.
+pub trait Boundary {
+    /// How far along a ray it first crosses the surface, within the ray's range.
+    fn crossing(&self, ray: Ray) -> Option<f32>;
+    fn bounds(&self) -> Bounds;
+}
+
 /// This went unused, but was a generic weighted mean trait, allowing the operation to be done
 /// on a variety of iterators.
 pub trait WeightedMean<T = Self>: Sized {
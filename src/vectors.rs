@@ -7,6 +7,7 @@ use std::ops::Mul;
 use std::ops::Sub;
 
 use crate::traits::WeightedMean;
+use crate::sampler::rng;
 
 use serde::{Serialize, Deserialize};
 
@@ -99,12 +100,20 @@ impl V3 {
         other.normalized() * self.scalar_projection(other)
     }
 
+    /// Build two unit vectors which, along with this (normalized) vector, form an orthonormal basis.
+    pub fn orthonormal_basis(&self) -> (V3, V3) {
+        let helper = if self.x.abs() > 0.9 { V3::y() } else { V3::x() };
+        let tangent = self.cross(&helper).normalized();
+        let bitangent = self.cross(&tangent).normalized();
+        (tangent, bitangent)
+    }
+
     pub fn near_zero(&self) -> bool {
         self.x.abs() < EPLISON && self.y.abs() < EPLISON && self.z.abs() < EPLISON
     }
 
     pub fn random() -> V3 {
-        let mut rand = rand::thread_rng();
+        let mut rand = rng();
         V3 {
             x: rand.gen(),
             y: rand.gen(),
@@ -115,7 +124,7 @@ impl V3 {
     pub fn random_in_range(min: f32, max: f32) -> V3 {
         assert!(min >= 0.0 && max >= 0.0);
         let delta = max - min;
-        let mut rand = rand::thread_rng();
+        let mut rand = rng();
         V3 {
             x: rand.gen::<f32>() * delta + min,
             y: rand.gen::<f32>() * delta + min,
@@ -123,16 +132,25 @@ impl V3 {
         }
     }
 
+    /// A random point inside the unit sphere. We pick points in the cube around it until one lands inside.
     pub fn random_in_unit_sphere() -> V3 {
-        let mut attempt = V3::random();
-        while attempt.dot(&attempt) > 1.0 {
-            attempt = V3::random();
+        loop {
+            let attempt = V3::random() * 2. - V3::one();
+            if attempt.dot(&attempt) <= 1.0 {
+                return attempt;
+            }
         }
-        attempt
     }
 
+    /// A random direction, spread evenly over every direction there is.
     pub fn random_on_unit_sphere() -> V3 {
-        V3::random().normalized()
+        loop {
+            let attempt = V3::random_in_unit_sphere();
+            // Points right at the center don't point anywhere in particular.
+            if attempt.dot(&attempt) > 1e-6 {
+                return attempt.normalized();
+            }
+        }
     }
 }
 
@@ -203,6 +221,134 @@ impl std::fmt::Display for V3 {
     }
 }
 
+/// M4 is a 4x4 matrix, for moving, turning and stretching things about. It's stored as rows, and
+/// points and directions go on its right as columns, so `a * b` does whatever b does first.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct M4(pub [[f32; 4]; 4]);
+
+impl M4 {
+    pub fn identity() -> M4 {
+        M4::scaling(V3::one())
+    }
+
+    pub fn translation(offset: V3) -> M4 {
+        M4([
+            [1., 0., 0., offset.x],
+            [0., 1., 0., offset.y],
+            [0., 0., 1., offset.z],
+            [0., 0., 0., 1.],
+        ])
+    }
+
+    pub fn scaling(scale: V3) -> M4 {
+        M4([
+            [scale.x, 0., 0., 0.],
+            [0., scale.y, 0., 0.],
+            [0., 0., scale.z, 0.],
+            [0., 0., 0., 1.],
+        ])
+    }
+
+    /// Rotate counter-clockwise by some angle in radians, looking back down the axis at the origin.
+    pub fn rotation(axis: V3, angle: f32) -> M4 {
+        let V3 { x, y, z } = axis.normalized();
+        let (sin, cos) = angle.sin_cos();
+        let t = 1. - cos;
+        M4([
+            [t * x * x + cos, t * x * y - sin * z, t * x * z + sin * y, 0.],
+            [t * x * y + sin * z, t * y * y + cos, t * y * z - sin * x, 0.],
+            [t * x * z - sin * y, t * y * z + sin * x, t * z * z + cos, 0.],
+            [0., 0., 0., 1.],
+        ])
+    }
+
+    pub fn transpose(&self) -> M4 {
+        let mut out = [[0.; 4]; 4];
+        for (i, row) in out.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = self.0[j][i];
+            }
+        }
+        M4(out)
+    }
+
+    /// The inverse of this matrix, if it doesn't squash everything down flat. We use Gauss-Jordan
+    /// elimination, picking the biggest pivot in each column to keep rounding error down.
+    pub fn inverse(&self) -> Option<M4> {
+        let mut m = self.0;
+        let mut inverse = M4::identity().0;
+        for column in 0..4 {
+            let pivot = (column..4)
+                .max_by(|&a, &b| m[a][column].abs().total_cmp(&m[b][column].abs()))
+                .unwrap();
+            if m[pivot][column].abs() < 1e-12 {
+                return None;
+            }
+            m.swap(column, pivot);
+            inverse.swap(column, pivot);
+
+            let scale = 1. / m[column][column];
+            for j in 0..4 {
+                m[column][j] *= scale;
+                inverse[column][j] *= scale;
+            }
+            for row in 0..4 {
+                let factor = m[row][column];
+                if row == column || factor == 0. {
+                    continue;
+                }
+                for j in 0..4 {
+                    m[row][j] -= factor * m[column][j];
+                    inverse[row][j] -= factor * inverse[column][j];
+                }
+            }
+        }
+        Some(M4(inverse))
+    }
+
+    /// The determinant of the top left 3x3 part, which is how much volumes get scaled by. It's
+    /// negative when things get mirrored.
+    pub fn determinant_3x3(&self) -> f32 {
+        let m = &self.0;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    /// Move a point, including by any translation.
+    pub fn transform_point(&self, point: V3) -> V3 {
+        let m = &self.0;
+        let row = |r: usize| m[r][0] * point.x + m[r][1] * point.y + m[r][2] * point.z + m[r][3];
+        let w = row(3);
+        let point = V3::new(row(0), row(1), row(2));
+        if w != 1. && w != 0. {
+            point / w
+        } else {
+            point
+        }
+    }
+
+    /// Turn and stretch a direction. Directions don't have a position, so they don't get moved.
+    pub fn transform_vector(&self, vector: V3) -> V3 {
+        let m = &self.0;
+        let row = |r: usize| m[r][0] * vector.x + m[r][1] * vector.y + m[r][2] * vector.z;
+        V3::new(row(0), row(1), row(2))
+    }
+}
+
+impl Mul for M4 {
+    type Output = M4;
+
+    fn mul(self, rhs: M4) -> Self::Output {
+        let mut out = [[0.; 4]; 4];
+        for (i, row) in out.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = (0..4).map(|k| self.0[i][k] * rhs.0[k][j]).sum();
+            }
+        }
+        M4(out)
+    }
+}
 
 /// This went unused, but I briefly toyed with the idea of partitioning a BVH across arbitrary planes,
 /// rather than axis-aligned ones. This would have made comparisons significantly more expensive with
@@ -234,3 +380,53 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_2;
+
+    fn assert_near(a: V3, b: V3) {
+        assert!((a - b).magnitude() < 1e-5, "{} vs {}", a, b);
+    }
+
+    #[test]
+    fn inverse_undoes_the_matrix() {
+        let matrix = M4::translation(V3::new(1., -2., 3.))
+            * M4::rotation(V3::new(1., 1., 0.), 0.7)
+            * M4::scaling(V3::new(2., 3., 0.5));
+        let inverse = matrix.inverse().unwrap();
+        for product in [inverse * matrix, matrix * inverse] {
+            for (i, row) in product.0.iter().enumerate() {
+                for (j, value) in row.iter().enumerate() {
+                    let expected = if i == j { 1. } else { 0. };
+                    assert!((value - expected).abs() < 1e-5, "{:?}", product);
+                }
+            }
+        }
+        let point = V3::new(0.3, -4., 2.);
+        assert_near(inverse.transform_point(matrix.transform_point(point)), point);
+
+        assert!(M4::scaling(V3::new(1., 0., 1.)).inverse().is_none());
+    }
+
+    #[test]
+    fn rotations_turn_counter_clockwise() {
+        let quarter = |axis: V3| M4::rotation(axis, FRAC_PI_2);
+        assert_near(quarter(V3::z()).transform_vector(V3::x()), V3::y());
+        assert_near(quarter(V3::x()).transform_vector(V3::y()), V3::z());
+        assert_near(quarter(V3::y()).transform_vector(V3::z()), V3::x());
+        // The axis doesn't need to be normalized, and points on it stay put.
+        assert_near(quarter(V3::z() * 5.).transform_point(V3::z() * 2.), V3::z() * 2.);
+
+        // Rotating never stretches anything, so the inverse is just the transpose.
+        let rotation = M4::rotation(V3::new(1., 2., 3.), 1.2);
+        let product = rotation * rotation.transpose();
+        for (i, row) in product.0.iter().enumerate() {
+            for (j, value) in row.iter().enumerate() {
+                assert!((value - if i == j { 1. } else { 0. }).abs() < 1e-5);
+            }
+        }
+        assert!((rotation.determinant_3x3() - 1.).abs() < 1e-5);
+    }
+}